@@ -0,0 +1,67 @@
+//! Decoding of events emitted by programs built with Anchor.
+//!
+//! Anchor programs emit events in one of two ways: `emit!` logs the event
+//! as a `Program data:` line, and `emit_cpi!` passes the event as the data
+//! of an instruction the program invokes on itself.  In both cases the event
+//! is encoded as an 8-byte discriminator followed by the Borsh-serialized
+//! event.
+
+use crate::{instruction::InstructionUpdate, logs::LogLine, ParseResult};
+
+/// The tag prefixing the data of the self-invoked instructions used by
+/// `emit_cpi!`.
+pub const EVENT_IX_TAG: [u8; 8] = [228, 69, 165, 46, 81, 203, 154, 29];
+
+/// An event type emitted by an Anchor program.
+pub trait AnchorEvent: Sized {
+    /// The discriminator identifying this event, which is the first 8 bytes
+    /// of the SHA-256 hash of `event:<EventName>`.
+    const DISCRIMINATOR: [u8; 8];
+
+    /// Decode the event from the bytes following its discriminator.
+    ///
+    /// # Errors
+    /// Returns an error if the event data is malformed.
+    fn decode(data: &[u8]) -> ParseResult<Self>;
+}
+
+/// Decode an event from its discriminator and data, returning `None` if the
+/// discriminator does not match `E`.
+#[must_use]
+pub fn decode_event<E: AnchorEvent>(data: &[u8]) -> Option<ParseResult<E>> {
+    data.strip_prefix(&E::DISCRIMINATOR).map(E::decode)
+}
+
+/// Decode an event emitted with `emit_cpi!` from the self-invoked instruction
+/// carrying it, returning `None` if the instruction does not carry an event
+/// of type `E`.
+#[must_use]
+pub fn decode_cpi_event<E: AnchorEvent>(ix: &InstructionUpdate) -> Option<ParseResult<E>> {
+    if ix.parent_program != Some(ix.program) {
+        return None;
+    }
+
+    decode_event(ix.data.strip_prefix(&EVENT_IX_TAG)?)
+}
+
+/// Decode every event of type `E` emitted by an instruction, first those
+/// logged with `emit!` and then those emitted with `emit_cpi!`.
+///
+/// Events logged with `emit!` are only found if the instruction's logs could
+/// be recovered, see [`InstructionUpdate::logs`].
+///
+/// # Errors
+/// Returns an error if any matching event is malformed.
+pub fn events<E: AnchorEvent>(ix: &InstructionUpdate) -> ParseResult<Vec<E>> {
+    let logged = ix
+        .logs()
+        .into_iter()
+        .flat_map(|l| &l.lines)
+        .filter_map(|l| match l {
+            LogLine::Data(fields) => fields.first().and_then(|d| decode_event::<E>(d)),
+            _ => None,
+        });
+    let emitted = ix.inner.iter().filter_map(decode_cpi_event::<E>);
+
+    logged.chain(emitted).collect()
+}
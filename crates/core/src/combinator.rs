@@ -0,0 +1,529 @@
+//! Adapters for composing parsers, returned by the combinator methods on
+//! [`Parser`].
+//!
+//! Adapters wrapping a single parser keep its prefilter, and adapters
+//! combining two parsers merge their prefilters with [`Prefilter::merge`].
+//! Each adapter derives its ID from the IDs of the parsers it wraps.  Since
+//! closures cannot be told apart, two adapters of the same kind over the same
+//! parser share an ID; use [`Parser::named`] to distinguish them when both
+//! are registered with the same runtime.
+
+use std::{borrow::Cow, fmt, future::Future};
+
+use crate::{DataSlices, ParseError, ParseResult, Parser, Prefilter, ProgramParser, Pubkey};
+
+/// A parser transforming each parsed value.  See [`Parser::map`].
+#[derive(Clone, Copy)]
+pub struct Map<P, F> {
+    pub(crate) parser: P,
+    pub(crate) f: F,
+}
+
+impl<P: fmt::Debug, F> fmt::Debug for Map<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Map")
+            .field("parser", &self.parser)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, F, U> Parser for Map<P, F>
+where
+    P: Parser + Sync,
+    F: Fn(P::Output) -> U + Sync,
+{
+    type Input = P::Input;
+    type Output = U;
+
+    fn id(&self) -> Cow<'_, str> { format!("Map({})", self.parser.id()).into() }
+
+    fn prefilter(&self) -> Prefilter { self.parser.prefilter() }
+
+    fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse(value);
+        async move { fut.await.map(&self.f) }
+    }
+
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse_sliced(value, slices);
+        async move { fut.await.map(&self.f) }
+    }
+}
+
+impl<P, F, U> ProgramParser for Map<P, F>
+where
+    P: ProgramParser + Sync,
+    F: Fn(P::Output) -> U + Sync,
+{
+    #[inline]
+    fn program_id(&self) -> Pubkey { self.parser.program_id() }
+}
+
+/// A parser discarding parsed values that do not match a predicate.  See
+/// [`Parser::filter`].
+#[derive(Clone, Copy)]
+pub struct Filter<P, F> {
+    pub(crate) parser: P,
+    pub(crate) f: F,
+}
+
+impl<P: fmt::Debug, F> fmt::Debug for Filter<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filter")
+            .field("parser", &self.parser)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, F> Filter<P, F>
+where
+    P: Parser,
+    F: Fn(&P::Output) -> bool,
+{
+    fn check(&self, res: ParseResult<P::Output>) -> ParseResult<P::Output> {
+        res.and_then(|v| {
+            if (self.f)(&v) {
+                Ok(v)
+            } else {
+                Err(ParseError::Filtered)
+            }
+        })
+    }
+}
+
+impl<P, F> Parser for Filter<P, F>
+where
+    P: Parser + Sync,
+    F: Fn(&P::Output) -> bool + Sync,
+{
+    type Input = P::Input;
+    type Output = P::Output;
+
+    fn id(&self) -> Cow<'_, str> { format!("Filter({})", self.parser.id()).into() }
+
+    fn prefilter(&self) -> Prefilter { self.parser.prefilter() }
+
+    fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<P::Output>> + Send {
+        let fut = self.parser.parse(value);
+        async move { self.check(fut.await) }
+    }
+
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<P::Output>> + Send {
+        let fut = self.parser.parse_sliced(value, slices);
+        async move { self.check(fut.await) }
+    }
+}
+
+impl<P, F> ProgramParser for Filter<P, F>
+where
+    P: ProgramParser + Sync,
+    F: Fn(&P::Output) -> bool + Sync,
+{
+    #[inline]
+    fn program_id(&self) -> Pubkey { self.parser.program_id() }
+}
+
+/// A parser transforming each parsed value and discarding those for which
+/// the transformation returns `None`.  See [`Parser::filter_map`].
+#[derive(Clone, Copy)]
+pub struct FilterMap<P, F> {
+    pub(crate) parser: P,
+    pub(crate) f: F,
+}
+
+impl<P: fmt::Debug, F> fmt::Debug for FilterMap<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilterMap")
+            .field("parser", &self.parser)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, F, U> Parser for FilterMap<P, F>
+where
+    P: Parser + Sync,
+    F: Fn(P::Output) -> Option<U> + Sync,
+{
+    type Input = P::Input;
+    type Output = U;
+
+    fn id(&self) -> Cow<'_, str> { format!("FilterMap({})", self.parser.id()).into() }
+
+    fn prefilter(&self) -> Prefilter { self.parser.prefilter() }
+
+    fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse(value);
+        async move {
+            fut.await
+                .and_then(|v| (self.f)(v).ok_or(ParseError::Filtered))
+        }
+    }
+
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse_sliced(value, slices);
+        async move {
+            fut.await
+                .and_then(|v| (self.f)(v).ok_or(ParseError::Filtered))
+        }
+    }
+}
+
+impl<P, F, U> ProgramParser for FilterMap<P, F>
+where
+    P: ProgramParser + Sync,
+    F: Fn(P::Output) -> Option<U> + Sync,
+{
+    #[inline]
+    fn program_id(&self) -> Pubkey { self.parser.program_id() }
+}
+
+/// A parser passing each parsed value through a fallible transformation.
+/// See [`Parser::and_then`].
+#[derive(Clone, Copy)]
+pub struct AndThen<P, F> {
+    pub(crate) parser: P,
+    pub(crate) f: F,
+}
+
+impl<P: fmt::Debug, F> fmt::Debug for AndThen<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AndThen")
+            .field("parser", &self.parser)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, F, U> Parser for AndThen<P, F>
+where
+    P: Parser + Sync,
+    F: Fn(P::Output) -> ParseResult<U> + Sync,
+{
+    type Input = P::Input;
+    type Output = U;
+
+    fn id(&self) -> Cow<'_, str> { format!("AndThen({})", self.parser.id()).into() }
+
+    fn prefilter(&self) -> Prefilter { self.parser.prefilter() }
+
+    fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse(value);
+        async move { fut.await.and_then(&self.f) }
+    }
+
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<U>> + Send {
+        let fut = self.parser.parse_sliced(value, slices);
+        async move { fut.await.and_then(&self.f) }
+    }
+}
+
+impl<P, F, U> ProgramParser for AndThen<P, F>
+where
+    P: ProgramParser + Sync,
+    F: Fn(P::Output) -> ParseResult<U> + Sync,
+{
+    #[inline]
+    fn program_id(&self) -> Pubkey { self.parser.program_id() }
+}
+
+/// A parser trying a second parser on updates the first one filtered out.
+/// See [`Parser::or`].
+#[derive(Debug, Clone, Copy)]
+pub struct Or<A, B> {
+    pub(crate) first: A,
+    pub(crate) second: B,
+}
+
+impl<A, B> Parser for Or<A, B>
+where
+    A: Parser + Sync,
+    A::Input: Sync,
+    A::Output: Send,
+    B: Parser<Input = A::Input, Output = A::Output> + Sync,
+{
+    type Input = A::Input;
+    type Output = A::Output;
+
+    fn id(&self) -> Cow<'_, str> { format!("Or({}, {})", self.first.id(), self.second.id()).into() }
+
+    fn prefilter(&self) -> Prefilter {
+        [self.first.prefilter(), self.second.prefilter()]
+            .into_iter()
+            .collect()
+    }
+
+    async fn parse(&self, value: &Self::Input) -> ParseResult<A::Output> {
+        match self.first.parse(value).await {
+            Err(ParseError::Filtered) => self.second.parse(value).await,
+            res => res,
+        }
+    }
+
+    async fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> ParseResult<A::Output> {
+        match self.first.parse_sliced(value, slices).await {
+            Err(ParseError::Filtered) => self.second.parse_sliced(value, slices).await,
+            res => res,
+        }
+    }
+}
+
+/// A parser running two parsers on each update and producing both of their
+/// parsed values.  See [`Parser::zip`].
+#[derive(Debug, Clone, Copy)]
+pub struct Zip<A, B> {
+    pub(crate) first: A,
+    pub(crate) second: B,
+}
+
+impl<A, B> Parser for Zip<A, B>
+where
+    A: Parser + Sync,
+    A::Input: Sync,
+    A::Output: Send,
+    B: Parser<Input = A::Input> + Sync,
+{
+    type Input = A::Input;
+    type Output = (A::Output, B::Output);
+
+    fn id(&self) -> Cow<'_, str> {
+        format!("Zip({}, {})", self.first.id(), self.second.id()).into()
+    }
+
+    fn prefilter(&self) -> Prefilter {
+        [self.first.prefilter(), self.second.prefilter()]
+            .into_iter()
+            .collect()
+    }
+
+    async fn parse(&self, value: &Self::Input) -> ParseResult<Self::Output> {
+        let first = self.first.parse(value).await?;
+        let second = self.second.parse(value).await?;
+        Ok((first, second))
+    }
+
+    async fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> ParseResult<Self::Output> {
+        let first = self.first.parse_sliced(value, slices).await?;
+        let second = self.second.parse_sliced(value, slices).await?;
+        Ok((first, second))
+    }
+}
+
+/// A parser with its ID replaced.  See [`Parser::named`].
+#[derive(Debug, Clone)]
+pub struct Named<P> {
+    pub(crate) parser: P,
+    pub(crate) id: Cow<'static, str>,
+}
+
+impl<P: Parser> Parser for Named<P> {
+    type Input = P::Input;
+    type Output = P::Output;
+
+    fn id(&self) -> Cow<'_, str> { Cow::Borrowed(&self.id) }
+
+    fn prefilter(&self) -> Prefilter { self.parser.prefilter() }
+
+    #[inline]
+    fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<P::Output>> + Send {
+        self.parser.parse(value)
+    }
+
+    #[inline]
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<P::Output>> + Send {
+        self.parser.parse_sliced(value, slices)
+    }
+}
+
+impl<P: ProgramParser> ProgramParser for Named<P> {
+    #[inline]
+    fn program_id(&self) -> Pubkey { self.parser.program_id() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    /// A parser accepting numbers with the given remainder mod 2, failing on
+    /// zero.
+    #[derive(Debug, Clone, Copy)]
+    struct Parity {
+        id: &'static str,
+        rem: u64,
+        owner: u8,
+    }
+
+    const EVEN: Parity = Parity {
+        id: "Even",
+        rem: 0,
+        owner: 1,
+    };
+    const ODD: Parity = Parity {
+        id: "Odd",
+        rem: 1,
+        owner: 2,
+    };
+
+    impl Parser for Parity {
+        type Input = u64;
+        type Output = u64;
+
+        fn id(&self) -> Cow<'_, str> { self.id.into() }
+
+        fn prefilter(&self) -> Prefilter {
+            Prefilter::builder()
+                .account_owners([[self.owner; 32]])
+                .build()
+                .unwrap()
+        }
+
+        async fn parse(&self, value: &u64) -> ParseResult<u64> {
+            match *value {
+                0 => Err("zero".into()),
+                v if v % 2 == self.rem => Ok(v),
+                _ => Err(ParseError::Filtered),
+            }
+        }
+    }
+
+    /// Parse a value, returning `None` if it was filtered out and the error
+    /// message if parsing failed.
+    fn parse<P: Parser<Input = u64>>(parser: &P, value: u64) -> Result<Option<P::Output>, String> {
+        let mut fut = pin!(parser.parse(&value));
+        let Poll::Ready(res) = fut.as_mut().poll(&mut Context::from_waker(Waker::noop())) else {
+            panic!("Parser did not complete");
+        };
+
+        match res {
+            Ok(v) => Ok(Some(v)),
+            Err(ParseError::Filtered) => Ok(None),
+            Err(ParseError::Other(e)) => Err(e.to_string()),
+        }
+    }
+
+    fn owners(prefilter: &Prefilter) -> Vec<String> {
+        let mut owners: Vec<_> = prefilter
+            .account_selection()
+            .unwrap()
+            .owners
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        owners.sort_unstable();
+        owners
+    }
+
+    #[test]
+    fn test_map() {
+        let parser = EVEN.map(|v| v * 10);
+        assert_eq!(parse(&parser, 4), Ok(Some(40)));
+        assert_eq!(parse(&parser, 3), Ok(None));
+        assert_eq!(parse(&parser, 0), Err("zero".into()));
+        assert_eq!(parser.id(), "Map(Even)");
+        assert_eq!(owners(&parser.prefilter()), owners(&EVEN.prefilter()));
+    }
+
+    #[test]
+    fn test_filter() {
+        let parser = EVEN.filter(|&v| v > 2);
+        assert_eq!(parse(&parser, 4), Ok(Some(4)));
+        assert_eq!(parse(&parser, 2), Ok(None));
+        assert_eq!(parse(&parser, 3), Ok(None));
+        assert_eq!(parser.id(), "Filter(Even)");
+        assert_eq!(owners(&parser.prefilter()), owners(&EVEN.prefilter()));
+
+        let parser = EVEN.filter_map(|v| (v > 2).then(|| v.to_string()));
+        assert_eq!(parse(&parser, 4), Ok(Some("4".into())));
+        assert_eq!(parse(&parser, 2), Ok(None));
+        assert_eq!(parser.id(), "FilterMap(Even)");
+        assert_eq!(owners(&parser.prefilter()), owners(&EVEN.prefilter()));
+    }
+
+    #[test]
+    fn test_and_then() {
+        let parser = EVEN.and_then(|v| {
+            if v > 2 {
+                Ok(v / 2)
+            } else {
+                Err("small".into())
+            }
+        });
+        assert_eq!(parse(&parser, 4), Ok(Some(2)));
+        assert_eq!(parse(&parser, 2), Err("small".into()));
+        assert_eq!(parse(&parser, 3), Ok(None));
+        assert_eq!(parser.id(), "AndThen(Even)");
+        assert_eq!(owners(&parser.prefilter()), owners(&EVEN.prefilter()));
+    }
+
+    #[test]
+    fn test_or() {
+        let parser = EVEN.or(ODD);
+        assert_eq!(parse(&parser, 2), Ok(Some(2)));
+        assert_eq!(parse(&parser, 3), Ok(Some(3)));
+        // Errors other than filtering are not retried
+        assert_eq!(parse(&parser, 0), Err("zero".into()));
+        assert_eq!(parser.id(), "Or(Even, Odd)");
+
+        let mut merged = EVEN.prefilter();
+        merged.merge(ODD.prefilter());
+        assert_eq!(owners(&parser.prefilter()), owners(&merged));
+        assert_eq!(owners(&merged).len(), 2);
+    }
+
+    #[test]
+    fn test_zip() {
+        let parser = EVEN.zip(EVEN.map(|v| v + 1));
+        assert_eq!(parse(&parser, 2), Ok(Some((2, 3))));
+        assert_eq!(parse(&parser, 3), Ok(None));
+        assert_eq!(parser.id(), "Zip(Even, Map(Even))");
+        assert_eq!(owners(&parser.prefilter()), owners(&EVEN.prefilter()));
+
+        // Both parsers must accept the update
+        let parser = EVEN.zip(ODD);
+        assert_eq!(parse(&parser, 2), Ok(None));
+        assert_eq!(parse(&parser, 3), Ok(None));
+        assert_eq!(owners(&parser.prefilter()).len(), 2);
+    }
+
+    #[test]
+    fn test_named() {
+        let parser = EVEN.or(ODD).map(|v| v + 1).named("Successor");
+        assert_eq!(parse(&parser, 2), Ok(Some(3)));
+        assert_eq!(parser.id(), "Successor");
+        assert_eq!(
+            owners(&parser.prefilter()),
+            owners(&EVEN.or(ODD).prefilter())
+        );
+    }
+}
@@ -1,16 +1,25 @@
 //! Helpers for parsing transaction updates into instructions.
 
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, OnceLock},
+};
 
 use yellowstone_grpc_proto::{
     geyser::SubscribeUpdateTransactionInfo,
     solana::storage::confirmed_block::{
-        CompiledInstruction, InnerInstruction, InnerInstructions, Message, Reward, TokenBalance,
-        Transaction, TransactionError, TransactionStatusMeta,
+        CompiledInstruction, InnerInstruction, InnerInstructions, Message, MessageHeader,
+        ReturnData as RawReturnData, Reward, TokenBalance, Transaction, TransactionError,
+        TransactionStatusMeta,
     },
 };
 
-use crate::{Pubkey, TransactionUpdate};
+use crate::{
+    anchor::{self, AnchorEvent},
+    logs::{self, Invocation, InvocationLogs},
+    BlockUpdate, Pubkey, TransactionUpdate,
+};
 
 /// Errors that can occur when parsing a transaction update into instructions.
 #[derive(Debug, Clone, Copy, thiserror::Error)]
@@ -24,6 +33,9 @@ pub enum ParseError {
     /// An error occurred while parsing an account key.
     #[error("Invalid account key in transaction data")]
     AccountKey(#[from] AccountKeyError),
+    /// The program ID of the transaction's return data was invalid.
+    #[error("Invalid return data program ID")]
+    ReturnDataProgram(#[source] std::array::TryFromSliceError),
 }
 
 /// A required field that was missing from the transaction update.
@@ -85,10 +97,111 @@ pub struct InstructionShared {
     pub rewards: Vec<Reward>,
     /// The number of compute units consumed by the transaction.
     pub compute_units_consumed: Option<u64>,
+    /// The data returned by the last program to call `set_return_data`, if
+    /// any.  `None` if the return data was malformed.
+    pub return_data: Option<ReturnData>,
     /// The recent blockhash submitted with the transaction.
     pub recent_blockhash: Vec<u8>,
     /// The keys of the accounts involved in the transaction.
     pub accounts: AccountKeys,
+    /// The program and position of every instruction in the transaction.
+    calls: OnceLock<Vec<Call>>,
+    /// The logs of each instruction, parsed from the log messages when first
+    /// requested.
+    logs: OnceLock<HashMap<InstructionPath, InvocationLogs>>,
+}
+
+impl InstructionShared {
+    /// Get the logs written by the instruction at the given path, excluding
+    /// those written by its inner instructions.
+    ///
+    /// The log messages are parsed on the first call, so transactions whose
+    /// logs are never requested do not pay for parsing them.  Returns `None`
+    /// if the logs of the instruction could not be recovered, for instance
+    /// because they were truncated.
+    #[must_use]
+    pub fn invocation_logs(&self, path: &InstructionPath) -> Option<&InvocationLogs> {
+        self.logs
+            .get_or_init(|| {
+                let mut logs = HashMap::new();
+                if let Some(calls) = self.calls.get() {
+                    Call::match_logs(calls, logs::parse(&self.log_messages), &mut logs);
+                }
+                logs
+            })
+            .get(path)
+    }
+}
+
+/// An instruction's program and position, used to match it up with the
+/// invocation recorded in the transaction's logs.
+#[derive(Debug)]
+struct Call {
+    program: Pubkey,
+    path: InstructionPath,
+    inner: Vec<Call>,
+}
+
+impl Call {
+    fn tree(ixs: &[InstructionUpdate]) -> Vec<Self> {
+        ixs.iter()
+            .map(|ix| Self {
+                program: ix.program,
+                path: ix.path.clone(),
+                inner: Self::tree(&ix.inner),
+            })
+            .collect()
+    }
+
+    /// Match each call with the next invocation of its program, skipping
+    /// calls with no invocation logged, such as those to precompiled programs,
+    /// which do not log their invocations.
+    fn match_logs(
+        calls: &[Self],
+        invocations: Vec<Invocation>,
+        out: &mut HashMap<InstructionPath, InvocationLogs>,
+    ) {
+        let mut invocations = invocations.into_iter().peekable();
+        for call in calls {
+            let Some(inv) = invocations.next_if(|i| i.program == call.program) else {
+                continue;
+            };
+            let Invocation {
+                program: _,
+                depth: _,
+                logs,
+                inner,
+            } = inv;
+
+            out.insert(call.path.clone(), logs);
+            Self::match_logs(&call.inner, inner, out);
+        }
+    }
+}
+
+/// Data returned by a program in a transaction.
+#[derive(Debug, Clone)]
+pub struct ReturnData {
+    /// The ID of the program that set the return data.
+    pub program_id: Pubkey,
+    /// The returned bytes.
+    pub data: Vec<u8>,
+}
+
+impl TryFrom<RawReturnData> for ReturnData {
+    type Error = ParseError;
+
+    fn try_from(value: RawReturnData) -> Result<Self, Self::Error> {
+        let RawReturnData { program_id, data } = value;
+
+        Ok(Self {
+            program_id: program_id
+                .as_slice()
+                .try_into()
+                .map_err(ParseError::ReturnDataProgram)?,
+            data,
+        })
+    }
 }
 
 /// A parsed instruction from a transaction update.
@@ -98,17 +211,104 @@ pub struct InstructionUpdate {
     pub program: Pubkey,
     /// The accounts passed to the instruction.
     pub accounts: Vec<Pubkey>,
+    /// The role of each account passed to the instruction, in the same order
+    /// as [`accounts`](Self::accounts).
+    pub account_roles: Vec<AccountRole>,
     /// The serialized binary instruction payload.
     pub data: Vec<u8>,
     /// Shared data between all instructions in this transaction.
     pub shared: Arc<InstructionShared>,
     /// Inner instructions invoked by this instruction.
     pub inner: Vec<InstructionUpdate>,
+    /// The position of this instruction in its transaction's tree of
+    /// invocations.
+    pub path: InstructionPath,
+    /// For inner instructions, the index of this instruction among all the
+    /// inner instructions invoked by its top-level instruction, in execution
+    /// order.  `None` for top-level instructions.
+    pub inner_index: Option<u32>,
+    /// For inner instructions, the program that invoked this instruction.
+    /// `None` for top-level instructions.
+    pub parent_program: Option<Pubkey>,
+    /// For inner instructions, the instruction that invoked this one.  The
+    /// parent is a copy of the invoking instruction without its
+    /// [`inner`](Self::inner) instructions, and itself links to its own
+    /// parent, so every ancestor up to the top-level instruction can be
+    /// reached, see [`ancestors`](Self::ancestors).  `None` for top-level
+    /// instructions.
+    pub parent: Option<Arc<InstructionUpdate>>,
+}
+
+/// The position of an instruction within its transaction.
+///
+/// The first element is the index of the top-level instruction, and each
+/// following element is an index into the [`inner`](InstructionUpdate::inner)
+/// instructions of the instruction before it.  A path displays as its indices
+/// separated by dots, e.g. `2.0.1`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstructionPath(Vec<u32>);
+
+impl InstructionPath {
+    /// Get the indices making up this path.
+    #[inline]
+    #[must_use]
+    pub fn as_slice(&self) -> &[u32] { &self.0 }
+
+    /// Get the index of the top-level instruction this path starts from.
+    #[inline]
+    #[must_use]
+    pub fn outer_index(&self) -> u32 { self.0.first().copied().unwrap_or_default() }
+
+    /// Get the invocation depth of the instruction at this path, which is 1
+    /// for top-level instructions.
+    #[inline]
+    #[must_use]
+    pub fn stack_height(&self) -> usize { self.0.len() }
+
+    /// Get the path of the instruction that invoked the instruction at this
+    /// path, or `None` for top-level instructions.
+    #[must_use]
+    pub fn parent(&self) -> Option<&[u32]> {
+        self.0
+            .split_last()
+            .and_then(|(_, p)| (!p.is_empty()).then_some(p))
+    }
+}
+
+impl From<Vec<u32>> for InstructionPath {
+    #[inline]
+    fn from(value: Vec<u32>) -> Self { Self(value) }
+}
+
+impl fmt::Display for InstructionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, idx) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{idx}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether an account must sign its transaction and whether it may be
+/// written to, as requested by the transaction message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AccountRole {
+    /// Whether the account signed the transaction.
+    pub is_signer: bool,
+    /// Whether the transaction locked the account for writing.
+    pub is_writable: bool,
 }
 
 /// The keys of the accounts involved in a transaction.
 #[derive(Debug, Default)]
 pub struct AccountKeys {
+    /// The message header, giving the number of signer and readonly static
+    /// keys.
+    header: Option<MessageHeader>,
     /// Account keys submitted directly with the transaction.
     static_keys: Vec<Vec<u8>>,
     /// Resolved writable account keys.
@@ -151,6 +351,43 @@ impl AccountKeys {
             })
             .unwrap_or(Err(AccountKeyError::InvalidIndex(idx)))
     }
+
+    /// Get the role of the account at the given index.  Static keys are
+    /// laid out as writable signers, readonly signers, writable non-signers
+    /// and readonly non-signers, followed by the loaded writable and readonly
+    /// keys.
+    fn role(&self, idx: usize) -> AccountRole {
+        let len = self.static_keys.len();
+        if idx >= len {
+            return AccountRole {
+                is_signer: false,
+                is_writable: idx - len < self.dynamic_rw.len(),
+            };
+        }
+
+        let Some(MessageHeader {
+            num_required_signatures,
+            num_readonly_signed_accounts,
+            num_readonly_unsigned_accounts,
+        }) = self.header
+        else {
+            return AccountRole::default();
+        };
+        let count = |n: u32| usize::try_from(n).unwrap_or(usize::MAX);
+        let signers = count(num_required_signatures);
+
+        let is_signer = idx < signers;
+        let is_writable = if is_signer {
+            idx < signers.saturating_sub(count(num_readonly_signed_accounts))
+        } else {
+            idx < len.saturating_sub(count(num_readonly_unsigned_accounts))
+        };
+
+        AccountRole {
+            is_signer,
+            is_writable,
+        }
+    }
 }
 
 impl InstructionUpdate {
@@ -159,8 +396,32 @@ impl InstructionUpdate {
     /// # Errors
     /// Returns an error if the transaction update received is in an unparseable
     /// form.
+    #[inline]
     pub fn parse_from_txn(txn: &TransactionUpdate) -> Result<Vec<Self>, ParseError> {
-        let TransactionUpdate { transaction, slot } = txn.clone();
+        Self::parse_owned(txn.clone())
+    }
+
+    /// Parse every transaction in a block update into a list of
+    /// instructions, in the order the transactions appear in the block.
+    ///
+    /// # Errors
+    /// Returns an error if any transaction in the block is in an unparseable
+    /// form.
+    pub fn parse_from_block(block: &BlockUpdate) -> Result<Vec<Vec<Self>>, ParseError> {
+        block
+            .transactions
+            .iter()
+            .map(|t| {
+                Self::parse_owned(TransactionUpdate {
+                    transaction: Some(t.clone()),
+                    slot: block.slot,
+                })
+            })
+            .collect()
+    }
+
+    fn parse_owned(txn: TransactionUpdate) -> Result<Vec<Self>, ParseError> {
+        let TransactionUpdate { transaction, slot } = txn;
         let SubscribeUpdateTransactionInfo {
             signature,
             is_vote,
@@ -186,13 +447,12 @@ impl InstructionUpdate {
             rewards,
             loaded_writable_addresses,
             loaded_readonly_addresses,
-            // TODO: how is this decoded
-            return_data: _,
+            return_data,
             return_data_none: _,
             compute_units_consumed,
         } = meta.ok_or(Missing::TransactionMeta)?;
         let Message {
-            header: _,
+            header,
             account_keys,
             recent_blockhash,
             instructions,
@@ -200,6 +460,20 @@ impl InstructionUpdate {
             address_table_lookups: _,
         } = message.ok_or(Missing::TransactionMessage)?;
 
+        // Malformed return data should not prevent the rest of the transaction
+        // from being parsed
+        let return_data = return_data.and_then(|r| {
+            ReturnData::try_from(r)
+                .inspect_err(|e| {
+                    tracing::warn!(
+                        err = %e,
+                        signature = %bs58::encode(&signature).into_string(),
+                        "Discarding malformed transaction return data",
+                    );
+                })
+                .ok()
+        });
+
         let shared = Arc::new(InstructionShared {
             slot,
             signature,
@@ -214,8 +488,12 @@ impl InstructionUpdate {
             log_messages,
             rewards,
             compute_units_consumed,
+            return_data,
             recent_blockhash,
+            calls: OnceLock::new(),
+            logs: OnceLock::new(),
             accounts: AccountKeys {
+                header,
                 static_keys: account_keys,
                 dynamic_rw: loaded_writable_addresses,
                 dynamic_ro: loaded_readonly_addresses,
@@ -229,6 +507,19 @@ impl InstructionUpdate {
 
         Self::parse_inner(&shared, inner_instructions, &mut outer)?;
 
+        for (i, ix) in (0..).zip(&mut outer) {
+            ix.set_position(vec![i], None);
+        }
+
+        shared
+            .calls
+            .set(Call::tree(&outer))
+            .unwrap_or_else(|_| unreachable!());
+
+        for ix in &mut outer {
+            ix.set_parent(None);
+        }
+
         Ok(outer)
     }
 
@@ -247,40 +538,87 @@ impl InstructionUpdate {
                 return Err(ParseError::InvalidInnerInstructionIndex(index));
             };
 
-            let mut inner = instructions
-                .into_iter()
-                .map(|i| Self::parse_one_inner(Arc::clone(shared), i))
-                .collect::<Result<Vec<_>, _>>()?;
-
-            if let Some(mut i) = inner.len().checked_sub(1) {
-                while i > 0 {
-                    let parent_idx = i - 1;
-                    let Some(height) = inner[parent_idx].1 else {
-                        continue;
-                    };
-                    while inner
-                        .get(i)
-                        .and_then(|&(_, h)| h)
-                        .is_some_and(|h| h > height)
-                    {
-                        let (child, _) = inner.remove(i);
-                        inner[parent_idx].0.inner.push(child);
-                    }
-                    i -= 1;
+            // Instructions whose children may still follow, along with their
+            // stack heights
+            let mut open: Vec<(Self, u32)> = vec![];
+            for (i, ins) in (0..).zip(instructions) {
+                let (mut ix, height) = Self::parse_one_inner(Arc::clone(shared), ins)?;
+                ix.inner_index = Some(i);
+                // Instructions recorded without a stack height are assumed to
+                // be invoked directly by the top-level instruction
+                let height = height.unwrap_or(2);
+
+                while open.last().is_some_and(|&(_, h)| h >= height) {
+                    let (done, _) = open.pop().unwrap_or_else(|| unreachable!());
+                    open.last_mut()
+                        .map_or(&mut outer.inner, |(p, _)| &mut p.inner)
+                        .push(done);
                 }
+
+                open.push((ix, height));
             }
 
-            let inner: Vec<_> = inner.into_iter().map(|(i, _)| i).collect();
-            if outer.inner.is_empty() {
-                outer.inner = inner;
-            } else {
-                outer.inner.extend(inner);
+            while let Some((done, _)) = open.pop() {
+                open.last_mut()
+                    .map_or(&mut outer.inner, |(p, _)| &mut p.inner)
+                    .push(done);
             }
         }
 
         Ok(())
     }
 
+    /// Record the path and invoking program of this instruction and its
+    /// inner instructions.
+    fn set_position(&mut self, path: Vec<u32>, parent_program: Option<Pubkey>) {
+        for (i, ix) in (0..).zip(&mut self.inner) {
+            let mut child = path.clone();
+            child.push(i);
+            ix.set_position(child, Some(self.program));
+        }
+
+        self.path = InstructionPath(path);
+        self.parent_program = parent_program;
+    }
+
+    /// Link the inner instructions of this instruction to a copy of it, and
+    /// this instruction to its own parent.
+    fn set_parent(&mut self, parent: Option<Arc<Self>>) {
+        self.parent = parent;
+        if self.inner.is_empty() {
+            return;
+        }
+
+        let Self {
+            program,
+            accounts,
+            account_roles,
+            data,
+            shared,
+            inner: _,
+            path,
+            inner_index,
+            parent_program,
+            parent,
+        } = self;
+        let copy = Arc::new(Self {
+            program: *program,
+            accounts: accounts.clone(),
+            account_roles: account_roles.clone(),
+            data: data.clone(),
+            shared: Arc::clone(shared),
+            inner: vec![],
+            path: path.clone(),
+            inner_index: *inner_index,
+            parent_program: *parent_program,
+            parent: parent.clone(),
+        });
+
+        for ix in &mut self.inner {
+            ix.set_parent(Some(Arc::clone(&copy)));
+        }
+    }
+
     #[inline]
     fn parse_one(
         shared: Arc<InstructionShared>,
@@ -319,15 +657,136 @@ impl InstructionUpdate {
                 .iter()
                 .map(|&i| shared.accounts.get(i))
                 .collect::<Result<_, _>>()?,
+            account_roles: accounts
+                .iter()
+                .map(|&i| shared.accounts.role(i.into()))
+                .collect(),
             data,
             shared,
             inner: vec![],
+            path: InstructionPath::default(),
+            inner_index: None,
+            parent_program: None,
+            parent: None,
         })
     }
 
     /// Iterate over all inner instructions stored in this instruction.
     #[inline]
     pub fn visit_all(&self) -> VisitAll<'_> { VisitAll::new(self) }
+
+    /// Check whether the account at the given index into
+    /// [`accounts`](Self::accounts) signed the transaction.
+    #[inline]
+    #[must_use]
+    pub fn is_signer(&self, idx: usize) -> bool {
+        self.account_roles.get(idx).is_some_and(|r| r.is_signer)
+    }
+
+    /// Check whether the account at the given index into
+    /// [`accounts`](Self::accounts) was locked for writing.
+    #[inline]
+    #[must_use]
+    pub fn is_writable(&self, idx: usize) -> bool {
+        self.account_roles.get(idx).is_some_and(|r| r.is_writable)
+    }
+
+    /// Get the data returned by the transaction, if it was set by this
+    /// instruction's program.
+    ///
+    /// Only the last return data set during a transaction is recorded, so
+    /// when a program is invoked more than once the data may have been set by
+    /// a different invocation than this one.
+    #[must_use]
+    pub fn return_data(&self) -> Option<&[u8]> {
+        self.shared
+            .return_data
+            .as_ref()
+            .filter(|r| r.program_id == self.program)
+            .map(|r| r.data.as_slice())
+    }
+
+    /// Decode the data returned by this instruction's program with the given
+    /// function.
+    ///
+    /// # Errors
+    /// Returns [`Filtered`](crate::ParseError::Filtered) if this
+    /// instruction's program did not set return data, or any error returned
+    /// by `decode`.
+    pub fn decode_return_data<T, E: Into<crate::ParseError>>(
+        &self,
+        decode: impl FnOnce(&[u8]) -> Result<T, E>,
+    ) -> crate::ParseResult<T> {
+        let data = self.return_data().ok_or(crate::ParseError::Filtered)?;
+        decode(data).map_err(Into::into)
+    }
+
+    /// Get the logs written by this instruction, excluding those written by
+    /// its inner instructions.  See [`InstructionShared::invocation_logs`].
+    #[inline]
+    #[must_use]
+    pub fn logs(&self) -> Option<&InvocationLogs> { self.shared.invocation_logs(&self.path) }
+
+    /// Decode every Anchor event of type `E` emitted by this instruction.  See
+    /// [`anchor::events`].
+    ///
+    /// # Errors
+    /// Returns an error if any matching event is malformed.
+    #[inline]
+    pub fn anchor_events<E: AnchorEvent>(&self) -> crate::ParseResult<Vec<E>> {
+        anchor::events(self)
+    }
+
+    /// Iterate over the accounts passed to this instruction which signed
+    /// the transaction.
+    pub fn signers(&self) -> impl Iterator<Item = Pubkey> + '_ {
+        self.accounts
+            .iter()
+            .zip(&self.account_roles)
+            .filter_map(|(&k, r)| r.is_signer.then_some(k))
+    }
+
+    /// Get the invocation depth of this instruction, which is 1 for
+    /// top-level instructions.
+    #[inline]
+    #[must_use]
+    pub fn stack_height(&self) -> usize { self.path.stack_height() }
+
+    /// Get an ID for this instruction which is unique across all
+    /// transactions, made up of the transaction signature and the
+    /// instruction's path, e.g. `<signature>:2.0.1`.
+    #[must_use]
+    pub fn id(&self) -> String {
+        format!(
+            "{}:{}",
+            bs58::encode(&self.shared.signature).into_string(),
+            self.path
+        )
+    }
+
+    /// Find the instruction at the given path among this instruction and its
+    /// inner instructions.
+    #[must_use]
+    pub fn find(&self, path: &[u32]) -> Option<&Self> {
+        let rest = path.strip_prefix(self.path.as_slice())?;
+        rest.iter()
+            .try_fold(self, |ix, &i| ix.inner.get(usize::try_from(i).ok()?))
+    }
+
+    /// Iterate over the instructions that invoked this instruction, from its
+    /// [`parent`](Self::parent) up to the top-level instruction.
+    ///
+    /// This walks up the chain of cross-program invocations, e.g. from a
+    /// token transfer to the swap that invoked it.  Each ancestor is a copy
+    /// without its inner instructions.
+    pub fn ancestors(&self) -> impl Iterator<Item = &Self> {
+        std::iter::successors(self.parent.as_deref(), |p| p.parent.as_deref())
+    }
+
+    /// Get the top-level instruction this instruction was invoked from, or
+    /// this instruction itself if it is a top-level instruction.
+    #[must_use]
+    pub fn root(&self) -> &Self { self.ancestors().last().unwrap_or(self) }
 }
 
 /// An iterator over all inner instructions stored in an instruction update.
@@ -368,3 +827,171 @@ impl<'a> Iterator for VisitAll<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: [[u8; 32]; 3] = [[1; 32], [2; 32], [3; 32]];
+
+    fn key(i: usize) -> Pubkey { Pubkey::new(PROGRAMS[i - 1]) }
+
+    fn return_data(program_id: Vec<u8>) -> TransactionUpdate {
+        let mut txn = txn(&[1], &[], &[]);
+        let meta = txn
+            .transaction
+            .as_mut()
+            .and_then(|t| t.meta.as_mut())
+            .unwrap();
+        meta.return_data = Some(RawReturnData {
+            program_id,
+            data: vec![42],
+        });
+        txn
+    }
+
+    /// Build a transaction update whose account keys are a fee payer followed
+    /// by [`PROGRAMS`], calling the given top-level programs and, for the
+    /// given top-level instructions, inner programs with their stack heights.
+    fn txn(outer: &[u32], inner: &[(u32, &[(u32, u32)])], logs: &[&str]) -> TransactionUpdate {
+        let ix = |program_id_index| CompiledInstruction {
+            program_id_index,
+            accounts: vec![0],
+            data: vec![],
+        };
+
+        TransactionUpdate {
+            transaction: Some(SubscribeUpdateTransactionInfo {
+                transaction: Some(Transaction {
+                    signatures: vec![],
+                    message: Some(Message {
+                        account_keys: std::iter::once(vec![0; 32])
+                            .chain(PROGRAMS.iter().map(|p| p.to_vec()))
+                            .collect(),
+                        instructions: outer.iter().copied().map(ix).collect(),
+                        ..Message::default()
+                    }),
+                }),
+                meta: Some(TransactionStatusMeta {
+                    inner_instructions: inner
+                        .iter()
+                        .map(|&(index, ixs)| InnerInstructions {
+                            index,
+                            instructions: ixs
+                                .iter()
+                                .map(|&(program_id_index, height)| InnerInstruction {
+                                    program_id_index,
+                                    accounts: vec![0],
+                                    data: vec![],
+                                    stack_height: Some(height),
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                    log_messages: logs.iter().map(ToString::to_string).collect(),
+                    ..TransactionStatusMeta::default()
+                }),
+                ..SubscribeUpdateTransactionInfo::default()
+            }),
+            slot: 0,
+        }
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let ixs = InstructionUpdate::parse_from_txn(&txn(
+            &[1, 2],
+            &[(0, &[(2, 2), (3, 3), (3, 2)])],
+            &[],
+        ))
+        .unwrap();
+
+        let swap = &ixs[0];
+        assert!(swap.parent.is_none());
+        assert_eq!(swap.root().path, swap.path);
+
+        let router = &swap.inner[0];
+        let transfer = &router.inner[0];
+        assert_eq!(transfer.path.as_slice(), [0, 0, 0]);
+        assert_eq!(
+            transfer.ancestors().map(|a| a.program).collect::<Vec<_>>(),
+            [key(2), key(1)]
+        );
+        assert_eq!(transfer.root().path, swap.path);
+        assert!(transfer.ancestors().all(|a| a.inner.is_empty()));
+
+        let sibling = &swap.inner[1];
+        assert_eq!(sibling.parent.as_ref().map(|p| p.program), Some(key(1)));
+        assert!(ixs[1].parent.is_none());
+    }
+
+    #[test]
+    fn test_return_data() {
+        let ixs = InstructionUpdate::parse_from_txn(&return_data(PROGRAMS[0].to_vec())).unwrap();
+        assert_eq!(ixs[0].return_data(), Some(&[42][..]));
+
+        let ixs = InstructionUpdate::parse_from_txn(&return_data(vec![1; 4])).unwrap();
+        assert!(ixs[0].shared.return_data.is_none());
+        assert_eq!(ixs[0].return_data(), None);
+    }
+
+    #[test]
+    fn test_logs_skip_unlogged_instructions() {
+        let [a, b, c] = [key(1), key(2), key(3)];
+        // The second top-level instruction calls a precompile, which logs
+        // nothing, and its logs were truncated before the third finished
+        let ixs = InstructionUpdate::parse_from_txn(&txn(&[1, 3, 2], &[(0, &[(2, 2)])], &[
+            &format!("Program {a} invoke [1]"),
+            &format!("Program {b} invoke [2]"),
+            "Program log: inner",
+            &format!("Program {b} success"),
+            &format!("Program {a} success"),
+            &format!("Program {b} invoke [1]"),
+            "Program log: last",
+        ]))
+        .unwrap();
+
+        let messages = |ix: &InstructionUpdate| {
+            ix.logs()
+                .map(|l| l.messages().map(ToOwned::to_owned).collect::<Vec<_>>())
+        };
+        assert_eq!(messages(&ixs[0]), Some(vec![]));
+        assert_eq!(messages(&ixs[0].inner[0]), Some(vec!["inner".to_owned()]));
+        assert_eq!(ixs[1].program, c);
+        assert_eq!(messages(&ixs[1]), None);
+        assert_eq!(messages(&ixs[2]), Some(vec!["last".to_owned()]));
+    }
+
+    #[test]
+    fn test_account_roles() {
+        let role = |is_signer, is_writable| AccountRole {
+            is_signer,
+            is_writable,
+        };
+        let mut keys = AccountKeys {
+            header: Some(MessageHeader {
+                num_required_signatures: 2,
+                num_readonly_signed_accounts: 1,
+                num_readonly_unsigned_accounts: 1,
+            }),
+            static_keys: vec![vec![0; 32]; 4],
+            dynamic_rw: vec![vec![0; 32]],
+            dynamic_ro: vec![vec![0; 32]],
+        };
+
+        assert_eq!(keys.role(0), role(true, true));
+        assert_eq!(keys.role(1), role(true, false));
+        assert_eq!(keys.role(2), role(false, true));
+        assert_eq!(keys.role(3), role(false, false));
+        // Loaded accounts never sign, and writable ones come first
+        assert_eq!(keys.role(4), role(false, true));
+        assert_eq!(keys.role(5), role(false, false));
+
+        // Without a header the roles of static keys are unknown
+        keys.header = None;
+        assert_eq!(keys.role(0), AccountRole::default());
+        assert_eq!(keys.role(2), AccountRole::default());
+        assert_eq!(keys.role(4), role(false, true));
+        assert_eq!(keys.role(5), role(false, false));
+    }
+}
@@ -25,15 +25,26 @@ use std::{
 };
 
 use yellowstone_grpc_proto::geyser::{
-    SubscribeRequest, SubscribeRequestFilterAccounts, SubscribeRequestFilterTransactions,
-    SubscribeUpdateAccount, SubscribeUpdateTransaction,
+    self, subscribe_request_filter_accounts_filter,
+    subscribe_request_filter_accounts_filter_lamports,
+    subscribe_request_filter_accounts_filter_memcmp, SubscribeRequest,
+    SubscribeRequestAccountsDataSlice, SubscribeRequestFilterAccounts,
+    SubscribeRequestFilterAccountsFilter, SubscribeRequestFilterAccountsFilterLamports,
+    SubscribeRequestFilterAccountsFilterMemcmp, SubscribeRequestFilterBlocks,
+    SubscribeRequestFilterBlocksMeta, SubscribeRequestFilterEntry, SubscribeRequestFilterSlots,
+    SubscribeRequestFilterTransactions, SubscribeUpdateAccount, SubscribeUpdateBlock,
+    SubscribeUpdateBlockMeta, SubscribeUpdateEntry, SubscribeUpdateSlot,
+    SubscribeUpdateTransaction, SubscribeUpdateTransactionStatus,
 };
 
 pub extern crate bs58;
 #[cfg(feature = "proto")]
 pub extern crate yellowstone_vixen_proto;
 
+pub mod anchor;
+pub mod combinator;
 pub mod instruction;
+pub mod logs;
 #[cfg(feature = "proto")]
 pub mod proto;
 
@@ -62,6 +73,17 @@ pub type ParseResult<T> = Result<T, ParseError>;
 pub type AccountUpdate = SubscribeUpdateAccount;
 /// A transaction update from Yellowstone.
 pub type TransactionUpdate = SubscribeUpdateTransaction;
+/// A slot status update from Yellowstone.
+pub type SlotUpdate = SubscribeUpdateSlot;
+/// A full block update from Yellowstone.
+pub type BlockUpdate = SubscribeUpdateBlock;
+/// A block metadata update from Yellowstone.
+pub type BlockMetaUpdate = SubscribeUpdateBlockMeta;
+/// A ledger entry update from Yellowstone.
+pub type EntryUpdate = SubscribeUpdateEntry;
+/// A transaction status update from Yellowstone, carrying only the
+/// signature and outcome of a transaction.
+pub type TransactionStatusUpdate = SubscribeUpdateTransactionStatus;
 
 /// A core trait that defines the parse logic for producing a parsed value from
 /// a Vixen update (typically [`AccountUpdate`], [`TransactionUpdate`], or
@@ -81,7 +103,7 @@ pub trait Parser {
     /// name of the parser.  However, for parsers that produce a different
     /// prefilter depending on some internal configuration, instances that
     /// output differing prefilters _must_ output different IDs.
-    fn id(&self) -> Cow<str>;
+    fn id(&self) -> Cow<'_, str>;
 
     /// Filter data passed to Yellowstone to coarsely narrow down updates
     /// to values parseable by this parser.
@@ -89,6 +111,102 @@ pub trait Parser {
 
     /// Parse the given update into a parsed value.
     fn parse(&self, value: &Self::Input) -> impl Future<Output = ParseResult<Self::Output>> + Send;
+
+    /// Parse the given account update, whose data has been reduced to the
+    /// slices requested by this parser's prefilter (see
+    /// [`PrefilterBuilder::account_data_slice`]), into a parsed value.
+    ///
+    /// The default implementation ignores the slices and calls
+    /// [`parse`](Self::parse).
+    fn parse_sliced(
+        &self,
+        value: &Self::Input,
+        slices: &DataSlices,
+    ) -> impl Future<Output = ParseResult<Self::Output>> + Send {
+        let _ = slices;
+        self.parse(value)
+    }
+
+    /// Transform each value produced by this parser with the given function.
+    fn map<F, U>(self, f: F) -> combinator::Map<Self, F>
+    where
+        Self: Sized,
+        F: Fn(Self::Output) -> U,
+    {
+        combinator::Map { parser: self, f }
+    }
+
+    /// Discard values produced by this parser for which the given predicate
+    /// returns `false`, as if they had failed with [`ParseError::Filtered`].
+    fn filter<F>(self, f: F) -> combinator::Filter<Self, F>
+    where
+        Self: Sized,
+        F: Fn(&Self::Output) -> bool,
+    {
+        combinator::Filter { parser: self, f }
+    }
+
+    /// Transform each value produced by this parser with the given function,
+    /// discarding values for which it returns `None` as if they had failed
+    /// with [`ParseError::Filtered`].
+    fn filter_map<F, U>(self, f: F) -> combinator::FilterMap<Self, F>
+    where
+        Self: Sized,
+        F: Fn(Self::Output) -> Option<U>,
+    {
+        combinator::FilterMap { parser: self, f }
+    }
+
+    /// Pass each value produced by this parser through the given fallible
+    /// function.
+    fn and_then<F, U>(self, f: F) -> combinator::AndThen<Self, F>
+    where
+        Self: Sized,
+        F: Fn(Self::Output) -> ParseResult<U>,
+    {
+        combinator::AndThen { parser: self, f }
+    }
+
+    /// Parse updates with `other` if this parser filters them out.  Other
+    /// errors are returned without trying `other`.
+    ///
+    /// The combined parser's prefilter is the union of both prefilters.
+    fn or<B>(self, other: B) -> combinator::Or<Self, B>
+    where
+        Self: Sized,
+        B: Parser<Input = Self::Input, Output = Self::Output>,
+    {
+        combinator::Or {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Parse each update with both this parser and `other`, producing both
+    /// parsed values.  The update is filtered out unless both parsers accept
+    /// it.
+    ///
+    /// The combined parser's prefilter is the union of both prefilters.
+    fn zip<B>(self, other: B) -> combinator::Zip<Self, B>
+    where
+        Self: Sized,
+        B: Parser<Input = Self::Input>,
+    {
+        combinator::Zip {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Replace the ID of this parser, for instance to tell apart two
+    /// combinators built from the same parser.
+    fn named(self, id: impl Into<Cow<'static, str>>) -> combinator::Named<Self>
+    where Self: Sized {
+        combinator::Named {
+            parser: self,
+            id: id.into(),
+        }
+    }
 }
 
 /// A parser that parses all relevant updates for a particular program ID.
@@ -100,17 +218,17 @@ pub trait ProgramParser: Parser {
 /// Helper trait for getting the ID of a parser.
 pub trait ParserId {
     /// Get the ID of this parser, see [`Parser::id`].
-    fn id(&self) -> Cow<str>;
+    fn id(&self) -> Cow<'_, str>;
 }
 
 impl ParserId for std::convert::Infallible {
     #[inline]
-    fn id(&self) -> Cow<str> { match *self {} }
+    fn id(&self) -> Cow<'_, str> { match *self {} }
 }
 
 impl<T: Parser> ParserId for T {
     #[inline]
-    fn id(&self) -> Cow<str> { Parser::id(self) }
+    fn id(&self) -> Cow<'_, str> { Parser::id(self) }
 }
 
 /// Helper trait for getting the prefilter of a parser.
@@ -131,10 +249,17 @@ impl<T: Parser> GetPrefilter for T {
 
 // TODO: why are so many fields on the prefilters and prefilter builder optional???
 /// A prefilter for narrowing down the updates that a parser will receive.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Prefilter {
     pub(crate) account: Option<AccountPrefilter>,
     pub(crate) transaction: Option<TransactionPrefilter>,
+    pub(crate) slot: Option<SlotPrefilter>,
+    pub(crate) block: Option<BlockPrefilter>,
+    pub(crate) block_meta: bool,
+    pub(crate) transaction_status: Option<TransactionPrefilter>,
+    pub(crate) entry: bool,
+    pub(crate) commitment: Option<CommitmentLevel>,
+    pub(crate) account_data_slices: Option<DataSlices>,
 }
 
 fn merge_opt<T, F: FnOnce(&mut T, T)>(lhs: &mut Option<T>, rhs: Option<T>, f: F) {
@@ -152,13 +277,297 @@ impl Prefilter {
 
     /// Merge another prefilter into this one, producing a prefilter that
     /// describes the union of the two.
+    ///
+    /// If both prefilters request a commitment level, the merged prefilter
+    /// requests the more final of the two.  If both prefilters match accounts
+    /// but request different account data slices, the merged prefilter
+    /// requests the full account data.
     pub fn merge(&mut self, other: Prefilter) {
         let Self {
             account,
             transaction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            commitment,
+            account_data_slices,
         } = self;
+        let active = |a: &Option<AccountPrefilter>| a.as_ref().is_some_and(|a| !a.inactive);
+        match (active(account), active(&other.account)) {
+            (_, false) => (),
+            (false, true) => *account_data_slices = other.account_data_slices,
+            (true, true) => {
+                if *account_data_slices != other.account_data_slices {
+                    *account_data_slices = None;
+                }
+            },
+        }
         merge_opt(account, other.account, AccountPrefilter::merge);
         merge_opt(transaction, other.transaction, TransactionPrefilter::merge);
+        merge_opt(slot, other.slot, SlotPrefilter::merge);
+        merge_opt(block, other.block, BlockPrefilter::merge);
+        *block_meta |= other.block_meta;
+        merge_opt(
+            transaction_status,
+            other.transaction_status,
+            TransactionPrefilter::merge,
+        );
+        *entry |= other.entry;
+        merge_opt(commitment, other.commitment, |l, r| *l = (*l).max(r));
+    }
+
+    /// Add every key matched by another prefilter to this one, leaving the
+    /// account data filters and commitment level of this prefilter unchanged.
+    ///
+    /// Keys are added to the account, transaction, and transaction status
+    /// portions of this prefilter from the same portions of the other.
+    pub fn extend(&mut self, other: Prefilter) {
+        let Self {
+            account,
+            transaction,
+            slot: _,
+            block: _,
+            block_meta: _,
+            transaction_status,
+            entry: _,
+            commitment: _,
+            account_data_slices: _,
+        } = self;
+
+        if let Some(b) = other.account {
+            let a = account.get_or_insert_with(AccountPrefilter::default);
+            a.accounts.extend(b.accounts);
+            a.owners.extend(b.owners);
+            a.inactive &= !a.has_keys();
+        }
+
+        TransactionPrefilter::extend(transaction, other.transaction);
+        TransactionPrefilter::extend(transaction_status, other.transaction_status);
+    }
+
+    /// Remove every key matched by another prefilter from this one, producing
+    /// a prefilter that no longer explicitly matches those keys.
+    ///
+    /// If this removes every key from the account or transaction portion of
+    /// the prefilter, that portion is marked inactive rather than left empty,
+    /// as an empty filter would match every update.  An inactive portion is
+    /// left out of subscriptions but keeps its remaining options, such as its
+    /// data filters, so that keys added back with [`extend`](Self::extend)
+    /// are matched as before.
+    pub fn subtract(&mut self, other: &Prefilter) {
+        let Self {
+            account,
+            transaction,
+            slot: _,
+            block: _,
+            block_meta: _,
+            transaction_status,
+            entry: _,
+            commitment: _,
+            account_data_slices: _,
+        } = self;
+
+        if let (Some(a), Some(b)) = (account.as_mut(), other.account.as_ref()) {
+            let had_keys = a.has_keys();
+            a.subtract(b);
+            a.inactive |= had_keys && !a.has_keys();
+        }
+
+        if let (Some(a), Some(b)) = (transaction.as_mut(), other.transaction.as_ref()) {
+            a.subtract(b);
+        }
+
+        if let (Some(a), Some(b)) = (
+            transaction_status.as_mut(),
+            other.transaction_status.as_ref(),
+        ) {
+            a.subtract(b);
+        }
+    }
+
+    /// The commitment level requested by this prefilter, if any.  If unset,
+    /// the runtime's configured default is used.
+    #[inline]
+    #[must_use]
+    pub fn commitment(&self) -> Option<CommitmentLevel> { self.commitment }
+
+    /// The accounts, owners, and data filters this prefilter selects account
+    /// updates with, if it matches account updates at all.
+    #[must_use]
+    pub fn account_selection(&self) -> Option<AccountSelection<'_>> {
+        self.active_account().map(|a| AccountSelection {
+            accounts: &a.accounts,
+            owners: &a.owners,
+            filters: &a.filters,
+        })
+    }
+
+    /// The slices of account data requested by this prefilter, if any.  If
+    /// unset, the full account data is requested.
+    #[inline]
+    #[must_use]
+    pub fn account_data_slices(&self) -> Option<&DataSlices> { self.account_data_slices.as_ref() }
+
+    #[inline]
+    fn active_account(&self) -> Option<&AccountPrefilter> {
+        self.account.as_ref().filter(|a| !a.inactive)
+    }
+
+    #[inline]
+    fn active_transaction(&self) -> Option<&TransactionPrefilter> {
+        self.transaction.as_ref().filter(|t| !t.inactive)
+    }
+
+    #[inline]
+    fn active_transaction_status(&self) -> Option<&TransactionPrefilter> {
+        self.transaction_status.as_ref().filter(|t| !t.inactive)
+    }
+}
+
+/// A byte range of account data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DataSlice {
+    /// The offset of the first byte of the range.
+    pub offset: u64,
+    /// The length of the range.
+    pub length: u64,
+}
+
+impl DataSlice {
+    #[inline]
+    fn end(self) -> u64 { self.offset.saturating_add(self.length) }
+}
+
+/// A set of slices of account data requested from the server.
+///
+/// The slices are sorted and non-overlapping.  Account data received for a
+/// subscription requesting these slices contains the bytes of each slice
+/// concatenated in order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DataSlices(Box<[DataSlice]>);
+
+impl DataSlices {
+    /// Normalize a list of slices, sorting them and joining any that overlap
+    /// or touch.
+    #[must_use]
+    pub fn new(slices: impl IntoIterator<Item = DataSlice>) -> Self {
+        let mut slices: Vec<_> = slices.into_iter().filter(|s| s.length > 0).collect();
+        slices.sort_unstable();
+
+        let mut joined: Vec<DataSlice> = Vec::with_capacity(slices.len());
+        for slice in slices {
+            match joined.last_mut() {
+                Some(last) if slice.offset <= last.end() => {
+                    last.length = last.end().max(slice.end()) - last.offset;
+                },
+                _ => joined.push(slice),
+            }
+        }
+
+        Self(joined.into_boxed_slice())
+    }
+
+    /// Iterate over the slices in order.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &DataSlice> { self.0.iter() }
+
+    /// Look up a range of the full account data within sliced account data.
+    ///
+    /// Returns `None` if the range is not entirely contained within a single
+    /// requested slice, or if the account data was too short to contain it.
+    #[must_use]
+    pub fn get<'a>(&self, data: &'a [u8], offset: u64, length: u64) -> Option<&'a [u8]> {
+        let end = offset.checked_add(length)?;
+        let mut start = 0_u64;
+
+        for slice in &*self.0 {
+            if offset >= slice.offset && end <= slice.end() {
+                let start = usize::try_from(start + (offset - slice.offset)).ok()?;
+                let length = usize::try_from(length).ok()?;
+                return data.get(start..start.checked_add(length)?);
+            }
+
+            start += slice.length;
+        }
+
+        None
+    }
+}
+
+/// The commitment level of the data requested from Yellowstone.
+///
+/// Levels are ordered from least to most final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CommitmentLevel {
+    /// Data from blocks that have been processed by the node, which may still
+    /// be rolled back.
+    Processed,
+    /// Data from blocks that have been voted on by a supermajority of the
+    /// cluster.
+    Confirmed,
+    /// Data from blocks that have been rooted and can no longer be rolled
+    /// back.
+    Finalized,
+}
+
+impl CommitmentLevel {
+    /// Get the lowercase name of this commitment level.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Processed => "processed",
+            Self::Confirmed => "confirmed",
+            Self::Finalized => "finalized",
+        }
+    }
+}
+
+impl fmt::Display for CommitmentLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+/// An error that can occur when parsing a commitment level from a string.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Invalid commitment level {0:?}, expected processed, confirmed, or finalized")]
+pub struct CommitmentFromStrError(String);
+
+impl FromStr for CommitmentLevel {
+    type Err = CommitmentFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "processed" => Ok(Self::Processed),
+            "confirmed" => Ok(Self::Confirmed),
+            "finalized" => Ok(Self::Finalized),
+            s => Err(CommitmentFromStrError(s.to_owned())),
+        }
+    }
+}
+
+impl From<CommitmentLevel> for geyser::CommitmentLevel {
+    fn from(value: CommitmentLevel) -> Self {
+        match value {
+            CommitmentLevel::Processed => Self::Processed,
+            CommitmentLevel::Confirmed => Self::Confirmed,
+            CommitmentLevel::Finalized => Self::Finalized,
+        }
+    }
+}
+
+impl TryFrom<geyser::CommitmentLevel> for CommitmentLevel {
+    type Error = geyser::CommitmentLevel;
+
+    /// Convert a Yellowstone slot status to a commitment level, returning the
+    /// status unchanged if it is not one of the three commitment levels.
+    fn try_from(value: geyser::CommitmentLevel) -> Result<Self, Self::Error> {
+        match value {
+            geyser::CommitmentLevel::Processed => Ok(Self::Processed),
+            geyser::CommitmentLevel::Confirmed => Ok(Self::Confirmed),
+            geyser::CommitmentLevel::Finalized => Ok(Self::Finalized),
+            s => Err(s),
+        }
     }
 }
 
@@ -175,29 +584,324 @@ impl FromIterator<Prefilter> for Prefilter {
     }
 }
 
+/// A filter on the contents of an account, evaluated by the Yellowstone
+/// server before the account update is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AccountDataFilter {
+    /// Match accounts whose data contains the given bytes at the given offset.
+    Memcmp {
+        /// The offset into the account data to compare at.
+        offset: u64,
+        /// The bytes to compare against.
+        bytes: Vec<u8>,
+    },
+    /// Match accounts whose data is exactly the given length in bytes.
+    DataSize(u64),
+    /// Match only accounts that are valid SPL token accounts.
+    TokenAccountState,
+    /// Match accounts by their lamport balance.
+    Lamports(LamportsFilter),
+}
+
+/// A comparison against the lamport balance of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LamportsFilter {
+    /// Match accounts holding exactly the given balance.
+    Eq(u64),
+    /// Match accounts not holding exactly the given balance.
+    Ne(u64),
+    /// Match accounts holding less than the given balance.
+    Lt(u64),
+    /// Match accounts holding more than the given balance.
+    Gt(u64),
+}
+
+// Layout constants for SPL token accounts, used to evaluate
+// AccountDataFilter::TokenAccountState locally
+const TOKEN_ACCOUNT_LEN: usize = 165;
+const TOKEN_ACCOUNT_STATE_OFFSET: usize = 108;
+const TOKEN_2022_ACCOUNT_TYPE: u8 = 2;
+
+impl AccountDataFilter {
+    /// Check whether an account with the given data and balance passes this
+    /// filter, mirroring the check performed by the Yellowstone server.
+    #[must_use]
+    pub fn matches(&self, data: &[u8], lamports: u64) -> bool {
+        match self {
+            Self::Memcmp { offset, bytes } => usize::try_from(*offset)
+                .ok()
+                .and_then(|o| data.get(o..)?.get(..bytes.len()))
+                .is_some_and(|d| d == &**bytes),
+            Self::DataSize(size) => data.len() as u64 == *size,
+            Self::TokenAccountState => {
+                // Token-2022 accounts with extensions are longer than a plain
+                // token account and are tagged with their account type
+                let is_account = data.len() == TOKEN_ACCOUNT_LEN
+                    || data.get(TOKEN_ACCOUNT_LEN) == Some(&TOKEN_2022_ACCOUNT_TYPE);
+                is_account
+                    && data
+                        .get(TOKEN_ACCOUNT_STATE_OFFSET)
+                        .is_some_and(|&s| s != 0)
+            },
+            Self::Lamports(LamportsFilter::Eq(l)) => lamports == *l,
+            Self::Lamports(LamportsFilter::Ne(l)) => lamports != *l,
+            Self::Lamports(LamportsFilter::Lt(l)) => lamports < *l,
+            Self::Lamports(LamportsFilter::Gt(l)) => lamports > *l,
+        }
+    }
+}
+
+impl From<&AccountDataFilter> for SubscribeRequestFilterAccountsFilter {
+    fn from(value: &AccountDataFilter) -> Self {
+        use subscribe_request_filter_accounts_filter::Filter;
+        use subscribe_request_filter_accounts_filter_lamports::Cmp;
+        use subscribe_request_filter_accounts_filter_memcmp::Data;
+
+        let filter = match value {
+            AccountDataFilter::Memcmp { offset, bytes } => {
+                Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+                    offset: *offset,
+                    data: Some(Data::Bytes(bytes.clone())),
+                })
+            },
+            AccountDataFilter::DataSize(s) => Filter::Datasize(*s),
+            AccountDataFilter::TokenAccountState => Filter::TokenAccountState(true),
+            AccountDataFilter::Lamports(l) => {
+                Filter::Lamports(SubscribeRequestFilterAccountsFilterLamports {
+                    cmp: Some(match *l {
+                        LamportsFilter::Eq(v) => Cmp::Eq(v),
+                        LamportsFilter::Ne(v) => Cmp::Ne(v),
+                        LamportsFilter::Lt(v) => Cmp::Lt(v),
+                        LamportsFilter::Gt(v) => Cmp::Gt(v),
+                    }),
+                })
+            },
+        };
+
+        Self {
+            filter: Some(filter),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq)]
 pub(crate) struct AccountPrefilter {
     pub accounts: HashSet<Pubkey>,
     pub owners: HashSet<Pubkey>,
+    // All data filters must match for an account to be sent
+    pub filters: HashSet<AccountDataFilter>,
+    // Every key has been removed, so no accounts are matched until some are
+    // added back
+    pub inactive: bool,
 }
 
 impl AccountPrefilter {
     pub fn merge(&mut self, other: AccountPrefilter) {
-        let Self { accounts, owners } = self;
+        if other.inactive {
+            return;
+        }
+        if self.inactive {
+            *self = other;
+            return;
+        }
+
+        let Self {
+            accounts,
+            owners,
+            filters,
+            inactive: _,
+        } = self;
         accounts.extend(other.accounts);
         owners.extend(other.owners);
+        // Data filters are conjunctive, so the union of two prefilters can
+        // only keep the filters both of them require
+        filters.retain(|f| other.filters.contains(f));
+    }
+
+    pub fn subtract(&mut self, other: &AccountPrefilter) {
+        let Self {
+            accounts,
+            owners,
+            filters: _,
+            inactive: _,
+        } = self;
+        accounts.retain(|k| !other.accounts.contains(k));
+        owners.retain(|k| !other.owners.contains(k));
+    }
+
+    #[inline]
+    fn has_keys(&self) -> bool { !(self.accounts.is_empty() && self.owners.is_empty()) }
+}
+
+/// The accounts, owners, and data filters a prefilter selects account
+/// updates with.
+#[derive(Debug, Clone, Copy)]
+pub struct AccountSelection<'a> {
+    /// If non-empty, only accounts with one of these keys are selected.
+    pub accounts: &'a HashSet<Pubkey>,
+    /// If non-empty, only accounts owned by one of these programs are
+    /// selected.
+    pub owners: &'a HashSet<Pubkey>,
+    /// Every one of these filters must match for an account to be selected.
+    pub filters: &'a HashSet<AccountDataFilter>,
+}
+
+impl AccountSelection<'_> {
+    /// Check whether an account is selected.
+    #[must_use]
+    pub fn matches(&self, pubkey: &Pubkey, owner: &Pubkey, data: &[u8], lamports: u64) -> bool {
+        let Self {
+            accounts,
+            owners,
+            filters,
+        } = self;
+
+        (accounts.is_empty() || accounts.contains(pubkey))
+            && (owners.is_empty() || owners.contains(owner))
+            && filters.iter().all(|f| f.matches(data, lamports))
     }
 }
 
 #[derive(Debug, Default, Clone, PartialEq)]
 pub(crate) struct TransactionPrefilter {
+    // A transaction must mention at least one of these accounts, if any are
+    // given
     pub accounts: HashSet<Pubkey>,
+    // A transaction must mention every one of these accounts
+    pub required: HashSet<Pubkey>,
+    // A transaction must mention none of these accounts
+    pub excluded: HashSet<Pubkey>,
+    pub include_failed: bool,
+    pub exclude_votes: bool,
+    // Every key has been removed, so no transactions are matched until some
+    // are added back
+    pub inactive: bool,
+}
+
+impl From<&TransactionPrefilter> for SubscribeRequestFilterTransactions {
+    fn from(value: &TransactionPrefilter) -> Self {
+        let TransactionPrefilter {
+            accounts,
+            required,
+            excluded,
+            include_failed,
+            exclude_votes,
+            inactive: _,
+        } = value;
+
+        Self {
+            vote: exclude_votes.then_some(false),
+            failed: (!include_failed).then_some(false),
+            signature: None,
+            account_include: accounts.iter().map(ToString::to_string).collect(),
+            account_exclude: excluded.iter().map(ToString::to_string).collect(),
+            account_required: required.iter().map(ToString::to_string).collect(),
+        }
+    }
 }
 
 impl TransactionPrefilter {
     pub fn merge(&mut self, other: TransactionPrefilter) {
-        let Self { accounts } = self;
+        if other.inactive {
+            return;
+        }
+        if self.inactive {
+            *self = other;
+            return;
+        }
+
+        let Self {
+            accounts,
+            required,
+            excluded,
+            include_failed,
+            exclude_votes,
+            inactive: _,
+        } = self;
+
+        // Every condition narrows the filter, so the union of two prefilters
+        // has to loosen each condition to match anything either one matches
+        if accounts.is_empty() || other.accounts.is_empty() {
+            accounts.clear();
+        } else {
+            accounts.extend(other.accounts);
+        }
+        required.retain(|k| other.required.contains(k));
+        excluded.retain(|k| other.excluded.contains(k));
+        *include_failed |= other.include_failed;
+        *exclude_votes &= other.exclude_votes;
+    }
+
+    fn extend(this: &mut Option<Self>, other: Option<TransactionPrefilter>) {
+        let Some(other) = other else { return };
+        let Self {
+            accounts,
+            required: _,
+            excluded: _,
+            include_failed: _,
+            exclude_votes: _,
+            inactive,
+        } = this.get_or_insert_with(Self::default);
         accounts.extend(other.accounts);
+        *inactive &= accounts.is_empty();
+    }
+
+    pub fn subtract(&mut self, other: &TransactionPrefilter) {
+        let Self {
+            accounts,
+            required: _,
+            excluded: _,
+            include_failed: _,
+            exclude_votes: _,
+            inactive,
+        } = self;
+        let had_keys = !accounts.is_empty();
+        accounts.retain(|k| !other.accounts.contains(k));
+        *inactive |= had_keys && accounts.is_empty();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SlotPrefilter {
+    // Only send slot updates matching the commitment level of the
+    // subscription, rather than every status transition
+    pub filter_by_commitment: bool,
+}
+
+impl SlotPrefilter {
+    pub fn merge(&mut self, other: SlotPrefilter) {
+        let Self {
+            filter_by_commitment,
+        } = self;
+
+        *filter_by_commitment &= other.filter_by_commitment;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockPrefilter {
+    // A block's transactions and accounts are narrowed to those mentioning
+    // at least one of these accounts, if any are given
+    pub accounts: HashSet<Pubkey>,
+    pub include_transactions: bool,
+    pub include_accounts: bool,
+}
+
+impl BlockPrefilter {
+    pub fn merge(&mut self, other: BlockPrefilter) {
+        let Self {
+            accounts,
+            include_transactions,
+            include_accounts,
+        } = self;
+
+        if accounts.is_empty() || other.accounts.is_empty() {
+            accounts.clear();
+        } else {
+            accounts.extend(other.accounts);
+        }
+        *include_transactions |= other.include_transactions;
+        *include_accounts |= other.include_accounts;
     }
 }
 
@@ -390,16 +1094,37 @@ pub enum PrefilterError {
     /// An error occurred while parsing a public key as a [`Pubkey`].
     #[error("Invalid pubkey {}", bs58::encode(.0).into_string())]
     BadPubkey(Vec<u8>, std::array::TryFromSliceError),
+    /// An error occurred while decoding base58 memcmp data.
+    #[error("Invalid base58 memcmp data {0:?}")]
+    BadMemcmp(String, #[source] bs58::decode::Error),
 }
 
 /// A builder for constructing a prefilter.
 #[derive(Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
 #[must_use = "Consider calling .build() on this builder"]
 pub struct PrefilterBuilder {
     error: Option<PrefilterError>,
     accounts: Option<HashSet<Pubkey>>,
     account_owners: Option<HashSet<Pubkey>>,
     transaction_accounts: Option<HashSet<Pubkey>>,
+    transaction_accounts_required: Option<HashSet<Pubkey>>,
+    transaction_accounts_excluded: Option<HashSet<Pubkey>>,
+    include_failed: Option<bool>,
+    include_votes: Option<bool>,
+    slots: bool,
+    filter_slots_by_commitment: Option<bool>,
+    blocks: bool,
+    block_accounts: Option<HashSet<Pubkey>>,
+    include_block_transactions: Option<bool>,
+    include_block_accounts: Option<bool>,
+    block_meta: bool,
+    transaction_statuses: bool,
+    entries: bool,
+    account_filters: HashSet<AccountDataFilter>,
+    data_size: Option<u64>,
+    account_data_slices: Vec<DataSlice>,
+    commitment: Option<CommitmentLevel>,
 }
 
 fn set_opt<T>(opt: &mut Option<T>, field: &'static str, val: T) -> Result<(), PrefilterError> {
@@ -434,23 +1159,76 @@ impl PrefilterBuilder {
             accounts,
             account_owners,
             transaction_accounts,
+            transaction_accounts_required,
+            transaction_accounts_excluded,
+            include_failed,
+            include_votes,
+            slots,
+            filter_slots_by_commitment,
+            blocks,
+            block_accounts,
+            include_block_transactions,
+            include_block_accounts,
+            block_meta,
+            transaction_statuses,
+            entries,
+            mut account_filters,
+            data_size,
+            account_data_slices,
+            commitment,
         } = self;
         if let Some(err) = error {
             return Err(err);
         }
 
+        account_filters.extend(data_size.map(AccountDataFilter::DataSize));
         let account = AccountPrefilter {
             accounts: accounts.unwrap_or_default(),
             owners: account_owners.unwrap_or_default(),
+            filters: account_filters,
+            inactive: false,
         };
 
         let transaction = TransactionPrefilter {
             accounts: transaction_accounts.unwrap_or_default(),
+            required: transaction_accounts_required.unwrap_or_default(),
+            excluded: transaction_accounts_excluded.unwrap_or_default(),
+            include_failed: include_failed.unwrap_or(false),
+            exclude_votes: !include_votes.unwrap_or(true),
+            inactive: false,
+        };
+        // Status subscriptions take the transaction options in place of full
+        // transaction subscriptions
+        let (transaction, transaction_status) = if transaction_statuses {
+            (None, Some(transaction))
+        } else {
+            (
+                (transaction != TransactionPrefilter::default()).then_some(transaction),
+                None,
+            )
         };
 
         Ok(Prefilter {
             account: (account != AccountPrefilter::default()).then_some(account),
-            transaction: (transaction != TransactionPrefilter::default()).then_some(transaction),
+            transaction,
+            slot: (slots || filter_slots_by_commitment.is_some()).then(|| SlotPrefilter {
+                filter_by_commitment: filter_slots_by_commitment.unwrap_or(false),
+            }),
+            block: (blocks
+                || block_accounts.is_some()
+                || include_block_transactions.is_some()
+                || include_block_accounts.is_some())
+            .then(|| BlockPrefilter {
+                accounts: block_accounts.unwrap_or_default(),
+                include_transactions: include_block_transactions.unwrap_or(true),
+                include_accounts: include_block_accounts.unwrap_or(false),
+            }),
+            block_meta,
+            transaction_status,
+            entry: entries,
+            commitment,
+            account_data_slices: (!account_data_slices.is_empty())
+                .then(|| DataSlices::new(account_data_slices)),
         })
     }
 
@@ -492,6 +1270,207 @@ impl PrefilterBuilder {
             )
         })
     }
+
+    /// Set the accounts that transactions must all mention to be matched by
+    /// this prefilter.
+    pub fn transaction_accounts_required<I: IntoIterator>(self, it: I) -> Self
+    where I::Item: AsRef<[u8]> {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.transaction_accounts_required,
+                "transaction_accounts_required",
+                collect_pubkeys(it)?,
+            )
+        })
+    }
+
+    /// Set the accounts that transactions must not mention to be matched by
+    /// this prefilter.
+    pub fn transaction_accounts_excluded<I: IntoIterator>(self, it: I) -> Self
+    where I::Item: AsRef<[u8]> {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.transaction_accounts_excluded,
+                "transaction_accounts_excluded",
+                collect_pubkeys(it)?,
+            )
+        })
+    }
+
+    /// Set whether this prefilter matches failed transactions.  Defaults to
+    /// `false`.
+    pub fn include_failed(self, include: bool) -> Self {
+        self.mutate(|this| set_opt(&mut this.include_failed, "include_failed", include))
+    }
+
+    /// Set whether this prefilter matches vote transactions.  Defaults to
+    /// `true`.
+    pub fn include_votes(self, include: bool) -> Self {
+        self.mutate(|this| set_opt(&mut this.include_votes, "include_votes", include))
+    }
+
+    /// Match slot status updates.
+    pub fn slots(mut self) -> Self {
+        self.slots = true;
+        self
+    }
+
+    /// Set whether this prefilter only matches slot updates at the
+    /// commitment level of its subscription, rather than every status
+    /// transition of each slot.  Implies [`slots`](Self::slots).  Defaults to
+    /// `false`.
+    pub fn filter_slots_by_commitment(self, filter: bool) -> Self {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.filter_slots_by_commitment,
+                "filter_slots_by_commitment",
+                filter,
+            )
+        })
+    }
+
+    /// Match full block updates.
+    pub fn blocks(mut self) -> Self {
+        self.blocks = true;
+        self
+    }
+
+    /// Set the accounts that the transactions and accounts of matched blocks
+    /// must mention.  Blocks are still sent when nothing in them matches, but
+    /// with their transactions and accounts narrowed down to those that do.
+    /// Implies [`blocks`](Self::blocks).
+    pub fn block_accounts<I: IntoIterator>(self, it: I) -> Self
+    where I::Item: AsRef<[u8]> {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.block_accounts,
+                "block_accounts",
+                collect_pubkeys(it)?,
+            )
+        })
+    }
+
+    /// Set whether matched blocks include their transactions.  Implies
+    /// [`blocks`](Self::blocks).  Defaults to `true`.
+    pub fn include_block_transactions(self, include: bool) -> Self {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.include_block_transactions,
+                "include_block_transactions",
+                include,
+            )
+        })
+    }
+
+    /// Set whether matched blocks include the accounts updated in them.
+    /// Implies [`blocks`](Self::blocks).  Defaults to `false`.
+    pub fn include_block_accounts(self, include: bool) -> Self {
+        self.mutate(|this| {
+            set_opt(
+                &mut this.include_block_accounts,
+                "include_block_accounts",
+                include,
+            )
+        })
+    }
+
+    /// Match block metadata updates.
+    pub fn block_meta(mut self) -> Self {
+        self.block_meta = true;
+        self
+    }
+
+    /// Match transaction status updates instead of full transaction updates.
+    /// The transaction accounts and options given to this builder apply to
+    /// the status updates instead.
+    pub fn transaction_statuses(mut self) -> Self {
+        self.transaction_statuses = true;
+        self
+    }
+
+    /// Match ledger entry updates.
+    pub fn entries(mut self) -> Self {
+        self.entries = true;
+        self
+    }
+
+    /// Only match accounts whose data contains the given bytes at the given
+    /// offset.  May be given more than once, in which case every comparison
+    /// must match.
+    pub fn memcmp(self, offset: u64, bytes: impl AsRef<[u8]>) -> Self {
+        self.mutate(|this| {
+            this.account_filters.insert(AccountDataFilter::Memcmp {
+                offset,
+                bytes: bytes.as_ref().to_vec(),
+            });
+            Ok(())
+        })
+    }
+
+    /// Only match accounts whose data contains the given base58-encoded bytes
+    /// at the given offset.  May be given more than once, in which case every
+    /// comparison must match.
+    pub fn memcmp_base58(self, offset: u64, data: &str) -> Self {
+        self.mutate(|this| {
+            let bytes = bs58::decode(data)
+                .into_vec()
+                .map_err(|e| PrefilterError::BadMemcmp(data.to_owned(), e))?;
+            this.account_filters
+                .insert(AccountDataFilter::Memcmp { offset, bytes });
+            Ok(())
+        })
+    }
+
+    /// Only match accounts whose data is exactly the given length in bytes.
+    pub fn data_size(self, len: u64) -> Self {
+        self.mutate(|this| set_opt(&mut this.data_size, "data_size", len))
+    }
+
+    /// Only match accounts that are valid SPL token accounts.
+    pub fn token_account_state(self) -> Self {
+        self.mutate(|this| {
+            this.account_filters
+                .insert(AccountDataFilter::TokenAccountState);
+            Ok(())
+        })
+    }
+
+    /// Only match accounts whose lamport balance satisfies the given
+    /// comparison.  May be given more than once, in which case every
+    /// comparison must match.
+    pub fn lamports(self, filter: LamportsFilter) -> Self {
+        self.mutate(|this| {
+            this.account_filters
+                .insert(AccountDataFilter::Lamports(filter));
+            Ok(())
+        })
+    }
+
+    /// Request only the given range of account data rather than the full
+    /// data.  May be given more than once to request several ranges, which
+    /// are passed to [`Parser::parse_sliced`] concatenated in order of
+    /// offset, with overlapping ranges joined.
+    pub fn account_data_slice(self, offset: u64, length: u64) -> Self {
+        self.mutate(|this| {
+            this.account_data_slices.push(DataSlice { offset, length });
+            Ok(())
+        })
+    }
+
+    /// Set the commitment level this prefilter requests, overriding the
+    /// runtime's configured default.
+    pub fn commitment(self, commitment: CommitmentLevel) -> Self {
+        self.mutate(|this| set_opt(&mut this.commitment, "commitment", commitment))
+    }
+}
+
+/// The options shared by every filter in a single Yellowstone subscription.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubscriptionKey {
+    /// The commitment level of the subscription.
+    pub commitment: Option<CommitmentLevel>,
+    /// The account data slices requested by the subscription.
+    pub account_data_slices: Option<DataSlices>,
 }
 
 /// A collection of filters for a Vixen subscription.
@@ -504,6 +1483,60 @@ impl<'a> Filters<'a> {
     #[inline]
     #[must_use]
     pub const fn new(filters: HashMap<&'a str, Prefilter>) -> Self { Self(filters) }
+
+    /// Split this collection into one collection per subscription, as each
+    /// Yellowstone subscription can only request a single commitment level
+    /// and a single set of account data slices.
+    ///
+    /// Prefilters that do not request a commitment level are assigned the
+    /// given default.
+    #[must_use]
+    pub fn by_subscription(
+        self,
+        default: Option<CommitmentLevel>,
+    ) -> HashMap<SubscriptionKey, Self> {
+        let mut groups = HashMap::<_, Self>::new();
+
+        for (name, mut prefilter) in self.0 {
+            prefilter.commitment = prefilter.commitment.or(default);
+            // Slices only apply to account updates
+            if prefilter.active_account().is_none() {
+                prefilter.account_data_slices = None;
+            }
+
+            let key = SubscriptionKey {
+                commitment: prefilter.commitment,
+                account_data_slices: prefilter.account_data_slices.clone(),
+            };
+            groups
+                .entry(key)
+                .or_insert_with(|| Self(HashMap::new()))
+                .0
+                .insert(name, prefilter);
+        }
+
+        groups
+    }
+
+    /// The account data slices requested by these filters, if every
+    /// prefilter matching accounts requests the same slices.
+    #[must_use]
+    pub fn account_data_slices(&self) -> Option<&DataSlices> {
+        let mut slices = self
+            .values()
+            .filter(|p| p.active_account().is_some())
+            .map(Prefilter::account_data_slices);
+        let first = slices.next()??;
+
+        slices.all(|s| s == Some(first)).then_some(first)
+    }
+
+    /// The commitment level requested by these filters, taken as the most
+    /// final level requested by any of its prefilters.
+    #[must_use]
+    pub fn commitment(&self) -> Option<CommitmentLevel> {
+        self.values().filter_map(Prefilter::commitment).max()
+    }
 }
 
 impl<'a> ops::Deref for Filters<'a> {
@@ -519,41 +1552,740 @@ impl<'a> From<Filters<'a>> for SubscribeRequest {
             accounts: value
                 .iter()
                 .filter_map(|(k, v)| {
-                    let v = v.account.as_ref()?;
+                    let v = v.active_account()?;
 
                     Some((k.to_owned().into(), SubscribeRequestFilterAccounts {
                         account: v.accounts.iter().map(ToString::to_string).collect(),
                         owner: v.owners.iter().map(ToString::to_string).collect(),
-                        // TODO: probably a good thing to look into
-                        filters: vec![],
+                        filters: v.filters.iter().map(Into::into).collect(),
+                        nonempty_txn_signature: None,
+                    }))
+                })
+                .collect(),
+            slots: value
+                .iter()
+                .filter_map(|(k, v)| {
+                    let v = v.slot.as_ref()?;
+
+                    Some((k.to_owned().into(), SubscribeRequestFilterSlots {
+                        filter_by_commitment: Some(v.filter_by_commitment),
+                        interslot_updates: None,
                     }))
                 })
                 .collect(),
-            slots: [].into_iter().collect(),
             transactions: value
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_owned().into(), v.active_transaction()?.into())))
+                .collect(),
+            transactions_status: value
                 .iter()
                 .filter_map(|(k, v)| {
-                    let v = v.transaction.as_ref()?;
-
-                    Some((k.to_owned().into(), SubscribeRequestFilterTransactions {
-                        vote: None,
-                        // TODO: make this configurable
-                        failed: Some(false),
-                        signature: None,
-                        // TODO: figure these out
+                    Some((k.to_owned().into(), v.active_transaction_status()?.into()))
+                })
+                .collect(),
+            blocks: value
+                .iter()
+                .filter_map(|(k, v)| {
+                    let v = v.block.as_ref()?;
+
+                    Some((k.to_owned().into(), SubscribeRequestFilterBlocks {
                         account_include: v.accounts.iter().map(ToString::to_string).collect(),
-                        account_exclude: [].into_iter().collect(),
-                        account_required: [].into_iter().collect(),
+                        include_transactions: Some(v.include_transactions),
+                        include_accounts: Some(v.include_accounts),
+                        include_entries: Some(false),
                     }))
                 })
                 .collect(),
-            transactions_status: [].into_iter().collect(),
-            blocks: [].into_iter().collect(),
-            blocks_meta: [].into_iter().collect(),
-            entry: [].into_iter().collect(),
-            commitment: None,
-            accounts_data_slice: vec![],
+            blocks_meta: value
+                .iter()
+                .filter(|(_, v)| v.block_meta)
+                .map(|(k, _)| (k.to_owned().into(), SubscribeRequestFilterBlocksMeta {}))
+                .collect(),
+            entry: value
+                .iter()
+                .filter(|(_, v)| v.entry)
+                .map(|(k, _)| (k.to_owned().into(), SubscribeRequestFilterEntry {}))
+                .collect(),
+            commitment: value
+                .commitment()
+                .map(|c| geyser::CommitmentLevel::from(c) as i32),
+            accounts_data_slice: value
+                .account_data_slices()
+                .map(|s| {
+                    s.iter()
+                        .map(
+                            |&DataSlice { offset, length }| SubscribeRequestAccountsDataSlice {
+                                offset,
+                                length,
+                            },
+                        )
+                        .collect()
+                })
+                .unwrap_or_default(),
             ping: None,
+            from_slot: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str, prefilter: &Prefilter) -> SubscribeRequest {
+        Filters::new([(name, prefilter.clone())].into_iter().collect()).into()
+    }
+
+    fn slices(ranges: &[(u64, u64)]) -> DataSlices {
+        DataSlices::new(
+            ranges
+                .iter()
+                .map(|&(offset, length)| DataSlice { offset, length }),
+        )
+    }
+
+    fn ranges(slices: &DataSlices) -> Vec<(u64, u64)> {
+        slices.iter().map(|s| (s.offset, s.length)).collect()
+    }
+
+    #[test]
+    fn test_slices_normalized() {
+        // Sorted, with overlapping and touching slices joined and empty slices
+        // dropped
+        let s = slices(&[(40, 8), (0, 4), (2, 4), (6, 2), (20, 0), (44, 2)]);
+        assert_eq!(ranges(&s), [(0, 8), (40, 8)]);
+
+        assert_eq!(slices(&[(8, 8), (0, 32)]), slices(&[(0, 32)]));
+        assert_eq!(ranges(&slices(&[])), []);
+    }
+
+    #[test]
+    fn test_slices_get() {
+        let s = slices(&[(4, 4), (16, 8)]);
+        // The sliced data holds bytes 4..8 followed by bytes 16..24
+        let data: Vec<u8> = (4..8).chain(16..24).collect();
+
+        assert_eq!(s.get(&data, 4, 4), Some(&[4, 5, 6, 7][..]));
+        assert_eq!(s.get(&data, 18, 2), Some(&[18, 19][..]));
+        assert_eq!(s.get(&data, 16, 8), Some(&data[4..]));
+        // Ranges outside of or spanning requested slices are unavailable
+        assert_eq!(s.get(&data, 0, 2), None);
+        assert_eq!(s.get(&data, 6, 12), None);
+        assert_eq!(s.get(&data, 22, 4), None);
+        assert_eq!(s.get(&data, u64::MAX, 2), None);
+        // As are ranges past the end of an account shorter than its slices
+        assert_eq!(s.get(&data[..6], 18, 2), None);
+    }
+
+    #[test]
+    fn test_slices_merged_and_grouped() {
+        let sliced = |offset| {
+            Prefilter::builder()
+                .account_owners([[1; 32]])
+                .account_data_slice(offset, 8)
+                .build()
+                .unwrap()
+        };
+
+        let mut merged = sliced(0);
+        merged.merge(sliced(0));
+        assert_eq!(merged.account_data_slices(), Some(&slices(&[(0, 8)])));
+        merged.merge(sliced(8));
+        assert_eq!(merged.account_data_slices(), None);
+
+        // Pipelines requesting different slices get separate subscriptions,
+        // and slices requested without accounts are ignored
+        let unsliced = Prefilter::builder()
+            .transaction_accounts([[1; 32]])
+            .account_data_slice(0, 8)
+            .build()
+            .unwrap();
+        let groups = Filters::new(
+            [
+                ("a", sliced(0)),
+                ("b", sliced(8)),
+                ("c", sliced(0)),
+                ("d", unsliced),
+            ]
+            .into_iter()
+            .collect(),
+        )
+        .by_subscription(None);
+        assert_eq!(groups.len(), 3);
+
+        for (key, filters) in groups {
+            let mut names: Vec<_> = filters.keys().copied().collect();
+            names.sort_unstable();
+            let req = SubscribeRequest::from(filters);
+            let requested: Vec<_> = req
+                .accounts_data_slice
+                .iter()
+                .map(|s| (s.offset, s.length))
+                .collect();
+
+            match key.account_data_slices.as_ref().map(ranges).as_deref() {
+                Some([(0, 8)]) => assert_eq!(names, ["a", "c"]),
+                Some([(8, 8)]) => assert_eq!(names, ["b"]),
+                None => assert_eq!(names, ["d"]),
+                s => panic!("Unexpected slices {s:?}"),
+            }
+            assert_eq!(
+                requested,
+                key.account_data_slices
+                    .as_ref()
+                    .map(ranges)
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    #[test]
+    fn test_by_subscription() {
+        let prefilter = |commitment: Option<CommitmentLevel>, slice: Option<u64>| {
+            let mut b = Prefilter::builder().account_owners([[1; 32]]);
+            if let Some(c) = commitment {
+                b = b.commitment(c);
+            }
+            if let Some(offset) = slice {
+                b = b.account_data_slice(offset, 8);
+            }
+            b.build().unwrap()
+        };
+        let filters = || {
+            let unsliced_txns = Prefilter::builder()
+                .transaction_accounts([[1; 32]])
+                .commitment(CommitmentLevel::Finalized)
+                .account_data_slice(0, 8)
+                .build()
+                .unwrap();
+            Filters::new(
+                [
+                    ("a", prefilter(Some(CommitmentLevel::Finalized), None)),
+                    ("b", prefilter(Some(CommitmentLevel::Processed), None)),
+                    ("c", prefilter(None, None)),
+                    ("d", prefilter(None, None)),
+                    ("e", prefilter(Some(CommitmentLevel::Finalized), Some(0))),
+                    ("f", unsliced_txns),
+                    ("g", prefilter(None, Some(0))),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+        let groups = |default| {
+            let mut groups: Vec<_> = filters()
+                .by_subscription(default)
+                .into_iter()
+                .map(|(key, filters)| {
+                    let mut names: Vec<_> = filters.keys().copied().collect();
+                    names.sort_unstable();
+                    let req = SubscribeRequest::from(filters);
+                    assert_eq!(
+                        req.commitment,
+                        key.commitment
+                            .map(|c| geyser::CommitmentLevel::from(c) as i32)
+                    );
+                    let slices = key.account_data_slices.as_ref().map(ranges);
+                    (key.commitment, slices, names)
+                })
+                .collect();
+            groups.sort_unstable();
+            groups
+        };
+
+        let sliced = Some(vec![(0, 8)]);
+        // Prefilters without a commitment level share a subscription at the
+        // default level, and only slices requested with accounts split them
+        assert_eq!(groups(None), [
+            (None, None, vec!["c", "d"]),
+            (None, sliced.clone(), vec!["g"]),
+            (Some(CommitmentLevel::Processed), None, vec!["b"]),
+            (Some(CommitmentLevel::Finalized), None, vec!["a", "f"]),
+            (Some(CommitmentLevel::Finalized), sliced.clone(), vec!["e"]),
+        ]);
+        assert_eq!(groups(Some(CommitmentLevel::Finalized)), [
+            (Some(CommitmentLevel::Processed), None, vec!["b"]),
+            (Some(CommitmentLevel::Finalized), None, vec![
+                "a", "c", "d", "f"
+            ]),
+            (Some(CommitmentLevel::Finalized), sliced, vec!["e", "g"]),
+        ]);
+    }
+
+    #[test]
+    fn test_account_selection() {
+        let [key, owner, other] = [[1; 32], [2; 32], [3; 32]].map(Pubkey::new);
+
+        let by_owner = Prefilter::builder()
+            .account_owners([owner])
+            .data_size(4)
+            .build()
+            .unwrap();
+        let selection = by_owner.account_selection().unwrap();
+        assert!(selection.matches(&key, &owner, &[0; 4], 0));
+        assert!(selection.matches(&other, &owner, &[0; 4], 0));
+        assert!(!selection.matches(&key, &other, &[0; 4], 0));
+        assert!(!selection.matches(&key, &owner, &[0; 5], 0));
+
+        let by_key = Prefilter::builder().accounts([key]).build().unwrap();
+        let selection = by_key.account_selection().unwrap();
+        assert!(selection.matches(&key, &other, &[], 0));
+        assert!(!selection.matches(&other, &owner, &[], 0));
+
+        // Prefilters without accounts select nothing
+        let transactions = Prefilter::builder()
+            .transaction_accounts([key])
+            .build()
+            .unwrap();
+        assert!(transactions.account_selection().is_none());
+    }
+
+    fn sorted(keys: &[String]) -> Vec<String> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys
+    }
+
+    fn keys<const N: usize>(keys: [[u8; 32]; N]) -> Vec<String> {
+        sorted(&keys.map(|k| Pubkey::new(k).to_string()))
+    }
+
+    #[test]
+    fn test_transaction_defaults() {
+        let req = request(
+            "p",
+            &Prefilter::builder()
+                .transaction_accounts([[1; 32]])
+                .build()
+                .unwrap(),
+        );
+        let txs = &req.transactions["p"];
+        assert_eq!(txs.failed, Some(false));
+        assert_eq!(txs.vote, None);
+        assert!(txs.account_required.is_empty());
+        assert!(txs.account_exclude.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_merge() {
+        let [k1, k2, r1, r2, x1, x2, x3] = [1, 2, 3, 4, 5, 6, 7].map(|i| [i; 32]);
+        let a = Prefilter::builder()
+            .transaction_accounts([k1])
+            .transaction_accounts_required([r1, r2])
+            .transaction_accounts_excluded([x1, x2])
+            .build()
+            .unwrap();
+        let b = Prefilter::builder()
+            .transaction_accounts([k2])
+            .transaction_accounts_required([r2])
+            .transaction_accounts_excluded([x2, x3])
+            .include_failed(true)
+            .include_votes(false)
+            .build()
+            .unwrap();
+
+        // The merged filter matches any transaction either filter matches, so
+        // it includes either's accounts but only requires and excludes the
+        // accounts both of them do
+        let mut merged = a;
+        merged.merge(b.clone());
+        let req = request("p", &merged);
+        let txs = &req.transactions["p"];
+        assert_eq!(sorted(&txs.account_include), keys([k1, k2]));
+        assert_eq!(txs.account_required, keys([r2]));
+        assert_eq!(txs.account_exclude, keys([x2]));
+        assert_eq!(txs.failed, None);
+        assert_eq!(txs.vote, None);
+
+        // A filter without included accounts matches transactions mentioning
+        // any account
+        let mut merged = b;
+        merged.merge(
+            Prefilter::builder()
+                .transaction_accounts_required([r2])
+                .build()
+                .unwrap(),
+        );
+        let req = request("p", &merged);
+        let txs = &req.transactions["p"];
+        assert!(txs.account_include.is_empty());
+        assert_eq!(txs.account_required, keys([r2]));
+        assert!(txs.account_exclude.is_empty());
+        assert_eq!(txs.failed, None);
+        assert_eq!(txs.vote, None);
+
+        // Failed and vote transactions stay excluded if both filters exclude
+        // them
+        let no_votes = |k| {
+            Prefilter::builder()
+                .transaction_accounts([k])
+                .include_votes(false)
+                .build()
+                .unwrap()
+        };
+        let mut merged = no_votes(k1);
+        merged.merge(no_votes(k2));
+        let txs = &request("p", &merged).transactions["p"];
+        assert_eq!(txs.failed, Some(false));
+        assert_eq!(txs.vote, Some(false));
+    }
+
+    #[test]
+    fn test_account_filter_merge() {
+        let [o1, o2] = [[1; 32], [2; 32]];
+        let mut merged = Prefilter::builder()
+            .account_owners([o1])
+            .memcmp(0, [1])
+            .data_size(165)
+            .build()
+            .unwrap();
+        merged.merge(
+            Prefilter::builder()
+                .account_owners([o2])
+                .memcmp(0, [1])
+                .token_account_state()
+                .build()
+                .unwrap(),
+        );
+
+        // Only the data filters both prefilters require are kept
+        let req = request("p", &merged);
+        let accounts = &req.accounts["p"];
+        assert_eq!(sorted(&accounts.owner), keys([o1, o2]));
+        assert_eq!(accounts.filters, vec![
+            SubscribeRequestFilterAccountsFilter::from(&AccountDataFilter::Memcmp {
+                offset: 0,
+                bytes: vec![1],
+            })
+        ]);
+    }
+
+    #[test]
+    fn test_memcmp_base58() {
+        let prefilter = Prefilter::builder()
+            .account_owners([[1; 32]])
+            .memcmp_base58(2, &bs58::encode([7, 8]).into_string())
+            .build()
+            .unwrap();
+        let selection = prefilter.account_selection().unwrap();
+        assert_eq!(selection.filters.iter().collect::<Vec<_>>(), [
+            &AccountDataFilter::Memcmp {
+                offset: 2,
+                bytes: vec![7, 8],
+            }
+        ]);
+
+        let err = Prefilter::builder()
+            .memcmp_base58(0, "not base58!")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, PrefilterError::BadMemcmp(ref d, _) if d == "not base58!"));
+    }
+
+    #[test]
+    fn test_account_data_filter_matches() {
+        let memcmp = AccountDataFilter::Memcmp {
+            offset: 2,
+            bytes: vec![7, 8],
+        };
+        assert!(memcmp.matches(&[0, 0, 7, 8, 0], 0));
+        assert!(!memcmp.matches(&[0, 0, 7, 9], 0));
+        // Data too short to hold the bytes never matches
+        assert!(!memcmp.matches(&[0, 0, 7], 0));
+
+        assert!(AccountDataFilter::DataSize(3).matches(&[0; 3], 0));
+        assert!(!AccountDataFilter::DataSize(3).matches(&[0; 4], 0));
+
+        for (filter, matching) in [
+            (LamportsFilter::Eq(10), [false, true, false]),
+            (LamportsFilter::Ne(10), [true, false, true]),
+            (LamportsFilter::Lt(10), [true, false, false]),
+            (LamportsFilter::Gt(10), [false, false, true]),
+        ] {
+            let filter = AccountDataFilter::Lamports(filter);
+            assert_eq!([9, 10, 11].map(|l| filter.matches(&[], l)), matching);
+        }
+    }
+
+    #[test]
+    fn test_token_account_state() {
+        let token = |len, state, account_type| {
+            let mut data = vec![0; len];
+            data[TOKEN_ACCOUNT_STATE_OFFSET] = state;
+            if let Some(t) = account_type {
+                data[TOKEN_ACCOUNT_LEN] = t;
+            }
+            AccountDataFilter::TokenAccountState.matches(&data, 0)
+        };
+
+        // Initialized and frozen token accounts match, uninitialized do not
+        assert!(token(TOKEN_ACCOUNT_LEN, 1, None));
+        assert!(token(TOKEN_ACCOUNT_LEN, 2, None));
+        assert!(!token(TOKEN_ACCOUNT_LEN, 0, None));
+        // Token-2022 accounts with extensions are tagged with their type
+        assert!(token(
+            TOKEN_ACCOUNT_LEN + 10,
+            1,
+            Some(TOKEN_2022_ACCOUNT_TYPE)
+        ));
+        assert!(!token(TOKEN_ACCOUNT_LEN + 10, 1, Some(1)));
+        assert!(!token(TOKEN_ACCOUNT_LEN + 10, 1, None));
+        assert!(!AccountDataFilter::TokenAccountState.matches(&[1; 100], 0));
+    }
+
+    #[test]
+    fn test_account_data_filter_request() {
+        use subscribe_request_filter_accounts_filter::Filter;
+        use subscribe_request_filter_accounts_filter_lamports::Cmp;
+        use subscribe_request_filter_accounts_filter_memcmp::Data;
+
+        let filter = |f: AccountDataFilter| SubscribeRequestFilterAccountsFilter::from(&f).filter;
+
+        assert_eq!(
+            filter(AccountDataFilter::Memcmp {
+                offset: 4,
+                bytes: vec![1, 2],
+            }),
+            Some(Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+                offset: 4,
+                data: Some(Data::Bytes(vec![1, 2])),
+            }))
+        );
+        assert_eq!(
+            filter(AccountDataFilter::DataSize(165)),
+            Some(Filter::Datasize(165))
+        );
+        assert_eq!(
+            filter(AccountDataFilter::TokenAccountState),
+            Some(Filter::TokenAccountState(true))
+        );
+
+        for (lamports, cmp) in [
+            (LamportsFilter::Eq(1), Cmp::Eq(1)),
+            (LamportsFilter::Ne(2), Cmp::Ne(2)),
+            (LamportsFilter::Lt(3), Cmp::Lt(3)),
+            (LamportsFilter::Gt(4), Cmp::Gt(4)),
+        ] {
+            assert_eq!(
+                filter(AccountDataFilter::Lamports(lamports)),
+                Some(Filter::Lamports(
+                    SubscribeRequestFilterAccountsFilterLamports { cmp: Some(cmp) }
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_remove_all_then_add_accounts() {
+        let [a, b] = [[1; 32], [2; 32]];
+        let mut prefilter = Prefilter::builder()
+            .accounts([a])
+            .memcmp(0, [7])
+            .data_size(165)
+            .build()
+            .unwrap();
+
+        prefilter.subtract(&Prefilter::builder().accounts([a]).build().unwrap());
+        let req = request("p", &prefilter);
+        assert!(req.accounts.is_empty());
+        assert!(prefilter.account_selection().is_none());
+
+        prefilter.extend(Prefilter::builder().accounts([b]).build().unwrap());
+        let req = request("p", &prefilter);
+        let accounts = &req.accounts["p"];
+        assert_eq!(accounts.account, vec![Pubkey::new(b).to_string()]);
+        assert_eq!(accounts.filters.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_all_then_add_transactions() {
+        let [a, b, required] = [[1; 32], [2; 32], [3; 32]];
+        let mut prefilter = Prefilter::builder()
+            .transaction_accounts([a])
+            .transaction_accounts_required([required])
+            .include_failed(true)
+            .include_votes(false)
+            .build()
+            .unwrap();
+
+        prefilter.subtract(
+            &Prefilter::builder()
+                .transaction_accounts([a])
+                .build()
+                .unwrap(),
+        );
+        assert!(request("p", &prefilter).transactions.is_empty());
+
+        prefilter.extend(
+            Prefilter::builder()
+                .transaction_accounts([b])
+                .build()
+                .unwrap(),
+        );
+        let req = request("p", &prefilter);
+        let txs = &req.transactions["p"];
+        assert_eq!(txs.account_include, vec![Pubkey::new(b).to_string()]);
+        assert_eq!(
+            txs.account_required,
+            vec![Pubkey::new(required).to_string()]
+        );
+        assert_eq!(txs.failed, None);
+        assert_eq!(txs.vote, Some(false));
+    }
+
+    #[test]
+    fn test_transaction_status_keys() {
+        let [a, b] = [[1; 32], [2; 32]];
+        let status_keys = |k| {
+            Prefilter::builder()
+                .transaction_accounts([k])
+                .transaction_statuses()
+                .build()
+                .unwrap()
+        };
+        let mut prefilter = status_keys(a);
+
+        prefilter.extend(status_keys(b));
+        let req = request("p", &prefilter);
+        assert!(req.transactions.is_empty());
+        assert_eq!(req.transactions_status["p"].account_include.len(), 2);
+
+        prefilter.subtract(&status_keys(a));
+        prefilter.subtract(&status_keys(b));
+        assert!(request("p", &prefilter).transactions_status.is_empty());
+
+        prefilter.extend(status_keys(a));
+        let req = request("p", &prefilter);
+        assert!(req.transactions.is_empty());
+        assert_eq!(req.transactions_status["p"].account_include, vec![
+            Pubkey::new(a).to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_slot_request() {
+        let slots = |filter: Option<bool>| {
+            let b = Prefilter::builder();
+            match filter {
+                Some(f) => b.filter_slots_by_commitment(f),
+                None => b.slots(),
+            }
+            .build()
+            .unwrap()
+        };
+        let requested = |prefilter: &Prefilter| {
+            let req = request("p", prefilter);
+            assert!(req.blocks.is_empty() && req.blocks_meta.is_empty());
+            req.slots["p"]
+        };
+
+        assert_eq!(requested(&slots(None)), SubscribeRequestFilterSlots {
+            filter_by_commitment: Some(false),
+            interslot_updates: None,
+        });
+        assert_eq!(
+            requested(&slots(Some(true))).filter_by_commitment,
+            Some(true)
+        );
+
+        // Every status transition is requested if any pipeline wants them
+        let mut merged = slots(Some(true));
+        merged.merge(slots(None));
+        assert_eq!(requested(&merged).filter_by_commitment, Some(false));
+        let mut merged = slots(Some(true));
+        merged.merge(slots(Some(true)));
+        assert_eq!(requested(&merged).filter_by_commitment, Some(true));
+        let mut merged = slots(Some(true));
+        merged.merge(Prefilter::builder().accounts([[1; 32]]).build().unwrap());
+        assert_eq!(requested(&merged).filter_by_commitment, Some(true));
+    }
+
+    #[test]
+    fn test_block_request() {
+        let [a, b] = [[1; 32], [2; 32]];
+        let requested = |prefilter: &Prefilter| {
+            let req = request("p", prefilter);
+            assert!(req.slots.is_empty() && req.blocks_meta.is_empty());
+            req.blocks["p"].clone()
+        };
+
+        assert_eq!(
+            requested(&Prefilter::builder().blocks().build().unwrap()),
+            SubscribeRequestFilterBlocks {
+                account_include: vec![],
+                include_transactions: Some(true),
+                include_accounts: Some(false),
+                include_entries: Some(false),
+            }
+        );
+
+        // Any block option implies blocks
+        let narrowed = |key| {
+            Prefilter::builder()
+                .block_accounts([key])
+                .include_block_transactions(false)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(requested(&narrowed(a)), SubscribeRequestFilterBlocks {
+            account_include: keys([a]),
+            include_transactions: Some(false),
+            include_accounts: Some(false),
+            include_entries: Some(false),
+        });
+
+        let mut merged = narrowed(a);
+        merged.merge(narrowed(b));
+        let req = requested(&merged);
+        assert_eq!(sorted(&req.account_include), keys([a, b]));
+        assert_eq!(req.include_transactions, Some(false));
+
+        // Options are combined so every pipeline gets what it asked for
+        merged.merge(
+            Prefilter::builder()
+                .include_block_accounts(true)
+                .build()
+                .unwrap(),
+        );
+        let req = requested(&merged);
+        assert!(req.account_include.is_empty());
+        assert_eq!(req.include_transactions, Some(true));
+        assert_eq!(req.include_accounts, Some(true));
+    }
+
+    #[test]
+    fn test_block_meta_request() {
+        let req = request("p", &Prefilter::builder().block_meta().build().unwrap());
+        assert_eq!(req.blocks_meta.into_iter().collect::<Vec<_>>(), [(
+            "p".to_owned(),
+            SubscribeRequestFilterBlocksMeta {}
+        )]);
+        assert!(req.slots.is_empty() && req.blocks.is_empty());
+
+        let mut merged = Prefilter::builder().slots().build().unwrap();
+        merged.merge(Prefilter::builder().block_meta().build().unwrap());
+        let req = request("p", &merged);
+        assert!(req.blocks_meta.contains_key("p"));
+        assert!(req.slots.contains_key("p"));
+
+        let req = request("p", &Prefilter::builder().blocks().build().unwrap());
+        assert!(req.blocks_meta.is_empty());
+    }
+
+    #[test]
+    fn test_merge_inactive() {
+        let [a, b] = [[1; 32], [2; 32]];
+        let mut inactive = Prefilter::builder().accounts([a]).build().unwrap();
+        inactive.subtract(&Prefilter::builder().accounts([a]).build().unwrap());
+
+        let mut merged = inactive.clone();
+        merged.merge(Prefilter::builder().accounts([b]).build().unwrap());
+        let req = request("p", &merged);
+        assert_eq!(req.accounts["p"].account, vec![Pubkey::new(b).to_string()]);
+
+        let mut merged = Prefilter::builder().accounts([b]).build().unwrap();
+        merged.merge(inactive);
+        let req = request("p", &merged);
+        assert_eq!(req.accounts["p"].account, vec![Pubkey::new(b).to_string()]);
+    }
+}
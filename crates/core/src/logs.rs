@@ -0,0 +1,322 @@
+//! Parsing of the log messages of a transaction into a tree of program
+//! invocations.
+//!
+//! The runtime brackets the logs of each program invocation with
+//! `Program <id> invoke [<depth>]` and `Program <id> success` (or
+//! `Program <id> failed: <error>`) lines, so the flat list of log messages in
+//! [`InstructionShared::log_messages`](crate::instruction::InstructionShared::log_messages)
+//! can be split back into the logs written by each instruction.
+
+use base64::Engine;
+
+use crate::Pubkey;
+
+/// A line logged during a program invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogLine {
+    /// A message logged by the program, from a `Program log:` line.
+    Log(String),
+    /// Binary data logged by the program, from a `Program data:` line, with
+    /// one entry per logged field.
+    Data(Vec<Vec<u8>>),
+    /// The data returned by the program, from a `Program return:` line.
+    Return(Vec<u8>),
+    /// Any other line, such as one written by a native program or the
+    /// runtime.
+    Other(String),
+}
+
+/// The compute units consumed by a program invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComputeUnits {
+    /// The number of compute units consumed, including by inner invocations.
+    pub consumed: u64,
+    /// The number of compute units that were available to the invocation.
+    pub limit: u64,
+}
+
+/// The outcome of a program invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvocationResult {
+    /// The program returned successfully.
+    Success,
+    /// The program failed with the given error.
+    Failed(String),
+    /// The logs ended before the invocation did, most likely because they
+    /// were truncated.
+    Unknown,
+}
+
+/// The logs written during a single program invocation, excluding those of
+/// the invocations it made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvocationLogs {
+    /// The lines logged, in order.
+    pub lines: Vec<LogLine>,
+    /// The compute units consumed, if logged.
+    pub compute_units: Option<ComputeUnits>,
+    /// The outcome of the invocation.
+    pub result: InvocationResult,
+}
+
+impl InvocationLogs {
+    /// Iterate over the messages from `Program log:` lines.
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|l| match l {
+            LogLine::Log(s) => Some(s.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the fields of each `Program data:` line.
+    pub fn data(&self) -> impl Iterator<Item = &[Vec<u8>]> {
+        self.lines.iter().filter_map(|l| match l {
+            LogLine::Data(d) => Some(d.as_slice()),
+            _ => None,
+        })
+    }
+}
+
+/// A program invocation recovered from a transaction's logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The ID of the invoked program.
+    pub program: Pubkey,
+    /// The invocation depth, which is 1 for top-level instructions.
+    pub depth: u32,
+    /// The logs written by this invocation.
+    pub logs: InvocationLogs,
+    /// The invocations made by this invocation, in order.
+    pub inner: Vec<Invocation>,
+}
+
+/// Parse the log messages of a transaction into the top-level program
+/// invocations they describe.
+///
+/// Lines that cannot be attributed to any invocation are discarded.
+/// Invocations still open when the logs end are given a result of
+/// [`InvocationResult::Unknown`].
+#[must_use]
+pub fn parse<S: AsRef<str>>(lines: &[S]) -> Vec<Invocation> {
+    let mut roots = vec![];
+    let mut open: Vec<Invocation> = vec![];
+
+    for line in lines {
+        let line = line.as_ref();
+        match parse_line(line) {
+            Line::Invoke(program, depth) => open.push(Invocation {
+                program,
+                depth,
+                logs: InvocationLogs {
+                    lines: vec![],
+                    compute_units: None,
+                    result: InvocationResult::Unknown,
+                },
+                inner: vec![],
+            }),
+            Line::Exit(result) => {
+                let Some(mut done) = open.pop() else {
+                    continue;
+                };
+                done.logs.result = result;
+                open.last_mut()
+                    .map_or(&mut roots, |p| &mut p.inner)
+                    .push(done);
+            },
+            Line::Consumed(units) => {
+                if let Some(top) = open.last_mut() {
+                    top.logs.compute_units = Some(units);
+                }
+            },
+            Line::Logged(l) => {
+                if let Some(top) = open.last_mut() {
+                    top.logs.lines.push(l);
+                }
+            },
+        }
+    }
+
+    while let Some(done) = open.pop() {
+        open.last_mut()
+            .map_or(&mut roots, |p| &mut p.inner)
+            .push(done);
+    }
+
+    roots
+}
+
+enum Line {
+    Invoke(Pubkey, u32),
+    Exit(InvocationResult),
+    Consumed(ComputeUnits),
+    Logged(LogLine),
+}
+
+fn parse_line(line: &str) -> Line {
+    let other = || Line::Logged(LogLine::Other(line.to_owned()));
+    let Some(rest) = line.strip_prefix("Program ") else {
+        return other();
+    };
+
+    if let Some(msg) = rest.strip_prefix("log: ") {
+        return Line::Logged(LogLine::Log(msg.to_owned()));
+    }
+
+    if let Some(data) = rest.strip_prefix("data: ") {
+        return data
+            .split_whitespace()
+            .map(|f| base64::engine::general_purpose::STANDARD.decode(f))
+            .collect::<Result<_, _>>()
+            .map_or_else(|_| other(), |d| Line::Logged(LogLine::Data(d)));
+    }
+
+    if let Some(ret) = rest.strip_prefix("return: ") {
+        return ret
+            .split_once(' ')
+            .and_then(|(_, d)| base64::engine::general_purpose::STANDARD.decode(d).ok())
+            .map_or_else(other, |d| Line::Logged(LogLine::Return(d)));
+    }
+
+    let Some((program, event)) = rest.split_once(' ') else {
+        return other();
+    };
+    let Ok(program) = Pubkey::try_from(program) else {
+        return other();
+    };
+
+    if let Some(depth) = event
+        .strip_prefix("invoke [")
+        .and_then(|d| d.strip_suffix(']'))
+        .and_then(|d| d.parse().ok())
+    {
+        Line::Invoke(program, depth)
+    } else if event == "success" {
+        Line::Exit(InvocationResult::Success)
+    } else if let Some(err) = event.strip_prefix("failed: ") {
+        Line::Exit(InvocationResult::Failed(err.to_owned()))
+    } else if let Some(units) = parse_consumed(event) {
+        Line::Consumed(units)
+    } else {
+        other()
+    }
+}
+
+fn parse_consumed(event: &str) -> Option<ComputeUnits> {
+    let (consumed, limit) = event
+        .strip_prefix("consumed ")?
+        .strip_suffix(" compute units")?
+        .split_once(" of ")?;
+
+    Some(ComputeUnits {
+        consumed: consumed.parse().ok()?,
+        limit: limit.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(i: u8) -> Pubkey { Pubkey::new([i; 32]) }
+
+    #[test]
+    fn test_nesting() {
+        let [a, b, c] = [program(1), program(2), program(3)];
+        let invocations = parse(&[
+            format!("Program {a} invoke [1]"),
+            "Program log: outer".to_owned(),
+            format!("Program {b} invoke [2]"),
+            "Program log: inner".to_owned(),
+            format!("Program {b} consumed 100 of 1000 compute units"),
+            format!("Program {b} success"),
+            "Program log: outer again".to_owned(),
+            format!("Program {a} consumed 500 of 2000 compute units"),
+            format!("Program {a} success"),
+            format!("Program {c} invoke [1]"),
+            format!("Program {c} success"),
+        ]);
+
+        assert_eq!(invocations.len(), 2);
+        let outer = &invocations[0];
+        assert_eq!((outer.program, outer.depth), (a, 1));
+        assert_eq!(outer.logs.messages().collect::<Vec<_>>(), [
+            "outer",
+            "outer again"
+        ]);
+        assert_eq!(
+            outer.logs.compute_units,
+            Some(ComputeUnits {
+                consumed: 500,
+                limit: 2000
+            })
+        );
+        assert_eq!(outer.logs.result, InvocationResult::Success);
+
+        let inner = &outer.inner[0];
+        assert_eq!((inner.program, inner.depth), (b, 2));
+        assert_eq!(inner.logs.messages().collect::<Vec<_>>(), ["inner"]);
+        assert_eq!(inner.logs.compute_units.map(|c| c.consumed), Some(100));
+
+        assert_eq!(invocations[1].program, c);
+        assert!(invocations[1].inner.is_empty());
+    }
+
+    #[test]
+    fn test_failed() {
+        let [a, b] = [program(1), program(2)];
+        let invocations = parse(&[
+            format!("Program {a} invoke [1]"),
+            format!("Program {b} invoke [2]"),
+            format!("Program {b} failed: custom program error: 0x1"),
+            format!("Program {a} failed: custom program error: 0x1"),
+        ]);
+
+        let failed = InvocationResult::Failed("custom program error: 0x1".to_owned());
+        assert_eq!(invocations[0].logs.result, failed);
+        assert_eq!(invocations[0].inner[0].logs.result, failed);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let [a, b] = [program(1), program(2)];
+        let invocations = parse(&[
+            format!("Program {a} invoke [1]"),
+            format!("Program {b} invoke [2]"),
+            "Program log: cut off".to_owned(),
+            "Log truncated".to_owned(),
+        ]);
+
+        assert_eq!(invocations.len(), 1);
+        let outer = &invocations[0];
+        assert_eq!(outer.logs.result, InvocationResult::Unknown);
+
+        let inner = &outer.inner[0];
+        assert_eq!(inner.logs.result, InvocationResult::Unknown);
+        assert_eq!(inner.logs.lines, [
+            LogLine::Log("cut off".to_owned()),
+            LogLine::Other("Log truncated".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn test_data_and_return() {
+        let a = program(1);
+        let invocations = parse(&[
+            format!("Program {a} invoke [1]"),
+            "Program data: AQID BAU=".to_owned(),
+            "Program data: not base64!".to_owned(),
+            format!("Program return: {a} KgA="),
+            format!("Program {a} success"),
+        ]);
+
+        let logs = &invocations[0].logs;
+        assert_eq!(logs.data().collect::<Vec<_>>(), [&[vec![1, 2, 3], vec![
+            4, 5
+        ]][..]]);
+        assert_eq!(
+            logs.lines[1],
+            LogLine::Other("Program data: not base64!".to_owned())
+        );
+        assert_eq!(logs.lines[2], LogLine::Return(vec![42, 0]));
+    }
+}
@@ -98,7 +98,7 @@ where
     type Output = T::Message;
 
     #[inline]
-    fn id(&self) -> std::borrow::Cow<str> {
+    fn id(&self) -> std::borrow::Cow<'_, str> {
         self.0.id()
     }
 
@@ -32,7 +32,7 @@ use solana_transaction_status::{
 };
 use yellowstone_grpc_proto::geyser::{SubscribeUpdateAccount, SubscribeUpdateAccountInfo};
 use yellowstone_vixen_core::{
-    instruction::{InstructionShared, InstructionUpdate},
+    instruction::{AccountRole, InstructionShared, InstructionUpdate},
     ProgramParser, Pubkey as VixenPubkey,
 };
 
@@ -145,7 +145,10 @@ pub struct SerializableInstructionUpdate {
 impl From<&InstructionUpdate> for SerializableInstructionUpdate {
     fn from(value: &InstructionUpdate) -> Self {
         Self {
-            ix_index: [0; 2],
+            ix_index: [
+                value.path.outer_index() as usize,
+                value.inner_index.map_or(0, |i| i as usize),
+            ],
             program: SerializablePubkey(value.program.0),
             accounts: value
                 .accounts
@@ -163,9 +166,15 @@ impl From<&SerializableInstructionUpdate> for InstructionUpdate {
         Self {
             program: value.program.into(),
             accounts: value.accounts.iter().copied().map(Into::into).collect(),
+            account_roles: vec![AccountRole::default(); value.accounts.len()],
             data: value.data.clone(),
             shared: Arc::new(InstructionShared::default()),
             inner: value.inner.iter().map(Into::into).collect(),
+            path: vec![u32::try_from(value.ix_index[0]).expect("Instruction index overflowed")]
+                .into(),
+            inner_index: None,
+            parent_program: None,
+            parent: None,
         }
     }
 }
@@ -382,6 +391,7 @@ pub async fn load_fixture<P: ProgramParser>(
     }
 }
 
+#[allow(clippy::result_large_err)]
 fn convert_account_info(pubkey: Pubkey) -> impl Fn(Account) -> ClientResult<AccountInfo> {
     move |value: Account| {
         Ok(AccountInfo {
@@ -45,7 +45,7 @@ impl Parser for AccountParser {
     type Input = AccountUpdate;
     type Output = OrcaProgramState;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "orca::AccountParser".into()
     }
 
@@ -18,7 +18,7 @@ impl Parser for InstructionParser {
     type Input = InstructionUpdate;
     type Output = OrcaProgramIx;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "orca::InstructionParser".into()
     }
 
@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+
+use yellowstone_vixen_core::{
+    anchor, instruction::InstructionUpdate, ParseError, ParseResult, Parser, ProgramParser,
+};
+
+use super::{instruction_helpers::TradeEvent, instruction_parser::PUMPFUN};
+
+/// Parser for the `TradeEvent`s Pump.fun emits with `emit_cpi!` on every buy
+/// and sell.
+#[derive(Debug, Clone, Copy)]
+pub struct TradeEventParser;
+
+impl Parser for TradeEventParser {
+    type Input = InstructionUpdate;
+    type Output = TradeEvent;
+
+    fn id(&self) -> Cow<'_, str> { "yellowstone_vixen_parser::pumpfun::TradeEventParser".into() }
+
+    fn prefilter(&self) -> yellowstone_vixen_core::Prefilter {
+        yellowstone_vixen_core::Prefilter::builder()
+            .transaction_accounts([PUMPFUN])
+            .build()
+            .unwrap()
+    }
+
+    async fn parse(&self, ix_update: &InstructionUpdate) -> ParseResult<Self::Output> {
+        if ix_update.program.equals_ref(PUMPFUN) {
+            anchor::decode_cpi_event(ix_update).unwrap_or(Err(ParseError::Filtered))
+        } else {
+            Err(ParseError::Filtered)
+        }
+    }
+}
+
+impl ProgramParser for TradeEventParser {
+    #[inline]
+    fn program_id(&self) -> yellowstone_vixen_core::Pubkey { PUMPFUN.to_bytes().into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use yellowstone_vixen_mock::{load_fixture, FixtureData};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trade_event_parsing() {
+        let parser = TradeEventParser;
+
+        let fixture = load_fixture(
+            "3A8c3N4Q6HmCBufjMdQUoRcoBPEuRRRswfmmCVVujC6ayEFBiqF8UAPa585ib3K5GJt6X8rw1f6XPXx1eN3yegYx",
+            &parser,
+        )
+        .await
+        .unwrap();
+        let FixtureData::Instructions(ixs) = fixture else {
+            panic!("Invalid transaction fixture");
+        };
+
+        let mut events = vec![];
+        for ix in &ixs {
+            let mut ix = InstructionUpdate::from(ix);
+            // Fixtures do not record the invoking program, but events emitted
+            // with emit_cpi! are always invoked by the program itself
+            if ix.data.starts_with(&anchor::EVENT_IX_TAG) {
+                ix.parent_program = Some(ix.program);
+            }
+
+            match parser.parse(&ix).await {
+                Ok(event) => events.push(event),
+                Err(ParseError::Filtered) => (),
+                Err(e) => panic!("Error parsing event: {e:?}"),
+            }
+        }
+
+        let [event] = &events[..] else {
+            panic!("Expected one trade event, found {}", events.len());
+        };
+        assert_eq!(
+            event.mint.to_string(),
+            "4svHchJwpb18beJC1DFrymAu4vsyvR5XwhVmiTkZpump"
+        );
+        assert_eq!(
+            event.user.to_string(),
+            "2W6Cb8VviaPWryGRQoaMihbyw4Lc1HFVhd3u4phDpQym"
+        );
+        assert!(event.is_buy);
+        assert_eq!(event.sol_amount, 70_000_000);
+        assert_eq!(event.token_amount, 2_497_838_377_120);
+        assert_eq!(event.timestamp, 1_732_636_459);
+        assert_eq!(event.virtual_sol_reserves, 30_070_000_000);
+        assert_eq!(event.virtual_token_reserves, 1_070_502_161_622_880);
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use yellowstone_vixen_core::Pubkey;
+use yellowstone_vixen_core::{anchor::AnchorEvent, ParseResult, Pubkey};
 
 pub const CREATE_IX_DISC: [u8; 8] = [24, 30, 200, 40, 5, 28, 7, 119];
 
@@ -54,6 +54,62 @@ pub struct BuyIxData {
     pub max_sol_cost: u64,
 }
 
+pub const TRADE_EVENT_DISC: [u8; 8] = [189, 219, 127, 211, 78, 230, 97, 238];
+
+#[derive(Debug, Clone, Copy)]
+pub struct TradeEvent {
+    pub mint: Pubkey,
+    pub sol_amount: u64,
+    pub token_amount: u64,
+    pub is_buy: bool,
+    pub user: Pubkey,
+    pub timestamp: i64,
+    pub virtual_sol_reserves: u64,
+    pub virtual_token_reserves: u64,
+}
+
+#[derive(BorshDeserialize)]
+struct TradeEventData {
+    mint: [u8; 32],
+    sol_amount: u64,
+    token_amount: u64,
+    is_buy: bool,
+    user: [u8; 32],
+    timestamp: i64,
+    virtual_sol_reserves: u64,
+    virtual_token_reserves: u64,
+}
+
+impl AnchorEvent for TradeEvent {
+    const DISCRIMINATOR: [u8; 8] = TRADE_EVENT_DISC;
+
+    fn decode(mut data: &[u8]) -> ParseResult<Self> {
+        // Newer versions of the program append fields, so trailing data is
+        // ignored
+        let TradeEventData {
+            mint,
+            sol_amount,
+            token_amount,
+            is_buy,
+            user,
+            timestamp,
+            virtual_sol_reserves,
+            virtual_token_reserves,
+        } = BorshDeserialize::deserialize(&mut data)?;
+
+        Ok(Self {
+            mint: mint.into(),
+            sol_amount,
+            token_amount,
+            is_buy,
+            user: user.into(),
+            timestamp,
+            virtual_sol_reserves,
+            virtual_token_reserves,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum PumpFunProgramIx {
     Create(CreateAccounts, CreateIxData),
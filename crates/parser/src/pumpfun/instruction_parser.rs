@@ -23,7 +23,7 @@ impl Parser for InstructionParser {
     type Input = InstructionUpdate;
     type Output = PumpFunProgramIx;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "yellowstone_vixen_parser::pumpfun::InstructionParser".into()
     }
 
@@ -173,13 +173,13 @@ mod tests {
         let ix = &ixs[1];
 
         if let PumpFunProgramIx::Buy(accounts, data) = ix {
-            println!("{:?}", accounts);
-            println!("{:?}", data);
+            println!("{accounts:?}");
+            println!("{data:?}");
             assert_eq!(
                 accounts.mint.to_string(),
                 "4svHchJwpb18beJC1DFrymAu4vsyvR5XwhVmiTkZpump".to_string()
             );
-            assert_eq!(data.amount, 2497838377120);
+            assert_eq!(data.amount, 2_497_838_377_120);
         } else {
             panic!("Invalid Instruction");
         }
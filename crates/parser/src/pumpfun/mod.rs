@@ -1,5 +1,7 @@
+mod event_parser;
 mod instruction_helpers;
 mod instruction_parser;
 
+pub use event_parser::*;
 pub use instruction_helpers::*;
 pub use instruction_parser::*;
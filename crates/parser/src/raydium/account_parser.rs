@@ -63,7 +63,7 @@ impl Parser for AccountParser {
     type Input = yellowstone_vixen_core::AccountUpdate;
     type Output = RaydiumProgramState;
 
-    fn id(&self) -> std::borrow::Cow<str> {
+    fn id(&self) -> std::borrow::Cow<'_, str> {
         "raydium::AccountParser".into()
     }
 
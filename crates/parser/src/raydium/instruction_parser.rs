@@ -20,7 +20,7 @@ impl Parser for InstructionParser {
     type Input = InstructionUpdate;
     type Output = RaydiumProgramIx;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "raydium::InstructionParser".into()
     }
 
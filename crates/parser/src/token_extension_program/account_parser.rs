@@ -116,7 +116,7 @@ impl Parser for AccountParser {
     type Input = AccountUpdate;
     type Output = TokenExtensionState;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "token_extensions::AccountParser".into()
     }
 
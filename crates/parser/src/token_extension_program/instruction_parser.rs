@@ -32,7 +32,7 @@ impl Parser for InstructionParser {
     type Input = InstructionUpdate;
     type Output = TokenExtensionProgramIx;
 
-    fn id(&self) -> std::borrow::Cow<str> {
+    fn id(&self) -> std::borrow::Cow<'_, str> {
         "token_extensions::InstructionParser".into()
     }
 
@@ -37,7 +37,7 @@ impl Parser for AccountParser {
     type Input = AccountUpdate;
     type Output = TokenProgramState;
 
-    fn id(&self) -> Cow<str> {
+    fn id(&self) -> Cow<'_, str> {
         "token_program::AccountParser".into()
     }
 
@@ -17,7 +17,7 @@ impl Parser for InstructionParser {
     type Input = InstructionUpdate;
     type Output = TokenProgramIx;
 
-    fn id(&self) -> std::borrow::Cow<str> {
+    fn id(&self) -> std::borrow::Cow<'_, str> {
         "token_program::InstructionParser".into()
     }
 
@@ -77,20 +77,8 @@ impl InstructionParser {
                 decimals,
                 mint_authority,
                 freeze_authority,
-            } => {
-                check_min_accounts_req(accounts_len, 1)?;
-                Ok(TokenProgramIx::InitializeMint(
-                    InitializeMintAccounts {
-                        mint: ix.accounts[0],
-                    },
-                    InitializeMintData {
-                        decimals,
-                        mint_authority: into_vixen_pubkey(mint_authority),
-                        freeze_authority: freeze_authority.map(into_vixen_pubkey).into(),
-                    },
-                ))
-            },
-            TokenInstruction::InitializeMint2 {
+            }
+            | TokenInstruction::InitializeMint2 {
                 decimals,
                 mint_authority,
                 freeze_authority,
@@ -1,29 +1,39 @@
-use std::{pin::pin, sync::Arc};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
 
-use futures_util::{Stream, StreamExt};
 use topograph::{
     executor::{self, Executor, Nonblock, Tokio},
     prelude::*,
 };
-use tracing::{warn, Instrument};
-use yellowstone_grpc_proto::{
-    geyser::{subscribe_update::UpdateOneof, SubscribeUpdate, SubscribeUpdatePing},
-    tonic::Status,
+use tracing::{trace, warn, Instrument};
+use yellowstone_grpc_proto::geyser::{
+    self, subscribe_update::UpdateOneof, SubscribeUpdate, SubscribeUpdatePing,
 };
 
 use crate::{
-    config::BufferConfig,
+    config::{BufferConfig, DispatchOrder},
+    dead_letter::DeadLetterSink,
+    handle::{Change, CommandRx, HandleError},
     handler::PipelineSets,
     metrics::{Counters, Instrumenter, UpdateType},
+    order::{Admit, KeyFn, Order, SharedPartitions},
+    queue::{Push, Queue, SharedQueue},
+    snapshot::Snapshot,
+    source::{Recorder, Source},
     stop::{self, StopCode, StopRx, StopTx},
-    yellowstone,
+    Chain,
 };
 
-type TaskHandle = tokio::task::JoinHandle<Result<StopCode, crate::Error>>;
+// Resolves to `None` if the buffer stopped because its source was exhausted
+type TaskHandle = tokio::task::JoinHandle<Result<Option<StopCode>, crate::Error>>;
 pub struct Buffer(TaskHandle, StopTx);
 
 impl Buffer {
-    pub async fn join(self) -> Result<StopCode, crate::Error> {
+    pub async fn join(self) -> Result<Option<StopCode>, crate::Error> {
         self.1.maybe_send();
         self.0
             .await
@@ -31,155 +41,1108 @@ impl Buffer {
             .and_then(std::convert::identity)
     }
 
-    // TODO: use never
-    pub async fn wait_for_stop(&mut self) -> Result<std::convert::Infallible, crate::Error> {
+    /// Wait for the buffer to stop on its own, returning `Ok` only if its
+    /// source was exhausted.
+    pub async fn wait_for_stop(&mut self) -> Result<(), crate::Error> {
         (&mut self.0)
             .await
             .map_err(|e| std::io::Error::from(e).into())
-            .and_then(|r| r.and(Err(crate::Error::ClientHangup)))
+            .and_then(|r| match r? {
+                Some(_) => Err(crate::Error::ClientHangup),
+                None => Ok(()),
+            })
+    }
+}
+
+/// Bookkeeping for the slots of the jobs dispatched from a single
+/// subscription group.
+#[derive(Debug)]
+struct GroupSlots {
+    in_flight: BTreeMap<u64, usize>,
+    highest: Option<u64>,
+    // The earliest slot of a job dropped from a full queue, which is never
+    // fully processed
+    dropped: Option<u64>,
+    // Whether any pipelines are still subscribed through this group
+    active: bool,
+}
+
+impl Default for GroupSlots {
+    fn default() -> Self {
+        Self {
+            in_flight: BTreeMap::new(),
+            highest: None,
+            dropped: None,
+            active: true,
+        }
+    }
+}
+
+impl GroupSlots {
+    /// Whether this group's progress holds back the processed slot.
+    fn tracked(&self) -> bool {
+        self.highest.is_some()
+            && (self.active || !self.in_flight.is_empty() || self.dropped.is_some())
+    }
+
+    fn processed(&self) -> Option<u64> {
+        // Updates for the newest slot received may still be arriving
+        let received = self.highest?.checked_sub(1);
+        self.in_flight
+            .keys()
+            .next()
+            .into_iter()
+            .chain(&self.dropped)
+            .fold(received, |p, &s| p.min(s.checked_sub(1)))
+    }
+}
+
+/// Bookkeeping for the slots of dispatched jobs, used to determine how far
+/// the runtime has fully processed the stream.
+///
+/// Each subscription group is a separate stream whose updates may lag behind
+/// the others, such as one streaming at a more final commitment level, so
+/// progress is tracked per group and the stream is only processed as far as
+/// the group furthest behind.  Updates are assigned to a group by the
+/// pipelines they are routed to; updates for pipelines not assigned to any
+/// group, such as those from a replayed recording, share a group of their
+/// own.
+#[derive(Debug, Default)]
+pub(crate) struct SlotTracker {
+    groups: HashMap<Option<usize>, GroupSlots>,
+    pipelines: HashMap<String, usize>,
+    resumed: Option<u64>,
+}
+
+/// The group and slot of a dispatched job, to be passed back to
+/// [`SlotTracker::finish`] once the job completes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SlotTicket {
+    group: Option<usize>,
+    slot: u64,
+}
+
+impl SlotTracker {
+    /// Assign the given pipelines to a subscription group, replacing any
+    /// pipelines previously assigned to it.
+    pub fn assign<S: Into<String>>(
+        &mut self,
+        group: usize,
+        pipelines: impl IntoIterator<Item = S>,
+    ) {
+        self.pipelines.retain(|_, &mut g| g != group);
+        self.pipelines
+            .extend(pipelines.into_iter().map(|p| (p.into(), group)));
+
+        let active = self.pipelines.values().any(|&g| g == group);
+        self.groups.entry(Some(group)).or_default().active = active;
+    }
+
+    fn start<S: AsRef<str>>(&mut self, pipelines: &[S], slot: u64) -> SlotTicket {
+        let group = pipelines
+            .iter()
+            .find_map(|p| self.pipelines.get(p.as_ref()).copied());
+        let slots = self.groups.entry(group).or_default();
+        *slots.in_flight.entry(slot).or_default() += 1;
+        slots.highest = slots.highest.max(Some(slot));
+
+        SlotTicket { group, slot }
+    }
+
+    fn finish(&mut self, ticket: SlotTicket) {
+        let SlotTicket { group, slot } = ticket;
+        let Some(slots) = self.groups.get_mut(&group) else {
+            return;
+        };
+
+        if let Entry::Occupied(mut o) = slots.in_flight.entry(slot) {
+            *o.get_mut() -= 1;
+            if *o.get() == 0 {
+                o.remove();
+            }
+        }
+    }
+
+    /// Record that a dispatched job was dropped without being run, holding
+    /// back the processed slot before its slot for as long as the runtime
+    /// runs.
+    fn discard(&mut self, ticket: SlotTicket) {
+        self.finish(ticket);
+        if let Some(slots) = self.groups.get_mut(&ticket.group) {
+            slots.dropped = Some(slots.dropped.map_or(ticket.slot, |d| d.min(ticket.slot)));
+        }
+    }
+
+    /// Resume tracking from a slot known to have been fully processed before
+    /// the runtime started.
+    pub fn resume(&mut self, slot: u64) { self.resumed = self.resumed.max(Some(slot)); }
+
+    /// The highest slot for which every update received by the given group,
+    /// and for every earlier slot, has been processed.
+    pub fn group_processed(&self, group: usize) -> Option<u64> {
+        let processed = self
+            .groups
+            .get(&Some(group))
+            .filter(|g| g.highest.is_some())
+            .and_then(GroupSlots::processed);
+
+        processed.max(self.resumed)
+    }
+
+    /// The highest slot for which every update received, and for every
+    /// earlier slot, has been processed.
+    ///
+    /// A group that has not yet received any updates does not hold back the
+    /// processed slot, nor does one no longer subscribed to by any pipelines
+    /// once its remaining jobs complete.
+    pub fn processed(&self) -> Option<u64> {
+        let processed = self
+            .groups
+            .values()
+            .filter(|g| g.tracked())
+            .map(GroupSlots::processed)
+            .min();
+
+        match processed {
+            Some(p) => p.max(self.resumed),
+            None => self.resumed,
+        }
+    }
+}
+
+/// Get the slot an update counts towards in the [`SlotTracker`].
+///
+/// Only account and transaction updates carry the data a checkpoint has to
+/// account for.  Slot statuses, block metadata and entries for a slot can
+/// arrive before every account write and transaction in it, so counting them
+/// would advance the processed slot past updates still to come.
+fn tracked_slot(update: &SubscribeUpdate) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::Account(a) => Some(a.slot),
+        UpdateOneof::Transaction(t) => Some(t.slot),
+        _ => None,
     }
 }
 
-struct Job(tracing::Span, SubscribeUpdate);
+pub(crate) type SharedSlots = Arc<Mutex<SlotTracker>>;
+
+#[inline]
+pub(crate) fn lock_slots(slots: &SharedSlots) -> std::sync::MutexGuard<'_, SlotTracker> {
+    slots.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The current pipeline set is swapped out wholesale when changed at runtime,
+// so each job sees a consistent snapshot of the pipelines
+type SharedPipelines = Arc<RwLock<Arc<PipelineSets>>>;
+
+struct Job {
+    span: tracing::Span,
+    received_at: SystemTime,
+    slot: Option<SlotTicket>,
+    key: Option<u64>,
+    update: SubscribeUpdate,
+}
+
+// Jobs are held in the buffer's own queue so the overflow policy can drop
+// them before they run.  Each tick sent to the executor runs the oldest
+// queued job.
+struct Tick;
 
 struct Handler<M: Instrumenter> {
-    pipelines: Arc<PipelineSets>,
+    pipelines: SharedPipelines,
     counters: Arc<Counters<M>>,
+    slots: SharedSlots,
+    queue: SharedQueue<Job>,
+    partitions: SharedPartitions<Job>,
+    dead_letters: Option<Arc<dyn DeadLetterSink>>,
 }
 impl<M: Instrumenter> Clone for Handler<M> {
     fn clone(&self) -> Self {
         let Self {
             pipelines,
             counters,
+            slots,
+            queue,
+            partitions,
+            dead_letters,
         } = self;
         Self {
             pipelines: Arc::clone(pipelines),
             counters: Arc::clone(counters),
+            slots: Arc::clone(slots),
+            queue: Arc::clone(queue),
+            partitions: Arc::clone(partitions),
+            dead_letters: dead_letters.as_ref().map(Arc::clone),
         }
     }
 }
-impl<M: Instrumenter, H: Send> topograph::AsyncHandler<Job, H> for Handler<M> {
+impl<M: Instrumenter, H: Send> topograph::AsyncHandler<Tick, H> for Handler<M> {
     type Output = ();
 
-    async fn handle(&self, update: Job, _: H) {
+    async fn handle(&self, Tick: Tick, _: H) {
+        // A job with a key already being processed is left for the worker
+        // processing it
+        let Some(Some(mut job)) = self.queue.pop(|j| match j.key {
+            Some(k) => self.partitions.enqueue(k, j),
+            None => Some(j),
+        }) else {
+            return;
+        };
+
+        // Jobs queued behind this one with the same key are run on this
+        // worker once it completes, so each key is processed serially
+        loop {
+            self.queue.started();
+            self.counters.set_queue_depth(self.queue.depth());
+
+            let Job {
+                span,
+                received_at,
+                slot,
+                key,
+                update,
+            } = job;
+            self.handle_update(span, received_at, update).await;
+
+            if let Some(ticket) = slot {
+                lock_slots(&self.slots).finish(ticket);
+            }
+            self.queue.finished();
+
+            match key.and_then(|k| self.partitions.next(k)) {
+                Some(next) => job = next,
+                None => break,
+            }
+        }
+    }
+}
+
+impl<M: Instrumenter> Handler<M> {
+    async fn handle_update(
+        &self,
+        span: tracing::Span,
+        received_at: SystemTime,
+        update: SubscribeUpdate,
+    ) {
         let Self {
             pipelines,
             counters,
+            slots: _,
+            queue: _,
+            partitions: _,
+            dead_letters,
         } = self;
-        let Job(
-            span,
-            SubscribeUpdate {
-                filters,
-                update_oneof,
-            },
-        ) = update;
+        let SubscribeUpdate {
+            filters,
+            update_oneof,
+            created_at: _,
+        } = update;
         let Some(update) = update_oneof else { return };
+        let pipelines = Arc::clone(&pipelines.read().unwrap_or_else(PoisonError::into_inner));
 
         match update {
             UpdateOneof::Account(a) => {
                 pipelines
                     .account
                     .get_handlers(&filters)
-                    .run(span, &a, counters)
+                    .run(span, &a, counters, dead_letters.as_ref(), received_at)
                     .await;
             },
             UpdateOneof::Transaction(t) => {
                 pipelines
                     .transaction
                     .get_handlers(&filters)
-                    .run(span, &t, counters)
+                    .run(span, &t, counters, dead_letters.as_ref(), received_at)
+                    .await;
+            },
+            UpdateOneof::Slot(s) => {
+                pipelines
+                    .slot
+                    .get_handlers(&filters)
+                    .run(span, &s, counters, dead_letters.as_ref(), received_at)
+                    .await;
+            },
+            UpdateOneof::Block(b) => {
+                pipelines
+                    .block
+                    .get_handlers(&filters)
+                    .run(span, &b, counters, dead_letters.as_ref(), received_at)
+                    .await;
+            },
+            UpdateOneof::BlockMeta(b) => {
+                pipelines
+                    .block_meta
+                    .get_handlers(&filters)
+                    .run(span, &b, counters, dead_letters.as_ref(), received_at)
+                    .await;
+            },
+            UpdateOneof::TransactionStatus(t) => {
+                pipelines
+                    .transaction_status
+                    .get_handlers(&filters)
+                    .run(span, &t, counters, dead_letters.as_ref(), received_at)
+                    .await;
+            },
+            UpdateOneof::Entry(e) => {
+                pipelines
+                    .entry
+                    .get_handlers(&filters)
+                    .run(span, &e, counters, dead_letters.as_ref(), received_at)
                     .await;
             },
             UpdateOneof::Ping(SubscribeUpdatePing {}) => (),
-            var => warn!(?var, "Unknown update variant"),
+            var @ UpdateOneof::Pong(_) => warn!(?var, "Unknown update variant"),
+        }
+    }
+}
+
+/// Record an update if recording, stopping the recording if it fails.
+async fn record(recorder: &mut Option<Recorder>, update: &SubscribeUpdate) {
+    let Some(r) = recorder else { return };
+
+    if let Err(e) = r.record(update).await {
+        warn!(err = %Chain(&e), "Error recording update, recording stopped");
+        *recorder = None;
+    }
+}
+
+/// Updates received from the source while the startup snapshot loads.
+///
+/// Stream updates are held until every snapshot update has been dispatched,
+/// so each account's snapshot write is dispatched before any stream write to
+/// it.  The source is drained while the snapshot loads rather than left to
+/// back up, as a Yellowstone server may disconnect a client that falls too
+/// far behind.
+#[derive(Default)]
+struct Held {
+    updates: VecDeque<Option<Result<SubscribeUpdate, crate::Error>>>,
+    // Whether the source has ended, successfully or not
+    ended: bool,
+}
+
+impl Held {
+    /// Run a future to completion, holding every update received from the
+    /// source in the meantime.
+    async fn hold_while<S: Source, F: Future>(
+        &mut self,
+        source: &mut S,
+        recorder: &mut Option<Recorder>,
+        fut: F,
+    ) -> F::Output {
+        tokio::pin!(fut);
+
+        loop {
+            tokio::select! {
+                biased;
+
+                out = &mut fut => break out,
+                u = source.next(), if !self.ended => {
+                    match &u {
+                        Some(Ok(u)) => record(recorder, u).await,
+                        Some(Err(_)) | None => self.ended = true,
+                    }
+                    self.updates.push_back(u);
+                },
+            }
         }
     }
 }
 
 impl Buffer {
-    fn dispatch<M: Instrumenter, E: ExecutorHandle<Job>>(
+    async fn dispatch<M: Instrumenter, E: ExecutorHandle<Tick>>(
         exec: &E,
         update: SubscribeUpdate,
+        order: Option<&mut Order>,
+        queue: &Queue<Job>,
         counters: &Counters<M>,
+        slots: &SharedSlots,
     ) {
+        let received_at = SystemTime::now();
         let span = tracing::trace_span!("process_update", ?update).entered();
-        if let Some(ty) = UpdateType::get(&update.update_oneof) {
+        if let Some(ty) = UpdateType::get(update.update_oneof.as_ref()) {
             counters.inc_received(ty);
         }
-        exec.push(Job(span.exit(), update));
+        if let Some(UpdateOneof::Slot(s)) = &update.update_oneof {
+            if let Some(status) = geyser::CommitmentLevel::try_from(s.status)
+                .ok()
+                .and_then(|s| s.try_into().ok())
+            {
+                counters.inc_slot_status(status);
+            }
+        }
+
+        let key = match order.map(|o| o.admit(&update)) {
+            Some(Admit::Stale) => {
+                trace!("Dropping stale account write");
+                return;
+            },
+            Some(Admit::Run(key)) => key,
+            None => None,
+        };
+
+        let slot = tracked_slot(&update).map(|s| lock_slots(slots).start(&update.filters, s));
+
+        let priority = queue.priority(&update.filters);
+        let job = Job {
+            span: span.exit(),
+            received_at,
+            slot,
+            key,
+            update,
+        };
+
+        queue.wait_for_space().await;
+        match queue.push(priority, job) {
+            Push::Queued => exec.push(Tick),
+            Push::Replaced(j) | Push::Rejected(j) => Self::drop_job(j, counters, slots),
+        }
+        counters.set_queue_depth(queue.depth());
+    }
+
+    fn drop_job<M: Instrumenter>(job: Job, counters: &Counters<M>, slots: &SharedSlots) {
+        let Job { slot, update, .. } = job;
+        trace!(?update, "Job queue full, dropping update");
+
+        if let Some(ty) = UpdateType::get(update.update_oneof.as_ref()) {
+            counters.inc_dropped(ty);
+        }
+        if let Some(ticket) = slot {
+            lock_slots(slots).discard(ticket);
+        }
     }
 
     fn run_impl<
         M: Instrumenter,
-        B: FnOnce(executor::Builder<Job, Nonblock<Tokio>>) -> executor::Builder<Job, Nonblock<Tokio>>,
-        S: FnOnce(Executor<Job, Nonblock<Tokio>>, StopRx, Arc<Counters<M>>) -> TaskHandle,
+        B: FnOnce(
+            executor::Builder<Tick, Nonblock<Tokio>>,
+        ) -> executor::Builder<Tick, Nonblock<Tokio>>,
+        S: FnOnce(
+            Executor<Tick, Nonblock<Tokio>>,
+            StopRx,
+            SharedPipelines,
+            SharedQueue<Job>,
+            Arc<Counters<M>>,
+            SharedSlots,
+        ) -> TaskHandle,
     >(
         config: BufferConfig,
         pipelines: PipelineSets,
-        counters: Counters<M>,
+        dead_letters: Option<Arc<dyn DeadLetterSink>>,
+        counters: Arc<Counters<M>>,
+        slots: SharedSlots,
         build: B,
         spawn: S,
     ) -> Self {
-        let BufferConfig { jobs } = config;
+        let BufferConfig {
+            jobs,
+            order: _,
+            capacity,
+            overflow,
+            priorities,
+            drain_timeout: _,
+        } = config;
 
-        let pipelines = Arc::new(pipelines);
-        let counters = Arc::new(counters);
+        let pipelines = Arc::new(RwLock::new(Arc::new(pipelines)));
+        let queue = Arc::new(Queue::new(capacity, overflow, priorities));
         let exec = build(Executor::builder(Nonblock(Tokio)).max_concurrency(jobs))
             .build_async(Handler {
-                pipelines,
+                pipelines: Arc::clone(&pipelines),
                 counters: Arc::clone(&counters),
+                slots: Arc::clone(&slots),
+                queue: Arc::clone(&queue),
+                partitions: SharedPartitions::default(),
+                dead_letters,
             })
             .unwrap_or_else(|i| match i {});
 
         let (stop_tx, rx) = stop::channel();
 
-        let task = spawn(exec, rx, counters);
+        let task = spawn(exec, rx, pipelines, queue, counters, slots);
         Self(task, stop_tx)
     }
 
-    pub fn run_yellowstone<
-        I,
-        T,
-        S: Stream<Item = Result<SubscribeUpdate, Status>> + 'static,
-        M: Instrumenter,
-    >(
+    /// Dispatch every update in the startup snapshot, holding updates from
+    /// the source in the meantime, and return the slot each pipeline's
+    /// accounts were loaded at.
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch_snapshot<M: Instrumenter, E: ExecutorHandle<Tick>, S: Source>(
+        mut snapshot: Snapshot,
+        source: &mut S,
+        recorder: &mut Option<Recorder>,
+        held: &mut Held,
+        exec: &E,
+        mut order: Option<&mut Order>,
+        queue: &Queue<Job>,
+        counters: &Counters<M>,
+        slots: &SharedSlots,
+    ) -> Result<HashMap<String, u64>, crate::Error> {
+        while let Some(update) = held.hold_while(source, recorder, snapshot.next()).await {
+            let dispatch =
+                Self::dispatch(exec, update, order.as_deref_mut(), queue, counters, slots);
+            held.hold_while(source, recorder, dispatch).await;
+        }
+
+        held.hold_while(source, recorder, snapshot.finish()).await
+    }
+
+    /// Drop stream account updates already reflected in the startup snapshot
+    /// from the pipelines they were loaded for, returning `false` if no
+    /// pipelines remain to receive the update.
+    fn filter_stale(stale: &mut HashMap<String, u64>, update: &mut SubscribeUpdate) -> bool {
+        let Some(UpdateOneof::Account(a)) = &update.update_oneof else {
+            return true;
+        };
+
+        update
+            .filters
+            .retain(|f| stale.get(f).is_none_or(|&s| a.slot > s));
+
+        // Once the stream has passed every snapshot slot nothing else can be
+        // stale
+        if stale.values().all(|&s| a.slot > s) {
+            stale.clear();
+        }
+
+        !update.filters.is_empty()
+    }
+
+    /// Wait for every queued and running job to complete, then flush every
+    /// pipeline.  Whatever remains when the deadline passes is abandoned and
+    /// reported.
+    async fn drain(timeout: Duration, queue: &Queue<Job>, pipelines: &SharedPipelines) {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        if tokio::time::timeout_at(deadline, queue.drained())
+            .await
+            .is_err()
+        {
+            let queued = queue.depth();
+            let running = queue.outstanding().saturating_sub(queued);
+            warn!(
+                queued,
+                running, "Drain deadline passed, abandoning remaining jobs"
+            );
+        }
+
+        let pipelines = Arc::clone(&pipelines.read().unwrap_or_else(PoisonError::into_inner));
+        if tokio::time::timeout_at(deadline, pipelines.flush())
+            .await
+            .is_err()
+        {
+            warn!("Drain deadline passed before every pipeline was flushed");
+        }
+    }
+
+    /// Apply a change requested through a
+    /// [`RuntimeHandle`](crate::handle::RuntimeHandle), updating the
+    /// Yellowstone subscription and the routed pipelines together.
+    async fn apply_change<S: Source>(
+        source: &mut S,
+        pipelines: &SharedPipelines,
+        change: Change,
+    ) -> Result<(), HandleError> {
+        let swap = |next| {
+            std::mem::replace(
+                &mut *pipelines.write().unwrap_or_else(PoisonError::into_inner),
+                next,
+            )
+        };
+
+        let prev = Arc::clone(&pipelines.read().unwrap_or_else(PoisonError::into_inner));
+        let mut next = PipelineSets::clone(&prev);
+        let grows = change.apply(&mut next)?;
+        let next = Arc::new(next);
+
+        // When adding to the subscription, route to the new pipelines before
+        // any new updates arrive; when removing from it, keep routing to the
+        // old pipelines until the server has been told to stop sending them.
+        if grows {
+            swap(Arc::clone(&next));
+        }
+
+        if let Err(e) = source.update(next.filters()).await {
+            swap(prev);
+            return Err(HandleError::Subscribe(e));
+        }
+
+        if !grows {
+            swap(next);
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments, clippy::too_many_lines)]
+    pub fn run<M: Instrumenter, S: Source + 'static>(
         config: BufferConfig,
-        client: yellowstone::YellowstoneStream<I, T, S>,
+        mut source: S,
+        snapshot: Option<Snapshot>,
+        mut recorder: Option<Recorder>,
         pipelines: PipelineSets,
-        counters: Counters<M>,
+        key_fn: Option<KeyFn>,
+        dead_letters: Option<Arc<dyn DeadLetterSink>>,
+        mut commands: CommandRx,
+        counters: Arc<Counters<M>>,
+        slots: SharedSlots,
     ) -> Self {
+        // A key function implies keyed ordering.  Without keyed ordering,
+        // account writes are still ordered if a snapshot is loaded, so a
+        // snapshot write and a stream write to the same account never run
+        // out of order.
+        let mut order = if config.order == DispatchOrder::Keyed || key_fn.is_some() {
+            Some(Order::new(key_fn))
+        } else {
+            snapshot.is_some().then(Order::accounts)
+        };
+        let drain_timeout = Duration::from_secs(config.drain_timeout);
+
         Self::run_impl(
             config,
             pipelines,
+            dead_letters,
             counters,
+            slots,
             std::convert::identity,
-            |exec, mut stop_rx, counters| {
+            |exec, mut stop_rx, pipelines, queue, counters, slots| {
                 tokio::task::spawn_local(async move {
                     enum Event {
-                        Update(Option<Result<SubscribeUpdate, Status>>),
+                        Update(Option<Result<SubscribeUpdate, crate::Error>>),
+                        Command(Change, crate::handle::Reply),
                         Stop(StopCode),
                     }
 
-                    let mut stream = pin!(client.stream);
-                    loop {
-                        let event = tokio::select! {
-                            u = stream
-                                .next()
-                                .instrument(tracing::trace_span!("await_update"))
-                                => Event::Update(u),
-                                c = &mut stop_rx => Event::Stop(c),
-                        };
-
-                        let update = match event {
-                            Event::Update(Some(u)) => u,
-                            Event::Update(None) => break Err(crate::Error::ServerHangup),
-                            Event::Stop(c) => break Ok(c),
-                        };
-
-                        Self::dispatch(&exec, update?, &counters);
+                    let mut held = Held::default();
+                    let mut stale = HashMap::new();
+
+                    let stopped = if let Some(snapshot) = snapshot {
+                        let load = Self::dispatch_snapshot(
+                            snapshot,
+                            &mut source,
+                            &mut recorder,
+                            &mut held,
+                            &exec,
+                            order.as_mut(),
+                            &queue,
+                            &counters,
+                            &slots,
+                        );
+
+                        tokio::select! {
+                            r = load => r.map(|s| stale = s).err().map(Err),
+                            c = &mut stop_rx => Some(Ok(Some(c))),
+                        }
+                    } else {
+                        None
+                    };
+
+                    let ret = if let Some(ret) = stopped {
+                        ret
+                    } else {
+                        loop {
+                            let event = if let Some(u) = held.updates.pop_front() {
+                                Event::Update(u)
+                            } else {
+                                tokio::select! {
+                                    u = source
+                                        .next()
+                                        .instrument(tracing::trace_span!("await_update"))
+                                        => {
+                                            if let Some(Ok(u)) = &u {
+                                                record(&mut recorder, u).await;
+                                            }
+                                            Event::Update(u)
+                                        },
+                                    Some((c, r)) = commands.recv() => Event::Command(c, r),
+                                    c = &mut stop_rx => Event::Stop(c),
+                                }
+                            };
+
+                            let update = match event {
+                                Event::Update(Some(u)) => u,
+                                Event::Update(None) => break Ok(None),
+                                Event::Command(change, reply) => {
+                                    let res =
+                                        Self::apply_change(&mut source, &pipelines, change).await;
+                                    if let Err(e) = &res {
+                                        warn!(err = %crate::Chain(e), "Error applying runtime change");
+                                    }
+                                    reply.send(res).ok();
+                                    continue;
+                                },
+                                Event::Stop(c) => break Ok(Some(c)),
+                            };
+                            let mut update = match update {
+                                Ok(u) => u,
+                                Err(e) => break Err(e),
+                            };
+
+                            if !stale.is_empty() && !Self::filter_stale(&mut stale, &mut update) {
+                                continue;
+                            }
+
+                            // Waiting for space here rather than only in dispatch
+                            // lets a stop request interrupt the wait
+                            tokio::select! {
+                                () = queue.wait_for_space() => (),
+                                c = &mut stop_rx => break Ok(Some(c)),
+                            }
+
+                            Self::dispatch(
+                                &exec,
+                                update,
+                                order.as_mut(),
+                                &queue,
+                                &counters,
+                                &slots,
+                            )
+                            .await;
+                        }
+                    };
+
+                    if let Some(r) = recorder {
+                        if let Err(e) = r.finish().await {
+                            warn!(err = %Chain(&e), "Error finishing update recording");
+                        }
                     }
+
+                    // No further updates are pulled from the source once the
+                    // loop exits, so only work already dispatched is drained
+                    Self::drain(drain_timeout, &queue, &pipelines).await;
+
+                    ret
                 })
             },
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use yellowstone_vixen_core::{GetPrefilter, ParserId, Prefilter};
+
+    use super::*;
+    use crate::{
+        config::OverflowPolicy,
+        handler::{BoxPipeline, DynPipeline, PipelineErrors, PipelineSet},
+    };
+
+    /// A pipeline counting its flushes, each taking the given time.
+    #[derive(Debug)]
+    struct Flushing {
+        id: &'static str,
+        delay: Duration,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl ParserId for Flushing {
+        fn id(&self) -> Cow<'_, str> { self.id.into() }
+    }
+
+    impl GetPrefilter for Flushing {
+        fn prefilter(&self) -> Prefilter { Prefilter::default() }
+    }
+
+    impl<T> DynPipeline<T> for Flushing {
+        fn handle<'h>(
+            &'h self,
+            _: &'h T,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn flush<'h>(
+            &'h self,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+            Box::pin(async move {
+                if !self.delay.is_zero() {
+                    tokio::time::sleep(self.delay).await;
+                }
+                self.flushes.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            })
+        }
+    }
+
+    /// Pipelines for block and block metadata updates, flushing with the
+    /// given delay.
+    fn pipelines(delay: Duration, flushes: &Arc<AtomicUsize>) -> SharedPipelines {
+        fn set<T>(pipeline: Option<Flushing>) -> PipelineSet<BoxPipeline<'static, T>> {
+            pipeline
+                .into_iter()
+                .map(|p| Box::new(p) as BoxPipeline<'static, T>)
+                .collect()
+        }
+
+        let pipeline = |id| {
+            Some(Flushing {
+                id,
+                delay,
+                flushes: Arc::clone(flushes),
+            })
+        };
+        Arc::new(RwLock::new(Arc::new(PipelineSets {
+            account: set(None),
+            transaction: set(None),
+            slot: set(None),
+            block: set(pipeline("block")),
+            block_meta: set(pipeline("block_meta")),
+            transaction_status: set(None),
+            entry: set(None),
+        })))
+    }
+
+    fn queue(updates: impl IntoIterator<Item = UpdateOneof>) -> SharedQueue<Job> {
+        let queue = Arc::new(Queue::new(None, OverflowPolicy::default(), HashMap::new()));
+        for update in updates {
+            let job = Job {
+                span: tracing::Span::none(),
+                received_at: SystemTime::now(),
+                slot: None,
+                key: None,
+                update: SubscribeUpdate {
+                    filters: vec![],
+                    update_oneof: Some(update),
+                    created_at: None,
+                },
+            };
+            assert!(matches!(queue.push(0, job), Push::Queued));
+        }
+        queue
+    }
+
+    /// Run every queued job in turn, each taking the given time.
+    fn run_jobs(queue: &SharedQueue<Job>, each: Duration) -> tokio::task::JoinHandle<()> {
+        let queue = Arc::clone(queue);
+        tokio::spawn(async move {
+            while queue.pop(|_| ()).is_some() {
+                queue.started();
+                tokio::time::sleep(each).await;
+                queue.finished();
+            }
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain() {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let pipelines = pipelines(Duration::ZERO, &flushes);
+        let queue = queue([
+            UpdateOneof::Block(geyser::SubscribeUpdateBlock::default()),
+            UpdateOneof::BlockMeta(geyser::SubscribeUpdateBlockMeta::default()),
+        ]);
+
+        let start = tokio::time::Instant::now();
+        let worker = run_jobs(&queue, Duration::from_secs(1));
+        Buffer::drain(Duration::from_secs(10), &queue, &pipelines).await;
+
+        // Every job completed before the pipelines were flushed
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(queue.outstanding(), 0);
+        assert_eq!(flushes.load(Ordering::Relaxed), 2);
+        worker.await.unwrap();
+    }
+
+    fn account_update(slot: u64, filters: &[&str]) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: filters.iter().map(ToString::to_string).collect(),
+            update_oneof: Some(UpdateOneof::Account(geyser::SubscribeUpdateAccount {
+                slot,
+                ..geyser::SubscribeUpdateAccount::default()
+            })),
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_stale() {
+        let mut stale: HashMap<_, _> = [("a".to_owned(), 10), ("b".to_owned(), 9)].into();
+
+        // Writes no newer than a pipeline's snapshot are dropped for it, and
+        // newer writes win over the snapshot
+        let mut update = account_update(10, &["a", "b", "c"]);
+        assert!(Buffer::filter_stale(&mut stale, &mut update));
+        assert_eq!(update.filters, ["b", "c"]);
+
+        let mut update = account_update(5, &["a", "b"]);
+        assert!(!Buffer::filter_stale(&mut stale, &mut update));
+
+        let mut update = SubscribeUpdate {
+            filters: vec!["a".to_owned()],
+            update_oneof: Some(UpdateOneof::Slot(geyser::SubscribeUpdateSlot::default())),
+            created_at: None,
+        };
+        assert!(Buffer::filter_stale(&mut stale, &mut update));
+        assert_eq!(update.filters, ["a"]);
+        assert_eq!(stale.len(), 2);
+
+        // Once the stream passes every snapshot nothing is stale
+        let mut update = account_update(11, &["a"]);
+        assert!(Buffer::filter_stale(&mut stale, &mut update));
+        assert_eq!(update.filters, ["a"]);
+        assert!(stale.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_deadline() {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let pipelines = pipelines(Duration::ZERO, &flushes);
+        let queue = queue([
+            UpdateOneof::Block(geyser::SubscribeUpdateBlock::default()),
+            UpdateOneof::Block(geyser::SubscribeUpdateBlock::default()),
+        ]);
+
+        // A job still running and another still queued at the deadline are
+        // abandoned, and the pipelines are flushed without them
+        let start = tokio::time::Instant::now();
+        let worker = run_jobs(&queue, Duration::from_secs(30));
+        Buffer::drain(Duration::from_secs(5), &queue, &pipelines).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!((queue.depth(), queue.outstanding()), (1, 2));
+        assert_eq!(flushes.load(Ordering::Relaxed), 2);
+        worker.abort();
+
+        // Pipelines still flushing at the deadline are abandoned too
+        let pipelines = self::pipelines(Duration::from_secs(30), &flushes);
+        let start = tokio::time::Instant::now();
+        Buffer::drain(Duration::from_secs(5), &self::queue([]), &pipelines).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(flushes.load(Ordering::Relaxed), 2);
+    }
+
+    fn tracker() -> SlotTracker {
+        let mut slots = SlotTracker::default();
+        slots.assign(0, ["processed"]);
+        slots.assign(1, ["finalized"]);
+        slots
+    }
+
+    #[test]
+    fn test_single_group() {
+        let mut slots = tracker();
+        assert_eq!(slots.processed(), None);
+
+        let a = slots.start(&["processed"], 10);
+        let b = slots.start(&["processed"], 11);
+        assert_eq!(slots.processed(), Some(9));
+
+        slots.finish(a);
+        // Updates for slot 11 may still be arriving
+        assert_eq!(slots.processed(), Some(10));
+
+        slots.finish(b);
+        assert_eq!(slots.processed(), Some(10));
+
+        let c = slots.start(&["processed"], 12);
+        slots.finish(c);
+        assert_eq!(slots.processed(), Some(11));
+    }
+
+    #[test]
+    fn test_interleaved_groups() {
+        let mut slots = tracker();
+
+        // The finalized group lags behind the processed group
+        let p40 = slots.start(&["processed"], 40);
+        let p41 = slots.start(&["processed"], 41);
+        slots.finish(p40);
+        slots.finish(p41);
+        assert_eq!(slots.processed(), Some(40));
+
+        let f8 = slots.start(&["finalized"], 8);
+        let p42 = slots.start(&["processed"], 42);
+        slots.finish(p42);
+        assert_eq!(slots.processed(), Some(7));
+        assert_eq!(slots.group_processed(0), Some(41));
+        assert_eq!(slots.group_processed(1), Some(7));
+
+        let f9 = slots.start(&["finalized"], 9);
+        slots.finish(f9);
+        assert_eq!(slots.processed(), Some(7));
+
+        slots.finish(f8);
+        assert_eq!(slots.processed(), Some(8));
+    }
+
+    #[test]
+    fn test_late_update() {
+        let mut slots = tracker();
+
+        for slot in 10..13 {
+            let t = slots.start(&["processed"], slot);
+            slots.finish(t);
+        }
+        assert_eq!(slots.processed(), Some(11));
+
+        // A write for an earlier slot arriving after later ones holds the
+        // processed slot back until it completes
+        let late = slots.start(&["processed"], 9);
+        assert_eq!(slots.processed(), Some(8));
+        slots.finish(late);
+        assert_eq!(slots.processed(), Some(11));
+    }
+
+    #[test]
+    fn test_resume_and_unsubscribed_groups() {
+        let mut slots = tracker();
+        slots.resume(100);
+        assert_eq!(slots.processed(), Some(100));
+        assert_eq!(slots.group_processed(1), Some(100));
+
+        // Replayed updates for already-processed slots do not move the
+        // processed slot backwards
+        let a = slots.start(&["processed"], 95);
+        assert_eq!(slots.processed(), Some(100));
+        slots.finish(a);
+
+        let b = slots.start(&["processed"], 110);
+        let c = slots.start(&["finalized"], 105);
+        slots.finish(b);
+        assert_eq!(slots.processed(), Some(104));
+
+        // A group no longer streaming for any pipeline stops holding back the
+        // processed slot once its jobs complete
+        slots.assign(1, Vec::<String>::new());
+        assert_eq!(slots.processed(), Some(104));
+        slots.finish(c);
+        assert_eq!(slots.processed(), Some(109));
+    }
+
+    #[test]
+    fn test_unassigned_pipelines() {
+        let mut slots = SlotTracker::default();
+
+        let a = slots.start(&["replayed"], 5);
+        let b = slots.start(&["other"], 6);
+        slots.finish(b);
+        assert_eq!(slots.processed(), Some(4));
+        slots.finish(a);
+        assert_eq!(slots.processed(), Some(5));
+    }
+
+    #[test]
+    fn test_dropped_jobs() {
+        let mut slots = tracker();
+
+        let a = slots.start(&["processed"], 10);
+        let b = slots.start(&["processed"], 11);
+        slots.discard(a);
+        slots.finish(b);
+        let c = slots.start(&["processed"], 13);
+        slots.finish(c);
+
+        // The dropped update is never processed, so a checkpoint resumes
+        // from before it
+        assert_eq!(slots.processed(), Some(9));
+        assert_eq!(slots.group_processed(0), Some(9));
+
+        // Even once nothing streams through the group any more
+        slots.assign(0, Vec::<String>::new());
+        assert_eq!(slots.processed(), Some(9));
+    }
+}
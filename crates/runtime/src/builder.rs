@@ -1,13 +1,22 @@
 //! Builder types for the Vixen runtime and stream server.
 
-use tracing::error;
-use vixen_core::{instruction::InstructionUpdate, AccountUpdate, TransactionUpdate};
+use std::{hash::Hash, sync::Arc};
+
+use vixen_core::{
+    instruction::InstructionUpdate, AccountUpdate, BlockMetaUpdate, BlockUpdate, EntryUpdate,
+    SlotUpdate, TransactionStatusUpdate, TransactionUpdate,
+};
+use yellowstone_grpc_proto::geyser::SubscribeUpdate;
 
 use crate::{
+    checkpoint::{self, Checkpoint, CheckpointError},
     config::{MaybeDefault, VixenConfig},
+    dead_letter::{self, DeadLetterSink},
+    handle,
     handler::{BoxPipeline, DynPipeline, PipelineSets},
     instruction::InstructionPipeline,
     metrics::{Counters, Metrics, MetricsFactory, NullMetrics},
+    order::KeyFn,
     util, Runtime,
 };
 
@@ -26,6 +35,22 @@ pub enum BuilderError {
     /// Two transaction pipelines were registered with the same parser ID.
     #[error("ID collision detected among transaction pipelines")]
     TransactionPipelineCollision,
+    /// Two slot pipelines were registered with the same parser ID.
+    #[error("ID collision detected among slot pipelines")]
+    SlotPipelineCollision,
+    /// Two block pipelines were registered with the same parser ID.
+    #[error("ID collision detected among block pipelines")]
+    BlockPipelineCollision,
+    /// Two block metadata pipelines were registered with the same parser ID.
+    #[error("ID collision detected among block metadata pipelines")]
+    BlockMetaPipelineCollision,
+    /// Two transaction status pipelines were registered with the same parser
+    /// ID.
+    #[error("ID collision detected among transaction status pipelines")]
+    TransactionStatusPipelineCollision,
+    /// Two entry pipelines were registered with the same parser ID.
+    #[error("ID collision detected among entry pipelines")]
+    EntryPipelineCollision,
     /// A required field was missing from the builder.
     #[error("Missing field {0:?}")]
     MissingField(&'static str),
@@ -35,6 +60,9 @@ pub enum BuilderError {
     /// An error occurred while instantiating the metrics backend.
     #[error("Error instantiating metrics backend")]
     Metrics(#[source] Box<dyn std::error::Error>),
+    /// An error occurred while opening the configured checkpoint store.
+    #[error("Error opening checkpoint store")]
+    Checkpoint(#[source] CheckpointError),
 }
 
 /// A builder used by both the [`Runtime`] and
@@ -46,6 +74,14 @@ pub struct Builder<K: BuilderKind, M> {
     pub(crate) account: Vec<BoxPipeline<'static, AccountUpdate>>,
     pub(crate) transaction: Vec<BoxPipeline<'static, TransactionUpdate>>,
     pub(crate) instruction: Vec<BoxPipeline<'static, InstructionUpdate>>,
+    pub(crate) slot: Vec<BoxPipeline<'static, SlotUpdate>>,
+    pub(crate) block: Vec<BoxPipeline<'static, BlockUpdate>>,
+    pub(crate) block_meta: Vec<BoxPipeline<'static, BlockMetaUpdate>>,
+    pub(crate) transaction_status: Vec<BoxPipeline<'static, TransactionStatusUpdate>>,
+    pub(crate) entry: Vec<BoxPipeline<'static, EntryUpdate>>,
+    pub(crate) checkpoint: Option<Arc<dyn Checkpoint>>,
+    pub(crate) dead_letters: Option<Arc<dyn DeadLetterSink>>,
+    pub(crate) dispatch_key: Option<KeyFn>,
     pub(crate) metrics: M,
     pub(crate) extra: K,
 }
@@ -57,6 +93,14 @@ impl<K: BuilderKind> Default for Builder<K, NullMetrics> {
             account: vec![],
             transaction: vec![],
             instruction: vec![],
+            slot: vec![],
+            block: vec![],
+            block_meta: vec![],
+            transaction_status: vec![],
+            entry: vec![],
+            checkpoint: None,
+            dead_letters: None,
+            dispatch_key: None,
             metrics: NullMetrics,
             extra: K::default(),
         }
@@ -101,6 +145,14 @@ impl<K: BuilderKind, M> Builder<K, M> {
             account,
             transaction,
             instruction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            checkpoint,
+            dead_letters,
+            dispatch_key,
             metrics: _,
             extra,
         } = self;
@@ -110,10 +162,43 @@ impl<K: BuilderKind, M> Builder<K, M> {
             account,
             transaction,
             instruction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            checkpoint,
+            dead_letters,
+            dispatch_key,
             metrics,
             extra,
         }
     }
+
+    /// Save the runtime's progress to the given store, overriding the
+    /// `checkpoint` configuration section.
+    pub fn checkpoint<C: Checkpoint>(self, checkpoint: C) -> Self {
+        self.mutate(|s| s.checkpoint = Some(Arc::new(checkpoint)))
+    }
+
+    /// Send updates pipelines fail to handle to the given sink, overriding
+    /// the `dead-letter` configuration section.
+    pub fn dead_letters<D: DeadLetterSink>(self, sink: D) -> Self {
+        self.mutate(|s| s.dead_letters = Some(Arc::new(sink)))
+    }
+
+    /// Run updates sharing a key one at a time, in the order they were
+    /// received, using the given function to compute each update's key.
+    /// Updates the function returns `None` for are run in any order.
+    ///
+    /// This enables [keyed ordering](crate::config::DispatchOrder::Keyed)
+    /// regardless of the `buffer` configuration section.
+    pub fn dispatch_key<T: Hash, F: Fn(&SubscribeUpdate) -> Option<T> + Send + Sync + 'static>(
+        self,
+        key: F,
+    ) -> Self {
+        self.mutate(|s| s.dispatch_key = Some(KeyFn::new(key)))
+    }
 }
 
 /// Marker type used for the [`RuntimeBuilder`] type.
@@ -146,7 +231,9 @@ impl<M: MetricsFactory> RuntimeBuilder<M> {
     /// Add a new instruction pipeline to the builder.
     ///
     /// **NOTE:** All registered instruction pipelines will be bundled into a
-    /// single [`InstructionPipeline`] instance.
+    /// single [`InstructionPipeline`] instance per requested commitment level.
+    /// Pipelines created with [`Pipeline::for_program`](crate::Pipeline::for_program)
+    /// are only passed instructions for their parser's program.
     pub fn instruction<I: DynPipeline<InstructionUpdate> + Send + Sync + 'static>(
         self,
         instruction: I,
@@ -154,6 +241,37 @@ impl<M: MetricsFactory> RuntimeBuilder<M> {
         self.mutate(|s| s.instruction.push(Box::new(instruction)))
     }
 
+    /// Add a new slot pipeline to the builder.
+    pub fn slot<S: DynPipeline<SlotUpdate> + Send + Sync + 'static>(self, slot: S) -> Self {
+        self.mutate(|s| s.slot.push(Box::new(slot)))
+    }
+
+    /// Add a new block pipeline to the builder.
+    pub fn block<B: DynPipeline<BlockUpdate> + Send + Sync + 'static>(self, block: B) -> Self {
+        self.mutate(|s| s.block.push(Box::new(block)))
+    }
+
+    /// Add a new block metadata pipeline to the builder.
+    pub fn block_meta<B: DynPipeline<BlockMetaUpdate> + Send + Sync + 'static>(
+        self,
+        block_meta: B,
+    ) -> Self {
+        self.mutate(|s| s.block_meta.push(Box::new(block_meta)))
+    }
+
+    /// Add a new transaction status pipeline to the builder.
+    pub fn transaction_status<T: DynPipeline<TransactionStatusUpdate> + Send + Sync + 'static>(
+        self,
+        transaction_status: T,
+    ) -> Self {
+        self.mutate(|s| s.transaction_status.push(Box::new(transaction_status)))
+    }
+
+    /// Add a new entry pipeline to the builder.
+    pub fn entry<E: DynPipeline<EntryUpdate> + Send + Sync + 'static>(self, entry: E) -> Self {
+        self.mutate(|s| s.entry.push(Box::new(entry)))
+    }
+
     /// Attempt to build a new [`Runtime`] instance from the current builder
     /// state and the provided configuration.
     ///
@@ -166,6 +284,14 @@ impl<M: MetricsFactory> RuntimeBuilder<M> {
             account,
             mut transaction,
             instruction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            checkpoint,
+            dead_letters,
+            dispatch_key,
             metrics,
             extra: RuntimeKind,
         } = self;
@@ -174,28 +300,49 @@ impl<M: MetricsFactory> RuntimeBuilder<M> {
         let VixenConfig {
             yellowstone: yellowstone_cfg,
             buffer: buffer_cfg,
+            snapshot: snapshot_cfg,
+            record: record_cfg,
+            checkpoint: checkpoint_cfg,
+            dead_letter: dead_letter_cfg,
             metrics: metrics_cfg,
         } = config;
 
+        let checkpoint = match checkpoint {
+            Some(c) => Some(c),
+            None => checkpoint::open(&checkpoint_cfg).map_err(BuilderError::Checkpoint)?,
+        };
+        let dead_letters = dead_letters.or_else(|| dead_letter::open(&dead_letter_cfg));
+
         let metrics_cfg = unwrap_cfg(
             "metrics",
             metrics_cfg.opt().or_else(MaybeDefault::default_opt),
         )?;
 
+        let endpoints = yellowstone_cfg.endpoints().len();
         let Metrics(instrumenter, exporter) = metrics
             .create(metrics_cfg, "vixen")
             .map_err(|e| BuilderError::Metrics(e.into()))?;
 
-        if let Some(i) = InstructionPipeline::new(instruction, &instrumenter) {
+        for i in InstructionPipeline::split_by_commitment(instruction, &instrumenter) {
             transaction.push(Box::new(i));
         }
 
         let account_len = account.len();
         let transaction_len = transaction.len();
+        let slot_len = slot.len();
+        let block_len = block.len();
+        let block_meta_len = block_meta.len();
+        let transaction_status_len = transaction_status.len();
+        let entry_len = entry.len();
 
         let pipelines = PipelineSets {
             account: account.into_iter().collect(),
             transaction: transaction.into_iter().collect(),
+            slot: slot.into_iter().collect(),
+            block: block.into_iter().collect(),
+            block_meta: block_meta.into_iter().collect(),
+            transaction_status: transaction_status.into_iter().collect(),
+            entry: entry.into_iter().collect(),
         };
 
         if pipelines.account.len() != account_len {
@@ -206,11 +353,41 @@ impl<M: MetricsFactory> RuntimeBuilder<M> {
             return Err(BuilderError::TransactionPipelineCollision);
         }
 
+        if pipelines.slot.len() != slot_len {
+            return Err(BuilderError::SlotPipelineCollision);
+        }
+
+        if pipelines.block.len() != block_len {
+            return Err(BuilderError::BlockPipelineCollision);
+        }
+
+        if pipelines.block_meta.len() != block_meta_len {
+            return Err(BuilderError::BlockMetaPipelineCollision);
+        }
+
+        if pipelines.transaction_status.len() != transaction_status_len {
+            return Err(BuilderError::TransactionStatusPipelineCollision);
+        }
+
+        if pipelines.entry.len() != entry_len {
+            return Err(BuilderError::EntryPipelineCollision);
+        }
+
+        let (handle, commands) = handle::channel();
+
         Ok(Runtime {
             yellowstone_cfg,
             buffer_cfg,
+            snapshot_cfg,
+            record_cfg,
+            checkpoint_cfg,
+            checkpoint,
+            dead_letters,
             pipelines,
-            counters: Counters::new(&instrumenter),
+            dispatch_key,
+            handle,
+            commands,
+            counters: Arc::new(Counters::new(&instrumenter, endpoints)),
             exporter,
         })
     }
@@ -0,0 +1,464 @@
+//! Saving the progress of the runtime so it can resume after a restart.
+//!
+//! A checkpoint records the highest slot for which every account write and
+//! transaction received, in every subscription, has been processed, along
+//! with any cursors handlers have set through
+//! [`RuntimeHandle::set_cursor`](crate::RuntimeHandle::set_cursor).  Both are
+//! written in a single save, so the cursors restored after a restart always
+//! belong to the same checkpoint as the slot the runtime resumes from.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{
+    buffer::{lock_slots, SharedSlots},
+    config::{CheckpointBackend, CheckpointConfig},
+    stop::{self, StopTx},
+    Chain,
+};
+
+/// An error that can occur while loading or saving a checkpoint.
+#[derive(Debug, thiserror::Error)]
+pub enum CheckpointError {
+    /// A system I/O error.
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    /// A checkpoint file could not be parsed.
+    #[error("Invalid checkpoint file")]
+    Json(#[from] serde_json::Error),
+    /// An error returned by the `SQLite` database.
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error")]
+    Sqlite(#[from] rusqlite::Error),
+    /// The `SQLite` backend was requested, but support for it was not enabled.
+    #[error("SQLite checkpoint backend requested, but the sqlite feature is not enabled")]
+    SqliteUnsupported,
+}
+
+/// The progress of the runtime at a point in time.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CheckpointState {
+    /// The highest slot for which every dispatched job had completed, if any
+    /// jobs had been dispatched.
+    pub slot: Option<u64>,
+    /// Cursors set by handlers, by name.
+    #[serde(default)]
+    pub cursors: BTreeMap<String, String>,
+}
+
+/// A durable store for runtime checkpoints.
+///
+/// Methods are called from a blocking thread, so implementations may perform
+/// synchronous I/O.
+pub trait Checkpoint: fmt::Debug + Send + Sync + 'static {
+    /// Load the most recently saved checkpoint, or `None` if none has been
+    /// saved yet.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint could not be read.
+    fn load(&self) -> Result<Option<CheckpointState>, CheckpointError>;
+
+    /// Save a checkpoint, replacing the previous one.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint could not be written.
+    fn save(&self, state: &CheckpointState) -> Result<(), CheckpointError>;
+}
+
+/// Open the store described by a configuration section, if one is
+/// configured.
+pub(crate) fn open(
+    config: &CheckpointConfig,
+) -> Result<Option<Arc<dyn Checkpoint>>, CheckpointError> {
+    let CheckpointConfig {
+        path,
+        backend,
+        interval: _,
+    } = config;
+    let Some(path) = path else { return Ok(None) };
+
+    Ok(Some(match backend {
+        CheckpointBackend::File => Arc::new(FileCheckpoint::new(path)),
+        CheckpointBackend::Sqlite => sqlite::open(path)?,
+    }))
+}
+
+/// A [`Checkpoint`] stored as a JSON file.
+///
+/// Each save writes a temporary file alongside the checkpoint and renames it
+/// into place, so a crash mid-save leaves the previous checkpoint intact.
+#[derive(Debug, Clone)]
+pub struct FileCheckpoint {
+    path: PathBuf,
+}
+
+impl FileCheckpoint {
+    /// Create a store saving to the given file.  The file is not created
+    /// until the first checkpoint is saved.
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self { Self { path: path.into() } }
+}
+
+impl Checkpoint for FileCheckpoint {
+    fn load(&self) -> Result<Option<CheckpointState>, CheckpointError> {
+        match std::fs::read(&self.path) {
+            Ok(b) => Ok(Some(serde_json::from_slice(&b)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, state: &CheckpointState) -> Result<(), CheckpointError> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteCheckpoint;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex, PoisonError},
+    };
+
+    use rusqlite::{Connection, OptionalExtension};
+
+    use super::{Checkpoint, CheckpointError, CheckpointState};
+
+    /// A [`Checkpoint`] stored in a `SQLite` database.
+    ///
+    /// The checkpoint is kept in the `vixen_checkpoint` and
+    /// `vixen_checkpoint_cursors` tables, which are created if they do not
+    /// exist, so the database may be shared with a handler's own tables.
+    #[derive(Debug)]
+    pub struct SqliteCheckpoint(Mutex<Connection>);
+
+    impl SqliteCheckpoint {
+        /// Open or create the database at the given path.
+        ///
+        /// # Errors
+        /// Returns an error if the database could not be opened or its tables
+        /// could not be created.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, CheckpointError> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS vixen_checkpoint (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    slot INTEGER
+                );
+                CREATE TABLE IF NOT EXISTS vixen_checkpoint_cursors (
+                    name TEXT PRIMARY KEY,
+                    cursor TEXT NOT NULL
+                );",
+            )?;
+
+            Ok(Self(Mutex::new(conn)))
+        }
+    }
+
+    impl Checkpoint for SqliteCheckpoint {
+        fn load(&self) -> Result<Option<CheckpointState>, CheckpointError> {
+            let conn = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+            let Some(slot) = conn
+                .query_row("SELECT slot FROM vixen_checkpoint WHERE id = 0", [], |r| {
+                    r.get(0)
+                })
+                .optional()?
+            else {
+                return Ok(None);
+            };
+
+            let cursors = conn
+                .prepare("SELECT name, cursor FROM vixen_checkpoint_cursors")?
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+
+            Ok(Some(CheckpointState { slot, cursors }))
+        }
+
+        fn save(&self, state: &CheckpointState) -> Result<(), CheckpointError> {
+            let CheckpointState { slot, cursors } = state;
+            let mut conn = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO vixen_checkpoint (id, slot) VALUES (0, ?1)
+                ON CONFLICT (id) DO UPDATE SET slot = excluded.slot",
+                [slot],
+            )?;
+            tx.execute("DELETE FROM vixen_checkpoint_cursors", [])?;
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO vixen_checkpoint_cursors (name, cursor) VALUES (?1, ?2)",
+                )?;
+                for (name, cursor) in cursors {
+                    insert.execute([name, cursor])?;
+                }
+            }
+
+            tx.commit()?;
+            Ok(())
+        }
+    }
+
+    #[inline]
+    pub fn open(path: &Path) -> Result<Arc<dyn Checkpoint>, CheckpointError> {
+        Ok(Arc::new(SqliteCheckpoint::open(path)?))
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use std::{path::Path, sync::Arc};
+
+    use super::{Checkpoint, CheckpointError};
+
+    #[inline]
+    pub fn open(_: &Path) -> Result<Arc<dyn Checkpoint>, CheckpointError> {
+        Err(CheckpointError::SqliteUnsupported)
+    }
+}
+
+// Cursors are shared between every clone of a runtime handle and the task
+// saving checkpoints
+pub(crate) type SharedCursors = Arc<Mutex<BTreeMap<String, String>>>;
+
+#[inline]
+pub(crate) fn lock_cursors(
+    cursors: &SharedCursors,
+) -> std::sync::MutexGuard<'_, BTreeMap<String, String>> {
+    cursors.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Load a checkpoint, seeding the slot tracker and handler cursors with it.
+pub(crate) async fn restore(
+    store: &Arc<dyn Checkpoint>,
+    slots: &SharedSlots,
+    cursors: &SharedCursors,
+) -> Result<Option<u64>, crate::Error> {
+    let store = Arc::clone(store);
+    let Some(CheckpointState {
+        slot,
+        cursors: saved,
+    }) = tokio::task::spawn_blocking(move || store.load())
+        .await
+        .map_err(std::io::Error::from)??
+    else {
+        return Ok(None);
+    };
+
+    if let Some(slot) = slot {
+        lock_slots(slots).resume(slot);
+    }
+
+    let mut cursors = lock_cursors(cursors);
+    for (name, cursor) in saved {
+        // Cursors set before the runtime started take precedence
+        cursors.entry(name).or_insert(cursor);
+    }
+
+    Ok(slot)
+}
+
+/// The first slot to request from Yellowstone when resuming after the given
+/// fully processed slot, if any.
+#[inline]
+pub(crate) fn from_slot(processed: Option<u64>) -> Option<u64> { processed.map(|s| s + 1) }
+
+/// Periodically saves the progress of a running runtime.
+pub(crate) struct Checkpointer {
+    store: Arc<dyn Checkpoint>,
+    slots: SharedSlots,
+    cursors: SharedCursors,
+    last: Option<CheckpointState>,
+}
+
+impl Checkpointer {
+    pub fn new(store: Arc<dyn Checkpoint>, slots: SharedSlots, cursors: SharedCursors) -> Self {
+        Self {
+            store,
+            slots,
+            cursors,
+            last: None,
+        }
+    }
+
+    async fn save(&mut self) {
+        let Self {
+            store,
+            slots,
+            cursors,
+            last,
+        } = self;
+
+        // Read the slot before the cursors, so every cursor saved was set no
+        // earlier than the slot it is saved alongside
+        let slot = lock_slots(slots).processed();
+        let state = CheckpointState {
+            slot,
+            cursors: lock_cursors(cursors).clone(),
+        };
+        if last.as_ref() == Some(&state) {
+            return;
+        }
+
+        let store = Arc::clone(store);
+        let res = tokio::task::spawn_blocking(move || store.save(&state).map(|()| state))
+            .await
+            .map_err(|e| CheckpointError::Io(e.into()))
+            .and_then(std::convert::identity);
+
+        match res {
+            Ok(s) => *last = Some(s),
+            Err(e) => warn!(err = %Chain(&e), "Error saving checkpoint"),
+        }
+    }
+
+    /// Save a checkpoint at the given interval, and once more when stopped.
+    pub fn spawn(mut self, interval: Duration) -> (tokio::task::JoinHandle<()>, StopTx) {
+        let (tx, mut rx) = stop::channel();
+
+        let task = tokio::task::spawn_local(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => self.save().await,
+                    c = &mut rx => {
+                        c.as_unit();
+                        break;
+                    },
+                }
+            }
+
+            self.save().await;
+        });
+
+        (task, tx)
+    }
+}
+
+impl fmt::Debug for Checkpointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpointer")
+            .field("store", &self.store)
+            .field("last", &self.last)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vixen-{}-{name}", std::process::id()))
+    }
+
+    fn state(slot: Option<u64>, cursors: &[(&str, &str)]) -> CheckpointState {
+        CheckpointState {
+            slot,
+            cursors: cursors
+                .iter()
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        }
+    }
+
+    fn round_trip(store: &dyn Checkpoint) {
+        assert_eq!(store.load().unwrap(), None);
+
+        let saved = state(Some(41), &[("a", "x"), ("b", "y")]);
+        store.save(&saved).unwrap();
+        assert_eq!(store.load().unwrap(), Some(saved));
+
+        // Each save replaces every cursor of the previous one
+        let saved = state(Some(42), &[("b", "z")]);
+        store.save(&saved).unwrap();
+        assert_eq!(store.load().unwrap(), Some(saved));
+
+        let saved = state(None, &[]);
+        store.save(&saved).unwrap();
+        assert_eq!(store.load().unwrap(), Some(saved));
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let path = temp_path("checkpoint.json");
+        round_trip(&FileCheckpoint::new(&path));
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!std::path::Path::new(&tmp).exists());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_round_trip() {
+        let path = temp_path("checkpoint.db");
+        round_trip(&SqliteCheckpoint::open(&path).unwrap());
+
+        // The last checkpoint outlives the connection that saved it
+        let store = SqliteCheckpoint::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), Some(state(None, &[])));
+        store.save(&state(Some(7), &[("a", "x")])).unwrap();
+        drop(store);
+        assert_eq!(
+            SqliteCheckpoint::open(&path).unwrap().load().unwrap(),
+            Some(state(Some(7), &[("a", "x")]))
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let path = temp_path("restore.json");
+        let store: Arc<dyn Checkpoint> = Arc::new(FileCheckpoint::new(&path));
+        let slots = SharedSlots::default();
+        let cursors = SharedCursors::default();
+
+        let resume_from = restore(&store, &slots, &cursors).await.unwrap();
+        assert_eq!(resume_from, None);
+        assert_eq!(from_slot(resume_from), None);
+        assert_eq!(lock_slots(&slots).processed(), None);
+
+        store
+            .save(&state(Some(41), &[("a", "saved"), ("b", "saved")]))
+            .unwrap();
+        lock_cursors(&cursors).insert("a".to_owned(), "live".to_owned());
+
+        let resume_from = restore(&store, &slots, &cursors).await.unwrap();
+        assert_eq!(resume_from, Some(41));
+        assert_eq!(from_slot(resume_from), Some(42));
+        assert_eq!(lock_slots(&slots).processed(), Some(41));
+        assert_eq!(from_slot(lock_slots(&slots).group_processed(0)), Some(42));
+        assert_eq!(
+            *lock_cursors(&cursors),
+            state(None, &[("a", "live"), ("b", "saved")]).cursors
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
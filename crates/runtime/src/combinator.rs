@@ -0,0 +1,640 @@
+//! Adapters for composing [`Handler`]s.
+//!
+//! Each adapter wraps another handler and is itself a handler, so adapters
+//! can be nested and passed to [`Pipeline::new`](crate::Pipeline::new) like
+//! any other handler.  [`Batched`] groups parsed values into batches for
+//! handlers writing to a database or other sink with a per-request cost,
+//! and [`ChannelHandler`] hands parsed values to application code as a
+//! [`Stream`].
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Once, PoisonError,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::Stream;
+use tokio::{
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{error, warn};
+
+use crate::{handler::HandlerContext, util::random_unit, Chain, Handler, HandlerResult};
+
+/// Await an optional handler future, succeeding if there is none.
+async fn forward<F: Future<Output = HandlerResult<()>>>(fut: Option<F>) -> HandlerResult<()> {
+    match fut {
+        Some(f) => f.await,
+        None => Ok(()),
+    }
+}
+
+/// A handler passing on only the values matching a predicate.
+pub struct Filtered<H, F> {
+    handler: H,
+    predicate: F,
+}
+
+impl<H: fmt::Debug, F> fmt::Debug for Filtered<H, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filtered")
+            .field("handler", &self.handler)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H, F> Filtered<H, F> {
+    /// Wrap a handler, passing it only the values for which `predicate`
+    /// returns `true`.
+    #[must_use]
+    pub fn new(handler: H, predicate: F) -> Self { Self { handler, predicate } }
+}
+
+impl<T, H: Handler<T>, F: Fn(&T) -> bool> Handler<T> for Filtered<H, F> {
+    fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send {
+        forward((self.predicate)(value).then(|| self.handler.handle(value)))
+    }
+
+    fn handle_with_context(
+        &self,
+        value: &T,
+        ctx: &HandlerContext<'_>,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        forward((self.predicate)(value).then(|| self.handler.handle_with_context(value, ctx)))
+    }
+
+    #[inline]
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send { self.handler.flush() }
+}
+
+/// A handler transforming each value before passing it on.
+pub struct Mapped<H, F> {
+    handler: H,
+    f: F,
+}
+
+impl<H: fmt::Debug, F> fmt::Debug for Mapped<H, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mapped")
+            .field("handler", &self.handler)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H, F> Mapped<H, F> {
+    /// Wrap a handler, passing it the result of calling `f` on each value.
+    #[must_use]
+    pub fn new(handler: H, f: F) -> Self { Self { handler, f } }
+}
+
+impl<T, U, H, F> Handler<T> for Mapped<H, F>
+where
+    U: Send,
+    H: Handler<U> + Sync,
+    F: Fn(&T) -> U + Sync,
+{
+    fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send {
+        let mapped = (self.f)(value);
+        async move { self.handler.handle(&mapped).await }
+    }
+
+    fn handle_with_context(
+        &self,
+        value: &T,
+        ctx: &HandlerContext<'_>,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        let mapped = (self.f)(value);
+        let ctx = *ctx;
+        async move { self.handler.handle_with_context(&mapped, &ctx).await }
+    }
+
+    #[inline]
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send { self.handler.flush() }
+}
+
+#[derive(Debug)]
+enum Sampling {
+    Every { n: u64, seen: AtomicU64 },
+    Ratio(f64),
+}
+
+/// A handler passing on a sample of the values it receives.
+#[derive(Debug)]
+pub struct Sampled<H> {
+    handler: H,
+    sampling: Sampling,
+}
+
+impl<H> Sampled<H> {
+    /// Wrap a handler, passing it the first value received and every `n`th
+    /// value after it.
+    #[must_use]
+    pub fn every(handler: H, n: u64) -> Self {
+        Self {
+            handler,
+            sampling: Sampling::Every {
+                n: n.max(1),
+                seen: AtomicU64::new(0),
+            },
+        }
+    }
+
+    /// Wrap a handler, passing it each value with the given probability
+    /// between 0 and 1.
+    #[must_use]
+    pub fn ratio(handler: H, ratio: f64) -> Self {
+        Self {
+            handler,
+            sampling: Sampling::Ratio(ratio.clamp(0.0, 1.0)),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        match self.sampling {
+            Sampling::Every { n, ref seen } => seen.fetch_add(1, Ordering::Relaxed) % n == 0,
+            Sampling::Ratio(r) => random_unit() < r,
+        }
+    }
+}
+
+impl<T, H: Handler<T>> Handler<T> for Sampled<H> {
+    fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send {
+        forward(self.sample().then(|| self.handler.handle(value)))
+    }
+
+    fn handle_with_context(
+        &self,
+        value: &T,
+        ctx: &HandlerContext<'_>,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        forward(
+            self.sample()
+                .then(|| self.handler.handle_with_context(value, ctx)),
+        )
+    }
+
+    #[inline]
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send { self.handler.flush() }
+}
+
+/// A handler limiting how often values are passed on.
+///
+/// Values arriving faster than the limit are delayed rather than dropped,
+/// holding up the pipeline until the inner handler may be called again.
+#[derive(Debug)]
+pub struct RateLimited<H> {
+    handler: H,
+    emission: Duration,
+    tolerance: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl<H> RateLimited<H> {
+    /// Wrap a handler, passing it at most `count` values in any window of
+    /// length `period`.  Up to `count` values may be passed on at once after
+    /// an idle period.
+    #[must_use]
+    pub fn new(handler: H, count: u32, period: Duration) -> Self {
+        let emission = period / count.max(1);
+        Self {
+            handler,
+            emission,
+            tolerance: period.saturating_sub(emission),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Reserve the next slot, returning when it begins.
+    fn reserve(&self) -> Instant {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let tat = next.map_or(now, |t| t.max(now));
+        *next = Some(tat + self.emission);
+
+        tat.checked_sub(self.tolerance).map_or(now, |t| t.max(now))
+    }
+}
+
+impl<T: Sync, H: Handler<T> + Sync> Handler<T> for RateLimited<H> {
+    async fn handle(&self, value: &T) -> HandlerResult<()> {
+        tokio::time::sleep_until(self.reserve()).await;
+        self.handler.handle(value).await
+    }
+
+    async fn handle_with_context(&self, value: &T, ctx: &HandlerContext<'_>) -> HandlerResult<()> {
+        tokio::time::sleep_until(self.reserve()).await;
+        self.handler.handle_with_context(value, ctx).await
+    }
+
+    #[inline]
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send { self.handler.flush() }
+}
+
+type SizeFn<T> = Box<dyn Fn(&T) -> usize + Send + Sync>;
+
+/// The default number of attempts made to write a batch before it is
+/// discarded.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+struct Batch<T> {
+    values: Vec<T>,
+    bytes: usize,
+    opened: Option<Instant>,
+    failures: u32,
+}
+
+impl<T> Batch<T> {
+    fn clear(&mut self) {
+        let Self {
+            values,
+            bytes,
+            opened,
+            failures,
+        } = self;
+        values.clear();
+        *bytes = 0;
+        *opened = None;
+        *failures = 0;
+    }
+}
+
+#[derive(Debug)]
+struct BatchShared<T, H> {
+    handler: H,
+    batch: tokio::sync::Mutex<Batch<T>>,
+}
+
+impl<T, H: Handler<Vec<T>>> BatchShared<T, H> {
+    /// Pass the buffered values to the handler, keeping them buffered if it
+    /// fails, or discarding them if it has failed `max_attempts` times.
+    async fn write(&self, batch: &mut Batch<T>, max_attempts: u32) -> HandlerResult<()> {
+        if batch.values.is_empty() {
+            return Ok(());
+        }
+
+        match self.handler.handle(&batch.values).await {
+            Ok(()) => {
+                batch.clear();
+                Ok(())
+            },
+            Err(e) => {
+                batch.failures += 1;
+                if batch.failures >= max_attempts {
+                    error!(
+                        err = %Chain(&*e),
+                        values = batch.values.len(),
+                        attempts = batch.failures,
+                        "Discarding batch after repeated write failures",
+                    );
+                    batch.clear();
+                }
+
+                Err(e)
+            },
+        }
+    }
+}
+
+/// A handler collecting values into batches, passing each batch to a
+/// handler of [`Vec`]s.
+///
+/// A batch is written once it holds the maximum number of values, once it
+/// holds the maximum number of bytes if configured with
+/// [`max_bytes`](Self::max_bytes), and once its oldest value has been
+/// buffered for the maximum delay if configured with
+/// [`max_delay`](Self::max_delay).  Any partial batch is written when the
+/// runtime shuts down.
+///
+/// If writing a batch fails, the value completing it is removed and the
+/// error is returned for that value, so it can be retried or dead-lettered
+/// by its pipeline.  The rest of the batch stays buffered and is written
+/// along with the next batch.  Once writing the same buffered values has
+/// failed [`max_attempts`](Self::max_attempts) times, they are logged and
+/// discarded, so a single value the handler cannot write does not hold up
+/// every later batch.
+pub struct Batched<T, H> {
+    max_len: usize,
+    max_bytes: Option<(usize, SizeFn<T>)>,
+    max_delay: Option<Duration>,
+    max_attempts: u32,
+    shared: Arc<BatchShared<T, H>>,
+    ticker: Once,
+}
+
+impl<T, H: fmt::Debug> fmt::Debug for Batched<T, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            max_len,
+            max_bytes,
+            max_delay,
+            max_attempts,
+            shared,
+            ticker: _,
+        } = self;
+
+        f.debug_struct("Batched")
+            .field("max_len", max_len)
+            .field("max_bytes", &max_bytes.as_ref().map(|(b, _)| b))
+            .field("max_delay", max_delay)
+            .field("max_attempts", max_attempts)
+            .field("handler", &shared.handler)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, H> Batched<T, H> {
+    /// Wrap a handler, passing it batches of at most `max_len` values.
+    #[must_use]
+    pub fn new(handler: H, max_len: usize) -> Self {
+        let max_len = max_len.max(1);
+        Self {
+            max_len,
+            max_bytes: None,
+            max_delay: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            shared: Arc::new(BatchShared {
+                handler,
+                batch: tokio::sync::Mutex::new(Batch {
+                    values: Vec::with_capacity(max_len),
+                    bytes: 0,
+                    opened: None,
+                    failures: 0,
+                }),
+            }),
+            ticker: Once::new(),
+        }
+    }
+
+    /// Also write a batch once the sizes of its values, as measured by
+    /// `size_of`, add up to at least `max` bytes.
+    #[must_use]
+    pub fn max_bytes<F: Fn(&T) -> usize + Send + Sync + 'static>(
+        self,
+        max: usize,
+        size_of: F,
+    ) -> Self {
+        Self {
+            max_bytes: Some((max, Box::new(size_of))),
+            ..self
+        }
+    }
+
+    /// Also write a batch once its oldest value has been buffered for
+    /// `delay`.  Batches are checked every half `delay`, so a value may be
+    /// buffered for up to one and a half times `delay` when no other values
+    /// arrive.
+    #[must_use]
+    pub fn max_delay(self, delay: Duration) -> Self {
+        Self {
+            max_delay: Some(delay),
+            ..self
+        }
+    }
+
+    /// Discard the buffered values of a batch once writing them has failed
+    /// `max` times, rather than the default of three.
+    #[must_use]
+    pub fn max_attempts(self, max: u32) -> Self {
+        Self {
+            max_attempts: max.max(1),
+            ..self
+        }
+    }
+
+    fn is_full(&self, batch: &Batch<T>) -> bool {
+        batch.values.len() >= self.max_len
+            || self
+                .max_bytes
+                .as_ref()
+                .is_some_and(|&(max, _)| batch.bytes >= max)
+            || self
+                .max_delay
+                .zip(batch.opened)
+                .is_some_and(|(delay, opened)| opened.elapsed() >= delay)
+    }
+}
+
+impl<T, H> Batched<T, H>
+where
+    T: Send + 'static,
+    H: Handler<Vec<T>> + Send + Sync + 'static,
+{
+    /// Start the task writing batches that have reached their maximum delay,
+    /// if it has not been started yet.
+    fn start_ticker(&self) {
+        let Some(delay) = self.max_delay else {
+            return;
+        };
+
+        let max_attempts = self.max_attempts;
+        self.ticker.call_once(|| {
+            let shared = Arc::downgrade(&self.shared);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(delay / 2);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                loop {
+                    interval.tick().await;
+                    let Some(shared) = shared.upgrade() else {
+                        break;
+                    };
+
+                    let mut batch = shared.batch.lock().await;
+                    if batch.opened.is_some_and(|o| o.elapsed() >= delay) {
+                        if let Err(e) = shared.write(&mut batch, max_attempts).await {
+                            warn!(err = %Chain(&*e), "Error writing batch");
+                        }
+                    }
+                }
+            });
+        });
+    }
+
+    async fn push(&self, value: T) -> HandlerResult<()> {
+        self.start_ticker();
+
+        let size = self.max_bytes.as_ref().map_or(0, |(_, f)| f(&value));
+        let mut batch = self.shared.batch.lock().await;
+        batch.values.push(value);
+        batch.bytes += size;
+        batch.opened.get_or_insert_with(Instant::now);
+
+        if !self.is_full(&batch) {
+            return Ok(());
+        }
+
+        self.shared
+            .write(&mut batch, self.max_attempts)
+            .await
+            .inspect_err(|_| {
+                if batch.values.pop().is_some() {
+                    batch.bytes -= size;
+                }
+                if batch.values.is_empty() {
+                    batch.clear();
+                }
+            })
+    }
+}
+
+impl<T, H> Handler<T> for Batched<T, H>
+where
+    T: Clone + Send + 'static,
+    H: Handler<Vec<T>> + Send + Sync + 'static,
+{
+    fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send {
+        self.push(value.clone())
+    }
+
+    async fn flush(&self) -> HandlerResult<()> {
+        let mut batch = self.shared.batch.lock().await;
+        self.shared.write(&mut batch, self.max_attempts).await?;
+        self.shared.handler.flush().await
+    }
+}
+
+/// The error returned by a [`ChannelHandler`] whose [`ChannelStream`] has
+/// been dropped.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Channel stream was dropped")]
+pub struct ChannelClosed;
+
+/// A handler sending a copy of each value into a bounded channel, to be
+/// consumed by application code from the paired [`ChannelStream`].
+///
+/// When the channel is full the handler waits for space, holding up its
+/// pipeline until the stream catches up.
+#[derive(Debug, Clone)]
+pub struct ChannelHandler<T>(mpsc::Sender<T>);
+
+impl<T> ChannelHandler<T> {
+    /// Create a handler and the stream receiving its values, with room for
+    /// `capacity` values in between.
+    #[must_use]
+    pub fn new(capacity: usize) -> (Self, ChannelStream<T>) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (Self(tx), ChannelStream(rx))
+    }
+}
+
+impl<T: Clone + Send> Handler<T> for ChannelHandler<T> {
+    fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send {
+        let value = value.clone();
+        async move { self.0.send(value).await.map_err(|_| ChannelClosed.into()) }
+    }
+}
+
+/// A [`Stream`] of the values sent by a [`ChannelHandler`].
+#[derive(Debug)]
+pub struct ChannelStream<T>(mpsc::Receiver<T>);
+
+impl<T> ChannelStream<T> {
+    /// Get the underlying Tokio receiver.
+    #[inline]
+    #[must_use]
+    pub fn into_inner(self) -> mpsc::Receiver<T> { self.0 }
+}
+
+impl<T> Stream for ChannelStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Sink {
+        written: Mutex<Vec<Vec<usize>>>,
+        poison: Option<usize>,
+    }
+
+    impl Handler<Vec<usize>> for Sink {
+        async fn handle(&self, batch: &Vec<usize>) -> HandlerResult<()> {
+            if self.poison.is_some_and(|p| batch.contains(&p)) {
+                return Err("Poisoned batch".into());
+            }
+
+            self.written.lock().unwrap().push(batch.clone());
+            Ok(())
+        }
+    }
+
+    fn written(batched: &Batched<usize, Sink>) -> Vec<Vec<usize>> {
+        batched.shared.handler.written.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_max_len() {
+        let batched = Batched::new(Sink::default(), 3);
+
+        for v in 1..=4 {
+            batched.handle(&v).await.unwrap();
+        }
+        assert_eq!(written(&batched), vec![vec![1, 2, 3]]);
+
+        batched.flush().await.unwrap();
+        assert_eq!(written(&batched), vec![vec![1, 2, 3], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn test_max_bytes() {
+        let batched = Batched::new(Sink::default(), 100).max_bytes(10, |v| *v);
+
+        batched.handle(&4).await.unwrap();
+        batched.handle(&5).await.unwrap();
+        assert!(written(&batched).is_empty());
+
+        batched.handle(&2).await.unwrap();
+        assert_eq!(written(&batched), vec![vec![4, 5, 2]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_delay() {
+        let batched = Batched::new(Sink::default(), 100).max_delay(Duration::from_millis(100));
+
+        batched.handle(&1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(written(&batched).is_empty());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(written(&batched), vec![vec![1]]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes() {
+        let sink = Sink {
+            poison: Some(2),
+            ..Sink::default()
+        };
+        let batched = Batched::new(sink, 2).max_attempts(2);
+
+        // The value completing a failed batch is handed back, and the rest is
+        // written with the next batch
+        batched.handle(&1).await.unwrap();
+        assert!(batched.handle(&2).await.is_err());
+        batched.handle(&3).await.unwrap();
+        assert_eq!(written(&batched), vec![vec![1, 3]]);
+
+        // A buffered value that cannot be written is discarded once the batch
+        // has failed the maximum number of times
+        batched.handle(&2).await.unwrap();
+        assert!(batched.handle(&4).await.is_err());
+        assert!(batched.handle(&5).await.is_err());
+        batched.handle(&6).await.unwrap();
+        batched.handle(&7).await.unwrap();
+        assert_eq!(written(&batched), vec![vec![1, 3], vec![6, 7]]);
+    }
+}
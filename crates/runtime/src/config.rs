@@ -1,7 +1,10 @@
 //! Configuration types for the Vixen runtime.
 
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr};
+
 #[cfg(feature = "prometheus")]
 pub use prometheus_impl::*;
+use vixen_core::CommitmentLevel;
 
 /// A helper trait for types that may or may not have a default value,
 /// determined at runtime.
@@ -28,6 +31,27 @@ pub struct VixenConfig<M: clap::Args> {
     #[serde(default)]
     pub buffer: BufferConfig,
 
+    /// Configuration for loading the current state of accounts before
+    /// streaming begins.
+    #[command(flatten)]
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+
+    /// Configuration for recording received updates to disk.
+    #[command(flatten)]
+    #[serde(default)]
+    pub record: RecordConfig,
+
+    /// Configuration for saving the runtime's progress.
+    #[command(flatten)]
+    #[serde(default)]
+    pub checkpoint: CheckpointConfig,
+
+    /// Configuration for storing updates pipelines failed to handle.
+    #[command(flatten)]
+    #[serde(default)]
+    pub dead_letter: DeadLetterConfig,
+
     // TODO: this doesn't show up in clap usage correctly, not sure why
     /// Configuration for the requested metrics backend.
     #[command(flatten)]
@@ -36,7 +60,7 @@ pub struct VixenConfig<M: clap::Args> {
 }
 
 /// Yellowstone connection configuration.
-#[derive(Debug, clap::Args, serde::Deserialize)]
+#[derive(Debug, Clone, clap::Args, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct YellowstoneConfig {
     /// The endpoint of the Yellowstone server.
@@ -48,15 +72,387 @@ pub struct YellowstoneConfig {
     /// The timeout for the connection.
     #[arg(long, env)]
     pub timeout: u64,
+    /// Additional Yellowstone endpoints to use for redundancy.
+    #[arg(skip)]
+    #[serde(default)]
+    pub backup_endpoints: Vec<EndpointConfig>,
+    /// How the runtime streams from multiple endpoints, if any backup
+    /// endpoints are configured.
+    #[arg(long, env, value_enum, default_value_t)]
+    #[serde(default)]
+    pub redundancy: RedundancyMode,
+    /// The number of slots to remember updates for when de-duplicating
+    /// redundant streams.  Updates older than this are assumed to have
+    /// already been received.
+    #[arg(long, env, default_value_t = default_dedup_window())]
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+    /// The default commitment level to subscribe at, for pipelines whose
+    /// prefilters do not request one.  If unset, the server's default is
+    /// used.
+    #[arg(long, env)]
+    #[serde(default, deserialize_with = "deserialize_opt_from_str")]
+    pub commitment: Option<CommitmentLevel>,
+    /// Configuration for reconnecting after the stream drops.
+    #[command(flatten)]
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
 }
 
+impl YellowstoneConfig {
+    /// List every configured endpoint, starting with the primary endpoint.
+    #[must_use]
+    pub fn endpoints(&self) -> Vec<EndpointConfig> {
+        let Self {
+            endpoint,
+            x_token,
+            backup_endpoints,
+            ..
+        } = self;
+
+        [EndpointConfig {
+            endpoint: endpoint.clone(),
+            x_token: x_token.clone(),
+        }]
+        .into_iter()
+        .chain(backup_endpoints.iter().cloned())
+        .collect()
+    }
+}
+
+#[inline]
+fn default_dedup_window() -> u64 { 150 }
+
+fn deserialize_opt_from_str<'de, D: serde::Deserializer<'de>, T: FromStr>(
+    de: D,
+) -> Result<Option<T>, D::Error>
+where T::Err: fmt::Display {
+    use serde::Deserialize;
+
+    Option::<String>::deserialize(de)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Connection details for a single Yellowstone endpoint.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EndpointConfig {
+    /// The endpoint of the Yellowstone server.
+    pub endpoint: String,
+    /// The token to use for authentication.
+    pub x_token: Option<String>,
+}
+
+/// Strategy for streaming from multiple Yellowstone endpoints.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedundancyMode {
+    /// Stream from one endpoint at a time, moving on to the next endpoint
+    /// when the current one fails.
+    #[default]
+    Failover,
+    /// Stream from every endpoint at once, passing along whichever copy of
+    /// an update arrives first.
+    HotHot,
+}
+
+/// Yellowstone reconnection configuration.
+#[derive(Debug, Clone, Copy, clap::Args, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReconnectConfig {
+    /// The maximum number of consecutive failed reconnect attempts before the
+    /// runtime gives up.  If unset, the runtime retries indefinitely.
+    #[arg(long = "reconnect-max-attempts", env = "RECONNECT_MAX_ATTEMPTS")]
+    pub max_attempts: Option<u32>,
+    /// The delay before the first reconnect attempt, in milliseconds.
+    #[arg(
+        long = "reconnect-initial-backoff",
+        env = "RECONNECT_INITIAL_BACKOFF",
+        default_value_t = default_initial_backoff()
+    )]
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// The upper bound on the delay between reconnect attempts, in
+    /// milliseconds.
+    #[arg(
+        long = "reconnect-max-backoff",
+        env = "RECONNECT_MAX_BACKOFF",
+        default_value_t = default_max_backoff()
+    )]
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    /// The factor the delay is multiplied by after each failed attempt.
+    #[arg(
+        long = "reconnect-multiplier",
+        env = "RECONNECT_MULTIPLIER",
+        default_value_t = default_multiplier()
+    )]
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// The fraction of each delay to randomly add or subtract, between 0 and
+    /// 1.
+    #[arg(
+        long = "reconnect-jitter",
+        env = "RECONNECT_JITTER",
+        default_value_t = default_jitter()
+    )]
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+#[inline]
+fn default_initial_backoff() -> u64 { 100 }
+
+#[inline]
+fn default_max_backoff() -> u64 { 30_000 }
+
+#[inline]
+fn default_multiplier() -> f64 { 2.0 }
+
+#[inline]
+fn default_jitter() -> f64 { 0.2 }
+
 /// Job scheduler configuration.
-#[derive(Default, Debug, Clone, Copy, clap::Args, serde::Deserialize)]
+#[derive(Debug, Clone, clap::Args, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct BufferConfig {
     /// The maximum number of concurrent jobs to run.  If unset, defaults to
     /// the number of CPUs.
     pub jobs: Option<usize>,
+    /// The order jobs are run in.
+    #[arg(
+        long = "dispatch-order",
+        env = "DISPATCH_ORDER",
+        value_enum,
+        default_value_t
+    )]
+    #[serde(default)]
+    pub order: DispatchOrder,
+    /// The maximum number of jobs waiting to run.  If unset, the queue is
+    /// unbounded.
+    #[arg(long = "buffer-capacity", env = "BUFFER_CAPACITY")]
+    pub capacity: Option<usize>,
+    /// What to do with new updates when the queue is full.
+    #[arg(
+        long = "buffer-overflow",
+        env = "BUFFER_OVERFLOW",
+        value_enum,
+        default_value_t
+    )]
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// The priority of each pipeline, by ID, used by the
+    /// [`Priority`](OverflowPolicy::Priority) overflow policy.  Pipelines not
+    /// listed have a priority of zero.
+    #[arg(skip)]
+    #[serde(default)]
+    pub priorities: HashMap<String, i32>,
+    /// How long to wait on shutdown, in seconds, for queued and running jobs
+    /// to complete and for handlers to flush.  Jobs still outstanding when
+    /// the deadline passes are abandoned.
+    #[arg(long = "drain-timeout", env = "DRAIN_TIMEOUT", default_value_t = default_drain_timeout())]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            jobs: None,
+            order: DispatchOrder::default(),
+            capacity: None,
+            overflow: OverflowPolicy::default(),
+            priorities: HashMap::new(),
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+#[inline]
+fn default_drain_timeout() -> u64 { 30 }
+
+/// Policy for handling new updates when the job queue is full.
+///
+/// Updates dropped by any policy other than [`Block`](Self::Block) are lost.
+/// Each drop is counted in the dropped updates metric, and the processed slot
+/// saved to checkpoints stays before the earliest dropped update for as long
+/// as the runtime runs, so a restart resuming from the checkpoint requests
+/// the dropped updates again if the server still retains their slot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Stop reading from the update source until there is room, applying
+    /// backpressure to the Yellowstone stream.
+    #[default]
+    Block,
+    /// Drop the oldest queued job to make room for the new one.
+    DropOldest,
+    /// Drop the new update.
+    DropNewest,
+    /// Drop the oldest of the lowest-priority queued jobs if it has a lower
+    /// priority than the new update, or the new update otherwise.  An
+    /// update's priority is the highest priority of the pipelines it is
+    /// routed to.
+    Priority,
+}
+
+/// Ordering guarantees for the jobs run by the runtime.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DispatchOrder {
+    /// Run jobs as soon as a worker is free, in any order.
+    #[default]
+    Unordered,
+    /// Run jobs sharing a key one at a time, in the order they were received,
+    /// while jobs with different keys still run concurrently.  Account
+    /// updates are keyed by account and transaction updates by fee payer,
+    /// unless a key function is given to
+    /// [`Builder::dispatch_key`](crate::builder::Builder::dispatch_key).
+    /// Account writes older than the latest write received for the same
+    /// account are dropped.
+    Keyed,
+}
+
+/// Startup account snapshot configuration.
+///
+/// If a source is configured, every account matching the prefilter of an
+/// account pipeline is loaded and run through that pipeline with
+/// `is_startup` set.  Updates streamed while the snapshot loads are held in
+/// memory until every snapshot update has been dispatched, and account
+/// writes are dispatched in order per account even without keyed dispatch
+/// ordering.  If both an RPC endpoint and a file are given, the RPC endpoint
+/// is used.
+#[derive(Debug, Clone, clap::Args, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SnapshotConfig {
+    /// A Solana JSON-RPC endpoint to load accounts from with
+    /// `getProgramAccounts` and `getMultipleAccounts`.  Requires the
+    /// `rpc-snapshot` feature.
+    #[arg(long = "snapshot-rpc-url", env = "SNAPSHOT_RPC_URL")]
+    pub rpc_url: Option<String>,
+    /// A recording of account updates to load accounts from, in the format
+    /// written by the `record` configuration section.
+    #[arg(long = "snapshot-file", env = "SNAPSHOT_FILE")]
+    pub file: Option<PathBuf>,
+    /// The timeout for each JSON-RPC request, in seconds.
+    #[arg(
+        long = "snapshot-timeout",
+        env = "SNAPSHOT_TIMEOUT",
+        default_value_t = default_snapshot_timeout()
+    )]
+    #[serde(default = "default_snapshot_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: None,
+            file: None,
+            timeout: default_snapshot_timeout(),
+        }
+    }
+}
+
+#[inline]
+fn default_snapshot_timeout() -> u64 { 120 }
+
+/// Update recording configuration.
+#[derive(Default, Debug, Clone, clap::Args, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecordConfig {
+    /// A file to record every update received to, for later replay with a
+    /// [`FileSource`](crate::source::FileSource).  If unset, updates are not
+    /// recorded.
+    #[arg(long = "record-path", env = "RECORD_PATH")]
+    pub path: Option<PathBuf>,
+    /// Whether to zstd-compress the recording.  Requires the `zstd` feature.
+    #[arg(long = "record-compress", env = "RECORD_COMPRESS")]
+    #[serde(default)]
+    pub compress: bool,
+    /// Whether to write a plain stream of length-delimited updates, omitting
+    /// the timing needed to replay the recording with its original pacing.
+    #[arg(long = "record-plain", env = "RECORD_PLAIN")]
+    #[serde(default)]
+    pub plain: bool,
+}
+
+/// Dead letter configuration.
+#[derive(Default, Debug, Clone, clap::Args, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeadLetterConfig {
+    /// A file to append updates pipelines failed to handle to, for later
+    /// replay with a [`DeadLetterSource`](crate::dead_letter::DeadLetterSource).
+    /// If unset, failed updates are only logged unless a sink is provided
+    /// through
+    /// [`RuntimeBuilder::dead_letters`](crate::builder::RuntimeBuilder::dead_letters).
+    #[arg(long = "dead-letter-path", env = "DEAD_LETTER_PATH")]
+    pub path: Option<PathBuf>,
+}
+
+/// Progress checkpointing configuration.
+#[derive(Debug, Clone, clap::Args, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CheckpointConfig {
+    /// The file to save checkpoints to.  If unset, progress is not saved
+    /// unless a store is provided through
+    /// [`RuntimeBuilder::checkpoint`](crate::builder::RuntimeBuilder::checkpoint).
+    #[arg(long = "checkpoint-path", env = "CHECKPOINT_PATH")]
+    pub path: Option<PathBuf>,
+    /// The format of the checkpoint file.
+    #[arg(
+        long = "checkpoint-backend",
+        env = "CHECKPOINT_BACKEND",
+        value_enum,
+        default_value_t
+    )]
+    #[serde(default)]
+    pub backend: CheckpointBackend,
+    /// How often to save a checkpoint, in milliseconds.
+    #[arg(
+        long = "checkpoint-interval",
+        env = "CHECKPOINT_INTERVAL",
+        default_value_t = default_checkpoint_interval()
+    )]
+    #[serde(default = "default_checkpoint_interval")]
+    pub interval: u64,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            backend: CheckpointBackend::default(),
+            interval: default_checkpoint_interval(),
+        }
+    }
+}
+
+#[inline]
+fn default_checkpoint_interval() -> u64 { 1000 }
+
+/// Storage format for checkpoints saved by the runtime.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckpointBackend {
+    /// A JSON file, replaced atomically on each save.
+    #[default]
+    File,
+    /// A `SQLite` database.  Requires the `sqlite` feature.
+    Sqlite,
 }
 
 /// Helper type for blank configuration sections.
@@ -0,0 +1,336 @@
+//! Capturing updates that pipelines failed to handle, so they can be replayed
+//! once the failure has been fixed.
+//!
+//! When a pipeline returns an error for an update, after any retries allowed
+//! by its [`RetryPolicy`](crate::retry::RetryPolicy), the update is passed to
+//! the configured [`DeadLetterSink`] along with the ID of the pipeline and a
+//! description of each error.  The update's filters are replaced with the ID
+//! of the failed pipeline, so replaying it with
+//! [`Runtime::try_replay_dead_letters`](crate::Runtime::try_replay_dead_letters)
+//! runs it through that pipeline alone.  The Prometheus example exposes this
+//! as its `replay-dead-letters` subcommand.
+//!
+//! When a sub-pipeline of an
+//! [`InstructionPipeline`](crate::instruction::InstructionPipeline) fails,
+//! one dead letter is stored per failed instruction, recording the ID of the
+//! sub-pipeline and the path of the instruction.  Its filter is then the ID
+//! of the instruction pipeline, the instruction path, and the ID of the
+//! sub-pipeline, separated by `#`, so replaying it passes only that
+//! instruction to only that sub-pipeline.
+//!
+//! A dead letter file is a sequence of frames, each holding a
+//! length-delimited protobuf [`SubscribeUpdate`], followed by the number of
+//! errors as a protobuf varint, followed by each error as a length-delimited
+//! UTF-8 string.
+
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tracing::warn;
+use vixen_core::instruction::InstructionPath;
+use yellowstone_grpc_proto::{
+    geyser::{subscribe_update::UpdateOneof, SubscribeUpdate},
+    prost::{encoding::encode_varint, Message},
+};
+
+use crate::{
+    config::DeadLetterConfig,
+    source::{
+        file::{read_bytes, read_delimited, read_varint, Resumable},
+        FileError, Source,
+    },
+    Chain,
+};
+
+/// An error that can occur while storing a dead letter.
+#[derive(Debug, thiserror::Error)]
+pub enum DeadLetterError {
+    /// A system I/O error.
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    /// An error returned by a custom sink.
+    #[error("Error storing dead letter")]
+    Other(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+/// An instruction a sub-pipeline of an
+/// [`InstructionPipeline`](crate::instruction::InstructionPipeline) failed
+/// to handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedInstruction {
+    /// The ID of the sub-pipeline that failed.
+    pub pipeline: String,
+    /// The position of the instruction in its transaction.
+    pub path: InstructionPath,
+}
+
+/// An update a pipeline failed to handle.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    /// The ID of the pipeline that failed.
+    pub pipeline: String,
+    /// The instruction that failed, if the pipeline is an
+    /// [`InstructionPipeline`](crate::instruction::InstructionPipeline).
+    pub instruction: Option<FailedInstruction>,
+    /// The update, with its filters set to route it to the failed pipeline.
+    pub update: SubscribeUpdate,
+    /// A description of each error returned, including its chain of causes.
+    pub errors: Vec<String>,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(
+        pipeline: &str,
+        instruction: Option<FailedInstruction>,
+        update: UpdateOneof,
+        errors: Vec<String>,
+    ) -> Self {
+        Self {
+            pipeline: pipeline.to_owned(),
+            update: SubscribeUpdate {
+                filters: vec![filter_name(pipeline, instruction.as_ref())],
+                update_oneof: Some(update),
+                created_at: None,
+            },
+            instruction,
+            errors,
+        }
+    }
+}
+
+const FILTER_SEPARATOR: char = '#';
+
+/// The filter name routing a dead letter to the pipeline that failed on it.
+fn filter_name(pipeline: &str, instruction: Option<&FailedInstruction>) -> String {
+    match instruction {
+        Some(FailedInstruction {
+            pipeline: sub,
+            path,
+        }) => format!("{pipeline}{FILTER_SEPARATOR}{path}{FILTER_SEPARATOR}{sub}"),
+        None => pipeline.to_owned(),
+    }
+}
+
+/// Split the filter name of a dead letter into the ID of the pipeline it is
+/// routed to and the instruction that failed, if any.
+pub(crate) fn split_filter(filter: &str) -> (&str, Option<FailedInstruction>) {
+    let mut parts = filter.splitn(3, FILTER_SEPARATOR);
+    let pipeline = parts.next().unwrap_or_default();
+    let instruction = parts.next().zip(parts.next()).and_then(|(path, sub)| {
+        let path = path
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()
+            .ok()?;
+
+        Some(FailedInstruction {
+            pipeline: sub.to_owned(),
+            path: path.into(),
+        })
+    });
+
+    (pipeline, instruction)
+}
+
+/// A destination for updates pipelines failed to handle.
+///
+/// Methods are called from a blocking thread, so implementations may perform
+/// synchronous I/O.
+pub trait DeadLetterSink: fmt::Debug + Send + Sync + 'static {
+    /// Store a dead letter.
+    ///
+    /// # Errors
+    /// Returns an error if the dead letter could not be stored.
+    fn send(&self, letter: &DeadLetter) -> Result<(), DeadLetterError>;
+}
+
+/// Open the sink described by a configuration section, if one is configured.
+pub(crate) fn open(config: &DeadLetterConfig) -> Option<Arc<dyn DeadLetterSink>> {
+    let DeadLetterConfig { path } = config;
+    path.as_ref()
+        .map(|p| Arc::new(FileDeadLetters::new(p)) as Arc<dyn DeadLetterSink>)
+}
+
+/// Store a dead letter, logging any error.
+pub(crate) async fn send(sink: &Arc<dyn DeadLetterSink>, letter: DeadLetter) {
+    let sink = Arc::clone(sink);
+    let res = tokio::task::spawn_blocking(move || sink.send(&letter))
+        .await
+        .map_err(|e| DeadLetterError::Io(e.into()))
+        .and_then(std::convert::identity);
+
+    if let Err(e) = res {
+        warn!(err = %Chain(&e), "Error storing dead letter, update lost");
+    }
+}
+
+/// A [`DeadLetterSink`] appending to a file, which can be replayed with a
+/// [`DeadLetterSource`].
+#[derive(Debug)]
+pub struct FileDeadLetters {
+    path: PathBuf,
+    file: Mutex<Option<std::fs::File>>,
+}
+
+impl FileDeadLetters {
+    /// Create a sink appending to the given file.  The file is not created
+    /// until the first dead letter is stored.
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+}
+
+impl DeadLetterSink for FileDeadLetters {
+    fn send(&self, letter: &DeadLetter) -> Result<(), DeadLetterError> {
+        let DeadLetter {
+            pipeline: _,
+            instruction: _,
+            update,
+            errors,
+        } = letter;
+
+        let mut buf = update.encode_length_delimited_to_vec();
+        encode_varint(errors.len() as u64, &mut buf);
+        for err in errors {
+            encode_varint(err.len() as u64, &mut buf);
+            buf.extend_from_slice(err.as_bytes());
+        }
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let file = match &mut *file {
+            Some(f) => f,
+            f @ None => f.insert(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+
+        // Each frame is written at once so a crash cannot interleave or split
+        // frames
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// A [`Source`] replaying the updates in a file written by
+/// [`FileDeadLetters`].
+///
+/// Only the dead letters present when the file is opened are replayed, so
+/// the file may also be the runtime's configured dead-letter sink: updates
+/// failing again are appended to it without being replayed a second time.
+pub struct DeadLetterSource {
+    path: PathBuf,
+    reader: Resumable<LetterReader, Result<Option<DeadLetter>, FileError>>,
+}
+
+type LetterReader = std::pin::Pin<Box<dyn AsyncRead>>;
+
+impl fmt::Debug for DeadLetterSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadLetterSource")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeadLetterSource {
+    /// Open a dead letter file for replay.
+    ///
+    /// # Errors
+    /// Returns an error if the file could not be opened.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, FileError> {
+        let path = path.as_ref().to_owned();
+        let file = tokio::fs::File::open(&path).await?;
+        let len = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            reader: Resumable::new(Box::pin(BufReader::new(file.take(len)))),
+        })
+    }
+
+    /// Read the next dead letter, or `None` if the file has been exhausted.
+    ///
+    /// # Errors
+    /// Returns an error if the file could not be read or is malformed.
+    ///
+    /// This method is cancel-safe.
+    pub async fn next_letter(&mut self) -> Result<Option<DeadLetter>, FileError> {
+        self.reader
+            .read(|mut r| async move {
+                let l = read_letter(&mut r).await;
+                (r, l)
+            })
+            .await
+    }
+}
+
+async fn read_letter(reader: &mut LetterReader) -> Result<Option<DeadLetter>, FileError> {
+    let Some(len) = read_varint(reader).await? else {
+        return Ok(None);
+    };
+
+    let update = SubscribeUpdate::decode(&*read_bytes(reader, len).await?)?;
+
+    let count = read_varint(reader).await?.ok_or(FileError::Truncated)?;
+    let mut errors = vec![];
+    for _ in 0..count {
+        errors.push(String::from_utf8_lossy(&read_delimited(reader).await?).into_owned());
+    }
+
+    let (pipeline, instruction) = update
+        .filters
+        .first()
+        .map_or(("", None), |f| split_filter(f));
+    let pipeline = pipeline.to_owned();
+
+    Ok(Some(DeadLetter {
+        pipeline,
+        instruction,
+        update,
+        errors,
+    }))
+}
+
+impl Source for DeadLetterSource {
+    async fn next(&mut self) -> Option<Result<SubscribeUpdate, crate::Error>> {
+        self.next_letter()
+            .await
+            .transpose()
+            .map(|r| r.map(|l| l.update).map_err(Into::into))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_filters() {
+        let instruction = FailedInstruction {
+            pipeline: "token#parser".to_owned(),
+            path: vec![2, 0, 1].into(),
+        };
+        let filter = filter_name("InstructionPipeline", Some(&instruction));
+        assert_eq!(filter, "InstructionPipeline#2.0.1#token#parser");
+        assert_eq!(
+            split_filter(&filter),
+            ("InstructionPipeline", Some(instruction))
+        );
+
+        assert_eq!(split_filter("pipeline"), ("pipeline", None));
+        assert_eq!(filter_name("pipeline", None), "pipeline");
+    }
+}
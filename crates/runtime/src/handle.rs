@@ -0,0 +1,581 @@
+//! A handle for changing the subscriptions of a running
+//! [`Runtime`](crate::Runtime).
+
+use tokio::sync::{mpsc, oneshot};
+use vixen_core::{
+    AccountUpdate, BlockMetaUpdate, BlockUpdate, EntryUpdate, Prefilter, PrefilterBuilder,
+    PrefilterError, SlotUpdate, TransactionStatusUpdate, TransactionUpdate,
+};
+
+use crate::{
+    checkpoint::{lock_cursors, SharedCursors},
+    handler::{BoxPipeline, DynPipeline, PipelineSets},
+};
+
+/// An error returned when a change requested through a [`RuntimeHandle`]
+/// could not be applied.
+#[derive(Debug, thiserror::Error)]
+pub enum HandleError {
+    /// The runtime is not running, or has already stopped.
+    #[error("The runtime is not running")]
+    Stopped,
+    /// No pipeline with the given ID is registered.
+    #[error("No pipeline registered with ID {0:?}")]
+    MissingPipeline(String),
+    /// A pipeline with the given ID is already registered.
+    #[error("A pipeline with ID {0:?} is already registered")]
+    PipelineCollision(String),
+    /// The requested prefilter change was invalid.
+    #[error("Invalid prefilter change")]
+    Prefilter(#[from] PrefilterError),
+    /// The updated subscription could not be sent to the update source.
+    #[error("Error updating subscription")]
+    Subscribe(#[source] crate::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind {
+    Account,
+    Transaction,
+    Slot,
+    Block,
+    BlockMeta,
+    TransactionStatus,
+    Entry,
+}
+
+#[derive(Debug)]
+pub(crate) enum Change {
+    AddKeys(Kind, String, Prefilter),
+    RemoveKeys(Kind, String, Prefilter),
+    AddAccountPipeline(BoxPipeline<'static, AccountUpdate>),
+    AddTransactionPipeline(BoxPipeline<'static, TransactionUpdate>),
+    AddSlotPipeline(BoxPipeline<'static, SlotUpdate>),
+    AddBlockPipeline(BoxPipeline<'static, BlockUpdate>),
+    AddBlockMetaPipeline(BoxPipeline<'static, BlockMetaUpdate>),
+    AddTransactionStatusPipeline(BoxPipeline<'static, TransactionStatusUpdate>),
+    AddEntryPipeline(BoxPipeline<'static, EntryUpdate>),
+    RemovePipeline(Kind, String),
+}
+
+fn prefilter_mut(
+    sets: &mut PipelineSets,
+    kind: Kind,
+    id: String,
+) -> Result<&mut Prefilter, HandleError> {
+    match kind {
+        Kind::Account => sets.account.prefilter_mut(&id),
+        Kind::Transaction => sets.transaction.prefilter_mut(&id),
+        Kind::Slot => sets.slot.prefilter_mut(&id),
+        Kind::Block => sets.block.prefilter_mut(&id),
+        Kind::BlockMeta => sets.block_meta.prefilter_mut(&id),
+        Kind::TransactionStatus => sets.transaction_status.prefilter_mut(&id),
+        Kind::Entry => sets.entry.prefilter_mut(&id),
+    }
+    .ok_or(HandleError::MissingPipeline(id))
+}
+
+impl Change {
+    /// Apply this change to a set of pipelines, returning whether the change
+    /// added to the set of updates being streamed.
+    pub(crate) fn apply(self, sets: &mut PipelineSets) -> Result<bool, HandleError> {
+        match self {
+            Self::AddKeys(kind, id, keys) => {
+                prefilter_mut(sets, kind, id)?.extend(keys);
+                Ok(true)
+            },
+            Self::RemoveKeys(kind, id, keys) => {
+                prefilter_mut(sets, kind, id)?.subtract(&keys);
+                Ok(false)
+            },
+            Self::AddAccountPipeline(p) => sets
+                .account
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddTransactionPipeline(p) => sets
+                .transaction
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddSlotPipeline(p) => sets
+                .slot
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddBlockPipeline(p) => sets
+                .block
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddBlockMetaPipeline(p) => sets
+                .block_meta
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddTransactionStatusPipeline(p) => sets
+                .transaction_status
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::AddEntryPipeline(p) => sets
+                .entry
+                .insert(p)
+                .map(|()| true)
+                .map_err(HandleError::PipelineCollision),
+            Self::RemovePipeline(kind, id) => {
+                let removed = match kind {
+                    Kind::Account => sets.account.remove(&id),
+                    Kind::Transaction => sets.transaction.remove(&id),
+                    Kind::Slot => sets.slot.remove(&id),
+                    Kind::Block => sets.block.remove(&id),
+                    Kind::BlockMeta => sets.block_meta.remove(&id),
+                    Kind::TransactionStatus => sets.transaction_status.remove(&id),
+                    Kind::Entry => sets.entry.remove(&id),
+                };
+
+                if removed {
+                    Ok(false)
+                } else {
+                    Err(HandleError::MissingPipeline(id))
+                }
+            },
+        }
+    }
+}
+
+pub(crate) type Reply = oneshot::Sender<Result<(), HandleError>>;
+pub(crate) type CommandRx = mpsc::UnboundedReceiver<(Change, Reply)>;
+
+/// A cloneable handle for changing the pipelines and prefilters of a running
+/// [`Runtime`](crate::Runtime) without reconnecting to Yellowstone.
+///
+/// Changes are applied in the order they are requested.  Each change sends an
+/// updated subscription request over the existing Yellowstone streams, and
+/// the set of pipelines updates are routed to is swapped atomically with it.
+///
+/// The handle also holds the cursors saved with each checkpoint, which
+/// handlers can use to record their own progress.
+#[derive(Debug, Clone)]
+pub struct RuntimeHandle(mpsc::UnboundedSender<(Change, Reply)>, SharedCursors);
+
+pub(crate) fn channel() -> (RuntimeHandle, CommandRx) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (RuntimeHandle(tx, SharedCursors::default()), rx)
+}
+
+impl RuntimeHandle {
+    #[inline]
+    pub(crate) fn cursors(&self) -> &SharedCursors { &self.1 }
+
+    /// Set a named cursor to be saved with the next checkpoint.
+    ///
+    /// Cursors are saved together with the slot the runtime has fully
+    /// processed, so a handler that sets its cursor once it has committed its
+    /// own output can use it to skip work it already did after a restart.
+    pub fn set_cursor(&self, name: impl Into<String>, cursor: impl Into<String>) {
+        lock_cursors(&self.1).insert(name.into(), cursor.into());
+    }
+
+    /// Get the current value of a named cursor, including one restored from
+    /// the checkpoint the runtime resumed from.
+    #[must_use]
+    pub fn cursor(&self, name: &str) -> Option<String> { lock_cursors(&self.1).get(name).cloned() }
+
+    /// Remove a named cursor, so it is no longer saved with checkpoints.
+    pub fn clear_cursor(&self, name: &str) { lock_cursors(&self.1).remove(name); }
+
+    async fn send(&self, change: Change) -> Result<(), HandleError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send((change, tx))
+            .map_err(|_| HandleError::Stopped)?;
+        rx.await.map_err(|_| HandleError::Stopped)?
+    }
+
+    async fn add_keys(
+        &self,
+        kind: Kind,
+        pipeline: impl Into<String>,
+        keys: Result<Prefilter, PrefilterError>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddKeys(kind, pipeline.into(), keys?))
+            .await
+    }
+
+    async fn remove_keys(
+        &self,
+        kind: Kind,
+        pipeline: impl Into<String>,
+        keys: Result<Prefilter, PrefilterError>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemoveKeys(kind, pipeline.into(), keys?))
+            .await
+    }
+
+    /// Add accounts to the prefilter of the account pipeline with the given
+    /// ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn add_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default().accounts(accounts).build();
+        self.add_keys(Kind::Account, pipeline, keys).await
+    }
+
+    /// Remove accounts from the prefilter of the account pipeline with the
+    /// given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn remove_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default().accounts(accounts).build();
+        self.remove_keys(Kind::Account, pipeline, keys).await
+    }
+
+    /// Add account owners to the prefilter of the account pipeline with the
+    /// given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn add_account_owners<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        owners: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default().account_owners(owners).build();
+        self.add_keys(Kind::Account, pipeline, keys).await
+    }
+
+    /// Remove account owners from the prefilter of the account pipeline with
+    /// the given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn remove_account_owners<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        owners: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default().account_owners(owners).build();
+        self.remove_keys(Kind::Account, pipeline, keys).await
+    }
+
+    /// Add accounts to the transaction prefilter of the transaction pipeline
+    /// with the given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn add_transaction_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default()
+            .transaction_accounts(accounts)
+            .build();
+        self.add_keys(Kind::Transaction, pipeline, keys).await
+    }
+
+    /// Remove accounts from the transaction prefilter of the transaction
+    /// pipeline with the given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn remove_transaction_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default()
+            .transaction_accounts(accounts)
+            .build();
+        self.remove_keys(Kind::Transaction, pipeline, keys).await
+    }
+
+    /// Add accounts to the prefilter of the transaction status pipeline with
+    /// the given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn add_transaction_status_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default()
+            .transaction_accounts(accounts)
+            .transaction_statuses()
+            .build();
+        self.add_keys(Kind::TransactionStatus, pipeline, keys).await
+    }
+
+    /// Remove accounts from the prefilter of the transaction status pipeline
+    /// with the given ID.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, any of the keys are invalid, or the subscription could not be
+    /// updated.
+    pub async fn remove_transaction_status_accounts<I: IntoIterator>(
+        &self,
+        pipeline: impl Into<String>,
+        accounts: I,
+    ) -> Result<(), HandleError>
+    where
+        I::Item: AsRef<[u8]>,
+    {
+        let keys = PrefilterBuilder::default()
+            .transaction_accounts(accounts)
+            .transaction_statuses()
+            .build();
+        self.remove_keys(Kind::TransactionStatus, pipeline, keys)
+            .await
+    }
+
+    /// Register a new account pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_account_pipeline<A: DynPipeline<AccountUpdate> + Send + Sync + 'static>(
+        &self,
+        account: A,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddAccountPipeline(Box::new(account)))
+            .await
+    }
+
+    /// Register a new transaction pipeline with the running runtime.
+    ///
+    /// **NOTE:** Instruction pipelines cannot be registered at runtime, as
+    /// they are bundled together when the runtime is built.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_transaction_pipeline<
+        T: DynPipeline<TransactionUpdate> + Send + Sync + 'static,
+    >(
+        &self,
+        transaction: T,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddTransactionPipeline(Box::new(transaction)))
+            .await
+    }
+
+    /// Register a new slot pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_slot_pipeline<S: DynPipeline<SlotUpdate> + Send + Sync + 'static>(
+        &self,
+        slot: S,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddSlotPipeline(Box::new(slot))).await
+    }
+
+    /// Register a new block pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_block_pipeline<B: DynPipeline<BlockUpdate> + Send + Sync + 'static>(
+        &self,
+        block: B,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddBlockPipeline(Box::new(block))).await
+    }
+
+    /// Register a new block metadata pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_block_meta_pipeline<
+        B: DynPipeline<BlockMetaUpdate> + Send + Sync + 'static,
+    >(
+        &self,
+        block_meta: B,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddBlockMetaPipeline(Box::new(block_meta)))
+            .await
+    }
+
+    /// Register a new transaction status pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_transaction_status_pipeline<
+        T: DynPipeline<TransactionStatusUpdate> + Send + Sync + 'static,
+    >(
+        &self,
+        transaction_status: T,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddTransactionStatusPipeline(Box::new(
+            transaction_status,
+        )))
+        .await
+    }
+
+    /// Register a new entry pipeline with the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, a pipeline with the
+    /// same ID is already registered, or the subscription could not be
+    /// updated.
+    pub async fn add_entry_pipeline<E: DynPipeline<EntryUpdate> + Send + Sync + 'static>(
+        &self,
+        entry: E,
+    ) -> Result<(), HandleError> {
+        self.send(Change::AddEntryPipeline(Box::new(entry))).await
+    }
+
+    /// Drop the account pipeline with the given ID from the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_account_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::Account, pipeline.into()))
+            .await
+    }
+
+    /// Drop the transaction pipeline with the given ID from the running
+    /// runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_transaction_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::Transaction, pipeline.into()))
+            .await
+    }
+
+    /// Drop the slot pipeline with the given ID from the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_slot_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::Slot, pipeline.into()))
+            .await
+    }
+
+    /// Drop the block pipeline with the given ID from the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_block_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::Block, pipeline.into()))
+            .await
+    }
+
+    /// Drop the block metadata pipeline with the given ID from the running
+    /// runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_block_meta_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::BlockMeta, pipeline.into()))
+            .await
+    }
+
+    /// Drop the transaction status pipeline with the given ID from the
+    /// running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_transaction_status_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(
+            Kind::TransactionStatus,
+            pipeline.into(),
+        ))
+        .await
+    }
+
+    /// Drop the entry pipeline with the given ID from the running runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime is not running, no such pipeline
+    /// exists, or the subscription could not be updated.
+    pub async fn remove_entry_pipeline(
+        &self,
+        pipeline: impl Into<String>,
+    ) -> Result<(), HandleError> {
+        self.send(Change::RemovePipeline(Kind::Entry, pipeline.into()))
+            .await
+    }
+}
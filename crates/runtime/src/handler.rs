@@ -1,24 +1,99 @@
 //! Helper types for bundling [Vixen parsers](crate::vixen_core::Parser) and
 //! handler callbacks.
 
-use std::{borrow::Cow, collections::HashMap, pin::Pin};
+use std::{borrow::Cow, collections::HashMap, pin::Pin, sync::Arc, time::SystemTime};
 
 use futures_util::{Future, FutureExt, StreamExt};
 use smallvec::SmallVec;
 use tracing::{warn, Instrument, Span};
-use vixen_core::{AccountUpdate, GetPrefilter, ParserId, TransactionUpdate};
-use yellowstone_vixen_core::{Filters, ParseError, Parser, Prefilter};
+use vixen_core::{
+    AccountUpdate, BlockMetaUpdate, BlockUpdate, CommitmentLevel, DataSlices, EntryUpdate,
+    GetPrefilter, ParserId, Pubkey, SlotUpdate, TransactionStatusUpdate, TransactionUpdate,
+};
+use yellowstone_vixen_core::{Filters, ParseError, Parser, Prefilter, ProgramParser};
 
-use crate::metrics::{Counters, Instrumenter, JobResult, Update};
+use crate::{
+    dead_letter::{self, DeadLetter, DeadLetterSink, FailedInstruction},
+    metrics::{Counters, Instrumenter, JobResult, Update},
+    retry::RetryPolicy,
+};
 
-type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
+pub(crate) type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
 /// The result returned by a handler.
 pub type HandlerResult<T> = Result<T, BoxedError>;
 
+/// Metadata about the update a value was parsed from.
+///
+/// Fields that do not apply to the type of update received are `None`.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct HandlerContext<'a> {
+    /// The name of the filter the update matched, which is the ID of the
+    /// pipeline it was routed to.
+    pub filter: &'a str,
+    /// The slot the update was produced in.
+    pub slot: Option<u64>,
+    /// The signature of the transaction the update was produced by.
+    pub signature: Option<&'a [u8]>,
+    /// The pubkey of the updated account.
+    pub pubkey: Option<Pubkey>,
+    /// The write version of the updated account, which orders writes to an
+    /// account within a slot.
+    pub write_version: Option<u64>,
+    /// The time the runtime received the update from its source.
+    pub received_at: SystemTime,
+    /// The commitment level requested by the pipeline's prefilter, or `None`
+    /// if it requested none and receives updates at the commitment level
+    /// configured for the runtime.
+    pub commitment: Option<CommitmentLevel>,
+    // The failed instruction a replayed dead letter is retried for, picked up
+    // by instruction pipelines
+    pub(crate) replay: Option<&'a FailedInstruction>,
+}
+
+impl<'a> HandlerContext<'a> {
+    /// Create a context holding only the filter name and receive time.
+    #[must_use]
+    pub fn new(filter: &'a str, received_at: SystemTime) -> Self {
+        Self {
+            filter,
+            slot: None,
+            signature: None,
+            pubkey: None,
+            write_version: None,
+            received_at,
+            commitment: None,
+            replay: None,
+        }
+    }
+}
+
 /// A handler callback for a parsed value.
 pub trait Handler<T> {
     /// Consume the parsed value.
     fn handle(&self, value: &T) -> impl Future<Output = HandlerResult<()>> + Send;
+
+    /// Consume the parsed value along with metadata about the update it was
+    /// parsed from.
+    ///
+    /// The runtime calls this method rather than [`handle`](Self::handle), so
+    /// handlers needing the context should override it.  The default
+    /// implementation ignores the context and calls `handle`.
+    fn handle_with_context(
+        &self,
+        value: &T,
+        ctx: &HandlerContext<'_>,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        let _ = ctx;
+        self.handle(value)
+    }
+
+    /// Write out any values the handler has buffered.
+    ///
+    /// Called once when the runtime shuts down, after every queued job has
+    /// completed or the drain deadline has passed.  The default
+    /// implementation does nothing.
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send { async { Ok(()) } }
 }
 
 impl<T: Handler<U>, U> Handler<U> for &T {
@@ -26,6 +101,20 @@ impl<T: Handler<U>, U> Handler<U> for &T {
     fn handle(&self, value: &U) -> impl Future<Output = HandlerResult<()>> + Send {
         <T as Handler<U>>::handle(self, value)
     }
+
+    #[inline]
+    fn handle_with_context(
+        &self,
+        value: &U,
+        ctx: &HandlerContext<'_>,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        <T as Handler<U>>::handle_with_context(self, value, ctx)
+    }
+
+    #[inline]
+    fn flush(&self) -> impl Future<Output = HandlerResult<()>> + Send {
+        <T as Handler<U>>::flush(self)
+    }
 }
 
 pub(crate) use pipeline_error::Errors as PipelineErrors;
@@ -34,6 +123,7 @@ mod pipeline_error {
     use smallvec::SmallVec;
 
     use super::BoxedError;
+    use crate::dead_letter::FailedInstruction;
 
     #[derive(Debug, Clone, Copy)]
     #[must_use]
@@ -49,6 +139,9 @@ mod pipeline_error {
         Parse(BoxedError),
         Handlers(SmallVec<[BoxedError; 1]>),
         AlreadyHandled(Handled),
+        // Errors already logged by the sub-pipelines of an instruction
+        // pipeline, with a description of each error per failed instruction
+        Instructions(Vec<(FailedInstruction, Vec<String>)>),
     }
 
     impl Errors {
@@ -60,16 +153,34 @@ mod pipeline_error {
 
         pub fn handle<T>(self, handler: &str) -> Handled {
             for e in self {
-                tracing::error!(
-                    err = %crate::Chain(&e),
-                    handler,
-                    r#type = std::any::type_name::<T>(),
-                    "Handler failed",
-                );
+                log::<T>(&e, handler);
             }
 
             Handled(())
         }
+
+        /// Log every error like [`handle`](Self::handle), returning a
+        /// description of each one.
+        pub fn handle_described<T>(self, handler: &str) -> (Handled, Vec<String>) {
+            let descs = self
+                .into_iter()
+                .map(|e| {
+                    log::<T>(&e, handler);
+                    crate::Chain(&e).to_string()
+                })
+                .collect();
+
+            (Handled(()), descs)
+        }
+    }
+
+    fn log<T>(e: &Error, handler: &str) {
+        tracing::error!(
+            err = %crate::Chain(e),
+            handler,
+            r#type = std::any::type_name::<T>(),
+            "Handler failed",
+        );
     }
 
     impl IntoIterator for Errors {
@@ -80,7 +191,9 @@ mod pipeline_error {
             match self {
                 Errors::Parse(e) => IntoIter::Parse([e].into_iter()),
                 Errors::Handlers(v) => IntoIter::Handlers(v.into_iter()),
-                Errors::AlreadyHandled(Handled(())) => IntoIter::AlreadyHandled,
+                Errors::AlreadyHandled(Handled(())) | Errors::Instructions(_) => {
+                    IntoIter::AlreadyHandled
+                },
             }
         }
     }
@@ -115,18 +228,44 @@ mod pipeline_error {
 
 /// A parser and a set of handlers its output is passed to.
 #[derive(Debug)]
-pub struct Pipeline<P, H>(P, H);
+pub struct Pipeline<P, H>(P, H, RetryPolicy, Option<Pubkey>);
 
 impl<P, H> Pipeline<P, H> {
     /// Create a new pipeline from a parser and a list of handlers.
     #[inline]
     #[must_use]
-    pub fn new(parser: P, handlers: H) -> Self { Self(parser, handlers) }
+    pub fn new(parser: P, handlers: H) -> Self {
+        Self(parser, handlers, RetryPolicy::default(), None)
+    }
+
+    /// Retry handlers that fail on a parsed value according to the given
+    /// policy.  By default failed values are not retried.
+    #[inline]
+    #[must_use]
+    pub fn retry(self, policy: RetryPolicy) -> Self {
+        let Self(parser, handlers, _, program) = self;
+        Self(parser, handlers, policy, program)
+    }
+}
+
+impl<P: ProgramParser, H> Pipeline<P, H> {
+    /// Create a new pipeline from a program parser and a list of handlers.
+    ///
+    /// When registered as an instruction pipeline, the pipeline is only
+    /// passed instructions for the parser's
+    /// [`program_id`](ProgramParser::program_id), rather than every
+    /// instruction in each transaction.
+    #[inline]
+    #[must_use]
+    pub fn for_program(parser: P, handlers: H) -> Self {
+        let program = parser.program_id();
+        Self(parser, handlers, RetryPolicy::default(), Some(program))
+    }
 }
 
 impl<P: ParserId, H> ParserId for Pipeline<P, H> {
     #[inline]
-    fn id(&self) -> Cow<str> { self.0.id() }
+    fn id(&self) -> Cow<'_, str> { self.0.id() }
 }
 
 impl<P: GetPrefilter, H> GetPrefilter for Pipeline<P, H> {
@@ -142,8 +281,17 @@ where
     P: Parser,
     for<'i> <&'i I as IntoIterator>::Item: Handler<P::Output>,
 {
-    async fn handle(&self, value: &P::Input) -> Result<(), PipelineErrors> {
-        let parsed = match self.0.parse(value).await {
+    async fn handle(
+        &self,
+        value: &P::Input,
+        slices: Option<&DataSlices>,
+        ctx: Option<&HandlerContext<'_>>,
+    ) -> Result<(), PipelineErrors> {
+        let parsed = match slices {
+            Some(s) => self.0.parse_sliced(value, s).await,
+            None => self.0.parse(value).await,
+        };
+        let parsed = match parsed {
             Ok(p) => p,
             Err(ParseError::Filtered) => return Ok(()),
             Err(ParseError::Other(e)) => return Err(PipelineErrors::Parse(e)),
@@ -152,7 +300,51 @@ where
 
         let errs = (&self.1)
             .into_iter()
-            .map(|h| async move { h.handle(parsed).await })
+            .map(|h| async move {
+                let mut retry = self.2.start();
+                loop {
+                    let res = match ctx {
+                        Some(c) => h.handle_with_context(parsed, c).await,
+                        None => h.handle(parsed).await,
+                    };
+                    match res {
+                        Ok(()) => break Ok(()),
+                        Err(e) => {
+                            if let Err(e) = retry.failed(e).await {
+                                break Err(e);
+                            }
+                        },
+                    }
+                }
+            })
+            .collect::<futures_util::stream::FuturesUnordered<_>>()
+            .filter_map(|r| async move { r.err() })
+            .collect::<SmallVec<[_; 1]>>()
+            .await;
+
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(PipelineErrors::Handlers(errs))
+        }
+    }
+
+    async fn flush(&self) -> Result<(), PipelineErrors> {
+        let errs = (&self.1)
+            .into_iter()
+            .map(|h| async move {
+                let mut retry = self.2.start();
+                loop {
+                    match h.flush().await {
+                        Ok(()) => break Ok(()),
+                        Err(e) => {
+                            if let Err(e) = retry.failed(e).await {
+                                break Err(e);
+                            }
+                        },
+                    }
+                }
+            })
             .collect::<futures_util::stream::FuturesUnordered<_>>()
             .filter_map(|r| async move { r.err() })
             .collect::<SmallVec<[_; 1]>>()
@@ -174,6 +366,55 @@ pub trait DynPipeline<T>: std::fmt::Debug + ParserId + GetPrefilter {
         &'h self,
         value: &'h T,
     ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>>;
+
+    /// Pass the provided value, whose account data has been reduced to the
+    /// given slices, to the parser and handlers comprising this pipeline.
+    ///
+    /// The default implementation ignores the slices and calls
+    /// [`handle`](Self::handle).
+    fn handle_sliced<'h>(
+        &'h self,
+        value: &'h T,
+        slices: &'h DataSlices,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        let _ = slices;
+        self.handle(value)
+    }
+
+    /// Pass the provided value, along with metadata about the update it was
+    /// received in, to the parser and handlers comprising this pipeline.
+    ///
+    /// The default implementation ignores the context and calls
+    /// [`handle_sliced`](Self::handle_sliced) or [`handle`](Self::handle).
+    fn handle_with_context<'h>(
+        &'h self,
+        value: &'h T,
+        slices: Option<&'h DataSlices>,
+        ctx: &'h HandlerContext<'h>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        let _ = ctx;
+        match slices {
+            Some(s) => self.handle_sliced(value, s),
+            None => self.handle(value),
+        }
+    }
+
+    /// Flush the handlers comprising this pipeline before the runtime shuts
+    /// down.
+    ///
+    /// The default implementation does nothing.
+    fn flush<'h>(
+        &'h self,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// The program whose instructions this pipeline parses, if it only
+    /// parses instructions for a single program.  Instruction pipelines
+    /// returning `None` are passed every instruction.
+    ///
+    /// The default implementation returns `None`.
+    fn program_id(&self) -> Option<Pubkey> { None }
 }
 
 impl<T> DynPipeline<T> for std::convert::Infallible {
@@ -197,20 +438,46 @@ where
         &'h self,
         value: &'h P::Input,
     ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
-        Box::pin(Pipeline::handle(self, value))
+        Box::pin(Pipeline::handle(self, value, None, None))
     }
+
+    fn handle_sliced<'h>(
+        &'h self,
+        value: &'h P::Input,
+        slices: &'h DataSlices,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        Box::pin(Pipeline::handle(self, value, Some(slices), None))
+    }
+
+    fn handle_with_context<'h>(
+        &'h self,
+        value: &'h P::Input,
+        slices: Option<&'h DataSlices>,
+        ctx: &'h HandlerContext<'h>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        Box::pin(Pipeline::handle(self, value, slices, Some(ctx)))
+    }
+
+    fn flush<'h>(
+        &'h self,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        Box::pin(Pipeline::flush(self))
+    }
+
+    #[inline]
+    fn program_id(&self) -> Option<Pubkey> { self.3 }
 }
 
-impl<'h, T> ParserId for BoxPipeline<'h, T> {
-    fn id(&self) -> Cow<str> { <dyn DynPipeline<T>>::id(&**self) }
+impl<T> ParserId for BoxPipeline<'_, T> {
+    fn id(&self) -> Cow<'_, str> { <dyn DynPipeline<T>>::id(&**self) }
 }
 
-impl<'h, T> GetPrefilter for BoxPipeline<'h, T> {
+impl<T> GetPrefilter for BoxPipeline<'_, T> {
     #[inline]
     fn prefilter(&self) -> Prefilter { <dyn DynPipeline<T>>::prefilter(&**self) }
 }
 
-impl<'j, T> DynPipeline<T> for BoxPipeline<'j, T> {
+impl<T> DynPipeline<T> for BoxPipeline<'_, T> {
     #[inline]
     fn handle<'h>(
         &'h self,
@@ -218,49 +485,163 @@ impl<'j, T> DynPipeline<T> for BoxPipeline<'j, T> {
     ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
         <dyn DynPipeline<T>>::handle(&**self, value)
     }
+
+    #[inline]
+    fn handle_sliced<'h>(
+        &'h self,
+        value: &'h T,
+        slices: &'h DataSlices,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        <dyn DynPipeline<T>>::handle_sliced(&**self, value, slices)
+    }
+
+    #[inline]
+    fn handle_with_context<'h>(
+        &'h self,
+        value: &'h T,
+        slices: Option<&'h DataSlices>,
+        ctx: &'h HandlerContext<'h>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        <dyn DynPipeline<T>>::handle_with_context(&**self, value, slices, ctx)
+    }
+
+    #[inline]
+    fn flush<'h>(
+        &'h self,
+    ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+        <dyn DynPipeline<T>>::flush(&**self)
+    }
+
+    #[inline]
+    fn program_id(&self) -> Option<Pubkey> { <dyn DynPipeline<T>>::program_id(&**self) }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct PipelineSets {
     pub account: PipelineSet<BoxPipeline<'static, AccountUpdate>>,
     pub transaction: PipelineSet<BoxPipeline<'static, TransactionUpdate>>,
+    pub slot: PipelineSet<BoxPipeline<'static, SlotUpdate>>,
+    pub block: PipelineSet<BoxPipeline<'static, BlockUpdate>>,
+    pub block_meta: PipelineSet<BoxPipeline<'static, BlockMetaUpdate>>,
+    pub transaction_status: PipelineSet<BoxPipeline<'static, TransactionStatusUpdate>>,
+    pub entry: PipelineSet<BoxPipeline<'static, EntryUpdate>>,
 }
 
 impl PipelineSets {
     #[must_use]
-    pub fn filters(&self) -> Filters {
+    pub fn filters(&self) -> Filters<'_> {
         Filters::new(
             self.account
                 .filters()
                 .chain(self.transaction.filters())
+                .chain(self.slot.filters())
+                .chain(self.block.filters())
+                .chain(self.block_meta.filters())
+                .chain(self.transaction_status.filters())
+                .chain(self.entry.filters())
                 .collect(),
         )
     }
+
+    /// Flush every pipeline in the set, logging any errors returned.
+    pub async fn flush(&self) {
+        let Self {
+            account,
+            transaction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+        } = self;
+
+        futures_util::join!(
+            account.flush::<AccountUpdate>(),
+            transaction.flush::<TransactionUpdate>(),
+            slot.flush::<SlotUpdate>(),
+            block.flush::<BlockUpdate>(),
+            block_meta.flush::<BlockMetaUpdate>(),
+            transaction_status.flush::<TransactionStatusUpdate>(),
+            entry.flush::<EntryUpdate>(),
+        );
+    }
 }
 
+// Pipelines are reference-counted so a set can be cheaply copied, modified,
+// and swapped in while the runtime is running.  Each pipeline's prefilter is
+// stored alongside it so it can be modified independently of the parser.
 #[derive(Debug)]
-pub(crate) struct PipelineSet<P>(HashMap<String, P>);
+pub(crate) struct PipelineSet<P>(HashMap<String, (Arc<P>, Prefilter)>);
+
+impl<P> Clone for PipelineSet<P> {
+    fn clone(&self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .map(|(k, (p, f))| (k.clone(), (Arc::clone(p), f.clone())))
+                .collect(),
+        )
+    }
+}
 
 impl<P> PipelineSet<P> {
     #[inline]
     #[must_use]
     pub fn len(&self) -> usize { self.0.len() }
-}
 
-impl<P: GetPrefilter> PipelineSet<P> {
     #[inline]
-    fn filters(&self) -> impl Iterator<Item = (&str, Prefilter)> {
-        self.0.iter().map(|(k, v)| (&**k, v.prefilter()))
+    pub fn filters(&self) -> impl Iterator<Item = (&str, Prefilter)> {
+        self.0.iter().map(|(k, (_, f))| (&**k, f.clone()))
+    }
+
+    #[inline]
+    pub fn prefilter_mut(&mut self, id: &str) -> Option<&mut Prefilter> {
+        self.0.get_mut(id).map(|(_, f)| f)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, id: &str) -> bool { self.0.remove(id).is_some() }
+
+    pub(crate) fn get_handlers<I>(&self, it: I) -> Pipelines<'_, P, I> { Pipelines(self, it) }
+
+    async fn flush<T>(&self)
+    where P: DynPipeline<T> {
+        futures_util::future::join_all(self.0.iter().map(|(id, (p, _))| {
+            <P as DynPipeline<T>>::flush(p).map(move |r| {
+                for e in r.err().into_iter().flatten() {
+                    warn!(err = %crate::Chain(&e), pipeline = id.as_str(), "Error flushing pipeline");
+                }
+            })
+        }))
+        .await;
     }
 }
 
-impl<P> PipelineSet<P> {
-    pub(crate) fn get_handlers<I>(&self, it: I) -> Pipelines<P, I> { Pipelines(self, it) }
+impl<P: ParserId + GetPrefilter> PipelineSet<P> {
+    /// Add a pipeline to the set, returning its ID as an error if a pipeline
+    /// with the same ID is already registered.
+    pub fn insert(&mut self, pipeline: P) -> Result<(), String> {
+        let id = pipeline.id().into_owned();
+        if self.0.contains_key(&id) {
+            return Err(id);
+        }
+
+        let prefilter = pipeline.prefilter();
+        self.0.insert(id, (Arc::new(pipeline), prefilter));
+        Ok(())
+    }
 }
 
-impl<P: ParserId> FromIterator<P> for PipelineSet<P> {
+impl<P: ParserId + GetPrefilter> FromIterator<P> for PipelineSet<P> {
     fn from_iter<I: IntoIterator<Item = P>>(iter: I) -> Self {
-        Self(iter.into_iter().map(|i| (i.id().into_owned(), i)).collect())
+        Self(
+            iter.into_iter()
+                .map(|i| {
+                    let prefilter = i.prefilter();
+                    (i.id().into_owned(), (Arc::new(i), prefilter))
+                })
+                .collect(),
+        )
     }
 }
 
@@ -270,44 +651,385 @@ pub(crate) struct Pipelines<'m, H, I>(&'m PipelineSet<H>, I);
 impl<'m, H, I: IntoIterator> Pipelines<'m, H, I>
 where I::Item: AsRef<str> + Send + 'm
 {
-    fn get_pipelines(self) -> impl Iterator<Item = (I::Item, &'m H)> {
+    fn get_pipelines(
+        self,
+    ) -> impl Iterator<
+        Item = (
+            I::Item,
+            &'m str,
+            &'m H,
+            &'m Prefilter,
+            Option<FailedInstruction>,
+        ),
+    > {
         let Self(pipelines, it) = self;
         it.into_iter().filter_map(|f| {
             let filter = f.as_ref();
-            let pipeline = pipelines.0.get(filter);
+            // Replayed dead letters may name an instruction after the pipeline,
+            // but pipeline IDs may themselves contain the separator
+            let pipeline = pipelines
+                .0
+                .get_key_value(filter)
+                .map(|p| (p, None))
+                .or_else(|| {
+                    let (id, instruction) = dead_letter::split_filter(filter);
+                    let instruction = instruction?;
+                    pipelines
+                        .0
+                        .get_key_value(id)
+                        .map(|p| (p, Some(instruction)))
+                });
 
             if pipeline.is_none() {
                 warn!(filter, "No pipeline matched filter on incoming update");
             }
 
-            pipeline.map(|p| (f, p))
+            pipeline.map(|((id, (p, prefilter)), replay)| (f, id.as_str(), &**p, prefilter, replay))
         })
     }
 
-    pub fn run<'h, T: Update, M: Instrumenter>(
+    pub fn run<'h, T: Update + Sync, M: Instrumenter>(
         self,
         span: Span,
         value: &'h T,
         metrics: &'h Counters<M>,
+        dead_letters: Option<&'h Arc<dyn DeadLetterSink>>,
+        received_at: SystemTime,
     ) -> impl Future<Output = ()> + Send + 'h
     where
-        H: DynPipeline<T>,
+        H: DynPipeline<T> + Sync,
         'm: 'h,
     {
         let _span = span.entered();
-        futures_util::future::join_all(self.get_pipelines().map(move |(f, h)| {
-            h.handle(value)
-                .map(move |r| {
+        futures_util::future::join_all(self.get_pipelines().map(
+            move |(_, pipeline, h, prefilter, replay)| {
+                async move {
+                    // Handlers see the pipeline ID rather than the filter of a
+                    // replayed dead letter, which also names the instruction
+                    let mut ctx = value.context(pipeline, received_at);
+                    ctx.commitment = prefilter.commitment();
+                    ctx.replay = replay.as_ref();
+                    let r = h
+                        .handle_with_context(value, T::data_slices(prefilter), &ctx)
+                        .await;
+
                     if let Some(r) = JobResult::from_pipeline(&r) {
                         metrics.inc_processed(T::TYPE, r);
                     }
-                    match r {
-                        Ok(()) => (),
-                        Err(v) => v.handle::<T>(f.as_ref()).as_unit(),
+                    let Err(v) = r else { return };
+
+                    // Updates still failing after any retries are sent to the
+                    // dead letter sink, if there is one
+                    match (dead_letters, v) {
+                        (Some(sink), PipelineErrors::Instructions(failed)) => {
+                            for (instruction, errors) in failed {
+                                let letter = DeadLetter::new(
+                                    pipeline,
+                                    Some(instruction),
+                                    value.to_oneof(),
+                                    errors,
+                                );
+                                dead_letter::send(sink, letter).await;
+                            }
+                        },
+                        (Some(sink), v) => {
+                            let (handled, errors) = v.handle_described::<T>(pipeline);
+                            handled.as_unit();
+                            let letter = DeadLetter::new(pipeline, None, value.to_oneof(), errors);
+                            dead_letter::send(sink, letter).await;
+                        },
+                        (None, v) => v.handle::<T>(pipeline).as_unit(),
                     }
-                })
+                }
                 .in_current_span()
-        }))
+            },
+        ))
         .map(move |v| v.into_iter().collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    };
+
+    use yellowstone_grpc_proto::geyser::{
+        SubscribeUpdateAccountInfo, SubscribeUpdateTransactionInfo,
+    };
+
+    use super::*;
+    use crate::metrics::NullMetrics;
+
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    impl ParserId for Named {
+        fn id(&self) -> Cow<'_, str> { self.0.into() }
+    }
+
+    impl GetPrefilter for Named {
+        fn prefilter(&self) -> Prefilter { Prefilter::default() }
+    }
+
+    #[test]
+    fn test_get_pipelines() {
+        let set: PipelineSet<_> = [Named("token#parser"), Named("InstructionPipeline")]
+            .into_iter()
+            .collect();
+        let filters = [
+            "token#parser",
+            "InstructionPipeline#0.1#token#parser",
+            "token#0.1",
+            "missing",
+        ];
+
+        // Pipeline IDs containing the separator match exactly, while replayed
+        // dead letters match the pipeline named before the instruction
+        let ids: Vec<_> = set
+            .get_handlers(filters)
+            .get_pipelines()
+            .map(|(f, id, _, _, replay)| (f, id, replay.map(|r| r.path.to_string())))
+            .collect();
+        assert_eq!(ids, [
+            ("token#parser", "token#parser", None),
+            (
+                "InstructionPipeline#0.1#token#parser",
+                "InstructionPipeline",
+                Some("0.1".to_owned())
+            )
+        ]);
+    }
+
+    #[derive(Debug)]
+    struct Unit;
+
+    impl Parser for Unit {
+        type Input = SlotUpdate;
+        type Output = ();
+
+        fn id(&self) -> Cow<'_, str> { "Unit".into() }
+
+        fn prefilter(&self) -> Prefilter { Prefilter::default() }
+
+        async fn parse(&self, _: &SlotUpdate) -> yellowstone_vixen_core::ParseResult<()> { Ok(()) }
+    }
+
+    /// A handler whose flushes fail the given number of times before
+    /// succeeding.
+    #[derive(Debug)]
+    struct Flaky {
+        failures: u32,
+        attempts: AtomicU32,
+    }
+
+    impl Flaky {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures,
+                attempts: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl Handler<()> for Flaky {
+        async fn handle(&self, (): &()) -> HandlerResult<()> { Ok(()) }
+
+        async fn flush(&self) -> HandlerResult<()> {
+            if self.attempts.fetch_add(1, Ordering::Relaxed) < self.failures {
+                Err("flush failed".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush() {
+        let pipeline = Pipeline::new(Unit, vec![Flaky::new(0), Flaky::new(1), Flaky::new(5)])
+            .retry(RetryPolicy::new(2));
+
+        // Every handler is flushed, with failed flushes retried like failed
+        // values
+        let res = DynPipeline::<SlotUpdate>::flush(&pipeline).await;
+        let attempts: Vec<_> = pipeline
+            .1
+            .iter()
+            .map(|h| h.attempts.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(attempts, [1, 2, 2]);
+        assert!(matches!(res, Err(PipelineErrors::Handlers(e)) if e.len() == 1));
+    }
+
+    /// The context fields a handler was passed.
+    #[derive(Debug, PartialEq)]
+    struct Seen {
+        filter: String,
+        slot: Option<u64>,
+        signature: Option<Vec<u8>>,
+        pubkey: Option<Pubkey>,
+        write_version: Option<u64>,
+        commitment: Option<CommitmentLevel>,
+    }
+
+    impl Seen {
+        fn at(slot: u64) -> Self {
+            Self {
+                filter: "ctx".to_owned(),
+                slot: Some(slot),
+                signature: None,
+                pubkey: None,
+                write_version: None,
+                commitment: Some(CommitmentLevel::Confirmed),
+            }
+        }
+    }
+
+    /// A pipeline recording the context of each update it is passed.
+    #[derive(Debug)]
+    struct Context(Option<CommitmentLevel>, Arc<Mutex<Vec<Seen>>>);
+
+    impl ParserId for Context {
+        fn id(&self) -> Cow<'_, str> { "ctx".into() }
+    }
+
+    impl GetPrefilter for Context {
+        fn prefilter(&self) -> Prefilter {
+            let builder = Prefilter::builder();
+            match self.0 {
+                Some(c) => builder.commitment(c),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        }
+    }
+
+    impl<T> DynPipeline<T> for Context {
+        fn handle<'h>(
+            &'h self,
+            _: &'h T,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+            unreachable!("The runtime always passes a context")
+        }
+
+        fn handle_with_context<'h>(
+            &'h self,
+            _: &'h T,
+            _: Option<&'h DataSlices>,
+            ctx: &'h HandlerContext<'h>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+            self.1.lock().unwrap().push(Seen {
+                filter: ctx.filter.to_owned(),
+                slot: ctx.slot,
+                signature: ctx.signature.map(ToOwned::to_owned),
+                pubkey: ctx.pubkey,
+                write_version: ctx.write_version,
+                commitment: ctx.commitment,
+            });
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    async fn seen<T: Update + Sync + 'static>(
+        value: &T,
+        filter: &str,
+        commitment: Option<CommitmentLevel>,
+    ) -> Seen {
+        let seen = Arc::default();
+        let set: PipelineSet<BoxPipeline<'static, T>> =
+            std::iter::once(Box::new(Context(commitment, Arc::clone(&seen))) as BoxPipeline<'_, T>)
+                .collect();
+
+        set.get_handlers([filter])
+            .run(
+                Span::none(),
+                value,
+                &Counters::new(&NullMetrics, 1),
+                None,
+                SystemTime::now(),
+            )
+            .await;
+
+        let mut seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        seen.pop().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_context() {
+        let confirmed = Some(CommitmentLevel::Confirmed);
+
+        let account = AccountUpdate {
+            slot: 1,
+            account: Some(SubscribeUpdateAccountInfo {
+                pubkey: vec![1; 32],
+                write_version: 7,
+                txn_signature: Some(vec![2; 64]),
+                ..SubscribeUpdateAccountInfo::default()
+            }),
+            ..AccountUpdate::default()
+        };
+        assert_eq!(seen(&account, "ctx", confirmed).await, Seen {
+            signature: Some(vec![2; 64]),
+            pubkey: Some(Pubkey::new([1; 32])),
+            write_version: Some(7),
+            ..Seen::at(1)
+        });
+
+        let transaction = TransactionUpdate {
+            slot: 2,
+            transaction: Some(SubscribeUpdateTransactionInfo {
+                signature: vec![3; 64],
+                ..SubscribeUpdateTransactionInfo::default()
+            }),
+        };
+        assert_eq!(seen(&transaction, "ctx", confirmed).await, Seen {
+            signature: Some(vec![3; 64]),
+            ..Seen::at(2)
+        });
+
+        let status = TransactionStatusUpdate {
+            slot: 3,
+            signature: vec![4; 64],
+            ..TransactionStatusUpdate::default()
+        };
+        assert_eq!(seen(&status, "ctx", confirmed).await, Seen {
+            signature: Some(vec![4; 64]),
+            ..Seen::at(3)
+        });
+
+        let slot = SlotUpdate {
+            slot: 4,
+            ..SlotUpdate::default()
+        };
+        assert_eq!(seen(&slot, "ctx", confirmed).await, Seen::at(4));
+        let block = BlockUpdate {
+            slot: 5,
+            ..BlockUpdate::default()
+        };
+        assert_eq!(seen(&block, "ctx", confirmed).await, Seen::at(5));
+        let block_meta = BlockMetaUpdate {
+            slot: 6,
+            ..BlockMetaUpdate::default()
+        };
+        assert_eq!(seen(&block_meta, "ctx", confirmed).await, Seen::at(6));
+        let entry = EntryUpdate {
+            slot: 7,
+            ..EntryUpdate::default()
+        };
+        assert_eq!(seen(&entry, "ctx", confirmed).await, Seen::at(7));
+
+        // Pipelines without a commitment level of their own are not given one
+        assert_eq!(seen(&slot, "ctx", None).await, Seen {
+            commitment: None,
+            ..Seen::at(4)
+        });
+        // Replayed dead letters are passed the pipeline ID as their filter
+        assert_eq!(
+            seen(&transaction, "ctx#0.1#sub", confirmed).await,
+            seen(&transaction, "ctx", confirmed).await
+        );
+    }
+}
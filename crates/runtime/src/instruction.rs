@@ -1,27 +1,52 @@
 //! Helper types for parsing and dispatching instructions from transaction
 //! updates.
 
-use std::fmt;
+use std::{collections::HashMap, fmt, sync::Arc};
 
-use vixen_core::{instruction::InstructionUpdate, GetPrefilter, ParserId, TransactionUpdate};
+use vixen_core::{
+    instruction::InstructionUpdate, CommitmentLevel, DataSlices, GetPrefilter, ParserId, Pubkey,
+    TransactionUpdate,
+};
 
 use crate::{
-    handler::{BoxPipeline, DynPipeline, PipelineErrors},
+    dead_letter::FailedInstruction,
+    handler::{BoxPipeline, DynPipeline, HandlerContext, PipelineErrors},
     metrics::{InstructionCounters, Instrumenter, JobResult},
 };
 
 /// A pipeline for dispatching instruction updates given a transaction update.
-pub struct InstructionPipeline<M: Instrumenter>(
-    Box<[BoxPipeline<'static, InstructionUpdate>]>,
-    InstructionCounters<M>,
-);
+///
+/// Sub-pipelines reporting a [`program_id`](DynPipeline::program_id) are only
+/// passed the instructions for that program, and all other sub-pipelines are
+/// passed every instruction.  Each sub-pipeline receives its instructions in
+/// order, while separate sub-pipelines run concurrently.
+///
+/// Instructions a sub-pipeline fails to handle are reported individually, so
+/// each is dead-lettered with the ID of the sub-pipeline and its path, and
+/// replaying it passes only that instruction to only that sub-pipeline.
+pub struct InstructionPipeline<M: Instrumenter> {
+    pipelines: Box<[BoxPipeline<'static, InstructionUpdate>]>,
+    routes: HashMap<Pubkey, Box<[usize]>>,
+    catch_all: Box<[usize]>,
+    counters: Arc<InstructionCounters<M>>,
+    commitment: Option<CommitmentLevel>,
+}
 
 impl<M: Instrumenter> fmt::Debug for InstructionPipeline<M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("InstructionPipeline")
-            .field(&self.0)
-            .field(&self.1)
-            .finish()
+        let Self {
+            pipelines,
+            routes: _,
+            catch_all: _,
+            counters,
+            commitment,
+        } = self;
+
+        f.debug_struct("InstructionPipeline")
+            .field("pipelines", pipelines)
+            .field("counters", counters)
+            .field("commitment", commitment)
+            .finish_non_exhaustive()
     }
 }
 
@@ -31,15 +56,70 @@ impl<M: Instrumenter> InstructionPipeline<M> {
     pub fn new(
         pipelines: Vec<BoxPipeline<'static, InstructionUpdate>>,
         instrumenter: &M,
+    ) -> Option<Self> {
+        Self::with_counters(
+            pipelines,
+            Arc::new(InstructionCounters::new(instrumenter)),
+            None,
+        )
+    }
+
+    /// Create one instruction pipeline per commitment level requested by the
+    /// given sub-pipelines, as each commitment level is streamed over a
+    /// separate subscription.
+    pub(crate) fn split_by_commitment(
+        pipelines: Vec<BoxPipeline<'static, InstructionUpdate>>,
+        instrumenter: &M,
+    ) -> Vec<Self> {
+        if pipelines.is_empty() {
+            return vec![];
+        }
+
+        let counters = Arc::new(InstructionCounters::new(instrumenter));
+        let mut groups = std::collections::BTreeMap::<_, Vec<_>>::new();
+        for pipe in pipelines {
+            groups
+                .entry(pipe.prefilter().commitment())
+                .or_default()
+                .push(pipe);
+        }
+
+        groups
+            .into_iter()
+            .filter_map(|(commitment, pipes)| {
+                Self::with_counters(pipes, Arc::clone(&counters), commitment)
+            })
+            .collect()
+    }
+
+    fn with_counters(
+        pipelines: Vec<BoxPipeline<'static, InstructionUpdate>>,
+        counters: Arc<InstructionCounters<M>>,
+        commitment: Option<CommitmentLevel>,
     ) -> Option<Self> {
         if pipelines.is_empty() {
             return None;
         }
 
-        Some(Self(
-            pipelines.into_boxed_slice(),
-            InstructionCounters::new(instrumenter),
-        ))
+        let mut routes = HashMap::<_, Vec<_>>::new();
+        let mut catch_all = vec![];
+        for (i, pipe) in pipelines.iter().enumerate() {
+            match pipe.program_id() {
+                Some(p) => routes.entry(p).or_default().push(i),
+                None => catch_all.push(i),
+            }
+        }
+
+        Some(Self {
+            pipelines: pipelines.into_boxed_slice(),
+            routes: routes
+                .into_iter()
+                .map(|(p, v)| (p, v.into_boxed_slice()))
+                .collect(),
+            catch_all: catch_all.into_boxed_slice(),
+            counters,
+            commitment,
+        })
     }
 
     /// Handle a transaction update by dispatching its instruction updates to
@@ -48,25 +128,119 @@ impl<M: Instrumenter> InstructionPipeline<M> {
     /// # Errors
     /// Returns an error if any of the sub-pipelines return an error.
     pub async fn handle(&self, txn: &TransactionUpdate) -> Result<(), PipelineErrors> {
-        let mut err = None;
+        self.handle_impl(txn, None).await
+    }
+
+    async fn handle_impl(
+        &self,
+        txn: &TransactionUpdate,
+        ctx: Option<&HandlerContext<'_>>,
+    ) -> Result<(), PipelineErrors> {
+        let Self {
+            pipelines,
+            routes,
+            catch_all,
+            counters,
+            commitment: _,
+        } = self;
+
         let ixs = InstructionUpdate::parse_from_txn(txn).map_err(PipelineErrors::parse)?;
-        // TODO: how should sub-pipeline delegation be handled for instruction trees?
-        for insn in ixs.iter().flat_map(|i| i.visit_all()) {
-            for pipe in &*self.0 {
-                // TODO: run these concurrently?
-                let res = pipe.handle(insn).await;
-                if let Some(r) = JobResult::from_pipeline(&res) {
-                    self.1.inc_processed(r);
-                }
-                match res {
-                    Ok(()) => (),
-                    Err(PipelineErrors::AlreadyHandled(h)) => h.as_unit(),
-                    Err(e) => err = Some(e.handle::<InstructionUpdate>(&pipe.id())),
+
+        // A replayed dead letter names the one instruction and sub-pipeline
+        // that failed on it
+        let (filter, target) = ctx.map_or(("", None), |c| (c.filter, c.replay));
+
+        let mut routed = vec![vec![]; pipelines.len()];
+        for insn in ixs.iter().flat_map(InstructionUpdate::visit_all) {
+            if target.is_some_and(|t| t.path != insn.path) {
+                continue;
+            }
+
+            let route = routes.get(&insn.program).map_or(&[][..], |r| &**r);
+            for &i in route.iter().chain(&**catch_all) {
+                if target.is_some_and(|t| pipelines[i].id() != t.pipeline) {
+                    continue;
                 }
+
+                routed[i].push(insn);
             }
         }
 
-        if let Some(h) = err {
+        let failed = futures_util::future::join_all(
+            pipelines
+                .iter()
+                .zip(routed)
+                .filter(|(_, insns)| !insns.is_empty())
+                .map(|(pipe, insns)| async move {
+                    let mut failed = vec![];
+                    for insn in insns {
+                        let res = match ctx {
+                            Some(c) => {
+                                // Instructions carry the slot and signature of
+                                // their transaction
+                                let c = HandlerContext {
+                                    slot: Some(insn.shared.slot),
+                                    signature: Some(&insn.shared.signature),
+                                    commitment: c.commitment,
+                                    ..HandlerContext::new(filter, c.received_at)
+                                };
+                                pipe.handle_with_context(insn, None, &c).await
+                            },
+                            None => pipe.handle(insn).await,
+                        };
+                        if let Some(r) = JobResult::from_pipeline(&res) {
+                            counters.inc_processed(r);
+                        }
+                        match res {
+                            Ok(()) => (),
+                            Err(PipelineErrors::AlreadyHandled(h)) => h.as_unit(),
+                            Err(PipelineErrors::Instructions(f)) => failed.extend(f),
+                            Err(e) => {
+                                let id = pipe.id();
+                                let (handled, errors) =
+                                    e.handle_described::<InstructionUpdate>(&id);
+                                handled.as_unit();
+                                failed.push((
+                                    FailedInstruction {
+                                        pipeline: id.into_owned(),
+                                        path: insn.path.clone(),
+                                    },
+                                    errors,
+                                ));
+                            },
+                        }
+                    }
+                    failed
+                }),
+        )
+        .await;
+
+        let failed: Vec<_> = failed.into_iter().flatten().collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(PipelineErrors::Instructions(failed))
+        }
+    }
+
+    /// Flush every sub-pipeline concurrently.
+    ///
+    /// # Errors
+    /// Returns an error if any of the sub-pipelines return an error.
+    pub async fn flush(&self) -> Result<(), PipelineErrors> {
+        let errs = futures_util::future::join_all(self.pipelines.iter().map(|pipe| async move {
+            match pipe.flush().await {
+                Ok(()) | Err(PipelineErrors::Instructions(_)) => None,
+                Err(PipelineErrors::AlreadyHandled(h)) => {
+                    h.as_unit();
+                    None
+                },
+                Err(e) => Some(e.handle::<InstructionUpdate>(&pipe.id())),
+            }
+        }))
+        .await;
+
+        if let Some(h) = errs.into_iter().flatten().last() {
             Err(PipelineErrors::AlreadyHandled(h))
         } else {
             Ok(())
@@ -75,14 +249,17 @@ impl<M: Instrumenter> InstructionPipeline<M> {
 }
 
 impl<M: Instrumenter> ParserId for InstructionPipeline<M> {
-    fn id(&self) -> std::borrow::Cow<str> {
-        "InstructionPipeline".into()
+    fn id(&self) -> std::borrow::Cow<'_, str> {
+        match self.commitment {
+            Some(c) => format!("InstructionPipeline({c})").into(),
+            None => "InstructionPipeline".into(),
+        }
     }
 }
 
 impl<M: Instrumenter> GetPrefilter for InstructionPipeline<M> {
     fn prefilter(&self) -> vixen_core::Prefilter {
-        self.0.iter().map(GetPrefilter::prefilter).collect()
+        self.pipelines.iter().map(GetPrefilter::prefilter).collect()
     }
 }
 
@@ -94,4 +271,266 @@ impl<M: Instrumenter> DynPipeline<TransactionUpdate> for InstructionPipeline<M>
     {
         Box::pin(InstructionPipeline::handle(self, value))
     }
+
+    fn handle_with_context<'h>(
+        &'h self,
+        value: &'h TransactionUpdate,
+        slices: Option<&'h DataSlices>,
+        ctx: &'h HandlerContext<'h>,
+    ) -> std::pin::Pin<Box<dyn futures_util::Future<Output = Result<(), PipelineErrors>> + Send + 'h>>
+    {
+        // Transaction updates are never sliced
+        let _ = slices;
+        Box::pin(self.handle_impl(value, Some(ctx)))
+    }
+
+    fn flush<'h>(
+        &'h self,
+    ) -> std::pin::Pin<Box<dyn futures_util::Future<Output = Result<(), PipelineErrors>> + Send + 'h>>
+    {
+        Box::pin(InstructionPipeline::flush(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, future::Future, pin::Pin, sync::Mutex, time::SystemTime};
+
+    use smallvec::smallvec;
+    use vixen_core::Prefilter;
+    use yellowstone_grpc_proto::{
+        geyser::{subscribe_update::UpdateOneof, SubscribeUpdateTransactionInfo},
+        solana::storage::confirmed_block::{
+            CompiledInstruction, InnerInstruction, InnerInstructions, Message, Transaction,
+            TransactionStatusMeta,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        dead_letter::{self, DeadLetter},
+        metrics::NullMetrics,
+    };
+
+    const PROGRAMS: [[u8; 32]; 2] = [[1; 32], [2; 32]];
+
+    /// A sub-pipeline recording the path of each instruction it is passed,
+    /// failing on the instruction at `fail_at` if set.
+    #[derive(Debug)]
+    struct Recorder {
+        id: &'static str,
+        program: Option<Pubkey>,
+        commitment: Option<CommitmentLevel>,
+        fail_at: Option<&'static str>,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn new(id: &'static str, program: Option<usize>) -> Self {
+            Self {
+                id,
+                program: program.map(|i| Pubkey::new(PROGRAMS[i - 1])),
+                commitment: None,
+                fail_at: None,
+                seen: Arc::default(),
+            }
+        }
+
+        fn seen(&self) -> Arc<Mutex<Vec<String>>> { Arc::clone(&self.seen) }
+    }
+
+    impl ParserId for Recorder {
+        fn id(&self) -> Cow<'_, str> { self.id.into() }
+    }
+
+    impl GetPrefilter for Recorder {
+        fn prefilter(&self) -> Prefilter {
+            let builder = Prefilter::builder();
+            match self.commitment {
+                Some(c) => builder.commitment(c),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        }
+    }
+
+    impl DynPipeline<InstructionUpdate> for Recorder {
+        fn handle<'h>(
+            &'h self,
+            value: &'h InstructionUpdate,
+        ) -> Pin<Box<dyn Future<Output = Result<(), PipelineErrors>> + Send + 'h>> {
+            let path = value.path.to_string();
+            let fail = self.fail_at == Some(&*path);
+            self.seen.lock().unwrap().push(path);
+
+            Box::pin(async move {
+                if fail {
+                    Err(PipelineErrors::Handlers(smallvec!["boom".into()]))
+                } else {
+                    Ok(())
+                }
+            })
+        }
+
+        fn program_id(&self) -> Option<Pubkey> { self.program }
+    }
+
+    fn pipeline(pipes: Vec<Recorder>) -> InstructionPipeline<NullMetrics> {
+        InstructionPipeline::new(
+            pipes
+                .into_iter()
+                .map(|p| Box::new(p) as BoxPipeline<'static, InstructionUpdate>)
+                .collect(),
+            &NullMetrics,
+        )
+        .unwrap()
+    }
+
+    /// Build a transaction update calling the first program, with an inner
+    /// call to the second, followed by a call to the second program.
+    fn txn() -> TransactionUpdate {
+        let ix = |program_id_index| CompiledInstruction {
+            program_id_index,
+            accounts: vec![0],
+            data: vec![],
+        };
+
+        TransactionUpdate {
+            transaction: Some(SubscribeUpdateTransactionInfo {
+                transaction: Some(Transaction {
+                    signatures: vec![],
+                    message: Some(Message {
+                        account_keys: std::iter::once(vec![0; 32])
+                            .chain(PROGRAMS.iter().map(|p| p.to_vec()))
+                            .collect(),
+                        instructions: vec![ix(1), ix(2)],
+                        ..Message::default()
+                    }),
+                }),
+                meta: Some(TransactionStatusMeta {
+                    inner_instructions: vec![InnerInstructions {
+                        index: 0,
+                        instructions: vec![InnerInstruction {
+                            program_id_index: 2,
+                            accounts: vec![0],
+                            data: vec![],
+                            stack_height: Some(2),
+                        }],
+                    }],
+                    ..TransactionStatusMeta::default()
+                }),
+                ..SubscribeUpdateTransactionInfo::default()
+            }),
+            slot: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let first = Recorder::new("first", Some(1));
+        let second = Recorder::new("second", Some(2));
+        let all = Recorder::new("all", None);
+        let seen = [first.seen(), second.seen(), all.seen()];
+
+        pipeline(vec![first, second, all])
+            .handle(&txn())
+            .await
+            .unwrap();
+
+        let seen = seen.map(|s| s.lock().unwrap().clone());
+        assert_eq!(seen, [vec!["0"], vec!["0.0", "1"], vec!["0", "0.0", "1"]]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_instructions() {
+        let mut first = Recorder::new("first", Some(1));
+        first.fail_at = Some("0");
+        let mut all = Recorder::new("all", None);
+        all.fail_at = Some("0.0");
+        let seen = all.seen();
+
+        let Err(PipelineErrors::Instructions(failed)) =
+            pipeline(vec![first, all]).handle(&txn()).await
+        else {
+            panic!("Expected failed instructions");
+        };
+
+        // A failed instruction does not stop the rest being handled
+        assert_eq!(*seen.lock().unwrap(), ["0", "0.0", "1"]);
+        let failed: Vec<_> = failed
+            .into_iter()
+            .map(|(FailedInstruction { pipeline, path }, errors)| {
+                assert_eq!(errors.len(), 1);
+                assert!(errors[0].contains("boom"));
+                (pipeline, path.to_string())
+            })
+            .collect();
+        assert_eq!(failed, [
+            ("first".to_owned(), "0".to_owned()),
+            ("all".to_owned(), "0.0".to_owned())
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_targets_instruction() {
+        let second = Recorder::new("second", Some(2));
+        let all = Recorder::new("all", None);
+        let seen = [second.seen(), all.seen()];
+        let pipeline = pipeline(vec![second, all]);
+
+        let letter = DeadLetter::new(
+            &pipeline.id(),
+            Some(FailedInstruction {
+                pipeline: "second".to_owned(),
+                path: vec![0, 0].into(),
+            }),
+            UpdateOneof::Transaction(txn()),
+            vec![],
+        );
+        let (filter, target) = dead_letter::split_filter(&letter.update.filters[0]);
+        assert_eq!(filter, pipeline.id());
+        let ctx = HandlerContext {
+            replay: target.as_ref(),
+            ..HandlerContext::new(filter, SystemTime::now())
+        };
+        DynPipeline::handle_with_context(&pipeline, &txn(), None, &ctx)
+            .await
+            .unwrap();
+
+        let seen = seen.map(|s| s.lock().unwrap().clone());
+        assert_eq!(seen, [vec!["0.0"], vec![]]);
+    }
+
+    #[test]
+    fn test_split_by_commitment() {
+        assert!(InstructionPipeline::new(vec![], &NullMetrics).is_none());
+        assert!(InstructionPipeline::split_by_commitment(vec![], &NullMetrics).is_empty());
+
+        let pipes = [
+            None,
+            Some(CommitmentLevel::Finalized),
+            None,
+            Some(CommitmentLevel::Confirmed),
+        ]
+        .into_iter()
+        .map(|commitment| {
+            Box::new(Recorder {
+                commitment,
+                ..Recorder::new("pipe", None)
+            }) as BoxPipeline<'static, InstructionUpdate>
+        })
+        .collect();
+
+        let split = InstructionPipeline::split_by_commitment(pipes, &NullMetrics);
+        let split: Vec<_> = split
+            .iter()
+            .map(|p| (p.commitment, p.pipelines.len()))
+            .collect();
+        assert_eq!(split, [
+            (None, 2),
+            (Some(CommitmentLevel::Confirmed), 1),
+            (Some(CommitmentLevel::Finalized), 1)
+        ]);
+    }
 }
@@ -8,14 +8,20 @@
 )]
 #![warn(clippy::pedantic, missing_docs)]
 #![allow(clippy::module_name_repetitions)]
+// Error carries the gRPC status returned by Yellowstone unboxed
+#![allow(clippy::result_large_err)]
 
 //! Vixen provides a simple API for requesting, parsing, and consuming data
 //! from Yellowstone.
 
+use std::{path::Path, sync::Arc, time::Duration};
+
 use builder::RuntimeBuilder;
-use config::{BufferConfig, YellowstoneConfig};
+use checkpoint::{Checkpoint, Checkpointer};
+use config::{BufferConfig, CheckpointConfig, RecordConfig, SnapshotConfig, YellowstoneConfig};
 use futures_util::future::OptionFuture;
 use metrics::{Counters, Exporter, MetricsFactory, NullMetrics};
+use source::{GrpcSource, Recorder};
 use stop::{StopCode, StopTx};
 use tokio::task::LocalSet;
 
@@ -31,16 +37,28 @@ pub extern crate yellowstone_vixen_proto as proto;
 
 mod buffer;
 pub mod builder;
+pub mod checkpoint;
+pub mod combinator;
 pub mod config;
+pub mod dead_letter;
+pub mod handle;
 pub mod handler;
 pub mod instruction;
 pub mod metrics;
+mod order;
+mod queue;
+pub mod retry;
+mod snapshot;
+pub mod source;
 #[cfg(feature = "stream")]
 pub mod stream;
 mod util;
 mod yellowstone;
 
-pub use handler::{Handler, HandlerResult, Pipeline};
+pub use handle::RuntimeHandle;
+pub use handler::{Handler, HandlerContext, HandlerResult, Pipeline};
+pub use retry::RetryPolicy;
+pub use source::Source;
 pub use util::*;
 
 /// An error thrown by the Vixen runtime.
@@ -61,6 +79,15 @@ pub enum Error {
     /// A gRPC error returned by the Yellowstone server.
     #[error("Yellowstone stream returned an error")]
     YellowstoneStatus(#[from] yellowstone_grpc_proto::tonic::Status),
+    /// An error reading or writing a recording of updates.
+    #[error("Error reading or writing update recording")]
+    File(#[from] source::FileError),
+    /// An error loading a saved checkpoint.
+    #[error("Error loading checkpoint")]
+    Checkpoint(#[from] checkpoint::CheckpointError),
+    /// An error loading the startup account snapshot.
+    #[error("Error loading startup account snapshot")]
+    Snapshot(#[from] snapshot::Error),
     /// An error caused by the metrics exporter.
     #[error("Error exporting metrics")]
     MetricsExporter(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
@@ -71,8 +98,16 @@ pub enum Error {
 pub struct Runtime<M: MetricsFactory> {
     yellowstone_cfg: YellowstoneConfig,
     buffer_cfg: BufferConfig,
+    snapshot_cfg: SnapshotConfig,
+    record_cfg: RecordConfig,
+    checkpoint_cfg: CheckpointConfig,
+    checkpoint: Option<Arc<dyn Checkpoint>>,
+    dead_letters: Option<Arc<dyn dead_letter::DeadLetterSink>>,
     pipelines: handler::PipelineSets,
-    counters: Counters<M::Instrumenter>,
+    dispatch_key: Option<order::KeyFn>,
+    handle: RuntimeHandle,
+    commands: handle::CommandRx,
+    counters: Arc<Counters<M::Instrumenter>>,
     exporter: Option<M::Exporter>,
 }
 
@@ -82,6 +117,12 @@ impl Runtime<NullMetrics> {
 }
 
 impl<M: MetricsFactory> Runtime<M> {
+    /// Get a handle for changing the pipelines and prefilters of this runtime
+    /// once it is running.
+    #[inline]
+    #[must_use]
+    pub fn handle(&self) -> RuntimeHandle { self.handle.clone() }
+
     /// Create a new Tokio runtime and run the Vixen runtime within it,
     /// terminating the current process if the runtime crashes.
     #[inline]
@@ -111,7 +152,10 @@ impl<M: MetricsFactory> Runtime<M> {
     /// This function returns an error if the runtime crashes.
     #[inline]
     pub async fn try_run_async(self) -> Result<(), Error> {
-        LocalSet::new().run_until(self.try_run_local()).await
+        // The runtime's state is boxed so callers' futures stay small
+        LocalSet::new()
+            .run_until(Box::pin(self.try_run_local()))
+            .await
     }
 
     /// Run the Vixen runtime.
@@ -123,24 +167,126 @@ impl<M: MetricsFactory> Runtime<M> {
     /// This function returns an error if the runtime crashes.
     #[tracing::instrument("Runtime::run", skip(self))]
     pub async fn try_run_local(self) -> Result<(), Error> {
+        let counters = Arc::clone(&self.counters);
+        let slots = buffer::SharedSlots::default();
+
+        let resume_from = match &self.checkpoint {
+            Some(c) => checkpoint::restore(c, &slots, self.handle.cursors()).await?,
+            None => None,
+        };
+        if let Some(slot) = resume_from {
+            tracing::info!(slot, "Resuming from checkpoint");
+        }
+
+        let client = yellowstone::Client::connect(
+            self.yellowstone_cfg.clone(),
+            self.pipelines.filters(),
+            resume_from,
+            &counters,
+            &slots,
+        )
+        .await?;
+
+        // The subscription is opened first so no updates are missed while the
+        // snapshot loads; the buffer holds them until every snapshot update
+        // has been dispatched
+        let snapshot = snapshot::load(
+            self.snapshot_cfg.clone(),
+            &self.pipelines,
+            self.yellowstone_cfg.commitment,
+        );
+
+        self.run_source(
+            GrpcSource::new(client, counters, Arc::clone(&slots)),
+            snapshot,
+            slots,
+        )
+        .await
+    }
+
+    /// Create a new Tokio runtime and run the Vixen runtime within it, reading
+    /// updates from the given source rather than from Yellowstone.  The
+    /// runtime stops once the source is exhausted.
+    ///
+    /// # Errors
+    /// This function returns an error if the runtime crashes.
+    #[inline]
+    pub fn try_replay<S: Source + 'static>(self, source: S) -> Result<(), Error> {
+        util::tokio_runtime()?.block_on(LocalSet::new().run_until(self.try_replay_local(source)))
+    }
+
+    /// Create a new Tokio runtime and replay the updates in a dead letter file
+    /// written by [`FileDeadLetters`](dead_letter::FileDeadLetters) through
+    /// the pipelines that failed on them.  The runtime stops once every dead
+    /// letter has been replayed.
+    ///
+    /// # Errors
+    /// This function returns an error if the file could not be read or the
+    /// runtime crashes.
+    pub fn try_replay_dead_letters(self, path: impl AsRef<Path>) -> Result<(), Error> {
+        util::tokio_runtime()?.block_on(LocalSet::new().run_until(async move {
+            let source = dead_letter::DeadLetterSource::open(path).await?;
+            self.try_replay_local(source).await
+        }))
+    }
+
+    /// Run the Vixen runtime, reading updates from the given source rather
+    /// than from Yellowstone.  The runtime stops once the source is exhausted.
+    ///
+    /// **NOTE:** This function **must** be called from within a Tokio
+    /// [`LocalSet`].
+    ///
+    /// # Errors
+    /// This function returns an error if the runtime crashes.
+    #[tracing::instrument("Runtime::replay", skip(self, source))]
+    pub async fn try_replay_local<S: Source + 'static>(self, source: S) -> Result<(), Error> {
+        // Replayed progress must not overwrite the checkpoint of a live runtime
+        Self {
+            checkpoint: None,
+            ..self
+        }
+        .run_source(source, None, buffer::SharedSlots::default())
+        .await
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn run_source<S: Source + 'static>(
+        self,
+        source: S,
+        snapshot: Option<snapshot::Snapshot>,
+        slots: buffer::SharedSlots,
+    ) -> Result<(), Error> {
         enum StopType<S, X> {
             Signal(S),
-            Buffer(Result<std::convert::Infallible, Error>),
+            Buffer(Result<(), Error>),
             Exporter(Result<Result<stop::StopCode, X>, tokio::task::JoinError>),
         }
 
         let Self {
-            yellowstone_cfg,
+            yellowstone_cfg: _,
             buffer_cfg,
+            snapshot_cfg: _,
+            record_cfg,
+            checkpoint_cfg: CheckpointConfig { interval, .. },
+            checkpoint,
+            dead_letters,
             pipelines,
+            dispatch_key,
+            handle,
+            commands,
             counters,
             exporter,
         } = self;
 
+        let recorder = Recorder::create(record_cfg).await?;
+        let checkpointer = checkpoint.map(|c| {
+            Checkpointer::new(c, Arc::clone(&slots), Arc::clone(handle.cursors()))
+                .spawn(Duration::from_millis(interval))
+        });
+
         let (stop_exporter, rx) = stop::channel();
         let mut exporter = OptionFuture::from(exporter.map(|e| tokio::spawn(e.run(rx))));
 
-        let client = yellowstone::connect(yellowstone_cfg, pipelines.filters()).await?;
         let signal;
 
         #[cfg(unix)]
@@ -183,7 +329,18 @@ impl<M: MetricsFactory> Runtime<M> {
                 .map_err(Into::into);
         }
 
-        let mut buffer = buffer::Buffer::run_yellowstone(buffer_cfg, client, pipelines, counters);
+        let mut buffer = buffer::Buffer::run(
+            buffer_cfg,
+            source,
+            snapshot,
+            recorder,
+            pipelines,
+            dispatch_key,
+            dead_letters,
+            commands,
+            counters,
+            slots,
+        );
 
         let stop_ty = tokio::select! {
             s = signal => StopType::Signal(s),
@@ -204,8 +361,10 @@ impl<M: MetricsFactory> Runtime<M> {
                 "Signal handler returned None",
             )
             .into()),
-            // Not sure why the compiler couldn't figure this one out
-            StopType::Buffer(Ok(o)) => match o {},
+            StopType::Buffer(Ok(())) => {
+                tracing::info!("Update source exhausted, shutting down...");
+                Ok(())
+            },
             StopType::Signal(Err(e)) | StopType::Buffer(Err(e)) => Err(e),
             StopType::Exporter(Ok(Ok(..))) => {
                 Err(Error::MetricsExporter("Exporter stopped early".into()))
@@ -218,6 +377,15 @@ impl<M: MetricsFactory> Runtime<M> {
             Self::stop_buffer(buffer).await;
         }
 
+        // Stopped after the buffer so the final checkpoint includes every job
+        // that completed
+        if let Some((task, tx)) = checkpointer {
+            tx.maybe_send();
+            if let Err(e) = task.await {
+                tracing::warn!(err = %Chain(&e), "Checkpoint task panicked");
+            }
+        }
+
         if should_stop_exporter {
             Self::stop_exporter(exporter, stop_exporter).await;
         }
@@ -228,7 +396,7 @@ impl<M: MetricsFactory> Runtime<M> {
     async fn stop_buffer(buffer: buffer::Buffer) {
         match buffer.join().await {
             Err(e) => tracing::warn!(err = %Chain(&e), "Error stopping runtime buffer"),
-            Ok(c) => c.as_unit(),
+            Ok(c) => c.map_or((), StopCode::as_unit),
         }
     }
 
@@ -267,7 +435,7 @@ impl<M: MetricsFactory> Runtime<M> {
                     );
                 },
                 Ok(Ok(c)) => c.as_unit(),
-            };
+            }
         }
     }
 }
@@ -6,17 +6,19 @@ use std::{
     error::Error,
     fmt,
     future::Future,
+    time::SystemTime,
 };
 
 #[cfg(feature = "opentelemetry")]
 pub use opentelemetry_impl::*;
 #[cfg(feature = "prometheus")]
 pub use prometheus_impl::*;
+use vixen_core::{CommitmentLevel, Pubkey};
 use yellowstone_grpc_proto::geyser::subscribe_update::UpdateOneof;
 
 use crate::{
     config::{MaybeDefault, NullConfig},
-    handler::PipelineErrors,
+    handler::{HandlerContext, PipelineErrors},
     stop::{StopCode, StopRx},
 };
 
@@ -51,6 +53,8 @@ pub trait MetricsFactory {
 pub trait Instrumenter: 'static {
     /// The type of an integer counter for this metrics backend.
     type Counter: Counter;
+    /// The type of an integer gauge for this metrics backend.
+    type Gauge: Gauge;
 
     /// Create a new integer counter with the given name and description.
     fn make_counter(
@@ -58,6 +62,13 @@ pub trait Instrumenter: 'static {
         name: impl Into<Cow<'static, str>>,
         desc: impl Into<Cow<'static, str>>,
     ) -> Self::Counter;
+
+    /// Create a new integer gauge with the given name and description.
+    fn make_gauge(
+        &self,
+        name: impl Into<Cow<'static, str>>,
+        desc: impl Into<Cow<'static, str>>,
+    ) -> Self::Gauge;
 }
 
 /// A metrics exporter.
@@ -94,6 +105,17 @@ impl<T: Counter> Counter for &T {
     fn inc_by(&self, by: u64) { T::inc_by(self, by); }
 }
 
+/// An integer gauge for a metrics backend.
+pub trait Gauge: Send + Sync {
+    /// Set the gauge to the given value.
+    fn set(&self, value: i64);
+}
+
+impl<T: Gauge> Gauge for &T {
+    #[inline]
+    fn set(&self, value: i64) { T::set(self, value); }
+}
+
 /// A no-op metrics backend.
 #[derive(Debug, Default, Clone, Copy)]
 pub struct NullMetrics;
@@ -111,6 +133,7 @@ impl MetricsFactory for NullMetrics {
 
 impl Instrumenter for NullMetrics {
     type Counter = NullMetrics;
+    type Gauge = NullMetrics;
 
     #[inline]
     fn make_counter(
@@ -120,6 +143,15 @@ impl Instrumenter for NullMetrics {
     ) -> Self::Counter {
         NullMetrics
     }
+
+    #[inline]
+    fn make_gauge(
+        &self,
+        _: impl Into<Cow<'static, str>>,
+        _: impl Into<Cow<'static, str>>,
+    ) -> Self::Gauge {
+        NullMetrics
+    }
 }
 
 impl Counter for NullMetrics {
@@ -127,6 +159,11 @@ impl Counter for NullMetrics {
     fn inc_by(&self, _: u64) {}
 }
 
+impl Gauge for NullMetrics {
+    #[inline]
+    fn set(&self, _: i64) {}
+}
+
 impl Exporter for Infallible {
     type Error = Infallible;
 
@@ -158,7 +195,6 @@ mod prometheus_impl {
         fn create(self, config: Self::Config, id: &'static str) -> FactoryResult<Self> {
             Registry::new_custom(Some(id.into()), None)
                 .map(|r| Metrics(r.clone(), Some(PrometheusExporter(r, config))))
-                .map_err(Into::into)
         }
     }
 
@@ -200,6 +236,7 @@ mod prometheus_impl {
 
     impl super::Instrumenter for Registry {
         type Counter = prometheus::IntCounter;
+        type Gauge = prometheus::IntGauge;
 
         fn make_counter(
             &self,
@@ -212,20 +249,40 @@ mod prometheus_impl {
             self.register(Box::new(counter.clone())).unwrap();
             counter
         }
+
+        fn make_gauge(
+            &self,
+            name: impl Into<Cow<'static, str>>,
+            desc: impl Into<Cow<'static, str>>,
+        ) -> Self::Gauge {
+            let gauge =
+                prometheus::IntGauge::with_opts(prometheus::Opts::new(name.into(), desc.into()))
+                    .unwrap();
+            self.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        }
     }
 
     impl super::Counter for prometheus::IntCounter {
         fn inc_by(&self, by: u64) { prometheus::IntCounter::inc_by(self, by); }
     }
+
+    impl super::Gauge for prometheus::IntGauge {
+        fn set(&self, value: i64) { prometheus::IntGauge::set(self, value); }
+    }
 }
 
 #[cfg(feature = "opentelemetry")]
 mod opentelemetry_impl {
-    use std::{borrow::Cow, convert::Infallible};
+    use std::{
+        borrow::Cow,
+        convert::Infallible,
+        sync::atomic::{AtomicI64, Ordering},
+    };
 
     use opentelemetry::{
         global::{self, GlobalMeterProvider},
-        metrics::{Counter, Meter, MeterProvider},
+        metrics::{Counter, Meter, MeterProvider, UpDownCounter},
     };
 
     use super::{FactoryResult, Metrics};
@@ -286,8 +343,14 @@ mod opentelemetry_impl {
         }
     }
 
+    /// Gauge for the OpenTelemetry metrics backend, implemented as an
+    /// up-down counter tracking the last value set.
+    #[derive(Debug)]
+    pub struct OpenTelemetryGauge(UpDownCounter<i64>, AtomicI64);
+
     impl super::Instrumenter for Meter {
         type Counter = Counter<u64>;
+        type Gauge = OpenTelemetryGauge;
 
         fn make_counter(
             &self,
@@ -296,11 +359,29 @@ mod opentelemetry_impl {
         ) -> Self::Counter {
             self.u64_counter(name).with_description(desc).init()
         }
+
+        fn make_gauge(
+            &self,
+            name: impl Into<Cow<'static, str>>,
+            desc: impl Into<Cow<'static, str>>,
+        ) -> Self::Gauge {
+            OpenTelemetryGauge(
+                self.i64_up_down_counter(name).with_description(desc).init(),
+                AtomicI64::new(0),
+            )
+        }
     }
 
     impl super::Counter for Counter<u64> {
         fn inc_by(&self, by: u64) { self.add(by, &[]); }
     }
+
+    impl super::Gauge for OpenTelemetryGauge {
+        fn set(&self, value: i64) {
+            let prev = self.1.swap(value, Ordering::Relaxed);
+            self.0.add(value.wrapping_sub(prev), &[]);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -316,21 +397,135 @@ impl JobResult {
             Ok(_) => Self::Ok,
             Err(PipelineErrors::Parse(_)) => Self::ParseErr,
             Err(PipelineErrors::Handlers(v)) => Self::HandleErr(v.len()),
-            Err(PipelineErrors::AlreadyHandled(_)) => return None,
+            Err(PipelineErrors::AlreadyHandled(_) | PipelineErrors::Instructions(_)) => {
+                return None
+            },
         })
     }
 }
 
 pub(crate) trait Update {
     const TYPE: UpdateType;
+
+    /// Copy this update back into the raw form received from Yellowstone.
+    fn to_oneof(&self) -> UpdateOneof;
+
+    /// Metadata about this update for the handlers of the pipeline matching
+    /// the given filter.
+    #[inline]
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        HandlerContext::new(filter, received_at)
+    }
+
+    /// The account data slices a pipeline with the given prefilter receives
+    /// updates of this type with, if any.
+    #[inline]
+    fn data_slices(prefilter: &vixen_core::Prefilter) -> Option<&vixen_core::DataSlices> {
+        let _ = prefilter;
+        None
+    }
 }
 
 impl Update for vixen_core::AccountUpdate {
     const TYPE: UpdateType = UpdateType::Account;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::Account(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let info = self.account.as_ref();
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx.signature = info.and_then(|a| a.txn_signature.as_deref());
+        ctx.pubkey = info.and_then(|a| Pubkey::try_from(&*a.pubkey).ok());
+        ctx.write_version = info.map(|a| a.write_version);
+        ctx
+    }
+
+    #[inline]
+    fn data_slices(prefilter: &vixen_core::Prefilter) -> Option<&vixen_core::DataSlices> {
+        prefilter.account_data_slices()
+    }
 }
 
 impl Update for vixen_core::TransactionUpdate {
     const TYPE: UpdateType = UpdateType::Transaction;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::Transaction(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx.signature = self.transaction.as_ref().map(|t| &*t.signature);
+        ctx
+    }
+}
+
+impl Update for vixen_core::SlotUpdate {
+    const TYPE: UpdateType = UpdateType::Slot;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::Slot(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx
+    }
+}
+
+impl Update for vixen_core::BlockUpdate {
+    const TYPE: UpdateType = UpdateType::Block;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::Block(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx
+    }
+}
+
+impl Update for vixen_core::BlockMetaUpdate {
+    const TYPE: UpdateType = UpdateType::BlockMeta;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::BlockMeta(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx
+    }
+}
+
+impl Update for vixen_core::TransactionStatusUpdate {
+    const TYPE: UpdateType = UpdateType::TransactionStatus;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::TransactionStatus(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx.signature = Some(&self.signature);
+        ctx
+    }
+}
+
+impl Update for vixen_core::EntryUpdate {
+    const TYPE: UpdateType = UpdateType::Entry;
+
+    #[inline]
+    fn to_oneof(&self) -> UpdateOneof { UpdateOneof::Entry(self.clone()) }
+
+    fn context<'a>(&'a self, filter: &'a str, received_at: SystemTime) -> HandlerContext<'a> {
+        let mut ctx = HandlerContext::new(filter, received_at);
+        ctx.slot = Some(self.slot);
+        ctx
+    }
 }
 
 /// Tuple of `(singular, plural)`
@@ -350,15 +545,29 @@ macro_rules! noun_formatters {
 pub(crate) enum UpdateType {
     Account,
     Transaction,
+    Slot,
+    Block,
+    BlockMeta,
+    TransactionStatus,
+    Entry,
 }
 
 impl UpdateType {
-    pub fn get(update: &Option<UpdateOneof>) -> Option<Self> {
+    pub fn get(update: Option<&UpdateOneof>) -> Option<Self> {
         match update {
             Some(UpdateOneof::Account(vixen_core::AccountUpdate { .. })) => Some(Self::Account),
             Some(UpdateOneof::Transaction(vixen_core::TransactionUpdate { .. })) => {
                 Some(Self::Transaction)
             },
+            Some(UpdateOneof::Slot(vixen_core::SlotUpdate { .. })) => Some(Self::Slot),
+            Some(UpdateOneof::Block(vixen_core::BlockUpdate { .. })) => Some(Self::Block),
+            Some(UpdateOneof::BlockMeta(vixen_core::BlockMetaUpdate { .. })) => {
+                Some(Self::BlockMeta)
+            },
+            Some(UpdateOneof::TransactionStatus(vixen_core::TransactionStatusUpdate {
+                ..
+            })) => Some(Self::TransactionStatus),
+            Some(UpdateOneof::Entry(vixen_core::EntryUpdate { .. })) => Some(Self::Entry),
             _ => None,
         }
     }
@@ -367,6 +576,11 @@ impl UpdateType {
         match self {
             UpdateType::Account => Noun("account", "accounts"),
             UpdateType::Transaction => Noun("transaction", "transactions"),
+            UpdateType::Slot => Noun("slot_update", "slot_updates"),
+            UpdateType::Block => Noun("block", "blocks"),
+            UpdateType::BlockMeta => Noun("block_meta", "block_metas"),
+            UpdateType::TransactionStatus => Noun("transaction_status", "transaction_statuses"),
+            UpdateType::Entry => Noun("entry", "entries"),
         }
     }
 }
@@ -374,6 +588,11 @@ impl UpdateType {
 struct UpdateCounters<B: Instrumenter> {
     account: B::Counter,
     transaction: B::Counter,
+    slot: B::Counter,
+    block: B::Counter,
+    block_meta: B::Counter,
+    transaction_status: B::Counter,
+    entry: B::Counter,
 }
 
 impl<B: Instrumenter> UpdateCounters<B> {
@@ -382,6 +601,11 @@ impl<B: Instrumenter> UpdateCounters<B> {
         Self {
             account: f(UpdateType::Account),
             transaction: f(UpdateType::Transaction),
+            slot: f(UpdateType::Slot),
+            block: f(UpdateType::Block),
+            block_meta: f(UpdateType::BlockMeta),
+            transaction_status: f(UpdateType::TransactionStatus),
+            entry: f(UpdateType::Entry),
         }
     }
 
@@ -389,6 +613,11 @@ impl<B: Instrumenter> UpdateCounters<B> {
         match ty {
             UpdateType::Account => &self.account,
             UpdateType::Transaction => &self.transaction,
+            UpdateType::Slot => &self.slot,
+            UpdateType::Block => &self.block,
+            UpdateType::BlockMeta => &self.block_meta,
+            UpdateType::TransactionStatus => &self.transaction_status,
+            UpdateType::Entry => &self.entry,
         }
     }
 }
@@ -445,11 +674,18 @@ impl<C> ResultCounters<C> {
 // TODO: this should probably use datapoint attributes rather than weird name formatting
 pub(crate) struct Counters<B: Instrumenter> {
     updates_recvd: UpdateCounters<B>,
+    updates_dropped: UpdateCounters<B>,
     update_results: ResultCounters<UpdateCounters<B>>,
+    queue_depth: B::Gauge,
+    reconnect_attempts: B::Counter,
+    reconnects: B::Counter,
+    missed_slots: B::Counter,
+    slot_statuses: SlotStatusCounters<B>,
+    endpoints: Box<[EndpointCounters<B>]>,
 }
 
 impl<B: Instrumenter> Counters<B> {
-    pub fn new(metrics: &B) -> Self {
+    pub fn new(metrics: &B, endpoints: usize) -> Self {
         Self {
             updates_recvd: UpdateCounters::new(|Noun(_, p)| {
                 metrics.make_counter(
@@ -457,9 +693,35 @@ impl<B: Instrumenter> Counters<B> {
                     format!("Number of {p} received for processing"),
                 )
             }),
+            updates_dropped: UpdateCounters::new(|Noun(_, p)| {
+                metrics.make_counter(
+                    format!("{p}_dropped"),
+                    format!("Number of {p} dropped because the job queue was full"),
+                )
+            }),
             update_results: ResultCounters::new(|c, d| {
                 UpdateCounters::new(|n| metrics.make_counter(c(n), d(n)))
             }),
+            queue_depth: metrics.make_gauge(
+                "buffer_queue_depth",
+                "Number of jobs dispatched that have not yet started running",
+            ),
+            reconnect_attempts: metrics.make_counter(
+                "yellowstone_reconnect_attempts",
+                "Number of attempts made to reconnect to Yellowstone",
+            ),
+            reconnects: metrics.make_counter(
+                "yellowstone_reconnects",
+                "Number of successful reconnections to Yellowstone",
+            ),
+            missed_slots: metrics.make_counter(
+                "yellowstone_missed_slots",
+                "Number of slots skipped over between a disconnect and the following reconnect",
+            ),
+            slot_statuses: SlotStatusCounters::new(metrics),
+            endpoints: (0..endpoints)
+                .map(|i| EndpointCounters::new(metrics, i))
+                .collect(),
         }
     }
 }
@@ -471,10 +733,121 @@ impl<B: Instrumenter> fmt::Debug for Counters<B> {
 impl<B: Instrumenter> Counters<B> {
     pub fn inc_received(&self, ty: UpdateType) { self.updates_recvd.get(ty).inc(); }
 
+    #[inline]
+    pub fn inc_dropped(&self, ty: UpdateType) { self.updates_dropped.get(ty).inc(); }
+
+    #[inline]
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth.try_into().unwrap_or(i64::MAX));
+    }
+
     #[inline]
     pub fn inc_processed(&self, ty: UpdateType, res: JobResult) {
         self.update_results.inc(res, |u| u.get(ty));
     }
+
+    #[inline]
+    pub fn inc_reconnect_attempts(&self) { self.reconnect_attempts.inc(); }
+
+    #[inline]
+    pub fn inc_reconnects(&self) { self.reconnects.inc(); }
+
+    #[inline]
+    pub fn inc_missed_slots(&self, by: u64) { self.missed_slots.inc_by(by); }
+
+    #[inline]
+    pub fn inc_slot_status(&self, status: CommitmentLevel) { self.slot_statuses.get(status).inc(); }
+
+    #[inline]
+    pub fn endpoint(&self, index: usize) -> Option<&EndpointCounters<B>> {
+        self.endpoints.get(index)
+    }
+}
+
+pub(crate) struct EndpointCounters<B: Instrumenter> {
+    connected: B::Gauge,
+    slot_lag: B::Gauge,
+    updates: B::Counter,
+    leading: B::Counter,
+    disconnects: B::Counter,
+}
+
+impl<B: Instrumenter> EndpointCounters<B> {
+    fn new(metrics: &B, index: usize) -> Self {
+        let name = |n: &str| format!("yellowstone_endpoint_{index}_{n}");
+        Self {
+            connected: metrics.make_gauge(
+                name("connected"),
+                format!("Whether Yellowstone endpoint {index} is currently streaming"),
+            ),
+            slot_lag: metrics.make_gauge(
+                name("slot_lag"),
+                format!(
+                    "Number of slots Yellowstone endpoint {index} trails the leading endpoint by, \
+                     in the subscription it trails furthest in"
+                ),
+            ),
+            updates: metrics.make_counter(
+                name("updates"),
+                format!("Number of updates received from Yellowstone endpoint {index}"),
+            ),
+            leading: metrics.make_counter(
+                name("leading_updates"),
+                format!(
+                    "Number of de-duplicated updates Yellowstone endpoint {index} delivered first"
+                ),
+            ),
+            disconnects: metrics.make_counter(
+                name("disconnects"),
+                format!("Number of times the stream from Yellowstone endpoint {index} dropped"),
+            ),
+        }
+    }
+
+    #[inline]
+    pub fn set_connected(&self, connected: bool) { self.connected.set(connected.into()); }
+
+    #[inline]
+    pub fn set_slot_lag(&self, lag: u64) { self.slot_lag.set(lag.try_into().unwrap_or(i64::MAX)); }
+
+    #[inline]
+    pub fn inc_updates(&self) { self.updates.inc(); }
+
+    #[inline]
+    pub fn inc_leading(&self) { self.leading.inc(); }
+
+    #[inline]
+    pub fn inc_disconnects(&self) { self.disconnects.inc(); }
+}
+
+struct SlotStatusCounters<B: Instrumenter> {
+    processed: B::Counter,
+    confirmed: B::Counter,
+    finalized: B::Counter,
+}
+
+impl<B: Instrumenter> SlotStatusCounters<B> {
+    fn new(metrics: &B) -> Self {
+        let f = |c: CommitmentLevel| {
+            metrics.make_counter(
+                format!("slot_status_{c}"),
+                format!("Number of slots reported as {c} by Yellowstone"),
+            )
+        };
+        Self {
+            processed: f(CommitmentLevel::Processed),
+            confirmed: f(CommitmentLevel::Confirmed),
+            finalized: f(CommitmentLevel::Finalized),
+        }
+    }
+
+    fn get(&self, status: CommitmentLevel) -> &B::Counter {
+        match status {
+            CommitmentLevel::Processed => &self.processed,
+            CommitmentLevel::Confirmed => &self.confirmed,
+            CommitmentLevel::Finalized => &self.finalized,
+        }
+    }
 }
 
 const INSTRUCTION_NOUN: Noun = Noun("instruction", "instructions");
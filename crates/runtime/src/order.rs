@@ -0,0 +1,330 @@
+//! Keyed ordering of the jobs run by the buffer.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use vixen_core::Pubkey;
+use yellowstone_grpc_proto::geyser::{subscribe_update::UpdateOneof, SubscribeUpdate};
+
+// Account versions are remembered for this many slots behind the newest
+// write seen.  Older writes are assumed to have been superseded long before
+// they could arrive.
+const VERSION_WINDOW: u64 = 256;
+
+type BoxKeyFn = Box<dyn Fn(&SubscribeUpdate) -> Option<u64> + Send + Sync>;
+
+/// A user-supplied function computing the ordering key of an update.
+pub(crate) struct KeyFn(BoxKeyFn);
+
+impl KeyFn {
+    pub fn new<K: Hash, F: Fn(&SubscribeUpdate) -> Option<K> + Send + Sync + 'static>(
+        f: F,
+    ) -> Self {
+        Self(Box::new(move |u| f(u).map(|k| hash(&k))))
+    }
+}
+
+impl fmt::Debug for KeyFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("KeyFn(..)") }
+}
+
+#[inline]
+fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The key of an account update: the account written to.
+fn account_key(update: &SubscribeUpdate) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::Account(a) => Some(hash(&*a.account.as_ref()?.pubkey)),
+        _ => None,
+    }
+}
+
+/// The default key of an update: the account for account updates, and the
+/// fee payer for transaction updates.
+fn default_key(update: &SubscribeUpdate) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::Account(_) => account_key(update),
+        UpdateOneof::Transaction(t) => {
+            let message = t
+                .transaction
+                .as_ref()?
+                .transaction
+                .as_ref()?
+                .message
+                .as_ref()?;
+            Some(hash(&**message.account_keys.first()?))
+        },
+        _ => None,
+    }
+}
+
+/// Jobs waiting on an earlier job with the same key, by key.  A key is present
+/// for as long as a job with that key is running.
+pub(crate) struct Partitions<J>(Mutex<HashMap<u64, VecDeque<J>>>);
+
+impl<J> Default for Partitions<J> {
+    fn default() -> Self { Self(Mutex::default()) }
+}
+
+impl<J> fmt::Debug for Partitions<J> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Partitions").finish_non_exhaustive()
+    }
+}
+
+impl<J> Partitions<J> {
+    /// Queue a job behind any running job with the same key, returning it if
+    /// it can run immediately.
+    pub fn enqueue(&self, key: u64, job: J) -> Option<J> {
+        let mut parts = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(queue) = parts.get_mut(&key) {
+            queue.push_back(job);
+            None
+        } else {
+            parts.insert(key, VecDeque::new());
+            Some(job)
+        }
+    }
+
+    /// Mark the running job with the given key as completed, returning the
+    /// next job with the same key, if any.
+    pub fn next(&self, key: u64) -> Option<J> {
+        let mut parts = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let next = parts.get_mut(&key).and_then(VecDeque::pop_front);
+        if next.is_none() {
+            parts.remove(&key);
+        }
+        next
+    }
+}
+
+pub(crate) type SharedPartitions<J> = Arc<Partitions<J>>;
+
+/// How an update should be dispatched.
+pub(crate) enum Admit {
+    /// The update is an account write older than one already received.
+    Stale,
+    /// The update should run, serially with others sharing its key if it has
+    /// one.
+    Run(Option<u64>),
+}
+
+/// Ordering state for the buffer, owned by the task dispatching updates.
+#[derive(Debug)]
+pub(crate) struct Order {
+    // Whether every update is keyed, rather than only account writes
+    keyed: bool,
+    key_fn: Option<KeyFn>,
+    // The newest (slot, write_version) received for each account
+    versions: HashMap<Pubkey, (u64, u64)>,
+    highest_slot: u64,
+    pruned_at: u64,
+}
+
+impl Order {
+    pub fn new(key_fn: Option<KeyFn>) -> Self {
+        Self {
+            keyed: true,
+            key_fn,
+            versions: HashMap::new(),
+            highest_slot: 0,
+            pruned_at: 0,
+        }
+    }
+
+    /// Ordering that only keys account writes, by account, leaving every
+    /// other update unordered.
+    pub fn accounts() -> Self {
+        Self {
+            keyed: false,
+            ..Self::new(None)
+        }
+    }
+
+    pub fn admit(&mut self, update: &SubscribeUpdate) -> Admit {
+        if let Some(UpdateOneof::Account(a)) = &update.update_oneof {
+            if let Some(info) = &a.account {
+                if !self.observe(&info.pubkey, a.slot, info.write_version) {
+                    return Admit::Stale;
+                }
+            }
+        }
+
+        Admit::Run(match &self.key_fn {
+            _ if !self.keyed => account_key(update),
+            Some(KeyFn(f)) => f(update),
+            None => default_key(update),
+        })
+    }
+
+    /// Record an account write, returning `false` if a newer write to the
+    /// same account was already received.
+    ///
+    /// A write with the same version as the newest is admitted, as the same
+    /// write arrives once for each subscription group it matches.
+    fn observe(&mut self, pubkey: &[u8], slot: u64, write_version: u64) -> bool {
+        let Ok(pubkey) = Pubkey::try_from(pubkey) else {
+            return true;
+        };
+
+        let version = (slot, write_version);
+        match self.versions.get_mut(&pubkey) {
+            Some(v) if *v > version => return false,
+            Some(v) => *v = version,
+            None => {
+                self.versions.insert(pubkey, version);
+            },
+        }
+
+        self.highest_slot = self.highest_slot.max(slot);
+        if self.highest_slot >= self.pruned_at + VERSION_WINDOW {
+            let oldest = self.highest_slot - VERSION_WINDOW;
+            self.versions.retain(|_, &mut (s, _)| s >= oldest);
+            self.pruned_at = self.highest_slot;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yellowstone_grpc_proto::geyser::{
+        SubscribeUpdateAccount, SubscribeUpdateAccountInfo, SubscribeUpdateTransaction,
+    };
+
+    use super::*;
+
+    fn account(filter: &str, pubkey: u8, slot: u64, write_version: u64) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec![filter.to_owned()],
+            update_oneof: Some(UpdateOneof::Account(SubscribeUpdateAccount {
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: vec![pubkey; 32],
+                    write_version,
+                    ..Default::default()
+                }),
+                slot,
+                is_startup: false,
+            })),
+            created_at: None,
+        }
+    }
+
+    fn admitted(order: &mut Order, update: &SubscribeUpdate) -> bool {
+        matches!(order.admit(update), Admit::Run(_))
+    }
+
+    fn key(order: &mut Order, update: &SubscribeUpdate) -> Option<u64> {
+        match order.admit(update) {
+            Admit::Run(k) => k,
+            Admit::Stale => panic!("Unexpected stale update"),
+        }
+    }
+
+    #[test]
+    fn test_stale_writes_dropped() {
+        let mut order = Order::new(None);
+
+        assert!(admitted(&mut order, &account("a", 1, 10, 5)));
+        assert!(admitted(&mut order, &account("a", 1, 10, 6)));
+        assert!(!admitted(&mut order, &account("a", 1, 10, 5)));
+        assert!(!admitted(&mut order, &account("a", 1, 9, 100)));
+        assert!(admitted(&mut order, &account("a", 2, 9, 100)));
+    }
+
+    #[test]
+    fn test_same_write_from_two_groups() {
+        let mut order = Order::new(None);
+
+        // The same write delivered to one group per commitment level
+        assert!(admitted(&mut order, &account("processed", 1, 10, 5)));
+        assert!(admitted(&mut order, &account("confirmed", 1, 10, 5)));
+
+        // A group lagging behind the other still has its older writes
+        // dropped
+        assert!(admitted(&mut order, &account("processed", 1, 11, 7)));
+        assert!(!admitted(&mut order, &account("confirmed", 1, 10, 6)));
+        assert!(admitted(&mut order, &account("confirmed", 1, 11, 7)));
+    }
+
+    #[test]
+    fn test_versions_pruned() {
+        let mut order = Order::new(None);
+
+        assert!(admitted(&mut order, &account("a", 1, 10, 5)));
+        assert!(admitted(
+            &mut order,
+            &account("a", 2, 10 + VERSION_WINDOW * 2, 0)
+        ));
+        assert!(!order.versions.contains_key(&Pubkey::from([1; 32])));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut order = Order::new(None);
+
+        let a = key(&mut order, &account("a", 1, 10, 0));
+        let b = key(&mut order, &account("a", 2, 10, 0));
+        assert!(a.is_some() && b.is_some());
+        assert_ne!(a, b);
+        assert_eq!(a, key(&mut order, &account("a", 1, 11, 0)));
+
+        let mut order = Order::new(Some(KeyFn::new(|u: &SubscribeUpdate| {
+            u.filters.first().cloned()
+        })));
+        assert_eq!(
+            key(&mut order, &account("a", 1, 10, 0)),
+            key(&mut order, &account("a", 2, 10, 0)),
+        );
+        assert_ne!(
+            key(&mut order, &account("a", 1, 11, 0)),
+            key(&mut order, &account("b", 1, 11, 0)),
+        );
+    }
+
+    #[test]
+    fn test_accounts_only() {
+        let mut order = Order::accounts();
+        let tx = SubscribeUpdate {
+            filters: vec![],
+            update_oneof: Some(UpdateOneof::Transaction(
+                SubscribeUpdateTransaction::default(),
+            )),
+            created_at: None,
+        };
+
+        assert!(key(&mut order, &account("a", 1, 10, 0)).is_some());
+        assert_eq!(key(&mut order, &tx), None);
+
+        // Snapshot writes are ordered against stream writes to the same
+        // account, and stale stream writes are dropped
+        assert!(!admitted(&mut order, &account("a", 1, 9, 3)));
+    }
+
+    #[test]
+    fn test_partitions() {
+        let parts = Partitions::default();
+
+        assert_eq!(parts.enqueue(1, "a"), Some("a"));
+        assert_eq!(parts.enqueue(2, "b"), Some("b"));
+        assert_eq!(parts.enqueue(1, "c"), None);
+        assert_eq!(parts.enqueue(1, "d"), None);
+
+        assert_eq!(parts.next(1), Some("c"));
+        assert_eq!(parts.next(1), Some("d"));
+        assert_eq!(parts.next(2), None);
+        assert_eq!(parts.next(1), None);
+
+        // Once a key's jobs are exhausted the next job runs immediately
+        assert_eq!(parts.enqueue(1, "e"), Some("e"));
+    }
+}
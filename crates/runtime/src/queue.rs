@@ -0,0 +1,279 @@
+//! The bounded queue of jobs waiting to be run by the buffer.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use tokio::sync::Notify;
+
+use crate::config::OverflowPolicy;
+
+/// The result of pushing a job onto a [`Queue`].
+pub(crate) enum Push<J> {
+    /// The job was added to the queue.
+    Queued,
+    /// The job was added to the queue in place of an older job, which was
+    /// removed to make room for it.
+    Replaced(J),
+    /// The queue was full and the job was not added.
+    Rejected(J),
+}
+
+/// Jobs waiting to be run, along with a count of every job dispatched that
+/// has not yet started running.
+///
+/// The count includes jobs that have left the queue but are waiting on an
+/// earlier job with the same ordering key, so the capacity bounds all queued
+/// work, while the overflow policy can only drop jobs still in the queue.
+/// A second count includes running jobs as well, so the buffer can wait for
+/// all of its work to complete before shutting down.
+pub(crate) struct Queue<J> {
+    jobs: Mutex<VecDeque<(i32, J)>>,
+    pending: AtomicUsize,
+    outstanding: AtomicUsize,
+    space: Notify,
+    idle: Notify,
+    capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    priorities: HashMap<String, i32>,
+}
+
+impl<J> fmt::Debug for Queue<J> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Queue")
+            .field("pending", &self.pending)
+            .field("outstanding", &self.outstanding)
+            .field("capacity", &self.capacity)
+            .field("overflow", &self.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) type SharedQueue<J> = Arc<Queue<J>>;
+
+impl<J> Queue<J> {
+    pub fn new(
+        capacity: Option<usize>,
+        overflow: OverflowPolicy,
+        priorities: HashMap<String, i32>,
+    ) -> Self {
+        Self {
+            jobs: Mutex::default(),
+            pending: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+            space: Notify::new(),
+            idle: Notify::new(),
+            capacity,
+            overflow,
+            priorities,
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<(i32, J)>> {
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The number of jobs dispatched that have not yet started running.
+    #[inline]
+    pub fn depth(&self) -> usize { self.pending.load(Ordering::Acquire) }
+
+    /// The number of jobs dispatched that have not yet completed.
+    #[inline]
+    pub fn outstanding(&self) -> usize { self.outstanding.load(Ordering::Acquire) }
+
+    #[inline]
+    fn is_full(&self) -> bool { self.capacity.is_some_and(|c| self.depth() >= c) }
+
+    /// The priority of a job for an update routed to the given pipelines: the
+    /// highest priority of any of them.
+    pub fn priority<S: AsRef<str>>(&self, pipelines: &[S]) -> i32 {
+        pipelines
+            .iter()
+            .filter_map(|p| self.priorities.get(p.as_ref()).copied())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// If the queue blocks when full, wait until there is room for another
+    /// job.
+    pub async fn wait_for_space(&self) {
+        if self.overflow != OverflowPolicy::Block {
+            return;
+        }
+
+        while self.is_full() {
+            self.space.notified().await;
+        }
+    }
+
+    /// Push a job onto the queue, applying the overflow policy if it is full.
+    pub fn push(&self, priority: i32, job: J) -> Push<J> {
+        let mut jobs = self.lock();
+
+        // The blocking policy waits for space before pushing, so a full queue
+        // is only ever overfilled by jobs dispatched without waiting
+        if !self.is_full() || self.overflow == OverflowPolicy::Block {
+            jobs.push_back((priority, job));
+            self.pending.fetch_add(1, Ordering::AcqRel);
+            self.outstanding.fetch_add(1, Ordering::AcqRel);
+            return Push::Queued;
+        }
+
+        let victim = match self.overflow {
+            OverflowPolicy::Block | OverflowPolicy::DropNewest => None,
+            OverflowPolicy::DropOldest => (!jobs.is_empty()).then_some(0),
+            OverflowPolicy::Priority => jobs
+                .iter()
+                .enumerate()
+                .min_by_key(|(i, (p, _))| (*p, *i))
+                .filter(|(_, (p, _))| *p < priority)
+                .map(|(i, _)| i),
+        };
+
+        match victim.and_then(|i| jobs.remove(i)) {
+            Some((_, old)) => {
+                jobs.push_back((priority, job));
+                Push::Replaced(old)
+            },
+            None => Push::Rejected(job),
+        }
+    }
+
+    /// Remove the oldest job from the queue, passing it to the given function
+    /// while the queue is still locked so jobs are claimed in order.
+    pub fn pop<R>(&self, f: impl FnOnce(J) -> R) -> Option<R> {
+        let mut jobs = self.lock();
+        jobs.pop_front().map(|(_, j)| f(j))
+    }
+
+    /// Record that a dispatched job has started running.
+    pub fn started(&self) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+        self.space.notify_one();
+    }
+
+    /// Record that a running job has completed.
+    pub fn finished(&self) {
+        if self.outstanding.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_one();
+        }
+    }
+
+    /// Wait until every dispatched job has completed.
+    pub async fn drained(&self) {
+        while self.outstanding() > 0 {
+            self.idle.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn queue(overflow: OverflowPolicy) -> Queue<u32> {
+        Queue::new(Some(2), overflow, [("high".to_owned(), 5)].into())
+    }
+
+    fn drain(queue: &Queue<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| {
+            queue.pop(|j| {
+                queue.started();
+                queue.finished();
+                j
+            })
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_block() {
+        let queue = queue(OverflowPolicy::Block);
+
+        queue.push(0, 1);
+        assert!(queue.wait_for_space().now_or_never().is_some());
+        queue.push(0, 2);
+        assert!(queue.wait_for_space().now_or_never().is_none());
+
+        // Jobs pushed without waiting overfill the queue rather than being
+        // dropped
+        assert!(matches!(queue.push(0, 3), Push::Queued));
+        assert_eq!(queue.depth(), 3);
+
+        queue.pop(|_| queue.started());
+        queue.pop(|_| queue.started());
+        assert!(queue.wait_for_space().now_or_never().is_some());
+        assert_eq!(drain(&queue), [3]);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let queue = queue(OverflowPolicy::DropNewest);
+
+        assert!(matches!(queue.push(0, 1), Push::Queued));
+        assert!(matches!(queue.push(0, 2), Push::Queued));
+        assert!(matches!(queue.push(9, 3), Push::Rejected(3)));
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(drain(&queue), [1, 2]);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = queue(OverflowPolicy::DropOldest);
+
+        queue.push(0, 1);
+        queue.push(0, 2);
+        assert!(matches!(queue.push(0, 3), Push::Replaced(1)));
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(drain(&queue), [2, 3]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let queue = queue(OverflowPolicy::Priority);
+        assert_eq!(queue.priority(&["low", "high"]), 5);
+        assert_eq!(queue.priority(&["low"]), 0);
+
+        queue.push(0, 1);
+        queue.push(5, 2);
+        // The oldest of the lowest-priority jobs is dropped, but only for a
+        // job of higher priority
+        assert!(matches!(queue.push(1, 3), Push::Replaced(1)));
+        assert!(matches!(queue.push(1, 4), Push::Rejected(4)));
+        assert!(matches!(queue.push(5, 5), Push::Replaced(3)));
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(drain(&queue), [2, 5]);
+    }
+
+    #[test]
+    fn test_bookkeeping() {
+        let queue = Queue::new(None, OverflowPolicy::DropNewest, HashMap::new());
+
+        queue.push(0, 1);
+        queue.push(0, 2);
+        assert_eq!((queue.depth(), queue.outstanding()), (2, 2));
+
+        // A job leaving the queue is pending until it starts running, and
+        // outstanding until it finishes
+        assert_eq!(queue.pop(|j| j), Some(1));
+        assert_eq!((queue.depth(), queue.outstanding()), (2, 2));
+        queue.started();
+        assert_eq!((queue.depth(), queue.outstanding()), (1, 2));
+        queue.finished();
+        assert_eq!((queue.depth(), queue.outstanding()), (1, 1));
+        assert!(queue.drained().now_or_never().is_none());
+
+        assert_eq!(drain(&queue), [2]);
+        assert_eq!((queue.depth(), queue.outstanding()), (0, 0));
+        assert!(queue.drained().now_or_never().is_some());
+        assert_eq!(queue.pop(|j| j), None);
+    }
+}
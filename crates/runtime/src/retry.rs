@@ -0,0 +1,153 @@
+//! Retrying handlers that return an error.
+//!
+//! Each [`Pipeline`](crate::Pipeline) can be given a [`RetryPolicy`] with
+//! [`Pipeline::retry`](crate::Pipeline::retry).  A handler that fails is
+//! retried on the same value with exponential backoff until it succeeds, the
+//! policy runs out of attempts, or the policy classifies the error as not
+//! retryable.  Only the handlers that failed are retried, and parse errors are
+//! never retried.
+
+use std::{error::Error, fmt, sync::Arc, time::Duration};
+
+use tracing::warn;
+
+use crate::{
+    handler::{BoxedError, HandlerResult},
+    util::Backoff,
+    Chain,
+};
+
+type Classifier = Arc<dyn Fn(&(dyn Error + Send + Sync + 'static)) -> bool + Send + Sync>;
+
+/// A policy for retrying handlers that return an error.
+///
+/// The default policy makes a single attempt, so failed values are never
+/// retried.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retryable: Option<Classifier>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            max_attempts,
+            initial_backoff,
+            max_backoff,
+            multiplier,
+            jitter,
+            retryable,
+        } = self;
+
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", max_attempts)
+            .field("initial_backoff", initial_backoff)
+            .field("max_backoff", max_backoff)
+            .field("multiplier", multiplier)
+            .field("jitter", jitter)
+            .field("retryable", &retryable.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self { Self::new(1) }
+}
+
+impl RetryPolicy {
+    /// Create a policy making at most the given number of attempts on each
+    /// value, including the first.  Every error is retried, starting 100ms
+    /// after the first failure and doubling each time up to 10 seconds.
+    #[must_use]
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retryable: None,
+        }
+    }
+
+    /// Set the delay before the first retry, the upper bound on the delay
+    /// between retries, and the factor the delay is multiplied by after each
+    /// failed retry.
+    #[must_use]
+    pub fn backoff(self, initial: Duration, max: Duration, multiplier: f64) -> Self {
+        Self {
+            initial_backoff: initial,
+            max_backoff: max,
+            multiplier,
+            ..self
+        }
+    }
+
+    /// Set the fraction of each delay to randomly add or subtract.
+    #[must_use]
+    pub fn jitter(self, jitter: f64) -> Self { Self { jitter, ..self } }
+
+    /// Only retry errors for which the given function returns `true`.  Other
+    /// errors fail the value immediately.
+    #[must_use]
+    pub fn retry_if<F: Fn(&(dyn Error + Send + Sync + 'static)) -> bool + Send + Sync + 'static>(
+        self,
+        retryable: F,
+    ) -> Self {
+        Self {
+            retryable: Some(Arc::new(retryable)),
+            ..self
+        }
+    }
+
+    fn is_retryable(&self, err: &(dyn Error + Send + Sync + 'static)) -> bool {
+        self.retryable.as_ref().is_none_or(|f| f(err))
+    }
+
+    /// Begin retrying an operation under this policy.
+    pub(crate) fn start(&self) -> Retry<'_> {
+        let Self {
+            max_attempts: _,
+            initial_backoff,
+            max_backoff,
+            multiplier,
+            jitter,
+            retryable: _,
+        } = *self;
+
+        Retry {
+            policy: self,
+            backoff: Backoff::new(initial_backoff, max_backoff, multiplier, jitter),
+        }
+    }
+}
+
+/// The state of an operation being retried under a [`RetryPolicy`].
+#[derive(Debug)]
+pub(crate) struct Retry<'a> {
+    policy: &'a RetryPolicy,
+    backoff: Backoff,
+}
+
+impl Retry<'_> {
+    /// Record a failed attempt, waiting out the backoff if the operation
+    /// should be attempted again, or returning the error otherwise.
+    pub async fn failed(&mut self, err: BoxedError) -> HandlerResult<()> {
+        let Self { policy, backoff } = self;
+
+        let attempt = backoff.attempts() + 1;
+        if attempt >= policy.max_attempts || !policy.is_retryable(&*err) {
+            return Err(err);
+        }
+
+        let delay = backoff.next_delay();
+        warn!(err = %Chain(&*err), attempt, ?delay, "Handler failed, retrying");
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
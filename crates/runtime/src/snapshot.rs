@@ -0,0 +1,558 @@
+//! Loading the current state of accounts before streaming begins.
+
+use std::collections::HashMap;
+
+use tokio::sync::mpsc;
+use tracing::info;
+use vixen_core::{AccountSelection, CommitmentLevel, DataSlices, Prefilter, Pubkey};
+use yellowstone_grpc_proto::geyser::{
+    subscribe_update::UpdateOneof, SubscribeUpdate, SubscribeUpdateAccount,
+    SubscribeUpdateAccountInfo,
+};
+
+use crate::{
+    config::SnapshotConfig,
+    handler::PipelineSets,
+    source::{FileSource, Pacing, Source},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Snapshot RPC URL given, but the rpc-snapshot feature is not enabled")]
+    RpcUnsupported,
+    #[cfg(feature = "rpc-snapshot")]
+    #[error("Error sending snapshot RPC request")]
+    Http(#[from] reqwest::Error),
+    #[error("Snapshot RPC request failed with code {code}: {message}")]
+    Rpc { code: i64, message: String },
+    #[error("Invalid {0} in snapshot RPC response")]
+    Invalid(&'static str),
+    #[error("Runtime stopped before the snapshot finished loading")]
+    Cancelled,
+}
+
+// Accounts are loaded at most this far ahead of the buffer dispatching them
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A startup account snapshot being loaded in the background, yielding each
+/// account update as it is loaded.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    rx: mpsc::Receiver<SubscribeUpdate>,
+    task: tokio::task::JoinHandle<Result<HashMap<String, u64>, crate::Error>>,
+}
+
+impl Snapshot {
+    /// Receive the next account update, or `None` once every account has
+    /// been loaded or loading failed.  This method is cancel-safe.
+    pub async fn next(&mut self) -> Option<SubscribeUpdate> { self.rx.recv().await }
+
+    /// Wait for loading to complete, returning the slot each pipeline's
+    /// accounts were loaded at.
+    pub async fn finish(self) -> Result<HashMap<String, u64>, crate::Error> {
+        let Self { rx, task } = self;
+        // Unblock the loader if it is still sending, so it can report its
+        // error rather than wait forever
+        drop(rx);
+        task.await.map_err(std::io::Error::from)?
+    }
+}
+
+/// The sending half of a [`Snapshot`].
+struct Loader {
+    tx: mpsc::Sender<SubscribeUpdate>,
+    accounts: usize,
+    slots: HashMap<String, u64>,
+}
+
+/// An account loaded for the snapshot.
+struct Account {
+    pubkey: Pubkey,
+    owner: Pubkey,
+    lamports: u64,
+    executable: bool,
+    rent_epoch: u64,
+    data: Vec<u8>,
+}
+
+impl Account {
+    #[inline]
+    fn matches(&self, selection: &AccountSelection) -> bool {
+        selection.matches(&self.pubkey, &self.owner, &self.data, self.lamports)
+    }
+}
+
+impl Loader {
+    async fn push(
+        &mut self,
+        pipeline: &str,
+        prefilter: &Prefilter,
+        slot: u64,
+        account: Account,
+    ) -> Result<(), Error> {
+        let Account {
+            pubkey,
+            owner,
+            lamports,
+            executable,
+            rent_epoch,
+            data,
+        } = account;
+
+        // Deliver the same data layout the pipeline's subscription does
+        let data = match prefilter.account_data_slices() {
+            Some(s) => slice(s, &data),
+            None => data,
+        };
+
+        let update = SubscribeUpdate {
+            filters: vec![pipeline.to_owned()],
+            update_oneof: Some(UpdateOneof::Account(SubscribeUpdateAccount {
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: pubkey.to_vec(),
+                    lamports,
+                    owner: owner.to_vec(),
+                    executable,
+                    rent_epoch,
+                    data,
+                    write_version: 0,
+                    txn_signature: None,
+                }),
+                slot,
+                is_startup: true,
+            })),
+            created_at: None,
+        };
+
+        self.tx.send(update).await.map_err(|_| Error::Cancelled)?;
+        self.accounts += 1;
+        Ok(())
+    }
+
+    /// Push every account loaded by a single request that matches the
+    /// pipeline's selection.
+    #[cfg(feature = "rpc-snapshot")]
+    async fn push_all(
+        &mut self,
+        pipeline: &str,
+        prefilter: &Prefilter,
+        selection: &AccountSelection<'_>,
+        slot: u64,
+        accounts: Vec<Account>,
+    ) -> Result<(), Error> {
+        for account in accounts {
+            if account.matches(selection) {
+                self.push(pipeline, prefilter, slot, account).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn slice(slices: &DataSlices, data: &[u8]) -> Vec<u8> {
+    slices
+        .iter()
+        .flat_map(|s| {
+            let start = usize::try_from(s.offset)
+                .unwrap_or(usize::MAX)
+                .min(data.len());
+            let len = usize::try_from(s.length).unwrap_or(usize::MAX);
+            &data[start..start.saturating_add(len).min(data.len())]
+        })
+        .copied()
+        .collect()
+}
+
+/// Start loading the startup snapshot for every account pipeline, if a
+/// snapshot source is configured.
+pub(crate) fn load(
+    config: SnapshotConfig,
+    pipelines: &PipelineSets,
+    default_commitment: Option<CommitmentLevel>,
+) -> Option<Snapshot> {
+    let SnapshotConfig {
+        rpc_url,
+        file,
+        timeout,
+    } = config;
+    if rpc_url.is_none() && file.is_none() {
+        return None;
+    }
+
+    let filters: Vec<_> = pipelines
+        .account
+        .filters()
+        .map(|(id, f)| (id.to_owned(), f))
+        .collect();
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    let task = tokio::task::spawn_local(async move {
+        let mut loader = Loader {
+            tx,
+            accounts: 0,
+            slots: HashMap::new(),
+        };
+
+        if let Some(url) = rpc_url {
+            load_rpc(&mut loader, &url, timeout, &filters, default_commitment).await?;
+        } else if let Some(path) = file {
+            let source = FileSource::open(path, Pacing::FullSpeed).await?;
+            load_file(&mut loader, source, &filters).await?;
+        }
+
+        info!(
+            accounts = loader.accounts,
+            "Loaded startup account snapshot"
+        );
+        Ok(loader.slots)
+    });
+
+    Some(Snapshot { rx, task })
+}
+
+async fn load_file(
+    loader: &mut Loader,
+    mut source: FileSource,
+    filters: &[(String, Prefilter)],
+) -> Result<(), crate::Error> {
+    // Keep only the latest write of each account in the recording
+    let mut accounts = HashMap::new();
+    let mut max_slot = 0;
+
+    while let Some(update) = source.next().await {
+        let Some(UpdateOneof::Account(SubscribeUpdateAccount {
+            account: Some(info),
+            slot,
+            ..
+        })) = update?.update_oneof
+        else {
+            continue;
+        };
+        let (Ok(pubkey), Ok(owner)) = (
+            Pubkey::try_from(&*info.pubkey),
+            Pubkey::try_from(&*info.owner),
+        ) else {
+            continue;
+        };
+
+        max_slot = max_slot.max(slot);
+        let version = (slot, info.write_version);
+        match accounts.get(&pubkey) {
+            Some(&(v, _)) if v >= version => (),
+            _ => {
+                accounts.insert(
+                    pubkey,
+                    (version, Account {
+                        pubkey,
+                        owner,
+                        lamports: info.lamports,
+                        executable: info.executable,
+                        rent_epoch: info.rent_epoch,
+                        data: info.data,
+                    }),
+                );
+            },
+        }
+    }
+
+    for (id, prefilter) in filters {
+        let Some(selection) = prefilter.account_selection() else {
+            continue;
+        };
+
+        for ((slot, _), account) in accounts.values() {
+            if account.matches(&selection) {
+                loader
+                    .push(id, prefilter, *slot, Account {
+                        data: account.data.clone(),
+                        ..*account
+                    })
+                    .await?;
+            }
+        }
+        loader.slots.insert(id.clone(), max_slot);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "rpc-snapshot"))]
+#[allow(clippy::unused_async)]
+async fn load_rpc(
+    _: &mut Loader,
+    _: &str,
+    _: u64,
+    _: &[(String, Prefilter)],
+    _: Option<CommitmentLevel>,
+) -> Result<(), crate::Error> {
+    Err(Error::RpcUnsupported.into())
+}
+
+#[cfg(feature = "rpc-snapshot")]
+async fn load_rpc(
+    loader: &mut Loader,
+    url: &str,
+    timeout: u64,
+    filters: &[(String, Prefilter)],
+    default_commitment: Option<CommitmentLevel>,
+) -> Result<(), crate::Error> {
+    let client = rpc::Client::new(url, timeout)?;
+
+    for (id, prefilter) in filters {
+        let Some(selection) = prefilter.account_selection() else {
+            continue;
+        };
+        let commitment = prefilter.commitment().or(default_commitment);
+
+        // Each request may be answered at a different slot, so only updates
+        // newer than the oldest of them are known to be missing
+        let mut min_slot = None::<u64>;
+
+        if !selection.owners.is_empty() {
+            for owner in selection.owners {
+                let (slot, accounts) = client
+                    .get_program_accounts(owner, selection.filters, commitment)
+                    .await?;
+                min_slot = Some(min_slot.map_or(slot, |m| m.min(slot)));
+                loader
+                    .push_all(id, prefilter, &selection, slot, accounts)
+                    .await?;
+            }
+        } else if !selection.accounts.is_empty() {
+            let keys: Vec<_> = selection.accounts.iter().collect();
+            for chunk in keys.chunks(rpc::MAX_MULTIPLE_ACCOUNTS) {
+                let (slot, accounts) = client.get_multiple_accounts(chunk, commitment).await?;
+                min_slot = Some(min_slot.map_or(slot, |m| m.min(slot)));
+                loader
+                    .push_all(id, prefilter, &selection, slot, accounts)
+                    .await?;
+            }
+        } else {
+            tracing::warn!(
+                pipeline = id,
+                "Skipping startup snapshot for pipeline matching every account"
+            );
+            continue;
+        }
+
+        if let Some(slot) = min_slot {
+            loader.slots.insert(id.clone(), slot);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "rpc-snapshot")]
+mod rpc {
+    use std::{collections::HashSet, time::Duration};
+
+    use base64::Engine;
+    use serde::de::DeserializeOwned;
+    use vixen_core::{AccountDataFilter, CommitmentLevel, Pubkey};
+
+    use super::{Account, Error};
+
+    /// The most accounts `getMultipleAccounts` accepts in a single request.
+    pub const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+    #[derive(serde::Deserialize)]
+    struct Response<T> {
+        result: Option<T>,
+        error: Option<RpcError>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct RpcError {
+        code: i64,
+        message: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct WithContext<T> {
+        context: Context,
+        value: T,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Context {
+        slot: u64,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct KeyedAccount {
+        pubkey: String,
+        account: RpcAccount,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RpcAccount {
+        data: (String, String),
+        executable: bool,
+        lamports: u64,
+        owner: String,
+        rent_epoch: u64,
+    }
+
+    impl RpcAccount {
+        fn decode(self, pubkey: Pubkey) -> Result<Account, Error> {
+            let Self {
+                data: (data, _),
+                executable,
+                lamports,
+                owner,
+                rent_epoch,
+            } = self;
+
+            Ok(Account {
+                pubkey,
+                owner: owner.parse().map_err(|_| Error::Invalid("account owner"))?,
+                lamports,
+                executable,
+                rent_epoch,
+                data: base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|_| Error::Invalid("account data"))?,
+            })
+        }
+    }
+
+    fn filter_json(filter: &AccountDataFilter) -> Option<serde_json::Value> {
+        use serde_json::json;
+
+        match filter {
+            AccountDataFilter::Memcmp { offset, bytes } => Some(json!({
+                "memcmp": {
+                    "offset": offset,
+                    "bytes": base64::engine::general_purpose::STANDARD.encode(bytes),
+                    "encoding": "base64",
+                },
+            })),
+            AccountDataFilter::DataSize(size) => Some(json!({ "dataSize": size })),
+            AccountDataFilter::TokenAccountState => Some(json!("tokenAccountState")),
+            // Not supported by the RPC API, so checked once the accounts are
+            // loaded
+            AccountDataFilter::Lamports(_) => None,
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Client {
+        http: reqwest::Client,
+        url: String,
+    }
+
+    impl Client {
+        pub fn new(url: &str, timeout: u64) -> Result<Self, Error> {
+            Ok(Self {
+                http: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(timeout))
+                    .build()?,
+                url: url.to_owned(),
+            })
+        }
+
+        async fn call<T: DeserializeOwned>(
+            &self,
+            method: &str,
+            params: serde_json::Value,
+        ) -> Result<T, Error> {
+            let res: Response<T> = self
+                .http
+                .post(&self.url)
+                .json(&serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": method,
+                    "params": params,
+                }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            match res {
+                Response {
+                    error: Some(RpcError { code, message }),
+                    ..
+                } => Err(Error::Rpc { code, message }),
+                Response {
+                    result: Some(r), ..
+                } => Ok(r),
+                Response { result: None, .. } => Err(Error::Invalid("response")),
+            }
+        }
+
+        fn config(commitment: Option<CommitmentLevel>) -> serde_json::Value {
+            let mut config = serde_json::json!({
+                "encoding": "base64",
+                "withContext": true,
+            });
+            if let Some(c) = commitment {
+                config["commitment"] = c.as_str().into();
+            }
+            config
+        }
+
+        pub async fn get_program_accounts(
+            &self,
+            owner: &Pubkey,
+            filters: &HashSet<AccountDataFilter>,
+            commitment: Option<CommitmentLevel>,
+        ) -> Result<(u64, Vec<Account>), Error> {
+            let mut config = Self::config(commitment);
+            config["filters"] = filters.iter().filter_map(filter_json).collect();
+
+            let WithContext {
+                context: Context { slot },
+                value,
+            } = self
+                .call::<WithContext<Vec<KeyedAccount>>>(
+                    "getProgramAccounts",
+                    serde_json::json!([owner.to_string(), config]),
+                )
+                .await?;
+
+            let accounts = value
+                .into_iter()
+                .map(|KeyedAccount { pubkey, account }| {
+                    let pubkey = pubkey.parse().map_err(|_| Error::Invalid("pubkey"))?;
+                    account.decode(pubkey)
+                })
+                .collect::<Result<_, _>>()?;
+
+            Ok((slot, accounts))
+        }
+
+        pub async fn get_multiple_accounts(
+            &self,
+            keys: &[&Pubkey],
+            commitment: Option<CommitmentLevel>,
+        ) -> Result<(u64, Vec<Account>), Error> {
+            let WithContext {
+                context: Context { slot },
+                value,
+            } = self
+                .call::<WithContext<Vec<Option<RpcAccount>>>>(
+                    "getMultipleAccounts",
+                    serde_json::json!([
+                        keys.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                        Self::config(commitment),
+                    ]),
+                )
+                .await?;
+
+            // Accounts that do not exist are returned as null
+            let accounts = keys
+                .iter()
+                .zip(value)
+                .filter_map(|(&&k, a)| a.map(|a| a.decode(k)))
+                .collect::<Result<_, _>>()?;
+
+            Ok((slot, accounts))
+        }
+    }
+}
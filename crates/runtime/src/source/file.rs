@@ -0,0 +1,519 @@
+//! Recording updates to disk and replaying them.
+//!
+//! Recordings come in two forms:
+//!
+//! - A *plain* recording is a stream of length-delimited protobuf
+//!   [`SubscribeUpdate`]s, readable by any protobuf library.  It carries no
+//!   timing, so it can only be replayed at full speed.
+//! - A *timed* recording, the default, begins with the four bytes `VXT1`,
+//!   followed by a sequence of frames, each holding the time elapsed since
+//!   the recording started in microseconds as a protobuf varint, followed by
+//!   a length-delimited protobuf [`SubscribeUpdate`].
+//!
+//! Either form may optionally be zstd-compressed.  Both the form and the
+//! compression are detected automatically when replaying.
+
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter,
+    },
+    time::Instant,
+};
+use tracing::warn;
+use yellowstone_grpc_proto::{geyser::SubscribeUpdate, prost::Message};
+
+use super::Source;
+use crate::config::RecordConfig;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+// A plain recording cannot begin with these bytes, as a length of 0x56
+// followed by field 11 with a varint wire type is not a valid update
+const TIMED_MAGIC: [u8; 4] = *b"VXT1";
+
+/// An error that can occur while reading or writing a recording.
+#[derive(Debug, thiserror::Error)]
+pub enum FileError {
+    /// A system I/O error.
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    /// A frame in the recording could not be decoded.
+    #[error("Invalid update in recording")]
+    Decode(#[from] yellowstone_grpc_proto::prost::DecodeError),
+    /// A frame in the recording had a malformed header.
+    #[error("Invalid frame header in recording")]
+    Varint,
+    /// The recording ended partway through a frame.
+    #[error("Recording truncated mid-update")]
+    Truncated,
+    /// The recording is compressed, but support for compression was not
+    /// enabled.
+    #[error("Recording is zstd-compressed, but the zstd feature is not enabled")]
+    CompressionUnsupported,
+}
+
+/// The rate at which a [`FileSource`] replays its updates.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pacing {
+    /// Replay updates as fast as they can be read.
+    #[default]
+    FullSpeed,
+    /// Replay updates with the same timing they were recorded with.  Plain
+    /// recordings, which carry no timing, are replayed at full speed.
+    Original,
+}
+
+type Reader = Pin<Box<dyn AsyncBufRead>>;
+type Writer = Pin<Box<dyn AsyncWrite>>;
+type Frame = (Option<Duration>, SubscribeUpdate);
+type ReadFuture<R, T> = Pin<Box<dyn Future<Output = (R, T)>>>;
+
+/// A reader whose reads can be cancelled and picked up again.
+///
+/// Reading a frame takes several reads from the underlying reader, so
+/// dropping a read partway through would lose the bytes already consumed.
+/// Instead the reader is moved into each read, which is kept until it
+/// completes and resumed by the next call if it was cancelled.
+pub(crate) struct Resumable<R, T> {
+    reader: Option<R>,
+    read: Option<ReadFuture<R, T>>,
+}
+
+impl<R: 'static, T: 'static> Resumable<R, T> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Some(reader),
+            read: None,
+        }
+    }
+
+    /// Resume the in-progress read, or if there is none start a new one with
+    /// the given function.
+    pub async fn read<F: Future<Output = (R, T)> + 'static>(
+        &mut self,
+        start: impl FnOnce(R) -> F,
+    ) -> T {
+        let read = self.read.get_or_insert_with(|| {
+            let reader = self
+                .reader
+                .take()
+                .unwrap_or_else(|| unreachable!("Reader missing with no read in progress"));
+            Box::pin(start(reader))
+        });
+
+        let (reader, ret) = read.await;
+        self.read = None;
+        self.reader = Some(reader);
+        ret
+    }
+}
+
+/// A [`Source`] replaying updates from a recording made with the `record`
+/// configuration section.
+pub struct FileSource {
+    path: PathBuf,
+    reader: Resumable<Reader, Result<Option<Frame>, FileError>>,
+    timed: bool,
+    pacing: Pacing,
+    // The wall-clock time the first update was replayed at, and its offset
+    // into the recording
+    start: Option<(Instant, Duration)>,
+    // A frame read but not yet returned, held while waiting for its replay
+    // time
+    pending: Option<Frame>,
+}
+
+impl fmt::Debug for FileSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSource")
+            .field("path", &self.path)
+            .field("timed", &self.timed)
+            .field("pacing", &self.pacing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileSource {
+    /// Open a recording for replay.
+    ///
+    /// # Errors
+    /// Returns an error if the file could not be opened, or is compressed and
+    /// compression support is not enabled.
+    pub async fn open(path: impl AsRef<Path>, pacing: Pacing) -> Result<Self, FileError> {
+        let path = path.as_ref().to_owned();
+        let mut file = BufReader::new(tokio::fs::File::open(&path).await?);
+        let compressed = file.fill_buf().await?.starts_with(&ZSTD_MAGIC);
+
+        let mut reader: Reader = if compressed {
+            zstd::decoder(file)?
+        } else {
+            Box::pin(file)
+        };
+
+        let timed = reader.fill_buf().await?.starts_with(&TIMED_MAGIC);
+        if timed {
+            reader.consume(TIMED_MAGIC.len());
+        } else if pacing == Pacing::Original {
+            warn!(
+                ?path,
+                "Recording has no timing, replaying at full speed instead"
+            );
+        }
+
+        Ok(Self {
+            path,
+            reader: Resumable::new(reader),
+            timed,
+            pacing,
+            start: None,
+            pending: None,
+        })
+    }
+}
+
+async fn read_frame(reader: &mut Reader, timed: bool) -> Result<Option<Frame>, FileError> {
+    let elapsed = if timed {
+        let Some(elapsed) = read_varint(reader).await? else {
+            return Ok(None);
+        };
+        Some(Duration::from_micros(elapsed))
+    } else {
+        None
+    };
+
+    let len = match read_varint(reader).await? {
+        Some(l) => l,
+        None if timed => return Err(FileError::Truncated),
+        None => return Ok(None),
+    };
+    let buf = read_bytes(reader, len).await?;
+
+    Ok(Some((elapsed, SubscribeUpdate::decode(&*buf)?)))
+}
+
+/// Read a varint, returning `None` if the file ended before its first byte.
+pub(crate) async fn read_varint<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+) -> Result<Option<u64>, FileError> {
+    let mut value = 0_u64;
+
+    for i in 0..10 {
+        let byte = match reader.read_u8().await {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return if i == 0 {
+                    Ok(None)
+                } else {
+                    Err(FileError::Truncated)
+                };
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(FileError::Varint)
+}
+
+/// Read a length-delimited byte string in the middle of a frame.
+pub(crate) async fn read_delimited<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+) -> Result<Vec<u8>, FileError> {
+    let len = read_varint(reader).await?.ok_or(FileError::Truncated)?;
+    read_bytes(reader, len).await
+}
+
+/// Read a byte string of the given length in the middle of a frame.
+pub(crate) async fn read_bytes<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+    len: u64,
+) -> Result<Vec<u8>, FileError> {
+    let mut buf = vec![0; usize::try_from(len).map_err(|_| FileError::Truncated)?];
+    reader
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => FileError::Truncated,
+            _ => e.into(),
+        })?;
+
+    Ok(buf)
+}
+
+impl Source for FileSource {
+    async fn next(&mut self) -> Option<Result<SubscribeUpdate, crate::Error>> {
+        let Self {
+            path: _,
+            reader,
+            timed,
+            pacing,
+            start,
+            pending,
+        } = self;
+
+        if pending.is_none() {
+            let timed = *timed;
+            let frame = reader
+                .read(|mut r| async move {
+                    let f = read_frame(&mut r, timed).await;
+                    (r, f)
+                })
+                .await;
+            match frame.transpose()? {
+                Ok(f) => *pending = Some(f),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+
+        // The frame stays pending until the sleep completes, so a cancelled
+        // call sleeps until the same deadline when next called
+        if let (Pacing::Original, Some((Some(elapsed), _))) = (*pacing, pending.as_ref()) {
+            let (start, offset) = *start.get_or_insert_with(|| (Instant::now(), *elapsed));
+            tokio::time::sleep_until(start + elapsed.saturating_sub(offset)).await;
+        }
+
+        pending.take().map(|(_, u)| Ok(u))
+    }
+}
+
+/// Captures the updates received by the runtime to a file that can later be
+/// replayed with a [`FileSource`].
+pub(crate) struct Recorder {
+    writer: Writer,
+    // The time recording started, if the recording is timed
+    start: Option<Instant>,
+    buf: Vec<u8>,
+}
+
+impl Recorder {
+    pub async fn create(config: RecordConfig) -> Result<Option<Self>, FileError> {
+        let RecordConfig {
+            path,
+            compress,
+            plain,
+        } = config;
+        let Some(path) = path else { return Ok(None) };
+
+        let file = BufWriter::new(tokio::fs::File::create(path).await?);
+        let mut writer: Writer = if compress {
+            zstd::encoder(file)?
+        } else {
+            Box::pin(file)
+        };
+
+        if !plain {
+            writer.write_all(&TIMED_MAGIC).await?;
+        }
+
+        Ok(Some(Self {
+            writer,
+            start: (!plain).then(Instant::now),
+            buf: vec![],
+        }))
+    }
+
+    pub async fn record(&mut self, update: &SubscribeUpdate) -> Result<(), FileError> {
+        let Self { writer, start, buf } = self;
+
+        buf.clear();
+        if let Some(start) = start {
+            let elapsed = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+            yellowstone_grpc_proto::prost::encoding::encode_varint(elapsed, buf);
+        }
+        update
+            .encode_length_delimited(buf)
+            .unwrap_or_else(|_| unreachable!("Vec has unbounded capacity"));
+
+        writer.write_all(buf).await?;
+        Ok(())
+    }
+
+    /// Flush any buffered updates and finish the compressed stream, if any.
+    pub async fn finish(mut self) -> Result<(), FileError> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+    use tokio::io::{BufReader, BufWriter};
+
+    use super::{FileError, Reader, Writer};
+
+    #[inline]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn decoder(file: BufReader<tokio::fs::File>) -> Result<Reader, FileError> {
+        Ok(Box::pin(BufReader::new(ZstdDecoder::new(file))))
+    }
+
+    #[inline]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn encoder(file: BufWriter<tokio::fs::File>) -> Result<Writer, FileError> {
+        Ok(Box::pin(ZstdEncoder::new(file)))
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd {
+    use tokio::io::{BufReader, BufWriter};
+
+    use super::{FileError, Reader, Writer};
+
+    #[inline]
+    pub fn decoder(_: BufReader<tokio::fs::File>) -> Result<Reader, FileError> {
+        Err(FileError::CompressionUnsupported)
+    }
+
+    #[inline]
+    pub fn encoder(_: BufWriter<tokio::fs::File>) -> Result<Writer, FileError> {
+        Err(FileError::CompressionUnsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yellowstone_grpc_proto::geyser::{subscribe_update::UpdateOneof, SubscribeUpdateSlot};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vixen-{}-{name}", std::process::id()))
+    }
+
+    fn updates() -> Vec<SubscribeUpdate> {
+        (0..3)
+            .map(|slot| SubscribeUpdate {
+                filters: vec![format!("f{slot}")],
+                update_oneof: Some(UpdateOneof::Slot(SubscribeUpdateSlot {
+                    slot,
+                    ..SubscribeUpdateSlot::default()
+                })),
+                created_at: None,
+            })
+            .collect()
+    }
+
+    async fn record(path: &Path, compress: bool, plain: bool) {
+        let mut recorder = Recorder::create(RecordConfig {
+            path: Some(path.to_owned()),
+            compress,
+            plain,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        for update in updates() {
+            recorder.record(&update).await.unwrap();
+        }
+        recorder.finish().await.unwrap();
+    }
+
+    async fn replay(source: &mut FileSource) -> Vec<Result<SubscribeUpdate, crate::Error>> {
+        let mut ret = vec![];
+        while let Some(res) = source.next().await {
+            ret.push(res);
+        }
+        ret
+    }
+
+    async fn round_trip(name: &str, compress: bool, plain: bool) {
+        let path = temp_path(name);
+        record(&path, compress, plain).await;
+
+        let mut source = FileSource::open(&path, Pacing::FullSpeed).await.unwrap();
+        assert_eq!(source.timed, !plain);
+        let replayed: Vec<_> = replay(&mut source)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(replayed, updates());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        round_trip("plain", false, true).await;
+        round_trip("timed", false, false).await;
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_round_trip_zstd() {
+        round_trip("plain.zst", true, true).await;
+        round_trip("timed.zst", true, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_delimited() {
+        let mut buf = vec![];
+        yellowstone_grpc_proto::prost::encoding::encode_varint(300, &mut buf);
+        buf.extend([3, 1, 2, 3, 0]);
+
+        let mut reader = &buf[..];
+        assert_eq!(read_varint(&mut reader).await.unwrap(), Some(300));
+        assert_eq!(read_delimited(&mut reader).await.unwrap(), [1, 2, 3]);
+        assert_eq!(read_delimited(&mut reader).await.unwrap(), [0; 0]);
+        assert_eq!(read_varint(&mut reader).await.unwrap(), None);
+
+        assert!(matches!(
+            read_varint(&mut &[0x80][..]).await,
+            Err(FileError::Truncated)
+        ));
+        assert!(matches!(
+            read_varint(&mut &[0xff; 10][..]).await,
+            Err(FileError::Varint)
+        ));
+        assert!(matches!(
+            read_delimited(&mut &[3, 1, 2][..]).await,
+            Err(FileError::Truncated)
+        ));
+        assert!(matches!(
+            read_delimited(&mut &[][..]).await,
+            Err(FileError::Truncated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        for plain in [true, false] {
+            let path = temp_path(&format!("truncated-{plain}"));
+            record(&path, false, plain).await;
+            let len = std::fs::metadata(&path).unwrap().len();
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(len - 1)
+                .unwrap();
+
+            let mut source = FileSource::open(&path, Pacing::FullSpeed).await.unwrap();
+            let mut replayed = replay(&mut source).await;
+            assert!(matches!(
+                replayed.pop(),
+                Some(Err(crate::Error::File(FileError::Truncated)))
+            ));
+            let replayed: Vec<_> = replayed.into_iter().map(Result::unwrap).collect();
+            assert_eq!(replayed, updates()[..2]);
+            // The replay ends after the truncated record
+            assert!(source.next().await.is_none());
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
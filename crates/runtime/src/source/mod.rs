@@ -0,0 +1,92 @@
+//! Sources of updates for the Vixen runtime.
+//!
+//! By default the runtime streams updates live from Yellowstone.  Any other
+//! [`Source`], such as a [`FileSource`] replaying a recording, can be run
+//! through the same pipelines with [`Runtime::try_replay`](crate::Runtime::try_replay).
+
+use std::{fmt, future::Future, sync::Arc};
+
+use vixen_core::Filters;
+use yellowstone_grpc_proto::geyser::SubscribeUpdate;
+
+pub use self::file::*;
+use crate::{
+    buffer::SharedSlots,
+    metrics::{Counters, Instrumenter},
+    yellowstone,
+};
+
+pub(crate) mod file;
+
+/// A stream of updates to run through the pipelines of a
+/// [`Runtime`](crate::Runtime).
+pub trait Source {
+    /// Receive the next update, or `None` if the source has been exhausted.
+    ///
+    /// This method must be cancel-safe: the runtime waits on it alongside
+    /// stop requests and runtime changes, and drops the returned future if
+    /// either arrives first.  An update that was partway through being
+    /// received when that happens must be returned by the next call rather
+    /// than lost.
+    fn next(&mut self) -> impl Future<Output = Option<Result<SubscribeUpdate, crate::Error>>>;
+
+    /// Notify the source that the filters of the running pipelines have
+    /// changed.  Sources that cannot be filtered, such as recordings, may
+    /// ignore this.
+    fn update(&mut self, filters: Filters<'_>) -> impl Future<Output = Result<(), crate::Error>> {
+        let _ = filters;
+        async { Ok(()) }
+    }
+}
+
+/// The default source, streaming live from one or more Yellowstone
+/// endpoints.
+///
+/// This source is created by the runtime from its `yellowstone`
+/// configuration section when it is run with
+/// [`Runtime::run`](crate::Runtime::run) or similar.
+pub struct GrpcSource<M: Instrumenter> {
+    client: yellowstone::Client,
+    counters: Arc<Counters<M>>,
+    slots: SharedSlots,
+}
+
+impl<M: Instrumenter> fmt::Debug for GrpcSource<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcSource").finish_non_exhaustive()
+    }
+}
+
+impl<M: Instrumenter> GrpcSource<M> {
+    pub(crate) fn new(
+        client: yellowstone::Client,
+        counters: Arc<Counters<M>>,
+        slots: SharedSlots,
+    ) -> Self {
+        Self {
+            client,
+            counters,
+            slots,
+        }
+    }
+}
+
+impl<M: Instrumenter> Source for GrpcSource<M> {
+    async fn next(&mut self) -> Option<Result<SubscribeUpdate, crate::Error>> {
+        // The gRPC client reconnects on its own, so it only stops by failing
+        Some(self.client.next(&self.counters).await)
+    }
+
+    async fn update(&mut self, filters: Filters<'_>) -> Result<(), crate::Error> {
+        let Self {
+            client,
+            counters,
+            slots,
+        } = self;
+
+        client
+            .update(filters, counters, slots)
+            .await
+            .map_err(Into::into)
+    }
+}
@@ -2,7 +2,7 @@ use std::{collections::HashMap, fmt::Debug};
 
 use tokio::sync::broadcast;
 use vixen_core::{
-    instruction::InstructionUpdate, AccountUpdate, Parser, ProgramParser, Pubkey, TransactionUpdate,
+    instruction::InstructionUpdate, AccountUpdate, ProgramParser, Pubkey, TransactionUpdate,
 };
 use yellowstone_vixen_proto::{
     prost::{Message, Name},
@@ -38,11 +38,11 @@ pub struct StreamKind<'a>(Vec<&'a [u8]>, Channels<HashMap<String, Receiver>>);
 /// A builder for the [`Server`] type.
 pub type StreamBuilder<'a, M = NullMetrics> = Builder<StreamKind<'a>, M>;
 
-impl<'a> BuilderKind for StreamKind<'a> {
+impl BuilderKind for StreamKind<'_> {
     type Error = BuilderError;
 }
 
-fn wrap_parser<P: Debug + Parser + Send + Sync + 'static>(
+fn wrap_parser<P: Debug + ProgramParser + Send + Sync + 'static>(
     parser: P,
     tx: broadcast::Sender<Any>,
 ) -> BoxPipeline<'static, P::Input>
@@ -50,7 +50,7 @@ where
     P::Input: Sync,
     P::Output: Message + Name + Send + Sync,
 {
-    Box::new(Pipeline::new(parser, [GrpcHandler(tx)]))
+    Box::new(Pipeline::for_program(parser, [GrpcHandler(tx)]))
 }
 
 impl<'a, M: MetricsFactory> StreamBuilder<'a, M> {
@@ -148,6 +148,14 @@ impl<'a, M: MetricsFactory> StreamBuilder<'a, M> {
             account,
             transaction,
             instruction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            checkpoint,
+            dead_letters,
+            dispatch_key,
             metrics,
             extra: StreamKind(desc_sets, channels),
         } = self;
@@ -168,6 +176,14 @@ impl<'a, M: MetricsFactory> StreamBuilder<'a, M> {
             account,
             transaction,
             instruction,
+            slot,
+            block,
+            block_meta,
+            transaction_status,
+            entry,
+            checkpoint,
+            dead_letters,
+            dispatch_key,
             metrics,
             extra: RuntimeKind,
         }
@@ -1,4 +1,4 @@
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, time::Duration};
 
 #[inline]
 pub(crate) fn handle_fatal<T, E: Error>(res: Result<T, E>) -> T {
@@ -21,6 +21,59 @@ pub(crate) fn tokio_runtime() -> Result<tokio::runtime::Runtime, std::io::Error>
         .build()
 }
 
+/// Exponential backoff with random jitter.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, multiplier: f64, jitter: f64) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier: multiplier.max(1.0),
+            jitter: jitter.clamp(0.0, 1.0),
+            attempt: 0,
+        }
+    }
+
+    /// The number of delays handed out since the last reset.
+    #[inline]
+    pub fn attempts(&self) -> u32 { self.attempt }
+
+    #[inline]
+    pub fn reset(&mut self) { self.attempt = 0; }
+
+    /// Compute the delay before the next attempt and advance the attempt
+    /// counter.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = i32::try_from(self.attempt).unwrap_or(i32::MAX);
+        let base =
+            (self.initial.as_secs_f64() * self.multiplier.powi(exp)).min(self.max.as_secs_f64());
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jitter = base * self.jitter * random_unit().mul_add(2.0, -1.0);
+        Duration::from_secs_f64((base + jitter).max(0.0))
+    }
+}
+
+/// Produce a pseudorandom number in the range `[0, 1)` without pulling in a
+/// dedicated RNG.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn random_unit() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let bits = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (bits >> 11) as f64 / (1_u64 << 53) as f64
+}
+
 /// A helper type for preventing sensitive strings from being printed.
 #[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Deserialize)]
 #[repr(transparent)]
@@ -53,9 +106,9 @@ impl From<PrivateString> for String {
 }
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct Chain<'a, E>(pub &'a E);
+pub(crate) struct Chain<'a, E: ?Sized>(pub &'a E);
 
-impl<'a, E: Error> fmt::Display for Chain<'a, E> {
+impl<E: Error + ?Sized> fmt::Display for Chain<'_, E> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use fmt::Write;
 
@@ -71,7 +124,7 @@ impl<'a, E: Error> fmt::Display for Chain<'a, E> {
             state: IndentState,
         }
 
-        impl<'a, F: Write> Indented<'a, F> {
+        impl<F: Write> Indented<'_, F> {
             fn write_pad(&mut self) -> fmt::Result {
                 match std::mem::replace(&mut self.state, IndentState::MidLine) {
                     IndentState::NumberStart(i) => write!(self.f, "{i: >5}: "),
@@ -82,7 +135,7 @@ impl<'a, E: Error> fmt::Display for Chain<'a, E> {
             }
         }
 
-        impl<'a, F: Write> Write for Indented<'a, F> {
+        impl<F: Write> Write for Indented<'_, F> {
             fn write_str(&mut self, mut s: &str) -> fmt::Result {
                 while let Some((head, tail)) = s.split_once('\n') {
                     if !head.is_empty() {
@@ -213,3 +266,50 @@ pub mod stop {
         (StopTx(tx), StopRx(rx))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_growth() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 2.0, 0.0);
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+
+        // Multipliers below one never shrink the delay
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 0.5, 0.0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let secs = Duration::from_secs_f64;
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8), 2.0, 0.25);
+
+        for _ in 0..100 {
+            backoff.reset();
+            let first = backoff.next_delay();
+            assert!((secs(0.75)..=secs(1.25)).contains(&first), "{first:?}");
+
+            // Jitter applies around the capped delay
+            for _ in 0..4 {
+                backoff.next_delay();
+            }
+            let capped = backoff.next_delay();
+            assert!((secs(6.0)..=secs(10.0)).contains(&capped), "{capped:?}");
+        }
+
+        // Jitter is clamped to at most the full delay
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(1), 2.0, 5.0);
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= secs(2.0));
+        }
+    }
+}
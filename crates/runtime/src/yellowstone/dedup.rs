@@ -0,0 +1,179 @@
+//! De-duplication of updates received from redundant Yellowstone streams.
+
+use std::collections::{BTreeMap, HashSet};
+
+use yellowstone_grpc_proto::geyser::{subscribe_update::UpdateOneof, SubscribeUpdate};
+
+/// Identity of an update within its slot.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Key {
+    /// An account write, keyed by pubkey and write version.
+    Account(Vec<u8>, u64),
+    /// A transaction, keyed by signature.
+    Transaction(Vec<u8>),
+    /// A slot status transition, keyed by status.
+    Slot(i32),
+    /// A block, of which there is at most one per slot.
+    Block,
+    /// Block metadata, of which there is at most one per slot.
+    BlockMeta,
+    /// A transaction status, keyed by signature.
+    TransactionStatus(Vec<u8>),
+    /// A ledger entry, keyed by its index within the slot.
+    Entry(u64),
+}
+
+impl Key {
+    fn get(update: &SubscribeUpdate) -> Option<(u64, Self)> {
+        match update.update_oneof.as_ref()? {
+            UpdateOneof::Account(a) => {
+                let info = a.account.as_ref()?;
+                Some((
+                    a.slot,
+                    Self::Account(info.pubkey.clone(), info.write_version),
+                ))
+            },
+            UpdateOneof::Transaction(t) => {
+                let info = t.transaction.as_ref()?;
+                Some((t.slot, Self::Transaction(info.signature.clone())))
+            },
+            UpdateOneof::Slot(s) => Some((s.slot, Self::Slot(s.status))),
+            UpdateOneof::Block(b) => Some((b.slot, Self::Block)),
+            UpdateOneof::BlockMeta(b) => Some((b.slot, Self::BlockMeta)),
+            UpdateOneof::TransactionStatus(t) => {
+                Some((t.slot, Self::TransactionStatus(t.signature.clone())))
+            },
+            UpdateOneof::Entry(e) => Some((e.slot, Self::Entry(e.index))),
+            _ => None,
+        }
+    }
+}
+
+/// A sliding window of recently-seen updates, bucketed by slot.
+#[derive(Debug)]
+pub(super) struct Dedup {
+    window: u64,
+    slots: BTreeMap<u64, HashSet<Key>>,
+}
+
+impl Dedup {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// Record an update, returning `true` if it has not been seen before.
+    ///
+    /// Updates without an identity (e.g. pings) are always passed through.
+    /// Updates for slots that have already left the window are assumed to be
+    /// late copies and are rejected.
+    pub fn insert(&mut self, update: &SubscribeUpdate) -> bool {
+        let Some((slot, key)) = Key::get(update) else {
+            return true;
+        };
+
+        let newest = self
+            .slots
+            .last_key_value()
+            .map_or(slot, |(&s, _)| s.max(slot));
+        let oldest = newest.saturating_sub(self.window);
+
+        if slot < oldest {
+            return false;
+        }
+
+        let fresh = self.slots.entry(slot).or_default().insert(key);
+
+        while let Some(entry) = self.slots.first_entry() {
+            if *entry.key() >= oldest {
+                break;
+            }
+            entry.remove();
+        }
+
+        fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yellowstone_grpc_proto::geyser::{
+        SubscribeUpdateAccount, SubscribeUpdateAccountInfo, SubscribeUpdatePing,
+        SubscribeUpdateSlot,
+    };
+
+    use super::*;
+
+    fn account(slot: u64, pubkey: u8, write_version: u64) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec![],
+            update_oneof: Some(UpdateOneof::Account(SubscribeUpdateAccount {
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: vec![pubkey; 32],
+                    write_version,
+                    ..Default::default()
+                }),
+                slot,
+                is_startup: false,
+            })),
+            created_at: None,
+        }
+    }
+
+    fn slot(slot: u64, status: i32) -> SubscribeUpdate {
+        SubscribeUpdate {
+            filters: vec![],
+            update_oneof: Some(UpdateOneof::Slot(SubscribeUpdateSlot {
+                slot,
+                status,
+                ..Default::default()
+            })),
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn test_duplicates_rejected() {
+        let mut dedup = Dedup::new(10);
+
+        assert!(dedup.insert(&account(5, 1, 0)));
+        assert!(!dedup.insert(&account(5, 1, 0)));
+        assert!(dedup.insert(&account(5, 1, 1)));
+        assert!(dedup.insert(&account(5, 2, 0)));
+        assert!(dedup.insert(&account(6, 1, 0)));
+
+        assert!(dedup.insert(&slot(5, 0)));
+        assert!(!dedup.insert(&slot(5, 0)));
+        assert!(dedup.insert(&slot(5, 1)));
+    }
+
+    #[test]
+    fn test_unidentified_passed_through() {
+        let mut dedup = Dedup::new(10);
+        let ping = SubscribeUpdate {
+            filters: vec![],
+            update_oneof: Some(UpdateOneof::Ping(SubscribeUpdatePing {})),
+            created_at: None,
+        };
+
+        assert!(dedup.insert(&ping));
+        assert!(dedup.insert(&ping));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut dedup = Dedup::new(10);
+
+        assert!(dedup.insert(&account(5, 1, 0)));
+        assert!(dedup.insert(&account(15, 1, 0)));
+        assert!(!dedup.insert(&account(5, 1, 0)));
+
+        // Slots behind the window are pruned and late copies rejected
+        assert!(dedup.insert(&account(16, 1, 0)));
+        assert!(!dedup.slots.contains_key(&5));
+        assert!(!dedup.insert(&account(5, 2, 0)));
+        assert!(dedup.insert(&account(6, 2, 0)));
+    }
+}
@@ -0,0 +1,730 @@
+use std::{collections::HashMap, pin::Pin, rc::Rc, sync::Arc, time::Duration};
+
+use futures_channel::mpsc::SendError;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+use tracing::{error, info, warn};
+use vixen_core::{CommitmentLevel, Filters, SubscriptionKey};
+use yellowstone_grpc_client::GeyserGrpcClient;
+use yellowstone_grpc_proto::{
+    geyser::{self, subscribe_update::UpdateOneof},
+    prelude::*,
+    tonic::{transport::ClientTlsConfig, Status},
+};
+
+use self::dedup::Dedup;
+use crate::{
+    buffer::{lock_slots, SharedSlots},
+    checkpoint,
+    config::{EndpointConfig, ReconnectConfig, RedundancyMode, YellowstoneConfig},
+    metrics::{Counters, Instrumenter},
+    util::Backoff,
+    Chain,
+};
+
+mod dedup;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Yellowstone client builder error")]
+    Builder(#[from] yellowstone_grpc_client::GeyserGrpcBuilderError),
+    #[error("Yellowstone client error")]
+    Client(#[from] yellowstone_grpc_client::GeyserGrpcClientError),
+}
+
+type SubscribeSink = Pin<Box<dyn Sink<SubscribeRequest, Error = SendError>>>;
+type UpdateStream = Pin<Box<dyn Stream<Item = Result<SubscribeUpdate, Status>>>>;
+
+pub struct YellowstoneStream {
+    sub_tx: SubscribeSink,
+    stream: UpdateStream,
+}
+
+pub async fn connect(
+    endpoint: EndpointConfig,
+    timeout: u64,
+    request: SubscribeRequest,
+) -> Result<YellowstoneStream, Error> {
+    let EndpointConfig { endpoint, x_token } = endpoint;
+    let timeout = Duration::from_secs(timeout);
+
+    // TODO: where are the docs on this stuff?
+    let mut client = GeyserGrpcClient::build_from_shared(endpoint)?
+        .x_token(x_token)?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .tls_config(ClientTlsConfig::new().with_native_roots())?
+        .connect()
+        .await?;
+
+    let (sub_tx, stream) = client.subscribe_with_request(Some(request)).await?;
+
+    Ok(YellowstoneStream {
+        sub_tx: Box::pin(sub_tx),
+        stream: Box::pin(stream),
+    })
+}
+
+/// Get the slot an update was produced in, if it carries one.
+pub fn update_slot(update: &SubscribeUpdate) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::Account(a) => Some(a.slot),
+        UpdateOneof::Transaction(t) => Some(t.slot),
+        UpdateOneof::Slot(s) => Some(s.slot),
+        UpdateOneof::Block(b) => Some(b.slot),
+        UpdateOneof::BlockMeta(b) => Some(b.slot),
+        UpdateOneof::TransactionStatus(t) => Some(t.slot),
+        UpdateOneof::Entry(e) => Some(e.slot),
+        _ => None,
+    }
+}
+
+// Updates are forwarded from the endpoint tasks through a bounded channel so
+// a slow consumer applies backpressure to the gRPC streams.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Identifies a single subscription: the request group it streams and the
+/// endpoint it streams from.
+#[derive(Debug, Clone, Copy)]
+struct Sub {
+    group: usize,
+    endpoint: usize,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Message {
+    Update(Sub, SubscribeUpdate),
+    Connected(Sub),
+    Disconnected(Sub),
+    Failed(Sub, Error),
+}
+
+/// Connection details shared by every endpoint task.
+struct Shared {
+    endpoints: Vec<EndpointConfig>,
+    timeout: u64,
+}
+
+impl Shared {
+    #[inline]
+    async fn connect(
+        &self,
+        endpoint: usize,
+        request: SubscribeRequest,
+    ) -> Result<YellowstoneStream, Error> {
+        connect(self.endpoints[endpoint].clone(), self.timeout, request).await
+    }
+}
+
+/// A task that streams from one or more endpoints, re-establishing the
+/// subscription with exponential backoff whenever it ends or fails.
+///
+/// In failover mode a single feeder per request group rotates through every
+/// endpoint; in hot-hot mode each endpoint is given its own feeder per group.
+struct Feeder<M: Instrumenter> {
+    shared: Rc<Shared>,
+    group: usize,
+    // The latest subscription request for this feeder's group, forwarded to
+    // the server whenever it changes
+    request: watch::Receiver<SubscribeRequest>,
+    candidates: Vec<usize>,
+    pos: usize,
+    backoff: Backoff,
+    max_attempts: Option<u32>,
+    tx: mpsc::Sender<Message>,
+    counters: Arc<Counters<M>>,
+    slots: SharedSlots,
+    // Whether to ask the server to replay the slots this feeder's group has
+    // not finished processing when reconnecting
+    replay: bool,
+    // Whether the current stream was opened with a replay request
+    replaying: bool,
+}
+
+impl<M: Instrumenter> Feeder<M> {
+    #[inline]
+    fn current(&self) -> Sub {
+        Sub {
+            group: self.group,
+            endpoint: self.candidates[self.pos],
+        }
+    }
+
+    #[inline]
+    fn advance(&mut self) { self.pos = (self.pos + 1) % self.candidates.len(); }
+
+    async fn run(mut self, mut stream: Option<YellowstoneStream>) {
+        // Whether the current stream has delivered any updates
+        let mut received = false;
+
+        loop {
+            let Some(s) = stream.as_mut() else {
+                match self.reconnect().await {
+                    Ok(s) => {
+                        stream = Some(s);
+                        received = false;
+                    },
+                    Err(e) => {
+                        self.tx.send(Message::Failed(self.current(), e)).await.ok();
+                        break;
+                    },
+                }
+                continue;
+            };
+
+            let sub = self.current();
+
+            let err = tokio::select! {
+                u = s.stream.next() => match u {
+                    Some(Ok(u)) => {
+                        received = true;
+                        self.replay = true;
+                        if self.tx.send(Message::Update(sub, u)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    },
+                    Some(Err(e)) => crate::Error::YellowstoneStatus(e),
+                    None => crate::Error::ServerHangup,
+                },
+                r = self.request.changed() => {
+                    // The client was dropped
+                    let Ok(()) = r else { break };
+
+                    let request = self.request.borrow_and_update().clone();
+                    if s.sub_tx.send(request).await.is_ok() {
+                        info!(
+                            endpoint = sub.endpoint,
+                            group = sub.group,
+                            "Updated Yellowstone subscription"
+                        );
+                        continue;
+                    }
+                    crate::Error::ServerHangup
+                },
+            };
+
+            warn!(
+                err = %Chain(&err),
+                endpoint = sub.endpoint,
+                group = sub.group,
+                "Yellowstone stream interrupted",
+            );
+
+            // A server that does not retain the requested slots fails the
+            // subscription outright, so stop requesting a replay until a
+            // stream succeeds without one
+            if self.replaying && !received {
+                warn!(
+                    endpoint = sub.endpoint,
+                    group = sub.group,
+                    "Yellowstone replay unavailable, resuming from the latest slot"
+                );
+                self.replay = false;
+            }
+
+            stream = None;
+            if self.tx.send(Message::Disconnected(sub)).await.is_err() {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<YellowstoneStream, Error> {
+        loop {
+            let sub = self.current();
+            let delay = self.backoff.next_delay();
+            let attempt = self.backoff.attempts();
+            info!(
+                ?delay,
+                attempt,
+                endpoint = sub.endpoint,
+                group = sub.group,
+                "Reconnecting to Yellowstone"
+            );
+            tokio::time::sleep(delay).await;
+            self.counters.inc_reconnect_attempts();
+
+            // Read after sleeping, so jobs completed in the meantime are not
+            // replayed
+            let from_slot = if self.replay {
+                checkpoint::from_slot(lock_slots(&self.slots).group_processed(self.group))
+            } else {
+                None
+            };
+            let mut request = self.request.borrow_and_update().clone();
+            request.from_slot = from_slot;
+
+            match self.shared.connect(sub.endpoint, request).await {
+                Ok(s) => {
+                    self.replaying = from_slot.is_some();
+                    self.backoff.reset();
+                    self.counters.inc_reconnects();
+                    info!(
+                        attempt,
+                        endpoint = sub.endpoint,
+                        group = sub.group,
+                        ?from_slot,
+                        "Reconnected to Yellowstone"
+                    );
+                    self.tx.send(Message::Connected(sub)).await.ok();
+                    break Ok(s);
+                },
+                Err(e) if self.max_attempts.is_some_and(|m| attempt >= m) => break Err(e),
+                Err(e) => {
+                    warn!(
+                        err = %Chain(&e),
+                        attempt,
+                        endpoint = sub.endpoint,
+                        group = sub.group,
+                        "Reconnect attempt failed"
+                    );
+                    self.advance();
+                },
+            }
+        }
+    }
+}
+
+/// Stream state for a single request group.
+struct Group {
+    key: SubscriptionKey,
+    request: watch::Sender<SubscribeRequest>,
+    dedup: Option<Dedup>,
+    connected: Vec<bool>,
+    highest_slots: Vec<Option<u64>>,
+    // The number of slots each connected endpoint trails the leader by
+    slot_lags: Vec<u64>,
+    // Highest slot received before every endpoint went down, used to detect
+    // slots missed while reconnecting
+    gap_from: Option<u64>,
+    // Number of feeders that have not yet given up
+    feeders: usize,
+}
+
+/// A set of Yellowstone subscriptions spanning one or more redundant
+/// endpoints, with one subscription per requested commitment level and set
+/// of account data slices.
+pub struct Client {
+    shared: Rc<Shared>,
+    redundancy: RedundancyMode,
+    dedup_window: u64,
+    default_commitment: Option<CommitmentLevel>,
+    reconnect: ReconnectConfig,
+    // The checkpointed slot the runtime is resuming from, used to request a
+    // replay of the slots after it and to detect any missed while it was
+    // stopped
+    resume_from: Option<u64>,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+    // Dropping the set aborts the endpoint tasks
+    tasks: JoinSet<()>,
+    groups: Vec<Group>,
+}
+
+impl Client {
+    /// Open the initial subscriptions.  If no endpoint can be reached for a
+    /// subscription the error is returned immediately rather than retried, so
+    /// misconfiguration fails fast.
+    pub async fn connect<M: Instrumenter>(
+        config: YellowstoneConfig,
+        filters: Filters<'_>,
+        resume_from: Option<u64>,
+        counters: &Arc<Counters<M>>,
+        slots: &SharedSlots,
+    ) -> Result<Self, Error> {
+        let endpoints = config.endpoints();
+        let YellowstoneConfig {
+            timeout,
+            redundancy,
+            dedup_window,
+            commitment,
+            reconnect,
+            ..
+        } = config;
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut client = Self {
+            shared: Rc::new(Shared { endpoints, timeout }),
+            redundancy,
+            dedup_window,
+            default_commitment: commitment,
+            reconnect,
+            resume_from,
+            tx,
+            rx,
+            tasks: JoinSet::new(),
+            groups: vec![],
+        };
+
+        let mut requests = client.requests(filters);
+        if requests.is_empty() {
+            let key = SubscriptionKey {
+                commitment,
+                account_data_slices: None,
+            };
+            let request = empty_request(&key);
+            requests.push((key, request));
+        }
+
+        for (key, request) in requests {
+            client.open(key, request, counters, slots).await?;
+        }
+        client.resume_from = None;
+
+        Ok(client)
+    }
+
+    /// Replace the filters of every subscription, sending the new requests
+    /// over the existing streams.  Subscriptions no longer requested are left
+    /// open with no filters, and new subscriptions are opened for
+    /// newly-requested commitment levels or account data slices.
+    pub async fn update<M: Instrumenter>(
+        &mut self,
+        filters: Filters<'_>,
+        counters: &Arc<Counters<M>>,
+        slots: &SharedSlots,
+    ) -> Result<(), Error> {
+        let mut requests = self.requests(filters);
+
+        for (i, group) in self.groups.iter().enumerate() {
+            let request = requests
+                .iter()
+                .position(|(k, _)| *k == group.key)
+                .map_or_else(|| empty_request(&group.key), |i| requests.remove(i).1);
+            lock_slots(slots).assign(i, filter_names(&request));
+            group.request.send_replace(request);
+        }
+
+        for (key, request) in requests {
+            self.open(key, request, counters, slots).await?;
+        }
+
+        Ok(())
+    }
+
+    fn requests(&self, filters: Filters<'_>) -> Vec<(SubscriptionKey, SubscribeRequest)> {
+        let mut requests: Vec<_> = filters
+            .by_subscription(self.default_commitment)
+            .into_iter()
+            .map(|(k, f)| (k, f.into()))
+            .collect();
+        requests.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        requests
+    }
+
+    /// Open a new request group, connecting to every endpoint it streams from
+    /// and spawning its feeders.
+    #[allow(clippy::too_many_lines)]
+    async fn open<M: Instrumenter>(
+        &mut self,
+        key: SubscriptionKey,
+        request: SubscribeRequest,
+        counters: &Arc<Counters<M>>,
+        slots: &SharedSlots,
+    ) -> Result<(), Error> {
+        let group = self.groups.len();
+        let len = self.shared.endpoints.len();
+        info!(
+            commitment = key.commitment.map_or("default", CommitmentLevel::as_str),
+            sliced = key.account_data_slices.is_some(),
+            group,
+            "Subscribing to Yellowstone"
+        );
+
+        // The initial subscription asks for a replay of everything after the
+        // checkpoint, if resuming from one
+        let from_slot = checkpoint::from_slot(self.resume_from);
+        let first_request = SubscribeRequest {
+            from_slot,
+            ..request.clone()
+        };
+
+        // Each feeder is given a list of endpoint indices to rotate through
+        // and, if the initial connection succeeded, the stream to start with
+        let feeders: Vec<(Vec<usize>, usize, Option<YellowstoneStream>)> = match self.redundancy {
+            RedundancyMode::Failover => {
+                let mut first_err = None;
+                let mut initial = None;
+                for i in 0..len {
+                    match self.shared.connect(i, first_request.clone()).await {
+                        Ok(s) => {
+                            initial = Some((i, s));
+                            break;
+                        },
+                        Err(e) => {
+                            warn!(
+                                err = %Chain(&e),
+                                endpoint = i,
+                                group,
+                                "Error connecting to Yellowstone"
+                            );
+                            first_err.get_or_insert(e);
+                        },
+                    }
+                }
+
+                let Some((pos, stream)) = initial else {
+                    return Err(first_err.unwrap_or_else(|| unreachable!()));
+                };
+                vec![((0..len).collect(), pos, Some(stream))]
+            },
+            RedundancyMode::HotHot => {
+                let streams = futures_util::future::join_all(
+                    (0..len).map(|i| self.shared.connect(i, first_request.clone())),
+                )
+                .await;
+                if streams.iter().all(Result::is_err) {
+                    return Err(streams
+                        .into_iter()
+                        .find_map(Result::err)
+                        .unwrap_or_else(|| unreachable!()));
+                }
+
+                streams
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, s)| {
+                        let s = s
+                            .inspect_err(|e| {
+                                warn!(
+                                    err = %Chain(e),
+                                    endpoint = i,
+                                    group,
+                                    "Error connecting to Yellowstone"
+                                );
+                            })
+                            .ok();
+                        (vec![i], 0, s)
+                    })
+                    .collect()
+            },
+        };
+
+        let mut connected = vec![false; len];
+        for (candidates, pos, stream) in &feeders {
+            if stream.is_some() {
+                connected[candidates[*pos]] = true;
+            }
+        }
+
+        lock_slots(slots).assign(group, filter_names(&request));
+        let (request_tx, request_rx) = watch::channel(request);
+        self.groups.push(Group {
+            key,
+            request: request_tx,
+            dedup: (self.redundancy == RedundancyMode::HotHot && len > 1)
+                .then(|| Dedup::new(self.dedup_window)),
+            connected,
+            highest_slots: vec![None; len],
+            slot_lags: vec![0; len],
+            gap_from: self.resume_from,
+            feeders: feeders.len(),
+        });
+        for i in 0..len {
+            self.update_connected(i, counters);
+        }
+
+        let ReconnectConfig {
+            max_attempts,
+            initial_backoff,
+            max_backoff,
+            multiplier,
+            jitter,
+        } = self.reconnect;
+        for (candidates, pos, stream) in feeders {
+            let feeder = Feeder {
+                shared: Rc::clone(&self.shared),
+                group,
+                request: request_rx.clone(),
+                candidates,
+                pos,
+                backoff: Backoff::new(
+                    Duration::from_millis(initial_backoff),
+                    Duration::from_millis(max_backoff),
+                    multiplier,
+                    jitter,
+                ),
+                max_attempts,
+                tx: self.tx.clone(),
+                counters: Arc::clone(counters),
+                slots: Arc::clone(slots),
+                replay: true,
+                replaying: from_slot.is_some() && stream.is_some(),
+            };
+            self.tasks.spawn_local(feeder.run(stream));
+        }
+
+        Ok(())
+    }
+
+    /// Receive the next update from any subscription.
+    ///
+    /// # Errors
+    /// Returns an error once every endpoint for any one subscription has
+    /// exhausted its reconnect attempts.
+    pub async fn next<M: Instrumenter>(
+        &mut self,
+        counters: &Counters<M>,
+    ) -> Result<SubscribeUpdate, crate::Error> {
+        loop {
+            let Some(msg) = self.rx.recv().await else {
+                return Err(crate::Error::ServerHangup);
+            };
+
+            match msg {
+                Message::Update(sub, update) => {
+                    let endpoint = counters.endpoint(sub.endpoint);
+                    if let Some(c) = endpoint {
+                        c.inc_updates();
+                    }
+
+                    if let Some(slot) = update_slot(&update) {
+                        self.observe_slot(sub, slot, counters);
+                    }
+
+                    if let Some(dedup) = &mut self.groups[sub.group].dedup {
+                        if !dedup.insert(&update) {
+                            continue;
+                        }
+                        if let Some(c) = endpoint {
+                            c.inc_leading();
+                        }
+                    }
+
+                    break Ok(update);
+                },
+                Message::Connected(sub) => {
+                    self.groups[sub.group].connected[sub.endpoint] = true;
+                    self.update_connected(sub.endpoint, counters);
+                },
+                Message::Disconnected(sub) => self.disconnected(sub, counters),
+                Message::Failed(sub, e) => {
+                    error!(
+                        err = %Chain(&e),
+                        endpoint = sub.endpoint,
+                        group = sub.group,
+                        "Giving up on Yellowstone endpoint"
+                    );
+
+                    // The feeder already reported its disconnect before
+                    // trying to reconnect
+                    let group = &mut self.groups[sub.group];
+                    group.feeders -= 1;
+                    if group.feeders == 0 {
+                        break Err(e.into());
+                    }
+                },
+            }
+        }
+    }
+
+    fn update_connected<M: Instrumenter>(&self, endpoint: usize, counters: &Counters<M>) {
+        if let Some(c) = counters.endpoint(endpoint) {
+            c.set_connected(self.groups.iter().any(|g| g.connected[endpoint]));
+        }
+    }
+
+    fn disconnected<M: Instrumenter>(&mut self, sub: Sub, counters: &Counters<M>) {
+        let group = &mut self.groups[sub.group];
+        let was_connected = std::mem::replace(&mut group.connected[sub.endpoint], false);
+        group.slot_lags[sub.endpoint] = 0;
+        if was_connected && !group.connected.iter().any(|&c| c) {
+            group.gap_from = group.highest_slots.iter().copied().max().flatten();
+        }
+
+        self.update_connected(sub.endpoint, counters);
+        if let Some(c) = counters.endpoint(sub.endpoint) {
+            c.inc_disconnects();
+        }
+    }
+
+    fn observe_slot<M: Instrumenter>(&mut self, sub: Sub, slot: u64, counters: &Counters<M>) {
+        let group = &mut self.groups[sub.group];
+
+        if let Some(prev) = group.gap_from.take() {
+            let missed = slot.saturating_sub(prev).saturating_sub(1);
+            if missed > 0 {
+                warn!(
+                    prev,
+                    next = slot,
+                    missed,
+                    group = sub.group,
+                    "Slots skipped while disconnected from Yellowstone"
+                );
+                counters.inc_missed_slots(missed);
+            }
+        }
+
+        let highest = &mut group.highest_slots[sub.endpoint];
+        *highest = (*highest).max(Some(slot));
+
+        let Some(leader) = group.highest_slots.iter().copied().max().flatten() else {
+            return;
+        };
+        for ((lag, &connected), &highest) in group
+            .slot_lags
+            .iter_mut()
+            .zip(&group.connected)
+            .zip(&group.highest_slots)
+        {
+            *lag = match (connected, highest) {
+                (true, Some(highest)) => leader - highest,
+                _ => 0,
+            };
+        }
+
+        // Each endpoint reports the lag of the group it trails furthest in
+        for i in 0..self.shared.endpoints.len() {
+            if let Some(c) = counters.endpoint(i) {
+                c.set_slot_lag(
+                    self.groups
+                        .iter()
+                        .map(|g| g.slot_lags[i])
+                        .max()
+                        .unwrap_or(0),
+                );
+            }
+        }
+    }
+}
+
+/// The names of the filters in a subscription request, which are the IDs of
+/// the pipelines it streams updates for.
+fn filter_names(request: &SubscribeRequest) -> impl Iterator<Item = &str> {
+    let SubscribeRequest {
+        accounts,
+        slots,
+        transactions,
+        transactions_status,
+        blocks,
+        blocks_meta,
+        entry,
+        ..
+    } = request;
+
+    accounts
+        .keys()
+        .chain(slots.keys())
+        .chain(transactions.keys())
+        .chain(transactions_status.keys())
+        .chain(blocks.keys())
+        .chain(blocks_meta.keys())
+        .chain(entry.keys())
+        .map(String::as_str)
+}
+
+/// A subscription request with no filters, used to keep a subscription open
+/// without receiving any updates.
+fn empty_request(key: &SubscriptionKey) -> SubscribeRequest {
+    SubscribeRequest {
+        commitment: key
+            .commitment
+            .map(|c| geyser::CommitmentLevel::from(c) as i32),
+        ..Filters::new(HashMap::new()).into()
+    }
+}
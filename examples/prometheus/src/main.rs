@@ -27,6 +27,19 @@ use yellowstone_vixen_parser::{
 pub struct Opts {
     #[arg(long, short)]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Replay a dead letter file through the pipelines that failed on it,
+    /// rather than streaming from Yellowstone.
+    ReplayDeadLetters {
+        /// The dead letter file to replay.
+        path: PathBuf,
+    },
 }
 
 #[derive(Debug)]
@@ -45,16 +58,24 @@ fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let Opts { config } = Opts::parse();
+    let Opts { config, command } = Opts::parse();
     let config = std::fs::read_to_string(config).expect("Error reading config file");
     let config = toml::from_str(&config).expect("Error parsing config");
 
-    vixen::Runtime::builder()
+    let runtime = vixen::Runtime::builder()
         .account(Pipeline::new(TokenExtensionProgramAccParser, [Handler]))
         .account(Pipeline::new(TokenProgramAccParser, [Handler]))
-        .instruction(Pipeline::new(TokenExtensionProgramIxParser, [Handler]))
-        .instruction(Pipeline::new(TokenProgramIxParser, [Handler]))
+        .instruction(Pipeline::for_program(TokenExtensionProgramIxParser, [
+            Handler,
+        ]))
+        .instruction(Pipeline::for_program(TokenProgramIxParser, [Handler]))
         .metrics(vixen::metrics::Prometheus)
-        .build(config)
-        .run();
+        .build(config);
+
+    match command {
+        Some(Command::ReplayDeadLetters { path }) => runtime
+            .try_replay_dead_letters(path)
+            .expect("Error replaying dead letters"),
+        None => runtime.run(),
+    }
 }
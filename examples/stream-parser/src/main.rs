@@ -12,6 +12,7 @@ use std::path::PathBuf;
 use clap::Parser as _;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use yellowstone_vixen::{self as vixen, proto::parser, vixen_core::proto::Proto};
+#[allow(unused_imports)] // Parsers can be enabled in main below
 use yellowstone_vixen_parser::{
     orca::{AccountParser as OrcaAccParser, InstructionParser as OrcaIxParser},
     pumpfun::InstructionParser as PumpFunIxParser,